@@ -1,119 +1,172 @@
 /// Currently a modified version of `poem`'s default websocket-chat example
-use cassandra_cpp::*;
 use futures_util::{SinkExt, StreamExt};
 use poem::{
     get, handler,
     listener::TcpListener,
     web::{
         websocket::{Message, WebSocket},
-        Data, Path,
+        Data,
     },
-    EndpointExt, IntoResponse, Route, Server,
+    http::StatusCode,
+    EndpointExt, IntoResponse, Request, Response, Route, Server,
 };
-use rustflake::Snowflake;
+use serde::Deserialize;
 use serde_json::Value;
 use std::result::Result;
-use serde::{Serialize, Deserialize};
-use chrono::{DateTime, Local};
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct MessageObj {
-    pub id: i64,
-    pub channel: i64,
-    pub author: i64,
-    pub content: String,
-}
-
-pub fn gen_id() -> i64 {
-    static STATE: std::sync::Mutex<Option<Snowflake>> = std::sync::Mutex::new(None);
+use std::sync::Arc;
+use common::{
+    authenticate, connect, gen_id, roles, set_worker_id, still_valid, Claims, Database, Permission,
+    ServerKeys, CHATTERBOX_WORKER,
+};
 
-    STATE
-        .lock()
-        .unwrap()
-        .get_or_insert_with(|| Snowflake::new(1_564_790_400_000, 2, 1))
-        .generate()
+/// A message sent by a client, to be posted in `channel`
+#[derive(Deserialize)]
+struct Incoming {
+    channel: i64,
+    content: String,
 }
 
-const KEYSPC: &'static str = "bsk";
+/// Whether the user (or bot) some claims came from may send a message: that needs the
+/// `send_messages` permission in the channel (after its overrides), and `mention_everyone`
+/// to mention everyone in it
+async fn may_send(db: &dyn Database, claims: &Claims, msg: &common::Message) -> bool {
+    match roles::channel_permissions(db, claims, msg.channel).await {
+        Ok(permissions) => {
+            permissions.contains(Permission::SendMessages)
+                && (!msg.content.contains("@everyone") || permissions.contains(Permission::MentionEveryone))
+        }
+        Err(_) => false,
+    }
+}
 
-fn setup_db() -> Session {
-    let contact_points = "127.0.0.1";
-    let mut cluster = Cluster::default();
-    cluster.set_contact_points(contact_points).unwrap();
-    cluster.set_load_balance_round_robin();
-    cluster.connect().unwrap()
+/// Whether the user (or bot) some claims came from may receive a message: they have to be
+/// able to read its channel (see `roles::can_read`)
+async fn may_receive(db: &dyn Database, claims: &Claims, msg: &common::Message) -> bool {
+    roles::can_read(db, claims, msg.channel).await.unwrap_or(false)
 }
 
 #[handler]
-fn ws(  
+async fn ws(  
     ws: WebSocket,
+    req: &Request,
     sender: Data<&tokio::sync::broadcast::Sender<String>>,
-) -> impl IntoResponse {
+    db: Data<&Arc<dyn Database>>,
+    keys: Data<&ServerKeys>,
+) -> Response {
+    // The JWT from scuttlebutt's `/login` (or a bot's API token) either comes in the
+    // `Authorization` header, or (for clients that can't set headers on a websocket)
+    // as the first frame
+    let header_claims = match req.header("Authorization") {
+        Some(token) => match authenticate(&keys, db.as_ref(), token).await {
+            None => return StatusCode::UNAUTHORIZED.into_response(),
+            claims => claims,
+        },
+        None => None,
+    };
     let sender = sender.clone();
+    let db = db.clone();
+    let keys = keys.clone();
     ws.on_upgrade(move |socket| async move {
-    let mut receiver = sender.subscribe();
         let (mut sink, mut stream) = socket.split();
 
-        tokio::spawn(async move {
-            let sess = setup_db();
-            let mut user: Option<Value> = None;
-            while let Some(Ok(msg)) = stream.next().await {
-                if let Message::Text(auth) = msg {                  
-                    let req: Value = serde_json::from_str(&auth).unwrap();
-                    let res = sess.execute(&stmt!(&format!(
-                        "SELECT hash FROM {}.users WHERE id={};",
-                        KEYSPC, req["id"].as_i64().unwrap(),
-                    ))).wait().unwrap();
-                    let row = res.first_row().unwrap();
-                    let db_hash: String = row.get(0).unwrap();
-                    if hex::decode(db_hash).unwrap() != hex::decode(req["hash"].as_str().unwrap()).unwrap() {
-                        return;
+        let claims = match header_claims {
+            Some(claims) => claims,
+            None => loop {
+                match stream.next().await {
+                    Some(Ok(Message::Text(auth))) => {
+                        let req: Value = serde_json::from_str(&auth).unwrap_or_default();
+                        let token = req["token"].as_str().unwrap_or_default();
+                        match authenticate(&keys, db.as_ref(), token).await {
+                            Some(claims) => break claims,
+                            None => return,
+                        }
                     }
-                    user = Some(req);
-                    break
+                    Some(Ok(_)) => continue,
+                    _ => return,
+                }
+            },
+        };
+        let claims = Arc::new(claims);
+        let mut receiver = sender.subscribe();
+        // Set by whichever half stops first, so the other stops too and the socket closes
+        let close = Arc::new(tokio::sync::watch::channel(false).0);
+
+        let reader_db = db.clone();
+        let reader_claims = claims.clone();
+        let reader_close = close.clone();
+        tokio::spawn(async move {
+            let db = reader_db;
+            let claims = reader_claims;
+            let mut closed = reader_close.subscribe();
+            // The socket outlives the (short-lived) access token it was opened with,
+            // but not the login session (or API token): logging out closes it
+            loop {
+                let mesg = tokio::select! {
+                    _ = closed.changed() => break,
+                    mesg = stream.next() => match mesg {
+                        Some(Ok(mesg)) => mesg,
+                        _ => break,
+                    },
+                };
+                if !still_valid(db.as_ref(), &claims).await {
+                    break;
                 }
-            }
-            while let Some(Ok(mesg)) = stream.next().await {
                 if let Message::Text(text) = mesg {
-                    let id = gen_id();
-                    let req: Value = serde_json::from_str(&text).unwrap();
-                    let msg = MessageObj {
-                        id,
-                        content: req["content"].as_str().unwrap().to_string(),
-                        author: user.clone().unwrap()["id"].as_i64().unwrap(),
-                        channel: req["channel"].as_i64().unwrap(),
+                    // Frames that aren't messages are ignored
+                    let Ok(incoming) = serde_json::from_str::<Incoming>(&text) else {
+                        continue;
                     };
-                    let mut stmt = stmt!(&format!(
-                        "INSERT INTO {}.messages (channel, id, author, content) VALUES ({},{},{},?);",
-                        KEYSPC, msg.channel, gen_id(), msg.author
-                    ));
-					stmt.bind(0, msg.content.as_str()).unwrap();
-				    sess.execute(&stmt).wait().unwrap();
+                    let msg = common::Message {
+                        id: gen_id(),
+                        content: incoming.content,
+                        author: claims.id,
+                        channel: incoming.channel,
+                        thread: None,
+                    };
+                    if !may_send(db.as_ref(), &claims, &msg).await {
+                        continue;
+                    }
+                    if db.create_message(msg.clone()).await.is_err() {
+                        break;
+                    }
                     if sender.send(serde_json::to_string(&msg).unwrap()).is_err() {
                         break;
                     }
                 }
             }
+            reader_close.send_replace(true);
         });
 
         tokio::spawn(async move {
-            let sess = setup_db();
-            while let Ok(msg) = receiver.recv().await {
-                let req: Value = serde_json::from_str(&msg).unwrap();
-                let res = sess.execute(&stmt!(&format!(
-                    "SELECT members FROM {}.channels WHERE id={};", KEYSPC, req["channel"].as_i64().unwrap(),
-                ))).wait().unwrap();
-                let row = res.first_row().unwrap();
-                let members: SetIterator = row.get(0).unwrap();
-                if !members.map(|i| i.get_i64().unwrap()).collect::<Vec<i64>>().contains(&req["author"].as_i64().unwrap()) {
-					continue
-				}
+            let mut closed = close.subscribe();
+            loop {
+                let msg = tokio::select! {
+                    _ = closed.changed() => break,
+                    msg = receiver.recv() => match msg {
+                        Ok(msg) => msg,
+                        Err(_) => break,
+                    },
+                };
+                let Ok(message) = serde_json::from_str::<common::Message>(&msg) else {
+                    continue;
+                };
+                if !may_receive(db.as_ref(), &claims, &message).await {
+                    continue;
+                }
+                // Nothing more goes out once the session has ended, even if the client
+                // never sends anything
+                if !still_valid(db.as_ref(), &claims).await {
+                    break;
+                }
                 if sink.send(Message::Text(msg)).await.is_err() {
                     break;
                 }
             }
+            close.send_replace(true);
+            let _ = sink.close().await;
         });
     })
+    .into_response()
 }
 
 #[tokio::main]
@@ -122,86 +175,90 @@ async fn main() -> Result<(), std::io::Error> {
         std::env::set_var("RUST_LOG", "poem=debug");
     }
     tracing_subscriber::fmt::init();
+    set_worker_id(CHATTERBOX_WORKER);
+
+    // Every socket shares this one connection (pool); `BSK_DB` picks the backend
+    // the same way it does for scuttlebutt
+    let db: Arc<dyn Database> = connect(&std::env::var("BSK_DB").unwrap_or_default()).await.into();
+    let keys = ServerKeys::from_env()
+        .expect("BSK_SERVER_KEY or BSK_SERVER_KEY_FILE must be set to the keys scuttlebutt signs tokens with")
+        .unwrap_or_else(|e| panic!("Invalid server keys: {e}"));
 
     let app = Route::new().at(
         "/",
-        get(ws.data(tokio::sync::broadcast::channel::<String>(32).0)),
+        get(ws
+            .data(tokio::sync::broadcast::channel::<String>(32).0)
+            .data(db)
+            .data(keys)),
     );
 
     Server::new(TcpListener::bind("127.0.0.1:3001")).run(app).await
 }
 
-// #[cfg(test)]
-// pub mod tests {
-//  use super::*;
-//  use websocket::{ClientBuilder, Message};
-    
-//  #[tokio::test]
-//  async fn simple_messaging_flow() {              
-//      let sess = setup_db();      
-//         sess.execute(&stmt!(&format!(
-//             "CREATE KEYSPACE IF NOT EXISTS test \
-//              WITH replication = {{'class':'SimpleStrategy', 'replication_factor': 1}}"
-//         ))).wait().unwrap();
-//         sess.execute(&stmt!(&format!(
-//             "CREATE TABLE IF NOT EXISTS test.users \
-//              (id bigint PRIMARY KEY, name text, email text, hash text);"
-//         ))).wait().unwrap();
-//         sess.execute(&stmt!(&format!(
-//             "CREATE TABLE IF NOT EXISTS test.groups \
-//              (id bigint PRIMARY KEY, name text, members set<bigint>, is_dm boolean, \
-//              channels set<bigint>, admin set<bigint>, owner bigint);"
-//         ))).wait().unwrap();
-
-//      sess.execute(&stmt!(
-//          "INSERT INTO test.users (id, name, email, hash) VALUES (1234, 'steve', 'no@you.com', 'abc');"
-//      )).wait().unwrap();
-//      sess.execute(&stmt!(
-//          "INSERT INTO test.users (id, name, email, hash) VALUES (1235, 'erica', 'yes@you.com', 'abc3');"
-//      )).wait().unwrap();
-//      sess.execute(&stmt!(
-//          "INSERT INTO test.channels (id, group, name, members, private) VALUES (1111, 2222, 'main', {1234, 1235}, false);"
-//      )).wait().unwrap();
-        
-//      // HACK HACK HACK
-//      std::process::Command::new("cargo")
-//             .args(["run", "-p", "chatterbox"])
-//             .spawn();
-//      std::thread::sleep(std::time::Duration::from_secs(10));
-        
-//      let steve = std::thread::spawn(|| {                     
-//          let mut client = ClientBuilder::new("ws://127.0.0.1:3001")
-//              .unwrap()
-//              .connect_insecure()
-//              .unwrap();
-            
-//          let message = Message::text("{\"hash\": \"abc\", \"id\": \"1234\"}");
-//          client.send_message(&message).unwrap();
-//          let message = Message::text("{\"content\": \"Hello\", \"channel\": \"1111\"}");
-//          client.send_message(&message).unwrap();
-//      });
-
-//      let erica = std::thread::spawn(|| {                     
-//          let mut client = ClientBuilder::new("ws://127.0.0.1:3001/")
-//              .unwrap()
-//              .connect_insecure()
-//              .unwrap();
-            
-//          let message = Message::text("{\"hash\": \"abc3\", \"id\": \"1235\"}");
-//          client.send_message(&message).unwrap();
-//          let recv = client.recv_message().unwrap();
-//          if let websocket::OwnedMessage::Text(msg) = recv {
-//              let req: MessageObj = serde_json::from_str(&msg).unwrap();
-//              println!("{}", req.content);
-//              assert_eq!(req.author, 1234);
-//              assert_eq!(req.content, "Hello");
-//              assert_eq!(req.channel, 1111);
-//          } else {
-//              panic!("Got non-textual message!")
-//          }
-//      });
-        
-//      steve.join().unwrap();
-//      erica.join().unwrap();
-//  }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{ChannelOverride, InMemory, Scope, ScopeKind};
+
+    fn claims(id: i64, scopes: Option<Vec<Scope>>) -> Claims {
+        Claims { id, sid: 0, exp: chrono::Local::now(), scopes }
+    }
+
+    fn message(channel: i64, author: i64, content: &str) -> common::Message {
+        common::Message { id: gen_id(), channel, author, content: content.to_string(), thread: None }
+    }
+
+    /// A group (1, owned by 10) with members 20 and 30, a public channel (2) and a private
+    /// one (3) that only 20 is in
+    async fn setup() -> InMemory {
+        let db = InMemory::new();
+        db.create_group(1, 10, "test".to_string(), false).await.unwrap();
+        db.create_channel(2, 1, 10, "general".to_string()).await.unwrap();
+        db.create_channel(3, 1, 10, "secret".to_string()).await.unwrap();
+        for uid in [20, 30] {
+            db.add_group_member(1, uid).await.unwrap();
+            db.add_channel_member(2, uid).await.unwrap();
+        }
+        db.set_channel_private(3, true).await.unwrap();
+        db.add_channel_member(3, 20).await.unwrap();
+        db
+    }
+
+    #[tokio::test]
+    async fn test_may_send() {
+        let db = setup().await;
+        assert!(may_send(&db, &claims(30, None), &message(2, 30, "hi")).await);
+        assert!(!may_send(&db, &claims(40, None), &message(2, 40, "hi")).await);
+        // Private channels are only for their members (and the owner)
+        assert!(may_send(&db, &claims(20, None), &message(3, 20, "hi")).await);
+        assert!(!may_send(&db, &claims(30, None), &message(3, 30, "hi")).await);
+        assert!(may_send(&db, &claims(10, None), &message(3, 10, "hi")).await);
+
+        // Mentioning everyone needs its own permission
+        let deny = ChannelOverride { channel: 2, target: 1, allow: vec![], deny: vec![Permission::MentionEveryone] };
+        db.set_channel_override(deny).await.unwrap();
+        assert!(!may_send(&db, &claims(30, None), &message(2, 30, "@everyone hi")).await);
+
+        let send = Scope { kind: ScopeKind::SendMessages, group: 1 };
+        let read = Scope { kind: ScopeKind::ReadMessages, group: 1 };
+        assert!(may_send(&db, &claims(20, Some(vec![send])), &message(3, 20, "hi")).await);
+        assert!(!may_send(&db, &claims(20, Some(vec![read])), &message(3, 20, "hi")).await);
+    }
+
+    #[tokio::test]
+    async fn test_may_receive() {
+        let db = setup().await;
+        // Who a message goes to doesn't depend on who sent it
+        let public = message(2, 10, "hi");
+        let private = message(3, 20, "psst");
+        for (uid, expected) in [(10, (true, true)), (20, (true, true)), (30, (true, false)), (40, (false, false))] {
+            let got = (may_receive(&db, &claims(uid, None), &public).await, may_receive(&db, &claims(uid, None), &private).await);
+            assert_eq!(got, expected, "{uid}");
+        }
+
+        let read = Scope { kind: ScopeKind::ReadMessages, group: 1 };
+        let send = Scope { kind: ScopeKind::SendMessages, group: 1 };
+        assert!(may_receive(&db, &claims(30, Some(vec![read])), &public).await);
+        assert!(!may_receive(&db, &claims(30, Some(vec![send])), &public).await);
+    }
+}
@@ -0,0 +1,68 @@
+-- Initial schema: the Cassandra tables, with every set<bigint> turned into a join table.
+
+CREATE TABLE users (
+    id BIGINT PRIMARY KEY,
+    name TEXT NOT NULL,
+    email TEXT NOT NULL,
+    hash TEXT NOT NULL
+);
+
+CREATE TABLE groups (
+    id BIGINT PRIMARY KEY,
+    name TEXT NOT NULL,
+    owner BIGINT NOT NULL,
+    is_dm BOOLEAN NOT NULL
+);
+
+CREATE TABLE channels (
+    id BIGINT PRIMARY KEY,
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    name TEXT NOT NULL,
+    private BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE group_members (
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    user_id BIGINT NOT NULL REFERENCES users (id),
+    PRIMARY KEY (group_id, user_id)
+);
+
+CREATE TABLE group_admins (
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    user_id BIGINT NOT NULL REFERENCES users (id),
+    PRIMARY KEY (group_id, user_id)
+);
+
+CREATE TABLE group_channels (
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    channel_id BIGINT NOT NULL REFERENCES channels (id),
+    PRIMARY KEY (group_id, channel_id)
+);
+
+CREATE TABLE channel_members (
+    channel_id BIGINT NOT NULL REFERENCES channels (id),
+    user_id BIGINT NOT NULL REFERENCES users (id),
+    PRIMARY KEY (channel_id, user_id)
+);
+
+CREATE TABLE user_groups (
+    user_id BIGINT NOT NULL REFERENCES users (id),
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    PRIMARY KEY (user_id, group_id)
+);
+
+CREATE TABLE user_dms (
+    user_id BIGINT NOT NULL REFERENCES users (id),
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    PRIMARY KEY (user_id, group_id)
+);
+
+CREATE TABLE messages (
+    id BIGINT PRIMARY KEY,
+    channel BIGINT NOT NULL REFERENCES channels (id),
+    author BIGINT NOT NULL,
+    content TEXT NOT NULL,
+    thread BIGINT
+);
+
+CREATE INDEX messages_by_channel ON messages (channel, id DESC);
@@ -0,0 +1,16 @@
+-- Login sessions (which refresh tokens belong to) and the revocation list checked
+-- for every authenticated request.
+
+CREATE TABLE sessions (
+    id BIGINT PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users (id),
+    refresh_hash TEXT NOT NULL,
+    expires BIGINT NOT NULL
+);
+
+CREATE INDEX sessions_by_user ON sessions (user_id);
+
+CREATE TABLE revoked_sessions (
+    id BIGINT PRIMARY KEY,
+    until BIGINT NOT NULL
+);
@@ -0,0 +1,9 @@
+-- TOTP two-factor authentication settings, one row per enrolled user.
+
+CREATE TABLE user_totp (
+    user_id BIGINT PRIMARY KEY REFERENCES users (id),
+    secret TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL,
+    last_step BIGINT NOT NULL,
+    recovery_codes TEXT[] NOT NULL
+);
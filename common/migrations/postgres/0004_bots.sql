@@ -0,0 +1,19 @@
+-- Bot users (which belong to a human user) and their API tokens.
+
+CREATE TABLE bots (
+    id BIGINT PRIMARY KEY REFERENCES users (id),
+    owner BIGINT NOT NULL REFERENCES users (id)
+);
+
+CREATE INDEX bots_by_owner ON bots (owner);
+
+-- scopes is space-separated, see `Scope::join`
+CREATE TABLE api_tokens (
+    id BIGINT PRIMARY KEY,
+    bot BIGINT NOT NULL REFERENCES users (id),
+    name TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    scopes TEXT NOT NULL
+);
+
+CREATE INDEX api_tokens_by_bot ON api_tokens (bot);
@@ -0,0 +1,10 @@
+-- Identities from OpenID Connect providers, linked to the users they log in as.
+
+CREATE TABLE identities (
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id BIGINT NOT NULL REFERENCES users (id),
+    PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX identities_by_user ON identities (user_id);
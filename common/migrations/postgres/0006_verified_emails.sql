@@ -0,0 +1,6 @@
+-- The email address each user last verified, if any.
+
+CREATE TABLE verified_emails (
+    user_id BIGINT PRIMARY KEY REFERENCES users (id),
+    email TEXT NOT NULL
+);
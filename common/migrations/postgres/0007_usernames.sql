@@ -0,0 +1,24 @@
+-- Claimed username and email keys (see `names`), so that both are unique ignoring case.
+-- Keys are compared byte by byte, which is what searching by prefix relies on.
+
+CREATE TABLE usernames (
+    key TEXT COLLATE "C" PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users (id)
+);
+
+CREATE INDEX usernames_by_user ON usernames (user_id);
+
+CREATE TABLE emails (
+    key TEXT COLLATE "C" PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users (id)
+);
+
+CREATE INDEX emails_by_user ON emails (user_id);
+
+-- Users from before this keep their names, but where several share one (or an email),
+-- only the first made can be found by it until the others change theirs.
+INSERT INTO usernames (key, user_id)
+SELECT DISTINCT ON (lower(name)) lower(name), id FROM users ORDER BY lower(name), id;
+
+INSERT INTO emails (key, user_id)
+SELECT DISTINCT ON (lower(email)) lower(email), id FROM users WHERE email <> '' ORDER BY lower(email), id;
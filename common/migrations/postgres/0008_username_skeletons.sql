@@ -0,0 +1,4 @@
+-- Username keys are now skeletons (see `names::username_key`), which SQL can't make.
+-- The claims are made again by `backfill_names` once migrations are done.
+
+DELETE FROM usernames;
@@ -0,0 +1,20 @@
+-- Roles in groups, and the members they've been given to.
+
+-- permissions is a bitset, see `Permission::bit`
+CREATE TABLE roles (
+    id BIGINT PRIMARY KEY,
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    name TEXT NOT NULL,
+    permissions BIGINT NOT NULL
+);
+
+CREATE INDEX roles_by_group ON roles (group_id);
+
+CREATE TABLE member_roles (
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    user_id BIGINT NOT NULL REFERENCES users (id),
+    role_id BIGINT NOT NULL REFERENCES roles (id),
+    PRIMARY KEY (group_id, user_id, role_id)
+);
+
+CREATE INDEX member_roles_by_role ON member_roles (role_id);
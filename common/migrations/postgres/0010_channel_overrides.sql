@@ -0,0 +1,11 @@
+-- Permissions channels allow or deny roles and users.
+
+-- allow and deny are bitsets, see `Permission::bit`. target is a role or a user, so it
+-- doesn't reference either.
+CREATE TABLE channel_overrides (
+    channel_id BIGINT NOT NULL REFERENCES channels (id),
+    target BIGINT NOT NULL,
+    allow BIGINT NOT NULL,
+    deny BIGINT NOT NULL,
+    PRIMARY KEY (channel_id, target)
+);
@@ -0,0 +1,18 @@
+-- Users banned from groups, and members timed out in them.
+
+-- expires and until are Unix timestamps; a ban without expires is for good
+CREATE TABLE bans (
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    user_id BIGINT NOT NULL REFERENCES users (id),
+    reason TEXT NOT NULL,
+    expires BIGINT,
+    PRIMARY KEY (group_id, user_id)
+);
+
+CREATE TABLE timeouts (
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    user_id BIGINT NOT NULL REFERENCES users (id),
+    reason TEXT NOT NULL,
+    until BIGINT NOT NULL,
+    PRIMARY KEY (group_id, user_id)
+);
@@ -0,0 +1,14 @@
+-- What was done in each group, by whom and why.
+
+-- actor and target aren't references, so entries outlive what they're about
+CREATE TABLE audit_log (
+    id BIGINT PRIMARY KEY,
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    actor BIGINT NOT NULL,
+    action TEXT NOT NULL,
+    target BIGINT NOT NULL,
+    timestamp BIGINT NOT NULL,
+    reason TEXT NOT NULL
+);
+
+CREATE INDEX audit_log_group ON audit_log (group_id, id);
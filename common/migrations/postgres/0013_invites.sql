@@ -0,0 +1,14 @@
+-- Invites to groups, which anyone with the code can accept.
+
+-- expires is a Unix timestamp; an invite without expires or max_uses doesn't run out
+CREATE TABLE invites (
+    code TEXT PRIMARY KEY,
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    channel BIGINT,
+    creator BIGINT NOT NULL,
+    expires BIGINT,
+    max_uses BIGINT,
+    uses BIGINT NOT NULL
+);
+
+CREATE INDEX invites_group ON invites (group_id);
@@ -0,0 +1,17 @@
+-- The group directory, and requests to join the groups listed in it.
+
+ALTER TABLE groups
+    ADD COLUMN discoverable BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN description TEXT NOT NULL DEFAULT '',
+    ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX groups_discoverable ON groups (id) WHERE discoverable;
+
+-- timestamp is a Unix timestamp
+CREATE TABLE join_requests (
+    group_id BIGINT NOT NULL REFERENCES groups (id),
+    user_id BIGINT NOT NULL REFERENCES users (id),
+    message TEXT NOT NULL,
+    timestamp BIGINT NOT NULL,
+    PRIMARY KEY (group_id, user_id)
+);
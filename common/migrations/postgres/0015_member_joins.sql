@@ -0,0 +1,4 @@
+-- When each member joined their groups, in milliseconds since the Unix epoch. Members
+-- from before this count as having joined at 0.
+
+ALTER TABLE group_members ADD COLUMN joined BIGINT NOT NULL DEFAULT 0;
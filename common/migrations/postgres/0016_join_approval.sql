@@ -0,0 +1,4 @@
+-- Whether users who find a group in the directory have to ask to join it. Groups listed
+-- before this kept needing approval.
+
+ALTER TABLE groups ADD COLUMN requires_approval BOOLEAN NOT NULL DEFAULT TRUE;
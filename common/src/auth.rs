@@ -0,0 +1,340 @@
+use chrono::{DateTime, Local};
+use hmac::{Hmac, Mac};
+use jwt::{SignWithStore, VerifyWithStore};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use crate::db::Database;
+use crate::models::{Scope, ScopeKind};
+
+/// A single key used to sign and verify the JWTs handed out by scuttlebutt's `/login`
+pub type ServerKey = Hmac<Sha256>;
+
+/// Prefix of bots' API tokens (`bsk_<token id>_<secret>`), which tells them apart from JWTs
+pub const API_TOKEN_PREFIX: &str = "bsk_";
+
+/// Struct representing the ID of the authorized users, the login session the token
+/// belongs to and the expiration date of the token
+/// The serialized form of this struct forms the content portion of the JWT returned by /login
+///
+/// Claims are also made for bots' API tokens, in which case `sid` is the ID of the token
+/// and `scopes` is set.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Claims {
+    pub id: i64,
+    pub sid: i64,
+    pub exp: DateTime<Local>,
+    // What an API token is allowed to do. Users' tokens can do anything their user can.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<Scope>>,
+}
+
+impl Claims {
+    /// Whether the token these claims came from has expired
+    pub fn expired(&self) -> bool {
+        self.exp < Local::now()
+    }
+
+    /// Whether these claims came from a bot's API token
+    pub fn is_bot(&self) -> bool {
+        self.scopes.is_some()
+    }
+
+    /// Whether the token these claims came from may do something in a group.
+    /// Always true for users' tokens; the usual membership/admin checks still apply.
+    ///
+    /// Arguments:
+    /// - `kind`: the kind of thing being done
+    /// - `group`: the ID of the group it's being done in
+    pub fn allows(&self, kind: ScopeKind, group: i64) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.contains(&Scope { kind, group }),
+            None => true,
+        }
+    }
+}
+
+/// Hash the secret part of an API token for storage. The secrets are long and random,
+/// so unlike passwords a fast hash is fine.
+///
+/// Arguments:
+/// - `secret`: the part of the token after its ID
+pub fn hash_api_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// The keys tokens can be signed with, by key ID (the `kid` in a token's header).
+///
+/// New tokens are always signed with the current key, but tokens signed with any of
+/// the others are still accepted, so keys can be rotated without logging everyone out:
+/// add a new key in front of the old one, then drop the old one once its tokens expire.
+#[derive(Clone)]
+pub struct ServerKeys {
+    // ID of the key new tokens are signed with
+    current: String,
+    keys: BTreeMap<String, ServerKey>,
+}
+
+impl ServerKeys {
+    /// Create a key set with a single key, used for signing
+    ///
+    /// Arguments:
+    /// - `kid`: the ID of the key
+    /// - `secret`: the key itself
+    pub fn new(kid: &str, secret: &[u8]) -> Self {
+        let mut keys = BTreeMap::new();
+        keys.insert(kid.to_string(), ServerKey::new_from_slice(secret).unwrap());
+        Self { current: kid.to_string(), keys }
+    }
+
+    /// Add a key that tokens are still accepted with but no longer signed with
+    ///
+    /// Arguments:
+    /// - `kid`: the ID of the key
+    /// - `secret`: the key itself
+    pub fn add(&mut self, kid: &str, secret: &[u8]) {
+        self.keys.insert(kid.to_string(), ServerKey::new_from_slice(secret).unwrap());
+    }
+
+    /// Parse a key file: one `<kid> <secret>` pair per line, the first of which is used
+    /// for signing. Blank lines and lines starting with `#` are ignored.
+    ///
+    /// Arguments:
+    /// - `contents`: the contents of the key file
+    pub fn parse(contents: &str) -> std::result::Result<Self, String> {
+        let mut keys: Option<Self> = None;
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let (kid, secret) = line.split_once(char::is_whitespace)
+                .ok_or_else(|| format!("expected `<kid> <secret>`, found `{line}`"))?;
+            let secret = secret.trim().as_bytes();
+            match keys.as_mut() {
+                None => keys = Some(Self::new(kid, secret)),
+                Some(keys) if keys.keys.contains_key(kid) => return Err(format!("duplicate kid `{kid}`")),
+                Some(keys) => keys.add(kid, secret),
+            }
+        }
+        keys.ok_or_else(|| "no keys found".to_string())
+    }
+
+    /// Load the keys shared by scuttlebutt and chatterbox. Either:
+    /// - `BSK_SERVER_KEY_FILE` names a key file (see `parse`), or
+    /// - `BSK_SERVER_KEY` holds a single secret (with the key ID `default`)
+    ///
+    /// Returns None if neither is set, and an error if the key file can't be used.
+    pub fn from_env() -> Option<std::result::Result<Self, String>> {
+        if let Ok(path) = std::env::var("BSK_SERVER_KEY_FILE") {
+            return Some(std::fs::read_to_string(&path)
+                .map_err(|e| format!("couldn't read {path}: {e}"))
+                .and_then(|contents| Self::parse(&contents)));
+        }
+        let secret = std::env::var("BSK_SERVER_KEY").ok()?;
+        Some(Ok(Self::new("default", secret.as_bytes())))
+    }
+
+    /// Sign claims with the current key, recording its ID in the token's header.
+    ///
+    /// Usually the claims are `Claims`, but other short-lived tokens (like the challenge
+    /// tokens for two-factor logins) can be signed too, as long as they can't be
+    /// deserialized as `Claims`.
+    pub fn sign(&self, claims: &impl Serialize) -> std::result::Result<String, jwt::Error> {
+        (self.current.as_str(), claims).sign_with_store(&self.keys)
+    }
+
+    /// Check a JWT's signature and deserialize its claims, without checking anything else
+    /// (callers are responsible for checking expiration dates).
+    ///
+    /// Arguments:
+    /// - `token`: the token to decode
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+        token.verify_with_store(&self.keys).ok()
+    }
+
+    /// Check a JWT's signature and expiration date.
+    ///
+    /// Returns None if the token is malformed, wasn't signed with one of the keys or
+    /// has expired, otherwise returns its claims.
+    ///
+    /// Arguments:
+    /// - `token`: the token, as returned by `/login`
+    pub fn verify(&self, token: &str) -> Option<Claims> {
+        let claims: Claims = self.decode(token)?;
+        if claims.expired() {
+            return None;
+        }
+        Some(claims)
+    }
+}
+
+/// Check a JWT like `ServerKeys::verify`, and also check that the session it belongs to
+/// hasn't been revoked (by logging out, changing passwords, etc.) since it was issued.
+/// Bots' API tokens are checked against the database instead.
+///
+/// Arguments:
+/// - `keys`: the keys the token could have been signed with
+/// - `db`: the database holding the revocation list and API tokens
+/// - `token`: the token, as returned by `/login` or `/refresh`, or an API token
+pub async fn authenticate(keys: &ServerKeys, db: &dyn Database, token: &str) -> Option<Claims> {
+    if let Some(token) = token.strip_prefix(API_TOKEN_PREFIX) {
+        return authenticate_api_token(db, token).await;
+    }
+    let claims = keys.verify(token)?;
+    if claims.is_bot() {
+        return None;
+    }
+    matches!(db.is_session_revoked(claims.sid).await, Ok(false)).then_some(claims)
+}
+
+/// Look up an API token (without its prefix) and make claims for its bot
+async fn authenticate_api_token(db: &dyn Database, token: &str) -> Option<Claims> {
+    let (id, secret) = token.split_once('_')?;
+    let token = db.get_api_token(id.parse().ok()?).await.ok()?;
+    if token.hash != hash_api_secret(secret) {
+        return None;
+    }
+    Some(Claims {
+        id: token.bot,
+        sid: token.id,
+        // API tokens don't expire, they get deleted
+        exp: Local::now() + chrono::Duration::days(365 * 100),
+        scopes: Some(token.scopes),
+    })
+}
+
+/// Whether the session (or API token) some claims came from is still valid: for
+/// long-lived connections, which should keep checking after `authenticate`. These outlive
+/// both the access token and its entry in the revocation list, so the session itself has
+/// to still be there (and not have expired).
+///
+/// Arguments:
+/// - `db`: the database holding the sessions and API tokens
+/// - `claims`: the claims returned by `authenticate`
+pub async fn still_valid(db: &dyn Database, claims: &Claims) -> bool {
+    if claims.is_bot() {
+        return db.get_api_token(claims.sid).await.is_ok();
+    }
+    match db.get_session(claims.sid).await {
+        Ok(session) => session.user == claims.id && session.expires > chrono::Utc::now().timestamp(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use chrono::Duration;
+    use jwt::SignWithKey;
+
+    use super::*;
+
+    fn claims(exp: Duration) -> Claims {
+        Claims { id: 5, sid: 6, exp: Local::now() + exp, scopes: None }
+    }
+
+    #[test]
+    fn test_verify() {
+        let keys = ServerKeys::new("a", b"secret");
+        let token = keys.sign(&claims(Duration::days(1))).unwrap();
+        assert_eq!(keys.verify(&token).unwrap().id, 5);
+
+        assert!(ServerKeys::new("a", b"other").verify(&token).is_none());
+        assert!(ServerKeys::new("b", b"secret").verify(&token).is_none());
+        assert!(keys.verify("not.a.token").is_none());
+
+        let expired = keys.sign(&claims(-Duration::seconds(1))).unwrap();
+        assert!(keys.verify(&expired).is_none());
+
+        // Tokens without a `kid` can't be matched to a key
+        let key = ServerKey::new_from_slice(b"secret").unwrap();
+        let no_kid = claims(Duration::days(1)).sign_with_key(&key).unwrap();
+        assert!(keys.verify(&no_kid).is_none());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = ServerKeys::parse("1 old-secret").unwrap();
+        let old_token = old.sign(&claims(Duration::days(1))).unwrap();
+
+        let new = ServerKeys::parse("# newest first\n2 new-secret\n\n1 old-secret\n").unwrap();
+        let new_token = new.sign(&claims(Duration::days(1))).unwrap();
+        assert_eq!(new.verify(&old_token).unwrap().id, 5);
+        assert_eq!(new.verify(&new_token).unwrap().id, 5);
+        assert!(old.verify(&new_token).is_none());
+
+        let retired = ServerKeys::parse("2 new-secret").unwrap();
+        assert!(retired.verify(&old_token).is_none());
+        assert!(retired.verify(&new_token).is_some());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(ServerKeys::parse("").is_err());
+        assert!(ServerKeys::parse("# just a comment").is_err());
+        assert!(ServerKeys::parse("missing-secret").is_err());
+        assert!(ServerKeys::parse("1 a\n1 b").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let db = crate::InMemory::new();
+        let keys = ServerKeys::new("a", b"secret");
+        let token = keys.sign(&claims(Duration::days(1))).unwrap();
+        assert_eq!(authenticate(&keys, &db, &token).await.unwrap().id, 5);
+        db.revoke_session(6, chrono::Utc::now().timestamp() + 60).await.unwrap();
+        assert!(authenticate(&keys, &db, &token).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_still_valid() {
+        let db = crate::InMemory::new();
+        let claims = claims(Duration::days(1));
+        assert!(!still_valid(&db, &claims).await);
+        let session = |user, expires| crate::AuthSession { id: 6, user, refresh_hash: String::new(), expires };
+        let later = chrono::Utc::now().timestamp() + 60;
+        db.create_session(session(5, later)).await.unwrap();
+        assert!(still_valid(&db, &claims).await);
+
+        // Ending the session ends it for good, not just while it's on the revocation list
+        db.delete_session(6).await.unwrap();
+        assert!(!still_valid(&db, &claims).await);
+        db.create_session(session(5, later - 120)).await.unwrap();
+        assert!(!still_valid(&db, &claims).await);
+        db.delete_session(6).await.unwrap();
+        db.create_session(session(4, later)).await.unwrap();
+        assert!(!still_valid(&db, &claims).await);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_api_token() {
+        let db = crate::InMemory::new();
+        let keys = ServerKeys::new("a", b"secret");
+        let scopes = vec![Scope { kind: ScopeKind::ReadMessages, group: 7 }];
+        db.create_api_token(crate::BotToken {
+            id: 3,
+            bot: 5,
+            name: "t".to_string(),
+            hash: hash_api_secret("hunter2"),
+            scopes: scopes.clone(),
+        }).await.unwrap();
+
+        let claims = authenticate(&keys, &db, "bsk_3_hunter2").await.unwrap();
+        assert_eq!((claims.id, claims.sid), (5, 3));
+        assert_eq!(claims.scopes, Some(scopes));
+        assert!(claims.allows(ScopeKind::ReadMessages, 7));
+        assert!(!claims.allows(ScopeKind::SendMessages, 7));
+        assert!(!claims.allows(ScopeKind::ReadMessages, 8));
+        assert!(still_valid(&db, &claims).await);
+
+        assert!(authenticate(&keys, &db, "bsk_3_hunter3").await.is_none());
+        assert!(authenticate(&keys, &db, "bsk_4_hunter2").await.is_none());
+        assert!(authenticate(&keys, &db, "bsk_hunter2").await.is_none());
+
+        db.delete_api_token(3).await.unwrap();
+        assert!(authenticate(&keys, &db, "bsk_3_hunter2").await.is_none());
+        assert!(!still_valid(&db, &claims).await);
+
+        // Signed tokens can't claim to be API tokens
+        let mut forged = claims;
+        forged.exp = Local::now() + Duration::days(1);
+        let token = keys.sign(&forged).unwrap();
+        assert!(authenticate(&keys, &db, &token).await.is_none());
+    }
+}
@@ -0,0 +1,2807 @@
+use async_trait::async_trait;
+use cassandra_cpp::{Value, SetIterator, Session, Statement, CassResult, AsRustType, BindRustType, Cluster, stmt};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use thiserror::Error;
+use crate::models::*;
+use crate::names::{email_key, prefix_range, username_key};
+
+/// Errors that can be returned by any `Database` backend.
+///
+/// Backends map their driver-specific errors into one of these so that the API
+/// can decide how to respond without knowing which database is in use.
+#[derive(Error, Debug)]
+pub enum DbError {
+    /// The row being accessed doesn't exist
+    #[error("not found: {0}")]
+    NotFound(String),
+    /// The operation clashes with existing data
+    #[error("conflict: {0}")]
+    Conflict(String),
+    /// The backend itself failed (connection issues, bad queries, etc.)
+    #[error("database error: {0}")]
+    Backend(String),
+    /// Data coming out of the backend couldn't be converted to/from Rust types
+    #[error("serialization error: {0}")]
+    Serialization(String),
+}
+
+pub type Result<T> = std::result::Result<T, DbError>;
+
+impl From<cassandra_cpp::Error> for DbError {
+    fn from(e: cassandra_cpp::Error) -> Self {
+        use cassandra_cpp::ErrorKind;
+        match e.kind() {
+            ErrorKind::UnsupportedType(..) | ErrorKind::InvalidUtf8(_) | ErrorKind::StringContainsNul(_) => {
+                DbError::Serialization(e.to_string())
+            }
+            _ => DbError::Backend(e.to_string()),
+        }
+    }
+}
+
+/// Collect the IDs in a CQL `set<bigint>`
+fn collect_set(set: SetIterator) -> Result<Vec<i64>> {
+    let mut ids = Vec::new();
+    for i in set {
+        ids.push(i.get_i64()?);
+    }
+    Ok(ids)
+}
+
+/// Read a nullable `bigint` column
+fn nullable_i64(value: Value) -> Result<Option<i64>> {
+    match value.is_null() {
+        true => Ok(None),
+        false => Ok(Some(value.get_i64()?)),
+    }
+}
+
+/// Build the error returned when an invite doesn't exist, or can't be used any more
+pub(crate) fn invite_not_found(code: &str) -> DbError {
+    DbError::NotFound(format!("no usable invite with code {code}"))
+}
+
+/// Build the error returned when a row the caller assumed exists is missing
+pub(crate) fn not_found(table: &str, id: i64) -> DbError {
+    DbError::NotFound(format!("no row with id {id} in {table}"))
+}
+
+/// Build the error returned when a username or email is already someone else's
+///
+/// Arguments:
+/// - `what`: `"username"` or `"email"`
+/// - `value`: the name or email being claimed
+pub(crate) fn taken(what: &str, value: &str) -> DbError {
+    match what {
+        // Since they're unique by skeleton, it might be someone else's lookalike
+        "username" => DbError::Conflict(format!("{what} {value} is already taken, or looks too much like one that is")),
+        _ => DbError::Conflict(format!("{what} {value} is already taken")),
+    }
+}
+
+/// Turn the `Conflict` from failing to claim a username or email into a `taken` error,
+/// leaving other errors as they are
+pub(crate) fn taken_if_conflict(e: DbError, what: &str, value: &str) -> DbError {
+    match e {
+        DbError::Conflict(_) => taken(what, value),
+        e => e,
+    }
+}
+
+#[derive(Debug)]
+pub enum IdType {
+    User,
+    Group,
+    Channel,
+    Message
+}
+
+/// A login session, which a user's refresh tokens belong to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthSession {
+    pub id: i64,
+    // ID of the user who logged in
+    pub user: i64,
+    // SHA-256 (hex) of the secret part of the session's current refresh token
+    pub refresh_hash: String,
+    // When the refresh token expires (Unix timestamp)
+    pub expires: i64,
+}
+
+/// A user's TOTP (two-factor authentication) settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserTotp {
+    // Base32 secret shared with the user's authenticator app
+    pub secret: String,
+    // Whether the user has confirmed the secret. Until then, logging in doesn't need a code.
+    pub enabled: bool,
+    // Last time step a code was accepted for, so that codes can't be replayed
+    pub last_step: i64,
+    // SHA-256 (hex) of each recovery code that hasn't been used yet
+    pub recovery_codes: Vec<String>,
+}
+
+/// One of a bot's API tokens, as stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BotToken {
+    pub id: i64,
+    // ID of the bot the token authenticates as
+    pub bot: i64,
+    pub name: String,
+    // SHA-256 (hex) of the token's secret
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// An identity from an OpenID Connect provider, which can be linked to a user
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Identity {
+    // The provider's issuer URL
+    pub issuer: String,
+    // The provider's ID for the user (the `sub` claim), unique per issuer
+    pub subject: String,
+}
+
+/// Which entries of a group's audit log to get, newest first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditQuery {
+    // Only get entries older than this one (by ID), to page through the log
+    pub before: Option<i64>,
+    // Only get entries made by this user
+    pub actor: Option<i64>,
+    // Only get entries about this user, channel, role or message
+    pub target: Option<i64>,
+    pub action: Option<AuditAction>,
+    // The most entries to get
+    pub limit: u64,
+}
+
+impl AuditQuery {
+    /// Whether an entry passes the query's filters (not counting its limit)
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.before.is_none_or(|before| entry.id < before)
+            && self.actor.is_none_or(|actor| entry.actor == actor)
+            && self.target.is_none_or(|target| entry.target == target)
+            && self.action.is_none_or(|action| entry.action == action)
+    }
+}
+
+/// Which groups to find in the group directory, oldest first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryQuery {
+    // Only find groups made after this one (by ID), to page through the directory
+    pub after: Option<i64>,
+    // Only find groups whose names contain this, ignoring case
+    pub name: Option<String>,
+    // Only find groups with this tag
+    pub tag: Option<String>,
+    // The most groups to find
+    pub limit: u64,
+}
+
+impl DirectoryQuery {
+    /// Whether a group passes the query's filters (not counting its limit). Groups that
+    /// aren't discoverable never do.
+    pub fn matches(&self, group: &Group) -> bool {
+        group.discoverable && !group.is_dm
+            && self.after.is_none_or(|after| group.id > after)
+            && self.name.as_ref().is_none_or(|name| group.name.to_lowercase().contains(&name.to_lowercase()))
+            && self.tag.as_ref().is_none_or(|tag| group.tags.contains(tag))
+    }
+}
+
+/// Trait for the back-end database that contains all CRUD database operations.
+///
+/// Every method is async so that API handlers can await the database instead of
+/// blocking the runtime's worker threads.
+///
+/// **Every method (outside of `valid_id`) assumes that the IDs passed are valid.**
+/// Backends return `DbError::NotFound` rather than panicking when that assumption
+/// doesn't hold.
+#[async_trait]
+pub trait Database: Sync + Send {
+    async fn valid_id(&self, kind: IdType, id: i64) -> Result<bool>;
+
+    /// Create a user. Usernames and (non-empty) emails are unique ignoring case (see
+    /// `names`), so this returns `Conflict` if either is already taken.
+    async fn create_user(&self, id: i64, name: String, email: String, hash: String) -> Result<()>;
+    /// Change a user's name and email, with the same rules as `create_user`
+    async fn update_user(&self, id: i64, name: String, email: String) -> Result<()>;
+    /// Get the ID of the user with a username, ignoring case. Returns `NotFound` if there isn't one.
+    async fn get_user_by_name(&self, name: String) -> Result<i64>;
+    /// Get the ID of the user with an email, ignoring case. Returns `NotFound` if there isn't one.
+    async fn get_user_by_email(&self, email: String) -> Result<i64>;
+    /// Get up to `limit` users whose usernames start with `prefix` (ignoring case), in
+    /// order of username
+    async fn search_users(&self, prefix: String, limit: u64) -> Result<Vec<User>>;
+    async fn get_user(&self, id: i64) -> Result<User>;
+    async fn get_user_hash(&self, id: i64) -> Result<String>;
+    async fn set_user_hash(&self, id: i64, hash: String) -> Result<()>;
+    async fn delete_user(&self, id: i64) -> Result<()>;
+
+    /// Get the email address a user has verified, if any. Their current address is only
+    /// verified if it's the same one, so changing it unverifies them.
+    async fn get_verified_email(&self, id: i64) -> Result<Option<String>>;
+    async fn set_verified_email(&self, id: i64, email: String) -> Result<()>;
+
+    async fn create_group(&self, gid: i64, uid: i64, name: String, dm: bool) -> Result<()>;
+    async fn get_group(&self, id: i64) -> Result<Group>;
+    async fn update_group(&self, id: i64, name: String) -> Result<()>;
+    async fn delete_group(&self, id: i64) -> Result<()>;
+    
+    async fn get_group_members(&self, gid: i64) -> Result<Vec<i64>>;
+    /// Add a member to a group, recording when they joined unless they're a member already
+    async fn add_group_member(&self, gid: i64, uid: i64) -> Result<()>;
+    async fn remove_group_member(&self, gid: i64, uid: i64) -> Result<()>;
+    /// Get when a member joined a group, in milliseconds since the Unix epoch (0 for
+    /// members from before that was recorded). Returns `NotFound` if they aren't a member.
+    async fn get_member_joined(&self, gid: i64, uid: i64) -> Result<i64>;
+    
+    async fn get_group_channels(&self, gid: i64) -> Result<Vec<i64>>;    
+    async fn add_group_channel(&self, gid: i64, uid: i64) -> Result<()>;
+    async fn remove_group_channel(&self, gid: i64, uid: i64) -> Result<()>;
+    
+    async fn get_group_admin(&self, gid: i64) -> Result<Vec<i64>>;
+    async fn add_group_admin(&self, gid: i64, uid: i64) -> Result<()>;
+    async fn remove_group_admin(&self, gid: i64, uid: i64) -> Result<()>;
+    
+    async fn get_group_owner(&self, gid: i64) -> Result<i64>;
+    async fn set_group_owner(&self, gid: i64, uid: i64) -> Result<()>;
+
+    async fn is_group_dm(&self, gid: i64) -> Result<bool>;
+
+    /// Set how a group is listed in the group directory. Groups this was never set for
+    /// aren't discoverable, require approval to join, and have no description or tags.
+    async fn set_group_profile(&self, gid: i64, discoverable: bool, requires_approval: bool, description: String, tags: Vec<String>) -> Result<()>;
+    /// Get the groups in the directory that match a query, oldest first
+    async fn search_groups(&self, query: DirectoryQuery) -> Result<Vec<Group>>;
+
+    /// Create a role, or replace the one with the same ID
+    async fn set_role(&self, role: Role) -> Result<()>;
+    async fn get_role(&self, id: i64) -> Result<Role>;
+    /// Get the roles stored for a group. Its default role is only there once it's been changed.
+    async fn get_group_roles(&self, gid: i64) -> Result<Vec<Role>>;
+    /// Delete a role, taking it away from every member who has it
+    async fn delete_role(&self, id: i64) -> Result<()>;
+
+    /// Get the IDs of the roles a member has been given, not counting the default role
+    async fn get_member_roles(&self, gid: i64, uid: i64) -> Result<Vec<i64>>;
+    async fn add_member_role(&self, gid: i64, uid: i64, rid: i64) -> Result<()>;
+    async fn remove_member_role(&self, gid: i64, uid: i64, rid: i64) -> Result<()>;
+
+    /// Ban a user from a group, replacing any ban they already had. Expired bans are
+    /// treated as if they'd been lifted.
+    async fn set_ban(&self, ban: Ban) -> Result<()>;
+    async fn get_ban(&self, gid: i64, uid: i64) -> Result<Ban>;
+    async fn get_group_bans(&self, gid: i64) -> Result<Vec<Ban>>;
+    async fn delete_ban(&self, gid: i64, uid: i64) -> Result<()>;
+
+    /// Time out a member of a group, replacing any timeout they already had. Expired
+    /// timeouts are treated as if they'd been lifted.
+    async fn set_timeout(&self, timeout: Timeout) -> Result<()>;
+    async fn get_timeout(&self, gid: i64, uid: i64) -> Result<Timeout>;
+    async fn get_group_timeouts(&self, gid: i64) -> Result<Vec<Timeout>>;
+    async fn delete_timeout(&self, gid: i64, uid: i64) -> Result<()>;
+
+    /// Add an entry to a group's audit log. Entries are deleted with their group.
+    async fn add_audit_entry(&self, entry: AuditEntry) -> Result<()>;
+    /// Get the entries of a group's audit log that match a query, newest first
+    async fn get_audit_log(&self, gid: i64, query: AuditQuery) -> Result<Vec<AuditEntry>>;
+    /// Delete every entry of a group's audit log
+    async fn delete_audit_log(&self, gid: i64) -> Result<()>;
+
+    /// Make an invite to a group. Invites that have expired or run out of uses are treated
+    /// as if they'd been deleted, and all of a group's are deleted with it.
+    async fn create_invite(&self, invite: Invite) -> Result<()>;
+    async fn get_invite(&self, code: String) -> Result<Invite>;
+    async fn get_group_invites(&self, gid: i64) -> Result<Vec<Invite>>;
+    /// Count a use of an invite, returning it with the use counted. Returns `NotFound` if
+    /// it can't be used, so two users can't both take its last use.
+    async fn use_invite(&self, code: String) -> Result<Invite>;
+    async fn delete_invite(&self, code: String) -> Result<()>;
+
+    /// Ask to join a group, replacing any request the user already made to it. A group's
+    /// requests are deleted with it.
+    async fn set_join_request(&self, request: JoinRequest) -> Result<()>;
+    async fn get_join_request(&self, gid: i64, uid: i64) -> Result<JoinRequest>;
+    /// Get the requests to join a group, oldest first
+    async fn get_join_requests(&self, gid: i64) -> Result<Vec<JoinRequest>>;
+    async fn delete_join_request(&self, gid: i64, uid: i64) -> Result<()>;
+
+    async fn create_channel(&self, cid: i64, gid: i64, uid: i64, name: String) -> Result<()>;
+    async fn get_channel(&self, id: i64) -> Result<Channel>;
+    async fn update_channel(&self, id: i64, name: String) -> Result<()>;
+    async fn delete_channel(&self, id: i64) -> Result<()>;
+
+    async fn get_channel_members(&self, gid: i64) -> Result<Vec<i64>>;
+    async fn add_channel_member(&self,cid: i64, id: i64) -> Result<()>;
+    async fn remove_channel_member(&self, cid: i64, id: i64) -> Result<()>;
+    async fn is_channel_member(&self, cid: i64, uid: i64) -> Result<bool>;
+
+    async fn is_channel_private(&self, id: i64) -> Result<bool>;
+    async fn set_channel_private(&self, id: i64, value: bool) -> Result<bool>;
+
+    /// Get a channel's permission overrides. They're deleted along with the channel; ones
+    /// for roles or users that have since been deleted are left, but never apply again.
+    async fn get_channel_overrides(&self, cid: i64) -> Result<Vec<ChannelOverride>>;
+    /// Set a channel's override for a role or user, replacing any it already had
+    async fn set_channel_override(&self, over: ChannelOverride) -> Result<()>;
+    async fn delete_channel_override(&self, cid: i64, target: i64) -> Result<()>;
+
+    async fn create_user_groups(&self, id: i64) -> Result<()>;
+    async fn get_user_groups(&self, id: i64) -> Result<Vec<i64>>;
+    async fn delete_user_groups(&self, id: i64) -> Result<()>;
+    async fn add_user_group(&self, uid: i64, gid: i64) -> Result<()>;
+    async fn remove_user_group(&self, uid: i64, gid: i64) -> Result<()>;
+
+    async fn create_user_dms(&self, id: i64) -> Result<()>;
+    async fn get_user_dms(&self, id: i64) -> Result<Vec<i64>>;
+    async fn delete_user_dms(&self, id: i64) -> Result<()>;
+    async fn add_user_dm(&self, uid: i64, gid: i64) -> Result<()>;    
+    async fn remove_user_dm(&self, uid: i64, gid: i64) -> Result<()>;
+
+    async fn create_session(&self, session: AuthSession) -> Result<()>;
+    async fn get_session(&self, id: i64) -> Result<AuthSession>;
+    async fn update_session(&self, id: i64, refresh_hash: String, expires: i64) -> Result<()>;
+    async fn delete_session(&self, id: i64) -> Result<()>;
+    async fn get_user_sessions(&self, uid: i64) -> Result<Vec<i64>>;
+
+    /// Add a session to the revocation list, so that access tokens issued for it are
+    /// refused until `until` (a Unix timestamp), by which point they'll have expired
+    async fn revoke_session(&self, id: i64, until: i64) -> Result<()>;
+    async fn is_session_revoked(&self, id: i64) -> Result<bool>;
+
+    /// Get a user's TOTP settings. Returns `NotFound` if they've never enrolled.
+    async fn get_totp(&self, uid: i64) -> Result<UserTotp>;
+    /// Create or replace a user's TOTP settings
+    async fn set_totp(&self, uid: i64, totp: UserTotp) -> Result<()>;
+    async fn delete_totp(&self, uid: i64) -> Result<()>;
+
+    /// Mark an existing user as a bot belonging to `owner`
+    async fn create_bot(&self, id: i64, owner: i64) -> Result<()>;
+    /// Get the owner of a bot. Returns `NotFound` if the user isn't a bot.
+    async fn get_bot_owner(&self, id: i64) -> Result<i64>;
+    async fn get_user_bots(&self, owner: i64) -> Result<Vec<i64>>;
+
+    async fn create_api_token(&self, token: BotToken) -> Result<()>;
+    async fn get_api_token(&self, id: i64) -> Result<BotToken>;
+    async fn get_bot_tokens(&self, bot: i64) -> Result<Vec<BotToken>>;
+    async fn delete_api_token(&self, id: i64) -> Result<()>;
+
+    /// Link an identity to a user. Returns `Conflict` if it's already linked to anyone.
+    async fn link_identity(&self, uid: i64, identity: Identity) -> Result<()>;
+    /// Get the user an identity is linked to. Returns `NotFound` if it isn't linked.
+    async fn get_identity_user(&self, identity: Identity) -> Result<i64>;
+    async fn get_user_identities(&self, uid: i64) -> Result<Vec<Identity>>;
+    
+    async fn create_message(&self, msg: Message) -> Result<()>;
+    async fn get_message(&self, id: i64) -> Result<Message>;
+    async fn get_messages(&self, cid: i64, num: u64) -> Result<Vec<Message>>;
+    async fn delete_message(&self, id: i64) -> Result<()>;
+    async fn set_thread(&self, id: i64, cid: i64) -> Result<()>;
+}
+
+/// Cassandra backend struct
+pub struct Cassandra {
+    kspc: String, // keyspace
+    sess: Session 
+}
+
+impl Cassandra {
+    /// Initialize the database session and creates tables
+    ///
+    /// Arguments:
+    /// - `keyspc`: the keyspace to use for all database queries.
+    pub fn new(keyspc: &str) -> Self {
+        let contact_points = "127.0.0.1"; // NOTE: generalize me
+        let mut cluster = Cluster::default();
+        cluster.set_contact_points(contact_points).unwrap();
+        cluster.set_load_balance_round_robin();
+        let session = cluster.connect().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE KEYSPACE IF NOT EXISTS {keyspc} \
+             WITH replication = {{'class':'SimpleStrategy', 'replication_factor': 1}}"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.users \
+             (id bigint PRIMARY KEY, name text, email text, hash text);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.verified_emails (id bigint PRIMARY KEY, email text);"
+        ))).wait().unwrap();
+
+        // Claimed usernames (by `username_key`), partitioned by their first character so
+        // that searching by prefix only has to read one partition
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.usernames \
+             (initial text, key text, id bigint, PRIMARY KEY (initial, key));"
+        ))).wait().unwrap();
+
+        // Claimed emails (by `email_key`)
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.emails (key text PRIMARY KEY, id bigint);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.groups \
+             (id bigint PRIMARY KEY, name text, members set<bigint>, is_dm boolean, \
+             channels set<bigint>, admin set<bigint>, owner bigint);"
+        ))).wait().unwrap();
+
+        // Permissions are stored as a bitset, see `Permission::bit`
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.roles \
+             (id bigint PRIMARY KEY, group bigint, name text, permissions bigint);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.group_roles \
+             (id bigint PRIMARY KEY, roles set<bigint>);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.member_roles \
+             (group bigint, user_id bigint, roles set<bigint>, PRIMARY KEY (group, user_id));"
+        ))).wait().unwrap();
+
+        // Bans and timeouts expire through their TTL
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.bans \
+             (group bigint, user_id bigint, reason text, expires bigint, PRIMARY KEY (group, user_id));"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.timeouts \
+             (group bigint, user_id bigint, reason text, until bigint, PRIMARY KEY (group, user_id));"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.invites \
+             (code text PRIMARY KEY, group bigint, channel bigint, creator bigint, \
+             expires bigint, max_uses bigint, uses bigint);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.group_invites \
+             (group bigint, code text, PRIMARY KEY (group, code));"
+        ))).wait().unwrap();
+
+        // Tags are space-separated
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.group_profiles \
+             (id bigint PRIMARY KEY, discoverable boolean, requires_approval boolean, \
+             description text, tags text);"
+        ))).wait().unwrap();
+
+        // The discoverable groups, all in one partition so they can be paged through by ID
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.directory \
+             (shard int, id bigint, PRIMARY KEY (shard, id));"
+        ))).wait().unwrap();
+
+        // When each member joined their groups, in milliseconds
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.member_joins \
+             (group bigint, user_id bigint, joined bigint, PRIMARY KEY (group, user_id));"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.join_requests \
+             (group bigint, user_id bigint, message text, timestamp bigint, PRIMARY KEY (group, user_id));"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.audit_log \
+             (group bigint, id bigint, actor bigint, action text, target bigint, \
+             timestamp bigint, reason text, \
+             PRIMARY KEY (group, id)) \
+             WITH CLUSTERING ORDER BY (id DESC);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.channels \
+             (id bigint PRIMARY KEY, group bigint, name text, \
+             members set<bigint>, private boolean);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.channel_overrides \
+             (channel bigint, target bigint, allow bigint, deny bigint, PRIMARY KEY (channel, target));"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.user_groups \
+             (id bigint PRIMARY KEY, groups set<bigint>);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.user_dms \
+             (id bigint PRIMARY KEY, dms set<bigint>);"
+        ))).wait().unwrap();
+        
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.sessions \
+             (id bigint PRIMARY KEY, user_id bigint, refresh_hash text, expires bigint);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.user_sessions \
+             (id bigint PRIMARY KEY, sessions set<bigint>);"
+        ))).wait().unwrap();
+
+        // Rows are inserted with a TTL, so revoked sessions drop off on their own
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.revoked_sessions (id bigint PRIMARY KEY);"
+        ))).wait().unwrap();
+
+        // Recovery codes are stored space-separated
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.user_totp \
+             (id bigint PRIMARY KEY, secret text, enabled boolean, last_step bigint, \
+             recovery_codes text);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.bots (id bigint PRIMARY KEY, owner bigint);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.user_bots \
+             (id bigint PRIMARY KEY, bots set<bigint>);"
+        ))).wait().unwrap();
+
+        // Scopes are stored space-separated, see `Scope::join`
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.api_tokens \
+             (id bigint PRIMARY KEY, bot bigint, name text, hash text, scopes text);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.bot_tokens \
+             (id bigint PRIMARY KEY, tokens set<bigint>);"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.identities \
+             (issuer text, subject text, user_id bigint, PRIMARY KEY ((issuer, subject)));"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.user_identities \
+             (user_id bigint, issuer text, subject text, PRIMARY KEY (user_id, issuer, subject));"
+        ))).wait().unwrap();
+
+        session.execute(&stmt!(&format!(
+            "CREATE TABLE IF NOT EXISTS {keyspc}.messages \
+             (channel bigint, id bigint, author bigint, \
+             content text, group bigint, thread bigint, \
+             PRIMARY KEY (channel, id)) \
+             WITH CLUSTERING ORDER BY (id DESC);"
+        ))).wait().unwrap();
+
+        Self {
+            kspc: keyspc.to_string(),
+            sess: session
+        }
+    }
+
+    /// Execute a statement and wait for its result.
+    ///
+    /// Takes the statement by value so that no reference to it (a `Statement` isn't
+    /// `Sync`) is held while the query is in flight.
+    async fn execute(&self, stmt: Statement) -> Result<CassResult> {
+        let fut = self.sess.execute(&stmt);
+        Ok(fut.await?)
+    }
+
+    /// Delete a row from the database.
+    ///
+    /// Arguments:
+    /// - `table`: the table to delete the row from
+    /// - `id`: the id of the row to delete
+    async fn delete_row(&self, table: &str, id: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "DELETE FROM {}.{table} WHERE id={id};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    /// Extract a set from a database row
+    ///
+    /// Arguments:
+    /// - `table`: the table with the desired row
+    /// - `set`: the name of the column with the set in it
+    /// - `id`: the id of the row to get the set from
+    async fn get_set(&self, table: &str, set: &str, id: i64) -> Result<Vec<i64>> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT {set} FROM {}.{table} WHERE id = {id};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found(table, id))?;
+        let set: Value = row.get_column(0)?;
+        Ok(match set.is_null() {
+            true => Vec::new(),
+            false => collect_set(set.get_set()?)?
+        })
+    }
+
+    /// Remove an element from a set in a database row
+    ///
+    /// Arguments:
+    /// - `table`: the table with the desired row
+    /// - `set`: the name of the column with the set in it
+    /// - `id`: the id of the row to get the set from
+    /// - `elem`: the element to remove from the set
+    async fn pop_set(&self, table: &str, set: &str, id: i64, elem: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "UPDATE {}.{table} SET {set} = {set} - {{{elem}}} WHERE ID={id};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    /// Add an element to a set in a database row
+    ///
+    /// Arguments:
+    /// - `table`: the table with the desired row
+    /// - `set`: the name of the column with the set in it
+    /// - `id`: the id of the row to get the set from
+    /// - `elem`: the element to add to the set
+    async fn push_set(&self, table: &str, set: &str, id: i64, elem: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "UPDATE {}.{table} SET {set} = {set} + {{{elem}}} WHERE ID={id};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    /// Record that a user joined a group now, unless they'd joined it already
+    ///
+    /// Arguments:
+    /// - `gid`: the group
+    /// - `uid`: the user joining it
+    async fn record_join(&self, gid: i64, uid: i64) -> Result<()> {
+        let now = chrono::Utc::now().timestamp_millis();
+        self.execute(stmt!(&format!(
+            "INSERT INTO {}.member_joins (group, user_id, joined) VALUES ({gid}, {uid}, {now}) IF NOT EXISTS;", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    /// Claim a username for a user, returning `Conflict` if someone else has it
+    ///
+    /// Arguments:
+    /// - `name`: the username
+    /// - `id`: the user claiming it
+    async fn claim_name(&self, name: &str, id: i64) -> Result<()> {
+        // Lightweight transaction, so two users can't race to claim the same name
+        let key = username_key(name);
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.usernames (initial, key, id) VALUES (?, ?, {id}) IF NOT EXISTS;", self.kspc
+        ));
+        stmt.bind(0, initial(&key).as_str())?;
+        stmt.bind(1, key.as_str())?;
+        let res = self.execute(stmt).await?;
+        let applied: bool = res.first_row().ok_or_else(|| DbError::Backend("no LWT result".to_string()))?.get(0)?;
+        if !applied && self.get_user_by_name(name.to_string()).await? != id {
+            return Err(taken("username", name));
+        }
+        Ok(())
+    }
+
+    /// Release a user's claim on a username, if they have it
+    async fn release_name(&self, name: &str, id: i64) -> Result<()> {
+        let key = username_key(name);
+        let mut stmt = stmt!(&format!(
+            "DELETE FROM {}.usernames WHERE initial = ? AND key = ? IF id = {id};", self.kspc
+        ));
+        stmt.bind(0, initial(&key).as_str())?;
+        stmt.bind(1, key.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    /// Claim an email for a user, returning `Conflict` if someone else has it. Empty
+    /// emails aren't claimed.
+    async fn claim_email(&self, email: &str, id: i64) -> Result<()> {
+        if email.is_empty() {
+            return Ok(());
+        }
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.emails (key, id) VALUES (?, {id}) IF NOT EXISTS;", self.kspc
+        ));
+        stmt.bind(0, email_key(email).as_str())?;
+        let res = self.execute(stmt).await?;
+        let applied: bool = res.first_row().ok_or_else(|| DbError::Backend("no LWT result".to_string()))?.get(0)?;
+        if !applied && self.get_user_by_email(email.to_string()).await? != id {
+            return Err(taken("email", email));
+        }
+        Ok(())
+    }
+
+    /// Release a user's claim on an email, if they have it
+    async fn release_email(&self, email: &str, id: i64) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "DELETE FROM {}.emails WHERE key = ? IF id = {id};", self.kspc
+        ));
+        stmt.bind(0, email_key(email).as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+}
+
+/// The partition a username key is stored in, in Cassandra's `usernames` table
+fn initial(key: &str) -> String {
+    key.chars().take(1).collect()
+}
+
+#[async_trait]
+impl Database for Cassandra {
+    async fn valid_id(&self, kind: IdType, id: i64) -> Result<bool> {
+        let table = match kind {
+            IdType::User => "users",
+            IdType::Group => "groups",
+            IdType::Channel => "channels",
+            IdType::Message => "messages",
+        };
+        let res = self.execute(stmt!(&format!(
+            "SELECT * FROM {}.{table} WHERE ID={id};", self.kspc
+        ))).await?;
+        if let Some(_row) = res.first_row() {
+            return Ok(true)
+        } else { return Ok(false) };
+    }
+
+    async fn create_user(&self, id: i64, name: String, email: String, hash: String) -> Result<()> {
+        self.claim_name(&name, id).await?;
+        if let Err(e) = self.claim_email(&email, id).await {
+            self.release_name(&name, id).await?;
+            return Err(e);
+        }
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.users (id, name, email, hash) VALUES ({id}, ?, ?, ?);", self.kspc
+        ));
+        stmt.bind(0, name.as_str())?;
+        stmt.bind(1, email.as_str())?;
+        stmt.bind(2, hash.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn get_user(&self, id: i64) -> Result<User> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT name, email FROM {}.users WHERE ID={id};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("users", id))?;
+        Ok(User {
+            id,
+            username: row.get(0)?,
+            email: row.get(1)?
+        })
+    }
+
+    async fn get_user_hash(&self, id: i64) -> Result<String> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT hash FROM {}.users WHERE ID={id};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("users", id))?;
+        Ok(row.get(0)?)
+    }
+
+    async fn set_user_hash(&self, id: i64, hash: String) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "UPDATE {}.users SET hash=? WHERE ID={id};", self.kspc
+        ));
+        stmt.bind(0, hash.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn update_user(&self, id: i64, name: String, email: String) -> Result<()> {
+        let old = self.get_user(id).await?;
+        let new_name = username_key(&name) != username_key(&old.username);
+        let new_email = email_key(&email) != email_key(&old.email);
+        if new_name {
+            self.claim_name(&name, id).await?;
+        }
+        if new_email {
+            if let Err(e) = self.claim_email(&email, id).await {
+                if new_name {
+                    self.release_name(&name, id).await?;
+                }
+                return Err(e);
+            }
+        }
+        let mut stmt = stmt!(&format!(
+            "UPDATE {}.users SET name=?, email=? WHERE ID={id};", self.kspc
+        ));
+        stmt.bind(0, name.as_str())?;
+        stmt.bind(1, email.as_str())?;
+        self.execute(stmt).await?;
+        if new_name {
+            self.release_name(&old.username, id).await?;
+        }
+        if new_email {
+            self.release_email(&old.email, id).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_user_by_name(&self, name: String) -> Result<i64> {
+        let key = username_key(&name);
+        let mut stmt = stmt!(&format!(
+            "SELECT id FROM {}.usernames WHERE initial = ? AND key = ?;", self.kspc
+        ));
+        stmt.bind(0, initial(&key).as_str())?;
+        stmt.bind(1, key.as_str())?;
+        let res = self.execute(stmt).await?;
+        let row = res.first_row().ok_or_else(|| DbError::NotFound(format!("no user named {name}")))?;
+        Ok(row.get(0)?)
+    }
+
+    async fn get_user_by_email(&self, email: String) -> Result<i64> {
+        let mut stmt = stmt!(&format!("SELECT id FROM {}.emails WHERE key = ?;", self.kspc));
+        stmt.bind(0, email_key(&email).as_str())?;
+        let res = self.execute(stmt).await?;
+        let row = res.first_row().ok_or_else(|| DbError::NotFound(format!("no user with email {email}")))?;
+        Ok(row.get(0)?)
+    }
+
+    async fn search_users(&self, prefix: String, limit: u64) -> Result<Vec<User>> {
+        let (start, end) = prefix_range(&prefix);
+        let mut stmt = stmt!(&format!(
+            "SELECT id FROM {}.usernames WHERE initial = ? AND key >= ? AND key < ? LIMIT {limit};",
+            self.kspc
+        ));
+        stmt.bind(0, initial(&start).as_str())?;
+        stmt.bind(1, start.as_str())?;
+        stmt.bind(2, end.as_str())?;
+        let res = self.execute(stmt).await?;
+        let mut users = Vec::new();
+        for row in res.iter() {
+            users.push(self.get_user(row.get(0)?).await?);
+        }
+        Ok(users)
+    }
+
+    async fn delete_user(&self, id: i64) -> Result<()> {
+        if let Ok(owner) = self.get_bot_owner(id).await {
+            self.pop_set("user_bots", "bots", owner, id).await?;
+            for token in self.get_bot_tokens(id).await? {
+                self.delete_row("api_tokens", token.id).await?;
+            }
+            self.delete_row("bot_tokens", id).await?;
+            self.delete_row("bots", id).await?;
+        }
+        for identity in self.get_user_identities(id).await? {
+            let mut stmt = stmt!(&format!(
+                "DELETE FROM {}.identities WHERE issuer = ? AND subject = ?;", self.kspc
+            ));
+            stmt.bind(0, identity.issuer.as_str())?;
+            stmt.bind(1, identity.subject.as_str())?;
+            self.execute(stmt).await?;
+        }
+        self.execute(stmt!(&format!(
+            "DELETE FROM {}.user_identities WHERE user_id={id};", self.kspc
+        ))).await?;
+        self.delete_row("user_totp", id).await?;
+        self.delete_row("verified_emails", id).await?;
+        if let Ok(user) = self.get_user(id).await {
+            self.release_name(&user.username, id).await?;
+            self.release_email(&user.email, id).await?;
+        }
+        self.delete_row("users", id).await
+    }
+
+    async fn get_verified_email(&self, id: i64) -> Result<Option<String>> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT email FROM {}.verified_emails WHERE id={id};", self.kspc
+        ))).await?;
+        Ok(match res.first_row() {
+            Some(row) => Some(row.get(0)?),
+            None => None,
+        })
+    }
+
+    async fn set_verified_email(&self, id: i64, email: String) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.verified_emails (id, email) VALUES ({id}, ?);", self.kspc
+        ));
+        stmt.bind(0, email.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn get_group(&self, id: i64) -> Result<Group> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT name, members, channels, owner, is_dm FROM {}.groups WHERE ID={id};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("groups", id))?;
+        let members: SetIterator = row.get(1)?;
+        let channels: SetIterator = row.get(2)?;        
+        let profile = self.execute(stmt!(&format!(
+            "SELECT discoverable, requires_approval, description, tags FROM {}.group_profiles WHERE id={id};", self.kspc
+        ))).await?;
+        let (discoverable, requires_approval, description, tags): (bool, bool, String, String) = match profile.first_row() {
+            Some(profile) => (profile.get(0)?, profile.get(1)?, profile.get(2)?, profile.get(3)?),
+            None => (false, true, String::new(), String::new()),
+        };
+        Ok(Group {
+            id,
+            name: row.get(0)?,
+            members: collect_set(members)?,
+            channels: collect_set(channels)?,
+            admin: self.get_set("groups", "admin", id).await?, // HACK
+            owner: row.get(3)?,
+            is_dm: row.get(4)?,
+            discoverable,
+            requires_approval,
+            description,
+            tags: tags.split_whitespace().map(str::to_string).collect(),
+        })
+    }
+
+    async fn create_group(&self, gid: i64, uid: i64, name: String, dm: bool) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.groups (id, name, channels, \
+             members, is_dm, owner) VALUES ({gid}, ?, {{}}, {{{uid}}}, {dm}, {uid});", self.kspc
+        ));
+        stmt.bind(0, name.as_str())?;
+        self.execute(stmt).await?;
+        self.record_join(gid, uid).await
+    }
+
+    async fn delete_group(&self, id: i64) -> Result<()> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT code FROM {}.group_invites WHERE group={id};", self.kspc
+        ))).await?;
+        for row in res.iter() {
+            let code: String = row.get(0)?;
+            self.delete_invite(code).await?;
+        }
+        self.delete_audit_log(id).await?;
+        for table in ["join_requests", "member_joins", "member_roles", "bans", "timeouts"] {
+            self.execute(stmt!(&format!("DELETE FROM {}.{table} WHERE group={id};", self.kspc))).await?;
+        }
+        self.execute(stmt!(&format!("DELETE FROM {}.directory WHERE shard=0 AND id={id};", self.kspc))).await?;
+        self.delete_row("group_profiles", id).await?;
+        self.delete_row("groups", id).await
+    }
+
+    async fn update_group(&self, id: i64, name: String) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "UPDATE {}.groups SET name = ? WHERE id = {id};", self.kspc
+        ));
+        stmt.bind(0, name.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn get_group_members(&self, gid: i64) -> Result<Vec<i64>> {
+        self.get_set("groups", "members", gid).await
+    }
+
+    async fn add_group_member(&self, gid: i64, uid: i64) -> Result<()> {
+        self.record_join(gid, uid).await?;
+        self.push_set("groups", "members", gid, uid).await
+    }
+
+    async fn remove_group_member(&self, gid: i64, uid: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "DELETE FROM {}.member_joins WHERE group={gid} AND user_id={uid};", self.kspc
+        ))).await?;
+        self.pop_set("groups", "members", gid, uid).await
+    }
+
+    async fn get_member_joined(&self, gid: i64, uid: i64) -> Result<i64> {
+        if !self.get_set("groups", "members", gid).await?.contains(&uid) {
+            return Err(not_found("group_members", uid));
+        }
+        let res = self.execute(stmt!(&format!(
+            "SELECT joined FROM {}.member_joins WHERE group={gid} AND user_id={uid};", self.kspc
+        ))).await?;
+        match res.first_row() {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(0),
+        }
+    }
+
+    async fn get_group_channels(&self, gid: i64) -> Result<Vec<i64>> {
+        self.get_set("groups", "channels", gid).await
+    }
+
+    async fn add_group_channel(&self, gid: i64, cid: i64) -> Result<()> {
+        self.push_set("groups", "channels", gid, cid).await
+    }
+
+    async fn remove_group_channel(&self, gid: i64, cid: i64) -> Result<()> {
+        self.pop_set("groups", "channels", gid, cid).await
+    }
+
+    async fn get_group_admin(&self, gid: i64) -> Result<Vec<i64>> {
+        self.get_set("groups", "admin", gid).await
+    }
+
+    async fn add_group_admin(&self, gid: i64, uid: i64) -> Result<()> {
+        self.push_set("groups", "admin", gid, uid).await
+    }
+
+
+    async fn remove_group_admin(&self, gid: i64, uid: i64) -> Result<()> {
+        self.pop_set("groups", "admin", gid, uid).await
+    }
+
+    async fn get_group_owner(&self, gid: i64) -> Result<i64> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT owner FROM {}.groups WHERE id={gid};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("groups", gid))?;
+        Ok(row.get(0)?)
+    }
+
+    async fn set_group_owner(&self, gid: i64, uid: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "UPDATE {}.groups SET owner = {uid} WHERE id = {gid};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    async fn is_group_dm(&self, gid: i64) -> Result<bool> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT is_dm FROM {}.groups WHERE id={gid};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("groups", gid))?;
+        Ok(row.get(0)?)
+    }
+
+    async fn set_group_profile(&self, gid: i64, discoverable: bool, requires_approval: bool, description: String, tags: Vec<String>) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.group_profiles (id, discoverable, requires_approval, description, tags) \
+             VALUES ({gid}, {discoverable}, {requires_approval}, ?, ?);",
+            self.kspc
+        ));
+        stmt.bind(0, description.as_str())?;
+        stmt.bind(1, tags.join(" ").as_str())?;
+        self.execute(stmt).await?;
+        self.execute(stmt!(&match discoverable {
+            true => format!("INSERT INTO {}.directory (shard, id) VALUES (0, {gid});", self.kspc),
+            false => format!("DELETE FROM {}.directory WHERE shard=0 AND id={gid};", self.kspc),
+        })).await?;
+        Ok(())
+    }
+
+    async fn search_groups(&self, query: DirectoryQuery) -> Result<Vec<Group>> {
+        // Paging is done by the clustering key, and the rest of the filters here
+        let after = query.after.map(|id| format!(" AND id > {id}")).unwrap_or_default();
+        let res = self.execute(stmt!(&format!(
+            "SELECT id FROM {}.directory WHERE shard=0{after};", self.kspc
+        ))).await?;
+        let mut groups = Vec::new();
+        for row in res.iter() {
+            let group = match self.get_group(row.get(0)?).await {
+                Ok(group) => group,
+                Err(DbError::NotFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            if query.matches(&group) {
+                groups.push(group);
+                if groups.len() as u64 >= query.limit {
+                    break;
+                }
+            }
+        }
+        Ok(groups)
+    }
+
+    async fn set_role(&self, role: Role) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.roles (id, group, name, permissions) VALUES ({}, {}, ?, {});",
+            self.kspc, role.id, role.group, role.permission_set().0
+        ));
+        stmt.bind(0, role.name.as_str())?;
+        self.execute(stmt).await?;
+        self.push_set("group_roles", "roles", role.group, role.id).await
+    }
+
+    async fn get_role(&self, id: i64) -> Result<Role> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT group, name, permissions FROM {}.roles WHERE id={id};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("roles", id))?;
+        let permissions: i64 = row.get(2)?;
+        Ok(Role {
+            id,
+            group: row.get(0)?,
+            name: row.get(1)?,
+            permissions: Permissions(permissions).list(),
+        })
+    }
+
+    async fn get_group_roles(&self, gid: i64) -> Result<Vec<Role>> {
+        let ids = match self.get_set("group_roles", "roles", gid).await {
+            Err(DbError::NotFound(_)) => Vec::new(),
+            res => res?,
+        };
+        let mut roles = Vec::with_capacity(ids.len());
+        for id in ids {
+            roles.push(self.get_role(id).await?);
+        }
+        Ok(roles)
+    }
+
+    async fn delete_role(&self, id: i64) -> Result<()> {
+        let role = match self.get_role(id).await {
+            Ok(role) => role,
+            Err(DbError::NotFound(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let res = self.execute(stmt!(&format!(
+            "SELECT user_id FROM {}.member_roles WHERE group={};", self.kspc, role.group
+        ))).await?;
+        for row in res.iter() {
+            let uid: i64 = row.get(0)?;
+            self.remove_member_role(role.group, uid, id).await?;
+        }
+        self.delete_row("roles", id).await?;
+        self.pop_set("group_roles", "roles", role.group, id).await
+    }
+
+    async fn get_member_roles(&self, gid: i64, uid: i64) -> Result<Vec<i64>> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT roles FROM {}.member_roles WHERE group={gid} AND user_id={uid};", self.kspc
+        ))).await?;
+        let roles: Value = match res.first_row() {
+            Some(row) => row.get_column(0)?,
+            None => return Ok(Vec::new()),
+        };
+        Ok(match roles.is_null() {
+            true => Vec::new(),
+            false => collect_set(roles.get_set()?)?,
+        })
+    }
+
+    async fn add_member_role(&self, gid: i64, uid: i64, rid: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "UPDATE {}.member_roles SET roles = roles + {{{rid}}} WHERE group={gid} AND user_id={uid};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    async fn remove_member_role(&self, gid: i64, uid: i64, rid: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "UPDATE {}.member_roles SET roles = roles - {{{rid}}} WHERE group={gid} AND user_id={uid};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    async fn set_ban(&self, ban: Ban) -> Result<()> {
+        let (expires, ttl) = match ban.expires {
+            Some(expires) => {
+                let ttl = (expires - chrono::Utc::now().timestamp()).max(1);
+                (expires.to_string(), format!(" USING TTL {ttl}"))
+            }
+            None => ("null".to_string(), String::new()),
+        };
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.bans (group, user_id, reason, expires) VALUES ({}, {}, ?, {}){};",
+            self.kspc, ban.group, ban.user, expires, ttl
+        ));
+        stmt.bind(0, ban.reason.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn get_ban(&self, gid: i64, uid: i64) -> Result<Ban> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT reason, expires FROM {}.bans WHERE group={gid} AND user_id={uid};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("bans", uid))?;
+        let expires: Value = row.get_column(1)?;
+        Ok(Ban {
+            group: gid,
+            user: uid,
+            reason: row.get(0)?,
+            expires: match expires.is_null() {
+                true => None,
+                false => Some(expires.get_i64()?),
+            },
+        })
+    }
+
+    async fn get_group_bans(&self, gid: i64) -> Result<Vec<Ban>> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT user_id, reason, expires FROM {}.bans WHERE group={gid};", self.kspc
+        ))).await?;
+        let mut bans = Vec::new();
+        for row in res.iter() {
+            let expires: Value = row.get_column(2)?;
+            bans.push(Ban {
+                group: gid,
+                user: row.get(0)?,
+                reason: row.get(1)?,
+                expires: match expires.is_null() {
+                    true => None,
+                    false => Some(expires.get_i64()?),
+                },
+            });
+        }
+        Ok(bans)
+    }
+
+    async fn delete_ban(&self, gid: i64, uid: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "DELETE FROM {}.bans WHERE group={gid} AND user_id={uid};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    async fn set_timeout(&self, timeout: Timeout) -> Result<()> {
+        let ttl = (timeout.until - chrono::Utc::now().timestamp()).max(1);
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.timeouts (group, user_id, reason, until) VALUES ({}, {}, ?, {}) USING TTL {ttl};",
+            self.kspc, timeout.group, timeout.user, timeout.until
+        ));
+        stmt.bind(0, timeout.reason.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn get_timeout(&self, gid: i64, uid: i64) -> Result<Timeout> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT reason, until FROM {}.timeouts WHERE group={gid} AND user_id={uid};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("timeouts", uid))?;
+        Ok(Timeout { group: gid, user: uid, reason: row.get(0)?, until: row.get(1)? })
+    }
+
+    async fn get_group_timeouts(&self, gid: i64) -> Result<Vec<Timeout>> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT user_id, reason, until FROM {}.timeouts WHERE group={gid};", self.kspc
+        ))).await?;
+        let mut timeouts = Vec::new();
+        for row in res.iter() {
+            timeouts.push(Timeout { group: gid, user: row.get(0)?, reason: row.get(1)?, until: row.get(2)? });
+        }
+        Ok(timeouts)
+    }
+
+    async fn delete_timeout(&self, gid: i64, uid: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "DELETE FROM {}.timeouts WHERE group={gid} AND user_id={uid};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    async fn add_audit_entry(&self, entry: AuditEntry) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.audit_log (group, id, actor, action, target, timestamp, reason) \
+             VALUES ({}, {}, {}, ?, {}, {}, ?);",
+            self.kspc, entry.group, entry.id, entry.actor, entry.target, entry.timestamp
+        ));
+        stmt.bind(0, entry.action.as_str())?;
+        stmt.bind(1, entry.reason.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn get_audit_log(&self, gid: i64, query: AuditQuery) -> Result<Vec<AuditEntry>> {
+        // Paging is done by the clustering key, and the rest of the filters here
+        let before = query.before.map(|id| format!(" AND id < {id}")).unwrap_or_default();
+        let res = self.execute(stmt!(&format!(
+            "SELECT id, actor, action, target, timestamp, reason FROM {}.audit_log \
+             WHERE group={gid}{before};", self.kspc
+        ))).await?;
+        let mut entries = Vec::new();
+        for row in res.iter() {
+            let action: String = row.get(2)?;
+            let Some(action) = AuditAction::parse(&action) else { continue };
+            let entry = AuditEntry {
+                id: row.get(0)?,
+                group: gid,
+                actor: row.get(1)?,
+                action,
+                target: row.get(3)?,
+                timestamp: row.get(4)?,
+                reason: row.get(5)?,
+            };
+            if query.matches(&entry) {
+                entries.push(entry);
+                if entries.len() as u64 >= query.limit {
+                    break;
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn delete_audit_log(&self, gid: i64) -> Result<()> {
+        self.execute(stmt!(&format!("DELETE FROM {}.audit_log WHERE group={gid};", self.kspc))).await?;
+        Ok(())
+    }
+
+    async fn create_invite(&self, invite: Invite) -> Result<()> {
+        let nullable = |value: Option<i64>| value.map_or("null".to_string(), |value| value.to_string());
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.invites (code, group, channel, creator, expires, max_uses, uses) \
+             VALUES (?, {}, {}, {}, {}, {}, {});",
+            self.kspc, invite.group, nullable(invite.channel), invite.creator,
+            nullable(invite.expires), nullable(invite.max_uses), invite.uses
+        ));
+        stmt.bind(0, invite.code.as_str())?;
+        self.execute(stmt).await?;
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.group_invites (group, code) VALUES ({}, ?);", self.kspc, invite.group
+        ));
+        stmt.bind(0, invite.code.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn get_invite(&self, code: String) -> Result<Invite> {
+        let mut stmt = stmt!(&format!(
+            "SELECT group, channel, creator, expires, max_uses, uses FROM {}.invites WHERE code = ?;", self.kspc
+        ));
+        stmt.bind(0, code.as_str())?;
+        let res = self.execute(stmt).await?;
+        let row = res.first_row().ok_or_else(|| invite_not_found(&code))?;
+        let invite = Invite {
+            group: row.get(0)?,
+            channel: nullable_i64(row.get_column(1)?)?,
+            creator: row.get(2)?,
+            expires: nullable_i64(row.get_column(3)?)?,
+            max_uses: nullable_i64(row.get_column(4)?)?,
+            uses: row.get(5)?,
+            code,
+        };
+        match invite.usable(chrono::Utc::now().timestamp()) {
+            true => Ok(invite),
+            false => Err(invite_not_found(&invite.code)),
+        }
+    }
+
+    async fn get_group_invites(&self, gid: i64) -> Result<Vec<Invite>> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT code FROM {}.group_invites WHERE group={gid};", self.kspc
+        ))).await?;
+        let mut invites = Vec::new();
+        for row in res.iter() {
+            match self.get_invite(row.get(0)?).await {
+                Ok(invite) => invites.push(invite),
+                Err(DbError::NotFound(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(invites)
+    }
+
+    async fn use_invite(&self, code: String) -> Result<Invite> {
+        // Lightweight transaction, retried until no one else has used the invite in between
+        loop {
+            let invite = self.get_invite(code.clone()).await?;
+            let mut stmt = stmt!(&format!(
+                "UPDATE {}.invites SET uses = {} WHERE code = ? IF uses = {};",
+                self.kspc, invite.uses + 1, invite.uses
+            ));
+            stmt.bind(0, code.as_str())?;
+            let res = self.execute(stmt).await?;
+            let applied: bool = res.first_row().ok_or_else(|| DbError::Backend("no LWT result".to_string()))?.get(0)?;
+            if applied {
+                return Ok(Invite { uses: invite.uses + 1, ..invite });
+            }
+        }
+    }
+
+    async fn delete_invite(&self, code: String) -> Result<()> {
+        let mut stmt = stmt!(&format!("SELECT group FROM {}.invites WHERE code = ?;", self.kspc));
+        stmt.bind(0, code.as_str())?;
+        let res = self.execute(stmt).await?;
+        if let Some(row) = res.first_row() {
+            let gid: i64 = row.get(0)?;
+            let mut stmt = stmt!(&format!("DELETE FROM {}.group_invites WHERE group={gid} AND code = ?;", self.kspc));
+            stmt.bind(0, code.as_str())?;
+            self.execute(stmt).await?;
+        }
+        let mut stmt = stmt!(&format!("DELETE FROM {}.invites WHERE code = ?;", self.kspc));
+        stmt.bind(0, code.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn set_join_request(&self, request: JoinRequest) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.join_requests (group, user_id, message, timestamp) VALUES ({}, {}, ?, {});",
+            self.kspc, request.group, request.user, request.timestamp
+        ));
+        stmt.bind(0, request.message.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn get_join_request(&self, gid: i64, uid: i64) -> Result<JoinRequest> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT message, timestamp FROM {}.join_requests WHERE group={gid} AND user_id={uid};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("join_requests", uid))?;
+        Ok(JoinRequest { group: gid, user: uid, message: row.get(0)?, timestamp: row.get(1)? })
+    }
+
+    async fn get_join_requests(&self, gid: i64) -> Result<Vec<JoinRequest>> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT user_id, message, timestamp FROM {}.join_requests WHERE group={gid};", self.kspc
+        ))).await?;
+        let mut requests = Vec::new();
+        for row in res.iter() {
+            requests.push(JoinRequest { group: gid, user: row.get(0)?, message: row.get(1)?, timestamp: row.get(2)? });
+        }
+        requests.sort_by_key(|request| (request.timestamp, request.user));
+        Ok(requests)
+    }
+
+    async fn delete_join_request(&self, gid: i64, uid: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "DELETE FROM {}.join_requests WHERE group={gid} AND user_id={uid};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    async fn get_channel(&self, id: i64) -> Result<Channel> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT group, name, members, private FROM {}.channels WHERE ID={id};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("channels", id))?;
+        let members: SetIterator = row.get(2)?;
+        Ok(Channel {
+            id,
+            group: row.get(0)?,
+            name: row.get(1)?,
+            members: collect_set(members)?,
+            private: row.get(3)?
+        })
+    }
+
+    async fn create_channel(&self, cid: i64, gid: i64, uid: i64, name: String) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.channels (id, group, name, members, private) VALUES ({cid}, {gid}, ?, {{{uid}}}, false);", self.kspc
+        ));
+        stmt.bind(0, name.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn delete_channel(&self, id: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "DELETE FROM {}.channel_overrides WHERE channel={id};", self.kspc
+        ))).await?;
+        self.delete_row("channels", id).await
+    }
+
+    async fn update_channel(&self, id: i64, name: String) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "UPDATE {}.channels SET name = ? WHERE id = {id};", self.kspc
+        ));
+        stmt.bind(0, name.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn get_channel_members(&self, cid: i64) -> Result<Vec<i64>> {
+        self.get_set("channels", "members", cid).await
+    }
+
+    async fn add_channel_member(&self, gid: i64, uid: i64) -> Result<()> {
+        self.push_set("channels", "members", gid, uid).await
+    }
+
+    async fn remove_channel_member(&self, gid: i64, uid: i64) -> Result<()> {
+        self.pop_set("channels", "members", gid, uid).await
+    }
+
+    async fn is_channel_member(&self, cid: i64, uid: i64) -> Result<bool> {
+        Ok(self.get_set("channels", "members", cid).await?.contains(&uid))
+    }
+
+    async fn is_channel_private(&self, id: i64) -> Result<bool> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT private FROM {}.channels WHERE id={id};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("channels", id))?;
+        Ok(row.get(0)?)
+    }
+
+    async fn set_channel_private(&self, id: i64, value: bool) -> Result<bool> {
+        self.execute(stmt!(&format!(
+            "UPDATE {}.channels SET private = {value} WHERE id={id};", self.kspc
+        ))).await?;
+        Ok(value)
+    }
+
+    async fn get_channel_overrides(&self, cid: i64) -> Result<Vec<ChannelOverride>> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT target, allow, deny FROM {}.channel_overrides WHERE channel={cid};", self.kspc
+        ))).await?;
+        let mut overrides = Vec::new();
+        for row in res.iter() {
+            let (allow, deny): (i64, i64) = (row.get(1)?, row.get(2)?);
+            overrides.push(ChannelOverride {
+                channel: cid,
+                target: row.get(0)?,
+                allow: Permissions(allow).list(),
+                deny: Permissions(deny).list(),
+            });
+        }
+        Ok(overrides)
+    }
+
+    async fn set_channel_override(&self, over: ChannelOverride) -> Result<()> {
+        let (allow, deny) = (over.allowed(), over.denied());
+        self.execute(stmt!(&format!(
+            "INSERT INTO {}.channel_overrides (channel, target, allow, deny) VALUES ({}, {}, {}, {});",
+            self.kspc, over.channel, over.target, allow.0, deny.0
+        ))).await?;
+        Ok(())
+    }
+
+    async fn delete_channel_override(&self, cid: i64, target: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "DELETE FROM {}.channel_overrides WHERE channel={cid} AND target={target};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    async fn create_user_dms(&self, id: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "INSERT INTO {}.user_dms (id, dms) VALUES ({id}, {{}});", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    async fn get_user_dms(&self, id: i64) -> Result<Vec<i64>> {
+        self.get_set("user_dms", "dms", id).await
+    }
+
+    async fn add_user_dm(&self, uid: i64, gid: i64) -> Result<()> {
+        self.push_set("user_dms", "dms", uid, gid).await
+    }
+
+    async fn remove_user_dm(&self, uid: i64, gid: i64) -> Result<()> {
+        self.pop_set("user_dms", "dms", uid, gid).await
+    }
+
+    async fn create_session(&self, session: AuthSession) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.sessions (id, user_id, refresh_hash, expires) VALUES ({}, {}, ?, {});",
+            self.kspc, session.id, session.user, session.expires
+        ));
+        stmt.bind(0, session.refresh_hash.as_str())?;
+        self.execute(stmt).await?;
+        self.push_set("user_sessions", "sessions", session.user, session.id).await
+    }
+
+    async fn get_session(&self, id: i64) -> Result<AuthSession> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT user_id, refresh_hash, expires FROM {}.sessions WHERE id={id};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("sessions", id))?;
+        Ok(AuthSession {
+            id,
+            user: row.get(0)?,
+            refresh_hash: row.get(1)?,
+            expires: row.get(2)?,
+        })
+    }
+
+    async fn update_session(&self, id: i64, refresh_hash: String, expires: i64) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "UPDATE {}.sessions SET refresh_hash = ?, expires = {expires} WHERE id = {id};", self.kspc
+        ));
+        stmt.bind(0, refresh_hash.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn delete_session(&self, id: i64) -> Result<()> {
+        let session = match self.get_session(id).await {
+            Ok(session) => session,
+            Err(DbError::NotFound(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        self.delete_row("sessions", id).await?;
+        self.pop_set("user_sessions", "sessions", session.user, id).await
+    }
+
+    async fn get_user_sessions(&self, uid: i64) -> Result<Vec<i64>> {
+        match self.get_set("user_sessions", "sessions", uid).await {
+            Err(DbError::NotFound(_)) => Ok(Vec::new()),
+            res => res,
+        }
+    }
+
+    async fn revoke_session(&self, id: i64, until: i64) -> Result<()> {
+        let ttl = (until - chrono::Utc::now().timestamp()).max(1);
+        self.execute(stmt!(&format!(
+            "INSERT INTO {}.revoked_sessions (id) VALUES ({id}) USING TTL {ttl};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    async fn is_session_revoked(&self, id: i64) -> Result<bool> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT id FROM {}.revoked_sessions WHERE id={id};", self.kspc
+        ))).await?;
+        Ok(res.first_row().is_some())
+    }
+
+    async fn get_totp(&self, uid: i64) -> Result<UserTotp> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT secret, enabled, last_step, recovery_codes FROM {}.user_totp WHERE id={uid};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("user_totp", uid))?;
+        let codes: String = row.get(3)?;
+        Ok(UserTotp {
+            secret: row.get(0)?,
+            enabled: row.get(1)?,
+            last_step: row.get(2)?,
+            recovery_codes: codes.split_whitespace().map(str::to_string).collect(),
+        })
+    }
+
+    async fn set_totp(&self, uid: i64, totp: UserTotp) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.user_totp (id, secret, enabled, last_step, recovery_codes) \
+             VALUES ({uid}, ?, {}, {}, ?);",
+            self.kspc, totp.enabled, totp.last_step
+        ));
+        stmt.bind(0, totp.secret.as_str())?;
+        stmt.bind(1, totp.recovery_codes.join(" ").as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn delete_totp(&self, uid: i64) -> Result<()> {
+        self.delete_row("user_totp", uid).await
+    }
+
+    async fn create_bot(&self, id: i64, owner: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "INSERT INTO {}.bots (id, owner) VALUES ({id}, {owner});", self.kspc
+        ))).await?;
+        self.push_set("user_bots", "bots", owner, id).await
+    }
+
+    async fn get_bot_owner(&self, id: i64) -> Result<i64> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT owner FROM {}.bots WHERE id={id};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("bots", id))?;
+        Ok(row.get(0)?)
+    }
+
+    async fn get_user_bots(&self, owner: i64) -> Result<Vec<i64>> {
+        match self.get_set("user_bots", "bots", owner).await {
+            Err(DbError::NotFound(_)) => Ok(Vec::new()),
+            res => res,
+        }
+    }
+
+    async fn create_api_token(&self, token: BotToken) -> Result<()> {
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.api_tokens (id, bot, name, hash, scopes) VALUES ({}, {}, ?, ?, ?);",
+            self.kspc, token.id, token.bot
+        ));
+        stmt.bind(0, token.name.as_str())?;
+        stmt.bind(1, token.hash.as_str())?;
+        stmt.bind(2, Scope::join(&token.scopes).as_str())?;
+        self.execute(stmt).await?;
+        self.push_set("bot_tokens", "tokens", token.bot, token.id).await
+    }
+
+    async fn get_api_token(&self, id: i64) -> Result<BotToken> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT bot, name, hash, scopes FROM {}.api_tokens WHERE id={id};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("api_tokens", id))?;
+        let scopes: String = row.get(3)?;
+        Ok(BotToken {
+            id,
+            bot: row.get(0)?,
+            name: row.get(1)?,
+            hash: row.get(2)?,
+            scopes: Scope::split(&scopes),
+        })
+    }
+
+    async fn get_bot_tokens(&self, bot: i64) -> Result<Vec<BotToken>> {
+        let ids = match self.get_set("bot_tokens", "tokens", bot).await {
+            Err(DbError::NotFound(_)) => Vec::new(),
+            res => res?,
+        };
+        let mut tokens = Vec::with_capacity(ids.len());
+        for id in ids {
+            tokens.push(self.get_api_token(id).await?);
+        }
+        Ok(tokens)
+    }
+
+    async fn delete_api_token(&self, id: i64) -> Result<()> {
+        let token = match self.get_api_token(id).await {
+            Ok(token) => token,
+            Err(DbError::NotFound(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        self.delete_row("api_tokens", id).await?;
+        self.pop_set("bot_tokens", "tokens", token.bot, id).await
+    }
+
+    async fn link_identity(&self, uid: i64, identity: Identity) -> Result<()> {
+        // Lightweight transaction, so two users can't race to link the same identity
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.identities (issuer, subject, user_id) VALUES (?, ?, {uid}) IF NOT EXISTS;",
+            self.kspc
+        ));
+        stmt.bind(0, identity.issuer.as_str())?;
+        stmt.bind(1, identity.subject.as_str())?;
+        let res = self.execute(stmt).await?;
+        let applied: bool = res.first_row().ok_or_else(|| DbError::Backend("no LWT result".to_string()))?.get(0)?;
+        if !applied {
+            return Err(DbError::Conflict(format!("identity {} already linked", identity.subject)));
+        }
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.user_identities (user_id, issuer, subject) VALUES ({uid}, ?, ?);", self.kspc
+        ));
+        stmt.bind(0, identity.issuer.as_str())?;
+        stmt.bind(1, identity.subject.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn get_identity_user(&self, identity: Identity) -> Result<i64> {
+        let mut stmt = stmt!(&format!(
+            "SELECT user_id FROM {}.identities WHERE issuer = ? AND subject = ?;", self.kspc
+        ));
+        stmt.bind(0, identity.issuer.as_str())?;
+        stmt.bind(1, identity.subject.as_str())?;
+        let res = self.execute(stmt).await?;
+        let row = res.first_row()
+            .ok_or_else(|| DbError::NotFound(format!("identity {} not found", identity.subject)))?;
+        Ok(row.get(0)?)
+    }
+
+    async fn get_user_identities(&self, uid: i64) -> Result<Vec<Identity>> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT issuer, subject FROM {}.user_identities WHERE user_id={uid};", self.kspc
+        ))).await?;
+        let mut identities = Vec::new();
+        for row in res.iter() {
+            identities.push(Identity { issuer: row.get(0)?, subject: row.get(1)? });
+        }
+        Ok(identities)
+    }
+
+    async fn delete_user_dms(&self, id: i64) -> Result<()> {
+        self.delete_row("user_dms", id).await
+    }
+    
+    async fn create_user_groups(&self, id: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "INSERT INTO {}.user_groups (id, groups) VALUES ({id}, {{}});", self.kspc
+        ))).await?;
+        Ok(())
+    }
+
+    async fn get_user_groups(&self, id: i64) -> Result<Vec<i64>> {
+        self.get_set("user_groups", "groups", id).await
+    }
+
+    async fn add_user_group(&self, uid: i64, gid: i64) -> Result<()> {
+        self.push_set("user_groups", "groups", uid, gid).await
+    }
+
+    async fn remove_user_group(&self, uid: i64, gid: i64) -> Result<()> {
+        self.pop_set("user_groups", "groups", uid, gid).await
+    }
+
+    async fn delete_user_groups(&self, id: i64) -> Result<()> {
+        self.delete_row("user_groups", id).await
+    }
+    
+    async fn get_messages(&self, cid: i64, num: u64) -> Result<Vec<Message>> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT * FROM {}.messages WHERE channel={cid} LIMIT {num};", self.kspc
+        ))).await?;
+        let mut messages = Vec::new();
+        for row in res.iter() {
+            let maybe_thread: Value = row.get_column(5)?;
+            messages.push(Message {
+                id: row.get(0)?,
+                author: row.get(2)?,
+                channel: row.get(1)?,
+                content: row.get(4)?,
+                thread: match maybe_thread.is_null() {
+                    true => None,
+                    false => Some(maybe_thread.get_i64()?)
+                }
+            });
+        }
+        Ok(messages)
+    }
+
+    async fn create_message(&self, msg: Message) -> Result<()> {
+        let thread = match msg.thread {
+            Some(thread) => thread.to_string(),
+            None => "null".to_string(),
+        };
+        let mut stmt = stmt!(&format!(
+            "INSERT INTO {}.messages (channel, id, author, content, thread) VALUES ({},{},{},?,{});",
+            self.kspc, msg.channel, msg.id, msg.author, thread
+        ));
+        stmt.bind(0, msg.content.as_str())?;
+        self.execute(stmt).await?;
+        Ok(())
+    }
+
+    async fn get_message(&self, id: i64) -> Result<Message> {
+        let res = self.execute(stmt!(&format!(
+            "SELECT channel, author, content, thread FROM {}.messages WHERE ID={id};", self.kspc
+        ))).await?;
+        let row = res.first_row().ok_or_else(|| not_found("messages", id))?;
+        let thread: Value = row.get_column(3)?;
+        Ok(Message {
+            id,
+            channel: row.get(0)?,
+            author: row.get(1)?,
+            content: row.get(2)?,
+            thread: match thread.is_null() {
+                true => None,
+                false => Some(thread.get_i64()?)
+            }
+        })
+    }
+
+    async fn delete_message(&self, id: i64) -> Result<()> {
+        self.delete_row("messages", id).await
+    }
+
+    async fn set_thread(&self, id: i64, cid: i64) -> Result<()> {
+        self.execute(stmt!(&format!(
+            "UPDATE {}.messages SET thread = {cid} WHERE id = {id};", self.kspc
+        ))).await?;
+        Ok(())
+    }
+}
+
+/// A row of the in-memory `users` table
+struct UserRow {
+    name: String,
+    email: String,
+    hash: String,
+}
+
+/// A row of the in-memory `groups` table
+struct GroupRow {
+    name: String,
+    members: BTreeSet<i64>,
+    /// When each member joined, in milliseconds
+    joined: HashMap<i64, i64>,
+    channels: BTreeSet<i64>,
+    admin: BTreeSet<i64>,
+    owner: i64,
+    is_dm: bool,
+    discoverable: bool,
+    requires_approval: bool,
+    description: String,
+    tags: Vec<String>,
+}
+
+/// A row of the in-memory `channels` table
+struct ChannelRow {
+    group: i64,
+    name: String,
+    members: BTreeSet<i64>,
+    private: bool,
+}
+
+/// All of the tables held by an `InMemory` backend.
+///
+/// Sets are kept as `BTreeSet`s so that they come back sorted like Cassandra's
+/// `set<bigint>` columns, and messages are keyed by ID so they can be read
+/// newest-first like the `messages` table's clustering order.
+#[derive(Default)]
+struct Tables {
+    users: HashMap<i64, UserRow>,
+    groups: HashMap<i64, GroupRow>,
+    channels: HashMap<i64, ChannelRow>,
+    user_groups: HashMap<i64, BTreeSet<i64>>,
+    user_dms: HashMap<i64, BTreeSet<i64>>,
+    messages: BTreeMap<i64, Message>,
+    sessions: HashMap<i64, AuthSession>,
+    // Revoked session IDs, and when they can be forgotten
+    revoked_sessions: HashMap<i64, i64>,
+    user_totp: HashMap<i64, UserTotp>,
+    verified_emails: HashMap<i64, String>,
+    // Bot IDs, and their owners
+    bots: HashMap<i64, i64>,
+    api_tokens: BTreeMap<i64, BotToken>,
+    // Linked identities, and their users
+    identities: BTreeMap<Identity, i64>,
+    // Claimed username and email keys (see `names`), and the users who have them
+    usernames: BTreeMap<String, i64>,
+    emails: HashMap<String, i64>,
+    roles: BTreeMap<i64, Role>,
+    // The roles given to each (group, member)
+    member_roles: HashMap<(i64, i64), BTreeSet<i64>>,
+    // Overrides by (channel, target)
+    channel_overrides: BTreeMap<(i64, i64), ChannelOverride>,
+    // Bans and timeouts by (group, user)
+    bans: BTreeMap<(i64, i64), Ban>,
+    timeouts: BTreeMap<(i64, i64), Timeout>,
+    // Audit log entries by (group, ID)
+    audit_log: BTreeMap<(i64, i64), AuditEntry>,
+    // Invites by code
+    invites: BTreeMap<String, Invite>,
+    // Join requests by (group, user)
+    join_requests: BTreeMap<(i64, i64), JoinRequest>,
+}
+
+/// In-memory backend struct
+///
+/// Keeps every table in process memory, which makes it useful for tests and for
+/// running scuttlebutt locally without Cassandra. Nothing is persisted.
+///
+/// Clones share the same underlying tables, so a test can hand one clone to `Api`
+/// and inspect the other.
+#[derive(Clone, Default)]
+pub struct InMemory {
+    tables: Arc<Mutex<Tables>>,
+}
+
+impl InMemory {
+    /// Create an empty database
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Tables> {
+        self.tables.lock().unwrap()
+    }
+
+}
+
+impl Tables {
+    /// Claim a username and email for a user, releasing the ones they had. Returns
+    /// `Conflict` (claiming neither) if someone else has either.
+    fn claim_names(&mut self, id: i64, name: &str, email: &str) -> Result<()> {
+        let (name_key, email_key) = (username_key(name), email_key(email));
+        if self.usernames.get(&name_key).is_some_and(|user| *user != id) {
+            return Err(taken("username", name));
+        } else if !email.is_empty() && self.emails.get(&email_key).is_some_and(|user| *user != id) {
+            return Err(taken("email", email));
+        }
+        self.release_names(id);
+        self.usernames.insert(name_key, id);
+        if !email.is_empty() {
+            self.emails.insert(email_key, id);
+        }
+        Ok(())
+    }
+
+    fn release_names(&mut self, id: i64) {
+        self.usernames.retain(|_, user| *user != id);
+        self.emails.retain(|_, user| *user != id);
+    }
+
+    fn group(&mut self, id: i64) -> Result<&mut GroupRow> {
+        self.groups.get_mut(&id).ok_or_else(|| not_found("groups", id))
+    }
+
+    fn channel(&mut self, id: i64) -> Result<&mut ChannelRow> {
+        self.channels.get_mut(&id).ok_or_else(|| not_found("channels", id))
+    }
+
+    fn user(&mut self, id: i64) -> Result<&mut UserRow> {
+        self.users.get_mut(&id).ok_or_else(|| not_found("users", id))
+    }
+
+    fn message(&mut self, id: i64) -> Result<&mut Message> {
+        self.messages.get_mut(&id).ok_or_else(|| not_found("messages", id))
+    }
+
+    fn session(&mut self, id: i64) -> Result<&mut AuthSession> {
+        self.sessions.get_mut(&id).ok_or_else(|| not_found("sessions", id))
+    }
+}
+
+#[async_trait]
+impl Database for InMemory {
+    async fn valid_id(&self, kind: IdType, id: i64) -> Result<bool> {
+        let tables = self.lock();
+        Ok(match kind {
+            IdType::User => tables.users.contains_key(&id),
+            IdType::Group => tables.groups.contains_key(&id),
+            IdType::Channel => tables.channels.contains_key(&id),
+            IdType::Message => tables.messages.contains_key(&id),
+        })
+    }
+
+    async fn create_user(&self, id: i64, name: String, email: String, hash: String) -> Result<()> {
+        let mut tables = self.lock();
+        tables.claim_names(id, &name, &email)?;
+        tables.users.insert(id, UserRow { name, email, hash });
+        Ok(())
+    }
+
+    async fn get_user(&self, id: i64) -> Result<User> {
+        let mut tables = self.lock();
+        let user = tables.user(id)?;
+        Ok(User {
+            id,
+            username: user.name.clone(),
+            email: user.email.clone(),
+        })
+    }
+
+    async fn get_user_hash(&self, id: i64) -> Result<String> {
+        Ok(self.lock().user(id)?.hash.clone())
+    }
+
+    async fn set_user_hash(&self, id: i64, hash: String) -> Result<()> {
+        self.lock().user(id)?.hash = hash;
+        Ok(())
+    }
+
+    async fn update_user(&self, id: i64, name: String, email: String) -> Result<()> {
+        let mut tables = self.lock();
+        tables.user(id)?;
+        tables.claim_names(id, &name, &email)?;
+        let user = tables.user(id)?;
+        user.name = name;
+        user.email = email;
+        Ok(())
+    }
+
+    async fn get_user_by_name(&self, name: String) -> Result<i64> {
+        self.lock().usernames.get(&username_key(&name)).copied()
+            .ok_or_else(|| DbError::NotFound(format!("no user named {name}")))
+    }
+
+    async fn get_user_by_email(&self, email: String) -> Result<i64> {
+        self.lock().emails.get(&email_key(&email)).copied()
+            .ok_or_else(|| DbError::NotFound(format!("no user with email {email}")))
+    }
+
+    async fn search_users(&self, prefix: String, limit: u64) -> Result<Vec<User>> {
+        let tables = self.lock();
+        let (start, end) = prefix_range(&prefix);
+        Ok(tables.usernames.range(start..end)
+            .take(limit as usize)
+            .filter_map(|(_, id)| tables.users.get(id).map(|user| User {
+                id: *id,
+                username: user.name.clone(),
+                email: user.email.clone(),
+            }))
+            .collect())
+    }
+
+    async fn delete_user(&self, id: i64) -> Result<()> {
+        let mut tables = self.lock();
+        tables.users.remove(&id);
+        tables.release_names(id);
+        tables.user_totp.remove(&id);
+        tables.verified_emails.remove(&id);
+        tables.bots.remove(&id);
+        tables.api_tokens.retain(|_, token| token.bot != id);
+        tables.identities.retain(|_, user| *user != id);
+        tables.member_roles.retain(|(_, user), _| *user != id);
+        tables.bans.retain(|(_, user), _| *user != id);
+        tables.timeouts.retain(|(_, user), _| *user != id);
+        tables.join_requests.retain(|(_, user), _| *user != id);
+        Ok(())
+    }
+
+    async fn get_verified_email(&self, id: i64) -> Result<Option<String>> {
+        Ok(self.lock().verified_emails.get(&id).cloned())
+    }
+
+    async fn set_verified_email(&self, id: i64, email: String) -> Result<()> {
+        self.lock().verified_emails.insert(id, email);
+        Ok(())
+    }
+
+    async fn get_group(&self, id: i64) -> Result<Group> {
+        let mut tables = self.lock();
+        let group = tables.group(id)?;
+        Ok(Group {
+            id,
+            name: group.name.clone(),
+            members: group.members.iter().copied().collect(),
+            channels: group.channels.iter().copied().collect(),
+            admin: group.admin.iter().copied().collect(),
+            owner: group.owner,
+            is_dm: group.is_dm,
+            discoverable: group.discoverable,
+            requires_approval: group.requires_approval,
+            description: group.description.clone(),
+            tags: group.tags.clone(),
+        })
+    }
+
+    async fn create_group(&self, gid: i64, uid: i64, name: String, dm: bool) -> Result<()> {
+        self.lock().groups.insert(gid, GroupRow {
+            name,
+            members: BTreeSet::from([uid]),
+            joined: HashMap::from([(uid, chrono::Utc::now().timestamp_millis())]),
+            channels: BTreeSet::new(),
+            admin: BTreeSet::new(),
+            owner: uid,
+            is_dm: dm,
+            discoverable: false,
+            requires_approval: true,
+            description: String::new(),
+            tags: Vec::new(),
+        });
+        Ok(())
+    }
+
+    async fn delete_group(&self, id: i64) -> Result<()> {
+        let mut tables = self.lock();
+        tables.groups.remove(&id);
+        tables.roles.retain(|_, role| role.group != id);
+        tables.member_roles.retain(|(group, _), _| *group != id);
+        tables.bans.retain(|(group, _), _| *group != id);
+        tables.timeouts.retain(|(group, _), _| *group != id);
+        tables.audit_log.retain(|(group, _), _| *group != id);
+        tables.invites.retain(|_, invite| invite.group != id);
+        tables.join_requests.retain(|(group, _), _| *group != id);
+        Ok(())
+    }
+
+    async fn update_group(&self, id: i64, name: String) -> Result<()> {
+        self.lock().group(id)?.name = name;
+        Ok(())
+    }
+
+    async fn get_group_members(&self, gid: i64) -> Result<Vec<i64>> {
+        Ok(self.lock().group(gid)?.members.iter().copied().collect())
+    }
+
+    async fn add_group_member(&self, gid: i64, uid: i64) -> Result<()> {
+        let mut tables = self.lock();
+        let group = tables.group(gid)?;
+        group.members.insert(uid);
+        group.joined.entry(uid).or_insert_with(|| chrono::Utc::now().timestamp_millis());
+        Ok(())
+    }
+
+    async fn remove_group_member(&self, gid: i64, uid: i64) -> Result<()> {
+        let mut tables = self.lock();
+        let group = tables.group(gid)?;
+        group.members.remove(&uid);
+        group.joined.remove(&uid);
+        Ok(())
+    }
+
+    async fn get_member_joined(&self, gid: i64, uid: i64) -> Result<i64> {
+        self.lock().group(gid)?.joined.get(&uid).copied().ok_or_else(|| not_found("group_members", uid))
+    }
+
+    async fn get_group_channels(&self, gid: i64) -> Result<Vec<i64>> {
+        Ok(self.lock().group(gid)?.channels.iter().copied().collect())
+    }
+
+    async fn add_group_channel(&self, gid: i64, cid: i64) -> Result<()> {
+        self.lock().group(gid)?.channels.insert(cid);
+        Ok(())
+    }
+
+    async fn remove_group_channel(&self, gid: i64, cid: i64) -> Result<()> {
+        self.lock().group(gid)?.channels.remove(&cid);
+        Ok(())
+    }
+
+    async fn get_group_admin(&self, gid: i64) -> Result<Vec<i64>> {
+        Ok(self.lock().group(gid)?.admin.iter().copied().collect())
+    }
+
+    async fn add_group_admin(&self, gid: i64, uid: i64) -> Result<()> {
+        self.lock().group(gid)?.admin.insert(uid);
+        Ok(())
+    }
+
+    async fn remove_group_admin(&self, gid: i64, uid: i64) -> Result<()> {
+        self.lock().group(gid)?.admin.remove(&uid);
+        Ok(())
+    }
+
+    async fn get_group_owner(&self, gid: i64) -> Result<i64> {
+        Ok(self.lock().group(gid)?.owner)
+    }
+
+    async fn set_group_owner(&self, gid: i64, uid: i64) -> Result<()> {
+        self.lock().group(gid)?.owner = uid;
+        Ok(())
+    }
+
+    async fn is_group_dm(&self, gid: i64) -> Result<bool> {
+        Ok(self.lock().group(gid)?.is_dm)
+    }
+
+    async fn set_group_profile(&self, gid: i64, discoverable: bool, requires_approval: bool, description: String, tags: Vec<String>) -> Result<()> {
+        let mut tables = self.lock();
+        let group = tables.group(gid)?;
+        group.discoverable = discoverable;
+        group.requires_approval = requires_approval;
+        group.description = description;
+        group.tags = tags;
+        Ok(())
+    }
+
+    async fn search_groups(&self, query: DirectoryQuery) -> Result<Vec<Group>> {
+        let ids: Vec<i64> = self.lock().groups.iter()
+            .filter(|(_, group)| group.discoverable)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut groups = Vec::new();
+        for id in ids {
+            let group = self.get_group(id).await?;
+            if query.matches(&group) {
+                groups.push(group);
+                if groups.len() as u64 >= query.limit {
+                    break;
+                }
+            }
+        }
+        Ok(groups)
+    }
+
+    async fn set_role(&self, role: Role) -> Result<()> {
+        self.lock().roles.insert(role.id, role);
+        Ok(())
+    }
+
+    async fn get_role(&self, id: i64) -> Result<Role> {
+        self.lock().roles.get(&id).cloned().ok_or_else(|| not_found("roles", id))
+    }
+
+    async fn get_group_roles(&self, gid: i64) -> Result<Vec<Role>> {
+        Ok(self.lock().roles.values().filter(|role| role.group == gid).cloned().collect())
+    }
+
+    async fn delete_role(&self, id: i64) -> Result<()> {
+        let mut tables = self.lock();
+        tables.roles.remove(&id);
+        for roles in tables.member_roles.values_mut() {
+            roles.remove(&id);
+        }
+        Ok(())
+    }
+
+    async fn get_member_roles(&self, gid: i64, uid: i64) -> Result<Vec<i64>> {
+        Ok(self.lock().member_roles.get(&(gid, uid)).into_iter().flatten().copied().collect())
+    }
+
+    async fn add_member_role(&self, gid: i64, uid: i64, rid: i64) -> Result<()> {
+        self.lock().member_roles.entry((gid, uid)).or_default().insert(rid);
+        Ok(())
+    }
+
+    async fn remove_member_role(&self, gid: i64, uid: i64, rid: i64) -> Result<()> {
+        if let Some(roles) = self.lock().member_roles.get_mut(&(gid, uid)) {
+            roles.remove(&rid);
+        }
+        Ok(())
+    }
+
+    async fn set_ban(&self, ban: Ban) -> Result<()> {
+        self.lock().bans.insert((ban.group, ban.user), ban);
+        Ok(())
+    }
+
+    async fn get_ban(&self, gid: i64, uid: i64) -> Result<Ban> {
+        let now = chrono::Utc::now().timestamp();
+        self.lock().bans.get(&(gid, uid))
+            .filter(|ban| ban.expires.is_none_or(|expires| expires > now))
+            .cloned()
+            .ok_or_else(|| not_found("bans", uid))
+    }
+
+    async fn get_group_bans(&self, gid: i64) -> Result<Vec<Ban>> {
+        let now = chrono::Utc::now().timestamp();
+        Ok(self.lock().bans.range((gid, i64::MIN)..=(gid, i64::MAX))
+            .map(|(_, ban)| ban)
+            .filter(|ban| ban.expires.is_none_or(|expires| expires > now))
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_ban(&self, gid: i64, uid: i64) -> Result<()> {
+        self.lock().bans.remove(&(gid, uid));
+        Ok(())
+    }
+
+    async fn set_timeout(&self, timeout: Timeout) -> Result<()> {
+        self.lock().timeouts.insert((timeout.group, timeout.user), timeout);
+        Ok(())
+    }
+
+    async fn get_timeout(&self, gid: i64, uid: i64) -> Result<Timeout> {
+        let now = chrono::Utc::now().timestamp();
+        self.lock().timeouts.get(&(gid, uid))
+            .filter(|timeout| timeout.until > now)
+            .cloned()
+            .ok_or_else(|| not_found("timeouts", uid))
+    }
+
+    async fn get_group_timeouts(&self, gid: i64) -> Result<Vec<Timeout>> {
+        let now = chrono::Utc::now().timestamp();
+        Ok(self.lock().timeouts.range((gid, i64::MIN)..=(gid, i64::MAX))
+            .map(|(_, timeout)| timeout)
+            .filter(|timeout| timeout.until > now)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_timeout(&self, gid: i64, uid: i64) -> Result<()> {
+        self.lock().timeouts.remove(&(gid, uid));
+        Ok(())
+    }
+
+    async fn add_audit_entry(&self, entry: AuditEntry) -> Result<()> {
+        self.lock().audit_log.insert((entry.group, entry.id), entry);
+        Ok(())
+    }
+
+    async fn get_audit_log(&self, gid: i64, query: AuditQuery) -> Result<Vec<AuditEntry>> {
+        Ok(self.lock().audit_log.range((gid, i64::MIN)..=(gid, i64::MAX))
+            .rev()
+            .map(|(_, entry)| entry)
+            .filter(|entry| query.matches(entry))
+            .take(query.limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_audit_log(&self, gid: i64) -> Result<()> {
+        self.lock().audit_log.retain(|(group, _), _| *group != gid);
+        Ok(())
+    }
+
+    async fn create_invite(&self, invite: Invite) -> Result<()> {
+        self.lock().invites.insert(invite.code.clone(), invite);
+        Ok(())
+    }
+
+    async fn get_invite(&self, code: String) -> Result<Invite> {
+        let now = chrono::Utc::now().timestamp();
+        self.lock().invites.get(&code)
+            .filter(|invite| invite.usable(now))
+            .cloned()
+            .ok_or_else(|| invite_not_found(&code))
+    }
+
+    async fn get_group_invites(&self, gid: i64) -> Result<Vec<Invite>> {
+        let now = chrono::Utc::now().timestamp();
+        Ok(self.lock().invites.values()
+            .filter(|invite| invite.group == gid && invite.usable(now))
+            .cloned()
+            .collect())
+    }
+
+    async fn use_invite(&self, code: String) -> Result<Invite> {
+        let now = chrono::Utc::now().timestamp();
+        let mut tables = self.lock();
+        let invite = tables.invites.get_mut(&code)
+            .filter(|invite| invite.usable(now))
+            .ok_or_else(|| invite_not_found(&code))?;
+        invite.uses += 1;
+        Ok(invite.clone())
+    }
+
+    async fn delete_invite(&self, code: String) -> Result<()> {
+        self.lock().invites.remove(&code);
+        Ok(())
+    }
+
+    async fn set_join_request(&self, request: JoinRequest) -> Result<()> {
+        self.lock().join_requests.insert((request.group, request.user), request);
+        Ok(())
+    }
+
+    async fn get_join_request(&self, gid: i64, uid: i64) -> Result<JoinRequest> {
+        self.lock().join_requests.get(&(gid, uid)).cloned().ok_or_else(|| not_found("join_requests", uid))
+    }
+
+    async fn get_join_requests(&self, gid: i64) -> Result<Vec<JoinRequest>> {
+        let mut requests: Vec<JoinRequest> = self.lock().join_requests.range((gid, i64::MIN)..=(gid, i64::MAX))
+            .map(|(_, request)| request.clone())
+            .collect();
+        requests.sort_by_key(|request| (request.timestamp, request.user));
+        Ok(requests)
+    }
+
+    async fn delete_join_request(&self, gid: i64, uid: i64) -> Result<()> {
+        self.lock().join_requests.remove(&(gid, uid));
+        Ok(())
+    }
+
+    async fn get_channel(&self, id: i64) -> Result<Channel> {
+        let mut tables = self.lock();
+        let channel = tables.channel(id)?;
+        Ok(Channel {
+            id,
+            group: channel.group,
+            name: channel.name.clone(),
+            members: channel.members.iter().copied().collect(),
+            private: channel.private,
+        })
+    }
+
+    async fn create_channel(&self, cid: i64, gid: i64, uid: i64, name: String) -> Result<()> {
+        self.lock().channels.insert(cid, ChannelRow {
+            group: gid,
+            name,
+            members: BTreeSet::from([uid]),
+            private: false,
+        });
+        Ok(())
+    }
+
+    async fn delete_channel(&self, id: i64) -> Result<()> {
+        let mut tables = self.lock();
+        tables.channels.remove(&id);
+        tables.channel_overrides.retain(|(channel, _), _| *channel != id);
+        Ok(())
+    }
+
+    async fn update_channel(&self, id: i64, name: String) -> Result<()> {
+        self.lock().channel(id)?.name = name;
+        Ok(())
+    }
+
+    async fn get_channel_members(&self, cid: i64) -> Result<Vec<i64>> {
+        Ok(self.lock().channel(cid)?.members.iter().copied().collect())
+    }
+
+    async fn add_channel_member(&self, cid: i64, uid: i64) -> Result<()> {
+        self.lock().channel(cid)?.members.insert(uid);
+        Ok(())
+    }
+
+    async fn remove_channel_member(&self, cid: i64, uid: i64) -> Result<()> {
+        self.lock().channel(cid)?.members.remove(&uid);
+        Ok(())
+    }
+
+    async fn is_channel_member(&self, cid: i64, uid: i64) -> Result<bool> {
+        Ok(self.lock().channel(cid)?.members.contains(&uid))
+    }
+
+    async fn is_channel_private(&self, id: i64) -> Result<bool> {
+        Ok(self.lock().channel(id)?.private)
+    }
+
+    async fn set_channel_private(&self, id: i64, value: bool) -> Result<bool> {
+        self.lock().channel(id)?.private = value;
+        Ok(value)
+    }
+
+    async fn get_channel_overrides(&self, cid: i64) -> Result<Vec<ChannelOverride>> {
+        Ok(self.lock().channel_overrides.range((cid, i64::MIN)..=(cid, i64::MAX)).map(|(_, o)| o.clone()).collect())
+    }
+
+    async fn set_channel_override(&self, over: ChannelOverride) -> Result<()> {
+        self.lock().channel_overrides.insert((over.channel, over.target), over);
+        Ok(())
+    }
+
+    async fn delete_channel_override(&self, cid: i64, target: i64) -> Result<()> {
+        self.lock().channel_overrides.remove(&(cid, target));
+        Ok(())
+    }
+
+    async fn create_user_dms(&self, id: i64) -> Result<()> {
+        self.lock().user_dms.insert(id, BTreeSet::new());
+        Ok(())
+    }
+
+    async fn get_user_dms(&self, id: i64) -> Result<Vec<i64>> {
+        let tables = self.lock();
+        let dms = tables.user_dms.get(&id).ok_or_else(|| not_found("user_dms", id))?;
+        Ok(dms.iter().copied().collect())
+    }
+
+    async fn add_user_dm(&self, uid: i64, gid: i64) -> Result<()> {
+        // Like a CQL `UPDATE`, this creates the row if it doesn't exist yet
+        self.lock().user_dms.entry(uid).or_default().insert(gid);
+        Ok(())
+    }
+
+    async fn remove_user_dm(&self, uid: i64, gid: i64) -> Result<()> {
+        if let Some(dms) = self.lock().user_dms.get_mut(&uid) {
+            dms.remove(&gid);
+        }
+        Ok(())
+    }
+
+    async fn create_session(&self, session: AuthSession) -> Result<()> {
+        self.lock().sessions.insert(session.id, session);
+        Ok(())
+    }
+
+    async fn get_session(&self, id: i64) -> Result<AuthSession> {
+        Ok(self.lock().session(id)?.clone())
+    }
+
+    async fn update_session(&self, id: i64, refresh_hash: String, expires: i64) -> Result<()> {
+        let mut tables = self.lock();
+        let session = tables.session(id)?;
+        session.refresh_hash = refresh_hash;
+        session.expires = expires;
+        Ok(())
+    }
+
+    async fn delete_session(&self, id: i64) -> Result<()> {
+        self.lock().sessions.remove(&id);
+        Ok(())
+    }
+
+    async fn get_user_sessions(&self, uid: i64) -> Result<Vec<i64>> {
+        Ok(self.lock().sessions.values().filter(|s| s.user == uid).map(|s| s.id).collect())
+    }
+
+    async fn revoke_session(&self, id: i64, until: i64) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let mut tables = self.lock();
+        tables.revoked_sessions.retain(|_, until| *until > now);
+        tables.revoked_sessions.insert(id, until);
+        Ok(())
+    }
+
+    async fn is_session_revoked(&self, id: i64) -> Result<bool> {
+        let now = chrono::Utc::now().timestamp();
+        Ok(self.lock().revoked_sessions.get(&id).is_some_and(|until| *until > now))
+    }
+
+    async fn get_totp(&self, uid: i64) -> Result<UserTotp> {
+        self.lock().user_totp.get(&uid).cloned().ok_or_else(|| not_found("user_totp", uid))
+    }
+
+    async fn set_totp(&self, uid: i64, totp: UserTotp) -> Result<()> {
+        self.lock().user_totp.insert(uid, totp);
+        Ok(())
+    }
+
+    async fn delete_totp(&self, uid: i64) -> Result<()> {
+        self.lock().user_totp.remove(&uid);
+        Ok(())
+    }
+
+    async fn create_bot(&self, id: i64, owner: i64) -> Result<()> {
+        self.lock().bots.insert(id, owner);
+        Ok(())
+    }
+
+    async fn get_bot_owner(&self, id: i64) -> Result<i64> {
+        self.lock().bots.get(&id).copied().ok_or_else(|| not_found("bots", id))
+    }
+
+    async fn get_user_bots(&self, owner: i64) -> Result<Vec<i64>> {
+        let mut bots: Vec<i64> = self.lock().bots.iter()
+            .filter(|(_, o)| **o == owner)
+            .map(|(id, _)| *id)
+            .collect();
+        bots.sort();
+        Ok(bots)
+    }
+
+    async fn create_api_token(&self, token: BotToken) -> Result<()> {
+        self.lock().api_tokens.insert(token.id, token);
+        Ok(())
+    }
+
+    async fn get_api_token(&self, id: i64) -> Result<BotToken> {
+        self.lock().api_tokens.get(&id).cloned().ok_or_else(|| not_found("api_tokens", id))
+    }
+
+    async fn get_bot_tokens(&self, bot: i64) -> Result<Vec<BotToken>> {
+        Ok(self.lock().api_tokens.values().filter(|t| t.bot == bot).cloned().collect())
+    }
+
+    async fn delete_api_token(&self, id: i64) -> Result<()> {
+        self.lock().api_tokens.remove(&id);
+        Ok(())
+    }
+
+    async fn link_identity(&self, uid: i64, identity: Identity) -> Result<()> {
+        let mut tables = self.lock();
+        if tables.identities.contains_key(&identity) {
+            return Err(DbError::Conflict(format!("identity {} already linked", identity.subject)));
+        }
+        tables.identities.insert(identity, uid);
+        Ok(())
+    }
+
+    async fn get_identity_user(&self, identity: Identity) -> Result<i64> {
+        self.lock().identities.get(&identity).copied()
+            .ok_or_else(|| DbError::NotFound(format!("identity {} not found", identity.subject)))
+    }
+
+    async fn get_user_identities(&self, uid: i64) -> Result<Vec<Identity>> {
+        Ok(self.lock().identities.iter()
+            .filter(|(_, user)| **user == uid)
+            .map(|(identity, _)| identity.clone())
+            .collect())
+    }
+
+    async fn delete_user_dms(&self, id: i64) -> Result<()> {
+        self.lock().user_dms.remove(&id);
+        Ok(())
+    }
+
+    async fn create_user_groups(&self, id: i64) -> Result<()> {
+        self.lock().user_groups.insert(id, BTreeSet::new());
+        Ok(())
+    }
+
+    async fn get_user_groups(&self, id: i64) -> Result<Vec<i64>> {
+        let tables = self.lock();
+        let groups = tables.user_groups.get(&id).ok_or_else(|| not_found("user_groups", id))?;
+        Ok(groups.iter().copied().collect())
+    }
+
+    async fn add_user_group(&self, uid: i64, gid: i64) -> Result<()> {
+        // Like a CQL `UPDATE`, this creates the row if it doesn't exist yet
+        self.lock().user_groups.entry(uid).or_default().insert(gid);
+        Ok(())
+    }
+
+    async fn remove_user_group(&self, uid: i64, gid: i64) -> Result<()> {
+        if let Some(groups) = self.lock().user_groups.get_mut(&uid) {
+            groups.remove(&gid);
+        }
+        Ok(())
+    }
+
+    async fn delete_user_groups(&self, id: i64) -> Result<()> {
+        self.lock().user_groups.remove(&id);
+        Ok(())
+    }
+
+    async fn get_messages(&self, cid: i64, num: u64) -> Result<Vec<Message>> {
+        Ok(self.lock().messages.values()
+            .rev() // newest first, like `CLUSTERING ORDER BY (id DESC)`
+            .filter(|msg| msg.channel == cid)
+            .take(num as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn create_message(&self, msg: Message) -> Result<()> {
+        self.lock().messages.insert(msg.id, msg);
+        Ok(())
+    }
+
+    async fn get_message(&self, id: i64) -> Result<Message> {
+        Ok(self.lock().message(id)?.clone())
+    }
+
+    async fn delete_message(&self, id: i64) -> Result<()> {
+        self.lock().messages.remove(&id);
+        Ok(())
+    }
+
+    async fn set_thread(&self, id: i64, cid: i64) -> Result<()> {
+        self.lock().message(id)?.thread = Some(cid);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_delete_row() {
+        let db = Cassandra::new("test");
+        db.sess.execute(&stmt!(
+            "INSERT INTO test.users (id, name, email, hash) VALUES (11, 'fred', '', '');"
+        )).wait().unwrap();
+        db.delete_row("users", 11).await.unwrap();
+        let res = db.sess.execute(&stmt!(
+            "SELECT * FROM test.users WHERE id=11;"
+        )).wait().unwrap();
+        assert_eq!(res.row_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_set() {
+        let db = Cassandra::new("test");
+        db.sess.execute(&stmt!(
+            "INSERT INTO test.user_groups (id, groups) VALUES (12, {1,2,3});"
+        )).wait().unwrap();
+        assert_eq!(db.get_set("user_groups", "groups", 12).await.unwrap(), vec![1,2,3]);
+        db.delete_row("user_groups", 12).await.unwrap();        
+    }
+
+    #[tokio::test]
+    async fn test_push_set() {
+        let db = Cassandra::new("test");
+        db.sess.execute(&stmt!(
+            "INSERT INTO test.user_groups (id, groups) VALUES (13, {1,2,3});"
+        )).wait().unwrap();
+        db.push_set("user_groups", "groups", 13, 4).await.unwrap();
+        assert_eq!(db.get_set("user_groups", "groups", 13).await.unwrap(), vec![1,2,3,4]);
+        db.delete_row("user_groups", 13).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pop_set() {
+        let db = Cassandra::new("test");
+        db.sess.execute(&stmt!(
+            "INSERT INTO test.user_groups (id, groups) VALUES (14, {1,2,3});"
+        )).wait().unwrap();
+        db.pop_set("user_groups", "groups", 14, 3).await.unwrap();
+        assert_eq!(db.get_set("user_groups", "groups", 14).await.unwrap(), vec![1,2]);
+        db.delete_row("user_groups", 14).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_sets() {
+        let db = InMemory::new();
+        db.create_group(1, 10, "test".to_string(), false).await.unwrap();
+        db.add_group_member(1, 30).await.unwrap();
+        db.add_group_member(1, 20).await.unwrap();
+        db.add_group_member(1, 20).await.unwrap();
+        assert_eq!(db.get_group_members(1).await.unwrap(), vec![10, 20, 30]);
+        db.remove_group_member(1, 10).await.unwrap();
+        assert_eq!(db.get_group_members(1).await.unwrap(), vec![20, 30]);
+        assert_eq!(db.get_group_admin(1).await.unwrap(), Vec::<i64>::new());
+        assert!(matches!(db.get_group_members(2).await, Err(DbError::NotFound(_))));
+
+        db.create_channel(3, 1, 20, "main".to_string()).await.unwrap();
+        assert!(db.is_channel_member(3, 20).await.unwrap());
+        assert!(!db.is_channel_member(3, 30).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_messages() {
+        let db = InMemory::new();
+        for (id, channel) in [(1, 5), (2, 6), (3, 5), (4, 5)] {
+            db.create_message(Message { id, channel, author: 9, content: String::new(), thread: None }).await.unwrap();
+        }
+        let ids: Vec<i64> = db.get_messages(5, 2).await.unwrap().iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![4, 3]);
+        db.set_thread(3, 7).await.unwrap();
+        assert_eq!(db.get_message(3).await.unwrap().thread, Some(7));
+        db.delete_message(3).await.unwrap();
+        assert!(!db.valid_id(IdType::Message, 3).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_names() {
+        let db = InMemory::new();
+        db.create_user(1, "Fred".to_string(), "fred@example.com".to_string(), String::new()).await.unwrap();
+        db.create_user(2, "freda".to_string(), String::new(), String::new()).await.unwrap();
+        // Bots (and anyone else without an email) don't clash
+        db.create_user(3, "bot".to_string(), String::new(), String::new()).await.unwrap();
+        assert!(matches!(db.create_user(4, "FRED".to_string(), "x@example.com".to_string(), String::new()).await, Err(DbError::Conflict(_))));
+        assert!(matches!(db.create_user(4, "x".to_string(), "Fred@Example.com".to_string(), String::new()).await, Err(DbError::Conflict(_))));
+        assert!(!db.valid_id(IdType::User, 4).await.unwrap());
+
+        assert_eq!(db.get_user_by_name("fRED".to_string()).await.unwrap(), 1);
+        assert_eq!(db.get_user_by_email("FRED@example.com".to_string()).await.unwrap(), 1);
+        let found: Vec<i64> = db.search_users("Fr".to_string(), 10).await.unwrap().iter().map(|u| u.id).collect();
+        assert_eq!(found, vec![1, 2]);
+        assert_eq!(db.search_users("fr".to_string(), 1).await.unwrap().len(), 1);
+
+        // Renaming frees the old name, but can't take someone else's
+        assert!(matches!(db.update_user(1, "freda".to_string(), "fred@example.com".to_string()).await, Err(DbError::Conflict(_))));
+        db.update_user(1, "fREd".to_string(), "fred@example.com".to_string()).await.unwrap();
+        db.update_user(1, "frederick".to_string(), "new@example.com".to_string()).await.unwrap();
+        assert!(matches!(db.get_user_by_name("fred".to_string()).await, Err(DbError::NotFound(_))));
+        assert!(matches!(db.get_user_by_email("fred@example.com".to_string()).await, Err(DbError::NotFound(_))));
+        db.delete_user(1).await.unwrap();
+        assert!(matches!(db.get_user_by_name("frederick".to_string()).await, Err(DbError::NotFound(_))));
+    }
+}
@@ -0,0 +1,79 @@
+//! Everything shared between `scuttlebutt` and `chatterbox`: the models sent over
+//! the wire, token verification, ID generation, and the database backends.
+use rustflake::Snowflake;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+
+pub mod models;
+pub use models::*;
+
+pub mod auth;
+pub use auth::{authenticate, still_valid, Claims, ServerKey, ServerKeys, API_TOKEN_PREFIX};
+
+pub mod names;
+pub use names::{email_key, username_key, NameKind, NameLimits};
+
+pub mod db;
+pub use db::*;
+
+pub mod roles;
+
+pub mod sqlite;
+pub use sqlite::Sqlite;
+
+pub mod postgres;
+pub use postgres::Postgres;
+
+/// The Cassandra keyspace both services read from and write to
+pub const KEYSPACE: &str = "bsk";
+
+/// Connect to the backend named by `backend` (usually the `BSK_DB` environment variable):
+/// - `memory` keeps everything in process memory; nothing is kept once the process stops
+/// - `sqlite:<path>` uses a SQLite file, creating it if needed
+/// - `postgres://...` (or `postgresql://...`) connects to PostgreSQL, migrating it if needed
+/// - anything else (or nothing) uses Cassandra
+///
+/// Arguments:
+/// - `backend`: the backend to connect to
+pub async fn connect(backend: &str) -> Box<dyn Database> {
+    if backend == "memory" {
+        Box::new(InMemory::new())
+    } else if let Some(path) = backend.strip_prefix("sqlite:") {
+        Box::new(Sqlite::new(path))
+    } else if backend.starts_with("postgres://") || backend.starts_with("postgresql://") {
+        Box::new(Postgres::new(backend).await)
+    } else {
+        Box::new(Cassandra::new(KEYSPACE))
+    }
+}
+
+/// Epoch (in ms since the Unix epoch) of all generated IDs
+pub const ID_EPOCH: i64 = 1_564_790_400_000;
+
+/// Snowflake worker IDs, one per service, so IDs made by different services never collide
+pub const SCUTTLEBUTT_WORKER: i64 = 1;
+pub const CHATTERBOX_WORKER: i64 = 2;
+
+static WORKER_ID: AtomicI64 = AtomicI64::new(SCUTTLEBUTT_WORKER);
+
+/// Set the worker ID used by `gen_id`. Must be called before the first ID is generated.
+///
+/// Arguments:
+/// - `id`: the worker ID of the current service (`SCUTTLEBUTT_WORKER`, `CHATTERBOX_WORKER`)
+pub fn set_worker_id(id: i64) {
+    WORKER_ID.store(id, Ordering::Relaxed);
+}
+
+/// Generates a unique i64 for ID generation
+// FIXME: Very bad performance - acts as a chokehold for parallelism since
+// every request that sends a message / makes a channel / etc. has to contest
+// a global mutex.
+pub fn gen_id() -> i64 {
+    static STATE: Mutex<Option<Snowflake>> = Mutex::new(None);
+
+    STATE
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| Snowflake::new(ID_EPOCH, WORKER_ID.load(Ordering::Relaxed), 1))
+        .generate()
+}
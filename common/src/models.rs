@@ -0,0 +1,583 @@
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing a user
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub email: String,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing a group.
+/// No guarantees are made for the order of any vector in this struct.
+pub struct Group {
+    pub id: i64,
+    pub name: String,
+    // The IDs of the group members
+    pub members: Vec<i64>,
+    // The IDs of the group's channels
+    pub channels: Vec<i64>,
+	// The IDs of the group's users with admin permissions
+	pub admin: Vec<i64>,
+	// The ID of the owner of the group
+	pub owner: i64,
+	// Whether or not the group is a DM
+	pub is_dm: bool,
+	// Whether the group is listed in the group directory, where anyone can ask to join it
+	pub discoverable: bool,
+	// Whether users who find the group in the directory have to ask to join it, rather
+	// than joining straight away
+	pub requires_approval: bool,
+	// What the group is about, shown in the directory
+	pub description: String,
+	// Lowercase words the group can be found by in the directory
+	pub tags: Vec<String>,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing a group as listed in the group directory, which is all that users
+/// outside the group get to see of it
+pub struct GroupListing {
+    pub id: i64,
+    pub name: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    // Whether joining the group needs a request to be approved
+    pub requires_approval: bool,
+    // How many members the group has
+    pub members: u64,
+}
+
+impl From<Group> for GroupListing {
+    fn from(group: Group) -> Self {
+        GroupListing {
+            id: group.id,
+            name: group.name,
+            description: group.description,
+            tags: group.tags,
+            requires_approval: group.requires_approval,
+            members: group.members.len() as u64,
+        }
+    }
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing a group's channel.
+/// No guarantees are made for the order of any vector in this struct.
+pub struct Channel {
+    pub id: i64,
+    pub name: String,
+	// ID of the group the channel is in
+	pub group: i64,
+	// The IDs of the group members
+    pub members: Vec<i64>,
+	// Whether or not the channel is private
+	pub private: bool,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing a message from a user.
+pub struct Message {
+    pub id: i64,
+    pub channel: i64,
+    pub author: i64,
+    pub content: String,
+	// The (optional) thread associated with the message
+	pub thread: Option<i64>
+}
+
+#[derive(Enum, Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+/// Something a bot's API token can be allowed to do in a group
+pub enum ScopeKind {
+    /// Read channels and their messages
+    ReadMessages,
+    /// Send messages (through `chatterbox`), make threads and delete them
+    SendMessages,
+    /// Create, rename, delete and change the members of channels
+    ManageChannels,
+}
+
+impl ScopeKind {
+    /// The name of the scope, as used in the API
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScopeKind::ReadMessages => "read_messages",
+            ScopeKind::SendMessages => "send_messages",
+            ScopeKind::ManageChannels => "manage_channels",
+        }
+    }
+
+    /// Parse the name of a scope, as returned by `as_str`
+    pub fn parse(s: &str) -> Option<Self> {
+        [ScopeKind::ReadMessages, ScopeKind::SendMessages, ScopeKind::ManageChannels]
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+    }
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+/// Permission for an API token to do one kind of thing in one group
+pub struct Scope {
+    pub kind: ScopeKind,
+    // ID of the group the scope applies to
+    pub group: i64,
+}
+
+impl Scope {
+    /// Serialize a list of scopes into the space-separated `<kind>:<group>` form the
+    /// backends store
+    pub fn join(scopes: &[Scope]) -> String {
+        scopes.iter()
+            .map(|s| format!("{}:{}", s.kind.as_str(), s.group))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Parse scopes serialized by `join`, skipping any that can't be parsed
+    pub fn split(scopes: &str) -> Vec<Scope> {
+        scopes.split_whitespace()
+            .filter_map(|scope| {
+                let (kind, group) = scope.split_once(':')?;
+                Some(Scope { kind: ScopeKind::parse(kind)?, group: group.parse().ok()? })
+            })
+            .collect()
+    }
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing one of a bot's API tokens
+pub struct ApiToken {
+    pub id: i64,
+    // ID of the bot the token authenticates as
+    pub bot: i64,
+    // Name given to the token by the bot's owner
+    pub name: String,
+    // What the token is allowed to do
+    pub scopes: Vec<Scope>,
+    // The token itself. Only returned when the token is created.
+    pub token: Option<String>,
+}
+
+#[derive(Enum, Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+/// Something a role can allow its members to do in a group
+pub enum Permission {
+    /// Rename the group, change its profile and read its audit log
+    ManageGroup,
+    /// Create, edit and delete roles, and give them to members. Only for roles with no
+    /// permissions you don't have yourself.
+    ManageRoles,
+    /// Add and remove members
+    ManageMembers,
+    /// Create, rename, delete and change the members of channels
+    ManageChannels,
+    /// Delete other members' messages
+    DeleteMessages,
+    /// Make threads
+    CreateThreads,
+    /// Send messages (through `chatterbox`)
+    SendMessages,
+    /// Send messages that mention `@everyone`
+    MentionEveryone,
+    /// Ban members, and lift bans
+    BanMembers,
+    /// Time out members, and lift timeouts
+    TimeoutMembers,
+}
+
+impl Permission {
+    /// Every permission
+    pub const ALL: [Permission; 10] = [
+        Permission::ManageGroup,
+        Permission::ManageRoles,
+        Permission::ManageMembers,
+        Permission::ManageChannels,
+        Permission::DeleteMessages,
+        Permission::CreateThreads,
+        Permission::SendMessages,
+        Permission::MentionEveryone,
+        Permission::BanMembers,
+        Permission::TimeoutMembers,
+    ];
+
+    /// The permission's bit in a `Permissions` bitset. These are stored, so never change one.
+    pub fn bit(&self) -> i64 {
+        match self {
+            Permission::ManageGroup => 1 << 0,
+            Permission::ManageRoles => 1 << 1,
+            Permission::ManageMembers => 1 << 2,
+            Permission::ManageChannels => 1 << 3,
+            Permission::DeleteMessages => 1 << 4,
+            Permission::CreateThreads => 1 << 5,
+            Permission::SendMessages => 1 << 6,
+            Permission::MentionEveryone => 1 << 7,
+            Permission::BanMembers => 1 << 8,
+            Permission::TimeoutMembers => 1 << 9,
+        }
+    }
+
+    /// The scope a bot's API token needs to use the permission, or `None` if bots can't
+    pub fn scope(&self) -> Option<ScopeKind> {
+        match self {
+            Permission::ManageChannels => Some(ScopeKind::ManageChannels),
+            Permission::DeleteMessages | Permission::CreateThreads
+            | Permission::SendMessages | Permission::MentionEveryone => Some(ScopeKind::SendMessages),
+            Permission::ManageGroup | Permission::ManageRoles | Permission::ManageMembers
+            | Permission::BanMembers | Permission::TimeoutMembers => None,
+        }
+    }
+
+    /// Whether the permission means anything in a single channel, and so can be allowed or
+    /// denied there by a `ChannelOverride`
+    pub fn overridable(&self) -> bool {
+        !matches!(
+            self,
+            Permission::ManageGroup | Permission::ManageRoles | Permission::ManageMembers
+            | Permission::BanMembers | Permission::TimeoutMembers
+        )
+    }
+}
+
+/// A set of permissions, as the bitset the backends store (see `Permission::bit`)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Permissions(pub i64);
+
+impl Permissions {
+    /// No permissions at all
+    pub const NONE: Permissions = Permissions(0);
+
+    /// Every permission, like the owner and admins of a group have
+    pub fn all() -> Self {
+        Permission::ALL.into_iter().collect()
+    }
+
+    /// What members of a new group can do before they're given any roles
+    pub fn member_default() -> Self {
+        [Permission::CreateThreads, Permission::SendMessages, Permission::MentionEveryone]
+            .into_iter()
+            .collect()
+    }
+
+    pub fn contains(&self, permission: Permission) -> bool {
+        self.0 & permission.bit() != 0
+    }
+
+    /// Whether every permission in `other` is in this set too
+    pub fn contains_all(&self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(&self, other: Permissions) -> Self {
+        Permissions(self.0 | other.0)
+    }
+
+    /// The permissions in this set that aren't in `other`
+    pub fn difference(&self, other: Permissions) -> Self {
+        Permissions(self.0 & !other.0)
+    }
+
+    /// The permissions in the set, in the order of `Permission::ALL`
+    pub fn list(&self) -> Vec<Permission> {
+        Permission::ALL.into_iter().filter(|p| self.contains(*p)).collect()
+    }
+}
+
+impl From<Permission> for Permissions {
+    fn from(permission: Permission) -> Self {
+        Permissions(permission.bit())
+    }
+}
+
+impl FromIterator<Permission> for Permissions {
+    fn from_iter<I: IntoIterator<Item = Permission>>(iter: I) -> Self {
+        Permissions(iter.into_iter().fold(0, |bits, p| bits | p.bit()))
+    }
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing a named set of permissions in a group, which members can be given.
+///
+/// Every group has a default role, whose ID is the group's own ID: every member has it,
+/// and it can't be deleted.
+pub struct Role {
+    pub id: i64,
+    // ID of the group the role is in
+    pub group: i64,
+    pub name: String,
+    // What the role allows its members to do
+    pub permissions: Vec<Permission>,
+}
+
+impl Role {
+    /// The role's permissions as a set
+    pub fn permission_set(&self) -> Permissions {
+        self.permissions.iter().copied().collect()
+    }
+
+    /// The default role of a group whose default role hasn't been changed
+    pub fn group_default(gid: i64) -> Self {
+        Role {
+            id: gid,
+            group: gid,
+            name: String::from("everyone"),
+            permissions: Permissions::member_default().list(),
+        }
+    }
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing permissions a channel allows or denies a role (or a single user),
+/// whatever they're allowed in the rest of the group.
+///
+/// Overrides for the group's default role come first, then those for any of the member's
+/// other roles, then the member's own; denying and allowing the same permission allows it.
+/// They don't apply to the group's owner or admins.
+pub struct ChannelOverride {
+    pub channel: i64,
+    // ID of a role (the group's ID for its default role) or of a user
+    pub target: i64,
+    pub allow: Vec<Permission>,
+    pub deny: Vec<Permission>,
+}
+
+impl ChannelOverride {
+    /// The permissions the override allows, as a set
+    pub fn allowed(&self) -> Permissions {
+        self.allow.iter().copied().collect()
+    }
+
+    /// The permissions the override denies, as a set
+    pub fn denied(&self) -> Permissions {
+        self.deny.iter().copied().collect()
+    }
+
+    /// Apply the override to what its target is allowed to do
+    pub fn apply(&self, permissions: Permissions) -> Permissions {
+        permissions.difference(self.denied()).union(self.allowed())
+    }
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing a user banned from a group, who can't be added back to it until
+/// the ban expires or is lifted
+pub struct Ban {
+    pub group: i64,
+    pub user: i64,
+    pub reason: String,
+    // When the ban expires, as a Unix timestamp, or nothing if it doesn't
+    pub expires: Option<i64>,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing a member of a group who's been timed out: until it expires (or is
+/// lifted), they can't do anything in the group, like sending messages
+pub struct Timeout {
+    pub group: i64,
+    pub user: i64,
+    pub reason: String,
+    // When the timeout expires, as a Unix timestamp
+    pub until: i64,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing an invite to a group, which anyone with its code can accept to
+/// join the group until it expires or runs out of uses
+pub struct Invite {
+    pub code: String,
+    pub group: i64,
+    // ID of the channel the invite is to, which they're added to as well as the public ones
+    pub channel: Option<i64>,
+    // ID of the user who made the invite
+    pub creator: i64,
+    // When the invite expires, as a Unix timestamp, or nothing if it doesn't
+    pub expires: Option<i64>,
+    // How many times the invite can be accepted, or nothing for no limit
+    pub max_uses: Option<i64>,
+    // How many times the invite has been accepted
+    pub uses: i64,
+}
+
+impl Invite {
+    /// Whether the invite can still be accepted at a given time (as a Unix timestamp)
+    pub fn usable(&self, now: i64) -> bool {
+        self.expires.is_none_or(|expires| expires > now) && self.max_uses.is_none_or(|max| self.uses < max)
+    }
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing a user asking to join a discoverable group, who joins it once a
+/// member who can add members approves
+pub struct JoinRequest {
+    pub group: i64,
+    pub user: i64,
+    // What the user said to the group when asking, if anything
+    pub message: String,
+    // When the user asked, as a Unix timestamp
+    pub timestamp: i64,
+}
+
+#[derive(Enum, Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+/// Something done in a group that goes in its audit log
+pub enum AuditAction {
+    /// Renamed the group
+    UpdateGroup,
+    /// Changed how the group is listed in the group directory
+    UpdateGroupProfile,
+    /// Added a member (the target)
+    AddMember,
+    /// Made an invite (the target is its channel, or the group for invites without one)
+    CreateInvite,
+    /// Deleted an invite (the target is who made it)
+    DeleteInvite,
+    /// Joined with an invite (the target is who made it)
+    AcceptInvite,
+    /// Let a user (the target) who asked to join the group in
+    ApproveJoinRequest,
+    /// Turned down a user's (the target's) request to join the group
+    DenyJoinRequest,
+    /// Removed a member
+    RemoveMember,
+    /// Made a member an admin
+    AddAdmin,
+    /// Took away a member's admin status
+    RemoveAdmin,
+    /// Handed the group to a member (the target)
+    TransferOwnership,
+    /// Banned a user
+    Ban,
+    /// Lifted a user's ban
+    Unban,
+    /// Timed out a member
+    Timeout,
+    /// Lifted a member's timeout
+    LiftTimeout,
+    /// Made a role
+    CreateRole,
+    /// Changed a role
+    UpdateRole,
+    /// Deleted a role
+    DeleteRole,
+    /// Gave a member (the target) a role
+    AddMemberRole,
+    /// Took a role away from a member (the target)
+    RemoveMemberRole,
+    /// Made a channel
+    CreateChannel,
+    /// Renamed a channel
+    UpdateChannel,
+    /// Made a channel private or public
+    SetChannelPrivate,
+    /// Deleted a channel
+    DeleteChannel,
+    /// Added a member (the target) to a channel
+    AddChannelMember,
+    /// Removed a member (the target) from a channel
+    RemoveChannelMember,
+    /// Set a channel's override for a role or user (the target)
+    SetChannelOverride,
+    /// Deleted a channel's override for a role or user (the target)
+    DeleteChannelOverride,
+    /// Deleted someone else's message
+    DeleteMessage,
+}
+
+impl AuditAction {
+    /// Every action
+    pub const ALL: [AuditAction; 30] = [
+        AuditAction::UpdateGroup,
+        AuditAction::UpdateGroupProfile,
+        AuditAction::AddMember,
+        AuditAction::CreateInvite,
+        AuditAction::DeleteInvite,
+        AuditAction::AcceptInvite,
+        AuditAction::ApproveJoinRequest,
+        AuditAction::DenyJoinRequest,
+        AuditAction::RemoveMember,
+        AuditAction::AddAdmin,
+        AuditAction::RemoveAdmin,
+        AuditAction::TransferOwnership,
+        AuditAction::Ban,
+        AuditAction::Unban,
+        AuditAction::Timeout,
+        AuditAction::LiftTimeout,
+        AuditAction::CreateRole,
+        AuditAction::UpdateRole,
+        AuditAction::DeleteRole,
+        AuditAction::AddMemberRole,
+        AuditAction::RemoveMemberRole,
+        AuditAction::CreateChannel,
+        AuditAction::UpdateChannel,
+        AuditAction::SetChannelPrivate,
+        AuditAction::DeleteChannel,
+        AuditAction::AddChannelMember,
+        AuditAction::RemoveChannelMember,
+        AuditAction::SetChannelOverride,
+        AuditAction::DeleteChannelOverride,
+        AuditAction::DeleteMessage,
+    ];
+
+    /// The name of the action, as used in the API and stored by the backends
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::UpdateGroup => "update_group",
+            AuditAction::UpdateGroupProfile => "update_group_profile",
+            AuditAction::AddMember => "add_member",
+            AuditAction::CreateInvite => "create_invite",
+            AuditAction::DeleteInvite => "delete_invite",
+            AuditAction::AcceptInvite => "accept_invite",
+            AuditAction::ApproveJoinRequest => "approve_join_request",
+            AuditAction::DenyJoinRequest => "deny_join_request",
+            AuditAction::RemoveMember => "remove_member",
+            AuditAction::AddAdmin => "add_admin",
+            AuditAction::RemoveAdmin => "remove_admin",
+            AuditAction::TransferOwnership => "transfer_ownership",
+            AuditAction::Ban => "ban",
+            AuditAction::Unban => "unban",
+            AuditAction::Timeout => "timeout",
+            AuditAction::LiftTimeout => "lift_timeout",
+            AuditAction::CreateRole => "create_role",
+            AuditAction::UpdateRole => "update_role",
+            AuditAction::DeleteRole => "delete_role",
+            AuditAction::AddMemberRole => "add_member_role",
+            AuditAction::RemoveMemberRole => "remove_member_role",
+            AuditAction::CreateChannel => "create_channel",
+            AuditAction::UpdateChannel => "update_channel",
+            AuditAction::SetChannelPrivate => "set_channel_private",
+            AuditAction::DeleteChannel => "delete_channel",
+            AuditAction::AddChannelMember => "add_channel_member",
+            AuditAction::RemoveChannelMember => "remove_channel_member",
+            AuditAction::SetChannelOverride => "set_channel_override",
+            AuditAction::DeleteChannelOverride => "delete_channel_override",
+            AuditAction::DeleteMessage => "delete_message",
+        }
+    }
+
+    /// Parse the name of an action, as returned by `as_str`
+    pub fn parse(s: &str) -> Option<Self> {
+        AuditAction::ALL.into_iter().find(|action| action.as_str() == s)
+    }
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Object representing an entry in a group's audit log, which records what was done in the
+/// group, by whom and why. Entries are never changed or deleted, apart from with the group.
+pub struct AuditEntry {
+    pub id: i64,
+    pub group: i64,
+    // ID of the user who did it
+    pub actor: i64,
+    pub action: AuditAction,
+    // ID of what it was done to: a user, channel, role or message, depending on the action
+    pub target: i64,
+    // When it was done, as a Unix timestamp
+    pub timestamp: i64,
+    // Why it was done, if the actor said. Empty otherwise.
+    pub reason: String,
+}
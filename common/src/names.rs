@@ -0,0 +1,249 @@
+//! Names of users, groups, channels and threads.
+//!
+//! Names are cleaned up before they're stored (see `NameLimits::clean`), so that what
+//! users see is what they typed: no invisible characters, no text direction overrides,
+//! no padding, and the same characters however they were composed.
+//!
+//! Usernames and emails are also unique: emails ignoring case, and usernames by their
+//! skeleton, so that nobody can take a lookalike of someone else's name. Each backend
+//! keeps a lookup table from the keys below to the user that has claimed them, which is
+//! also what users are found by when logging in or searching.
+use std::fmt;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::confusable_detection::skeleton;
+use unicode_security::mixed_script::{is_potential_mixed_script_confusable_char, MixedScript};
+
+/// What a name is for, which decides how long it can be
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NameKind {
+    User,
+    Group,
+    Channel,
+    Thread,
+    Role,
+}
+
+impl fmt::Display for NameKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NameKind::User => "username",
+            NameKind::Group => "group name",
+            NameKind::Channel => "channel name",
+            NameKind::Thread => "thread name",
+            NameKind::Role => "role name",
+        })
+    }
+}
+
+/// The longest names allowed, in characters (after cleaning)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NameLimits {
+    pub user: usize,
+    // Groups, channels, threads and roles
+    pub other: usize,
+}
+
+impl Default for NameLimits {
+    fn default() -> Self {
+        NameLimits { user: 32, other: 100 }
+    }
+}
+
+impl NameLimits {
+    /// Read the limits from `BSK_MAX_USERNAME_LEN` and `BSK_MAX_NAME_LEN` (for groups,
+    /// channels, threads and roles), keeping the defaults for any that aren't set
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str, default: usize| match std::env::var(name) {
+            Ok(len) => match len.parse() {
+                Ok(len) if len > 0 => Ok(len),
+                _ => Err(format!("invalid {name} {len}")),
+            },
+            Err(_) => Ok(default),
+        };
+        let default = NameLimits::default();
+        Ok(NameLimits {
+            user: var("BSK_MAX_USERNAME_LEN", default.user)?,
+            other: var("BSK_MAX_NAME_LEN", default.other)?,
+        })
+    }
+
+    /// The longest a name of the given kind can be
+    pub fn max(&self, kind: NameKind) -> usize {
+        match kind {
+            NameKind::User => self.user,
+            _ => self.other,
+        }
+    }
+
+    /// Clean up a name, or say why it can't be used. The name is:
+    /// - normalized to NFC, so precomposed and combining accents are stored the same way
+    /// - stripped of control, invisible and text direction characters
+    /// - stripped of leading and trailing whitespace, with any other whitespace (tabs,
+    ///   exotic spaces) turned into plain spaces
+    ///
+    /// after which it can't be empty or longer than the limit. Usernames can't have an
+    /// `@` in them either, so logging in can tell them apart from emails. Neither they
+    /// nor group names can pass off one script as another (see `is_mixed_lookalike`).
+    ///
+    /// Arguments:
+    /// - `kind`: what the name is for
+    /// - `name`: the name, as sent by the user
+    pub fn clean(&self, kind: NameKind, name: &str) -> Result<String, String> {
+        let cleaned: String = name.nfc()
+            .filter(|&c| !is_invisible(c))
+            .map(|c| if c.is_whitespace() { ' ' } else { c })
+            .collect();
+        let cleaned = cleaned.trim();
+        let len = cleaned.chars().count();
+        if len == 0 {
+            Err(format!("{kind} can't be empty"))
+        } else if len > self.max(kind) {
+            Err(format!("{kind} can't be longer than {} characters", self.max(kind)))
+        } else if kind == NameKind::User && cleaned.contains('@') {
+            Err(format!("{kind} can't contain @"))
+        } else if matches!(kind, NameKind::User | NameKind::Group) && cleaned.split(' ').any(is_mixed_lookalike) {
+            Err(format!("{kind} can't mix lookalike letters from different scripts"))
+        } else {
+            Ok(cleaned.to_string())
+        }
+    }
+}
+
+/// Whether a character doesn't show up, or changes how the text around it is shown,
+/// without being whitespace: controls, zero-width characters, text direction marks,
+/// overrides and isolates, and blank-looking fillers. Whitespace controls like tabs and
+/// newlines count as whitespace instead.
+fn is_invisible(c: char) -> bool {
+    (c.is_control() && !c.is_whitespace()) || matches!(c,
+        '\u{00AD}' | '\u{034F}' | '\u{061C}' | '\u{115F}' | '\u{1160}' | '\u{17B4}' | '\u{17B5}'
+        | '\u{180B}'..='\u{180F}' | '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}'
+        | '\u{2060}'..='\u{206F}' | '\u{2800}' | '\u{3164}' | '\u{FEFF}' | '\u{FFA0}'
+        | '\u{FFF0}'..='\u{FFF8}' | '\u{1D159}' | '\u{1D173}'..='\u{1D17A}' | '\u{E0000}'..='\u{E007F}'
+    )
+}
+
+/// Whether a word mixes scripts with letters that look like another script's, like a
+/// Latin `paypal` with a Cyrillic `а` in it. Words mixing scripts that don't look alike
+/// (or mixing them with digits, punctuation and emoji, which belong to none) are fine.
+fn is_mixed_lookalike(word: &str) -> bool {
+    !word.is_single_script() && word.chars().any(is_potential_mixed_script_confusable_char)
+}
+
+/// The key a username is looked up and kept unique by: its skeleton, ignoring case.
+///
+/// The skeleton (from Unicode's confusables data, see UTS #39) maps characters that look
+/// alike to the same one, so `аdmin` with a Cyrillic `а`, `AdMIN` and `adrnin` all get
+/// the same key as `admin`. Case is ignored first, so that names differing only in case
+/// always clash (at the cost of not telling an uppercase `I` from an `l`). It's only for
+/// comparing names, never for showing them.
+///
+/// Backends that store these keys need to make them again whenever this changes.
+pub fn username_key(name: &str) -> String {
+    skeleton(&name.to_lowercase()).collect::<String>().to_lowercase()
+}
+
+/// The key an email address is looked up and kept unique by. Users without one (like
+/// bots) have an empty email, which is never claimed.
+pub fn email_key(email: &str) -> String {
+    email.to_lowercase()
+}
+
+/// The range of keys starting with `prefix`, for backends that store them in order of
+/// their UTF-8 bytes: `start <= key < end`
+pub(crate) fn prefix_range(prefix: &str) -> (String, String) {
+    let start = username_key(prefix);
+    let end = format!("{start}{}", char::MAX);
+    (start, end)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(username_key("Fred"), username_key("fRED"));
+        assert_eq!(username_key("ÉMILE"), username_key("\u{e9}mile"));
+        assert_eq!(email_key("Fred@Example.com"), "fred@example.com");
+    }
+
+    #[test]
+    fn test_skeletons() {
+        // Cyrillic and Greek lookalikes, digits, and letter pairs that look like one
+        for name in ["\u{430}dmin", "\u{391}DMIN", "AdMIN", "adrnin"] {
+            assert_eq!(username_key(name), username_key("admin"), "{name}");
+        }
+        assert_eq!(username_key("paypa1"), username_key("PAYPAL"));
+        for name in ["admins", "amin", "\u{e1}dmin", "bob"] {
+            assert_ne!(username_key(name), username_key("admin"), "{name}");
+        }
+        // Other scripts are kept apart from each other where they don't look alike
+        assert_ne!(username_key("\u{65e5}\u{672c}"), username_key("\u{4e2d}\u{6587}"));
+        // Keys of prefixes are prefixes of the keys, which searching relies on
+        assert!(username_key("\u{430}dmin").starts_with(&username_key("\u{430}dm")));
+    }
+
+    #[test]
+    fn test_clean() {
+        let limits = NameLimits::default();
+        let clean = |kind, name: &str| limits.clean(kind, name);
+        assert_eq!(clean(NameKind::User, "fred"), Ok("fred".to_string()));
+        // Combining accents are composed
+        assert_eq!(clean(NameKind::User, "e\u{301}mile"), Ok("\u{e9}mile".to_string()));
+        // Invisible and direction characters go, and other whitespace is tidied up
+        assert_eq!(clean(NameKind::User, "\u{200B}fr\u{202E}ed\u{FEFF}"), Ok("fred".to_string()));
+        assert_eq!(clean(NameKind::Group, " \u{3000}the\tgroup\u{2800} \n"), Ok("the group".to_string()));
+        assert_eq!(clean(NameKind::Channel, "a\u{0}b\u{7f}"), Ok("ab".to_string()));
+        // Emoji and other scripts are fine
+        assert_eq!(clean(NameKind::Thread, "日本語 🎉"), Ok("日本語 🎉".to_string()));
+
+        for name in ["", "   ", "\u{200B}\u{200D}", "\u{3164}"] {
+            assert_eq!(clean(NameKind::User, name), Err("username can't be empty".to_string()), "{name:?}");
+        }
+        assert_eq!(clean(NameKind::Channel, ""), Err("channel name can't be empty".to_string()));
+        assert_eq!(clean(NameKind::User, "a@b"), Err("username can't contain @".to_string()));
+        assert_eq!(clean(NameKind::Group, "a@b"), Ok("a@b".to_string()));
+    }
+
+    #[test]
+    fn test_clean_lookalikes() {
+        let limits = NameLimits::default();
+        let mixed = |kind| Err(format!("{kind} can't mix lookalike letters from different scripts"));
+        assert_eq!(limits.clean(NameKind::User, "p\u{430}ypal"), mixed(NameKind::User));
+        assert_eq!(limits.clean(NameKind::Group, "The P\u{430}yPal group"), mixed(NameKind::Group));
+        // Each word can be in its own script
+        assert_eq!(limits.clean(NameKind::Group, "Москва fans"), Ok("Москва fans".to_string()));
+        assert_eq!(limits.clean(NameKind::User, "\u{430}\u{431}\u{432}"), Ok("\u{430}\u{431}\u{432}".to_string()));
+        assert_eq!(limits.clean(NameKind::User, "fred_99 🎉"), Ok("fred_99 🎉".to_string()));
+        // Channels and threads are only seen inside a group, so they're left alone
+        assert_eq!(limits.clean(NameKind::Channel, "p\u{430}ypal"), Ok("p\u{430}ypal".to_string()));
+    }
+
+    #[test]
+    fn test_clean_length() {
+        let limits = NameLimits { user: 4, other: 6 };
+        // Limits count characters, after cleaning
+        assert_eq!(limits.clean(NameKind::User, " éééé\u{200B} "), Ok("éééé".to_string()));
+        assert_eq!(limits.clean(NameKind::User, "e\u{301}e\u{301}e\u{301}e\u{301}"), Ok("éééé".to_string()));
+        assert_eq!(
+            limits.clean(NameKind::User, "fredd"),
+            Err("username can't be longer than 4 characters".to_string())
+        );
+        assert_eq!(limits.clean(NameKind::Thread, "thread"), Ok("thread".to_string()));
+        assert!(limits.clean(NameKind::Thread, "threads").is_err());
+        // Long names used to overflow the old fixed-size buffer
+        assert!(NameLimits::default().clean(NameKind::User, &"a".repeat(1000)).is_err());
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        let (start, end) = prefix_range("Fr");
+        for key in ["fr", "fred", "fr\u{ffff}"] {
+            assert!(start.as_str() <= key && key < end.as_str(), "{key}");
+        }
+        for key in ["f", "fq", "fs", "gr"] {
+            assert!(!(start.as_str() <= key && key < end.as_str()), "{key}");
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! Working out what users (and bots) can do in a group, from its owner, admins, roles and
+//! timeouts, and in a channel, from the channel's overrides too.
+//!
+//! Both services check permissions through here, so that they always agree.
+use crate::auth::Claims;
+use crate::db::{Database, DbError, Result};
+use crate::models::*;
+
+/// Get a group's default role, which every member has. Groups whose default role
+/// hasn't been changed don't have it stored, so it's made up from `Role::group_default`.
+pub async fn default_role(db: &dyn Database, gid: i64) -> Result<Role> {
+    match db.get_role(gid).await {
+        Err(DbError::NotFound(_)) => Ok(Role::group_default(gid)),
+        res => res,
+    }
+}
+
+/// Whether a member of a group is timed out in it
+pub async fn timed_out(db: &dyn Database, gid: i64, uid: i64) -> Result<bool> {
+    match db.get_timeout(gid, uid).await {
+        Ok(_) => Ok(true),
+        Err(DbError::NotFound(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// What a user can do in a group before their roles count, if that's decided already:
+/// nothing if they aren't a member or are timed out, and everything for the owner and
+/// admins (though the owner can't be timed out)
+async fn fixed_permissions(db: &dyn Database, group: &Group, uid: i64) -> Result<Option<Permissions>> {
+    if group.owner == uid && group.members.contains(&uid) {
+        Ok(Some(Permissions::all()))
+    } else if !group.members.contains(&uid) || timed_out(db, group.id, uid).await? {
+        Ok(Some(Permissions::NONE))
+    } else if group.admin.contains(&uid) {
+        Ok(Some(Permissions::all()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Work out what a user can do in a group:
+/// - anyone who isn't a member, or is timed out, can't do anything
+/// - the owner and admins can do everything
+/// - everyone else can do what the default role and the roles they've been given allow
+pub async fn member_permissions(db: &dyn Database, gid: i64, uid: i64) -> Result<Permissions> {
+    let group = db.get_group(gid).await?;
+    if let Some(permissions) = fixed_permissions(db, &group, uid).await? {
+        return Ok(permissions);
+    }
+    role_permissions(db, gid, &db.get_member_roles(gid, uid).await?).await
+}
+
+/// What a group's default role and some of its other roles allow together
+async fn role_permissions(db: &dyn Database, gid: i64, roles: &[i64]) -> Result<Permissions> {
+    let mut permissions = default_role(db, gid).await?.permission_set();
+    for rid in roles {
+        match db.get_role(*rid).await {
+            Ok(role) => permissions = permissions.union(role.permission_set()),
+            // Deleted since it was given out
+            Err(DbError::NotFound(_)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(permissions)
+}
+
+/// Work out what a user can do in a channel: what they can do in its group, changed by
+/// the channel's overrides for the default role, then for their other roles (all at once),
+/// then for them. Overrides don't change what the owner and admins (or anyone timed out)
+/// can do, and nobody else can do anything in a private channel they aren't a member of.
+pub async fn member_channel_permissions(db: &dyn Database, cid: i64, uid: i64) -> Result<Permissions> {
+    let channel = db.get_channel(cid).await?;
+    let group = db.get_group(channel.group).await?;
+    if let Some(permissions) = fixed_permissions(db, &group, uid).await? {
+        return Ok(permissions);
+    }
+    if channel.private && !channel.members.contains(&uid) {
+        return Ok(Permissions::NONE);
+    }
+    let roles = db.get_member_roles(group.id, uid).await?;
+    let mut permissions = role_permissions(db, group.id, &roles).await?;
+    let overrides = db.get_channel_overrides(cid).await?;
+    if let Some(over) = overrides.iter().find(|over| over.target == group.id) {
+        permissions = over.apply(permissions);
+    }
+    let (mut allow, mut deny) = (Permissions::NONE, Permissions::NONE);
+    for over in overrides.iter().filter(|over| roles.contains(&over.target)) {
+        allow = allow.union(over.allowed());
+        deny = deny.union(over.denied());
+    }
+    permissions = permissions.difference(deny).union(allow);
+    if let Some(over) = overrides.iter().find(|over| over.target == uid) {
+        permissions = over.apply(permissions);
+    }
+    Ok(permissions)
+}
+
+/// Only keep the permissions a bot's token has a scope for in a group (see `Permission::scope`)
+fn scoped(permissions: Permissions, claims: &Claims, gid: i64) -> Permissions {
+    if !claims.is_bot() {
+        return permissions;
+    }
+    permissions.list()
+        .into_iter()
+        .filter(|p| p.scope().is_some_and(|kind| claims.allows(kind, gid)))
+        .collect()
+}
+
+/// Work out what the user (or bot) some claims came from can do in a group. Bots can
+/// only use the permissions their token has a scope for (see `Permission::scope`).
+pub async fn permissions(db: &dyn Database, claims: &Claims, gid: i64) -> Result<Permissions> {
+    Ok(scoped(member_permissions(db, gid, claims.id).await?, claims, gid))
+}
+
+/// Work out what the user (or bot) some claims came from can do in a channel, like
+/// `permissions` does for a group
+pub async fn channel_permissions(db: &dyn Database, claims: &Claims, cid: i64) -> Result<Permissions> {
+    let gid = db.get_channel(cid).await?.group;
+    Ok(scoped(member_channel_permissions(db, cid, claims.id).await?, claims, gid))
+}
+
+/// Whether the user (or bot) some claims came from has a permission in a group
+pub async fn authorize(db: &dyn Database, claims: &Claims, gid: i64, permission: Permission) -> Result<bool> {
+    Ok(permissions(db, claims, gid).await?.contains(permission))
+}
+
+/// Whether the user (or bot) some claims came from has a permission in a channel
+pub async fn authorize_channel(db: &dyn Database, claims: &Claims, cid: i64, permission: Permission) -> Result<bool> {
+    Ok(channel_permissions(db, claims, cid).await?.contains(permission))
+}
+
+/// Whether the user (or bot) some claims came from can see a group and its members: they
+/// have to be a member of it, and bots need a `read_messages` scope for it too
+pub async fn can_view(db: &dyn Database, claims: &Claims, gid: i64) -> Result<bool> {
+    Ok(claims.allows(ScopeKind::ReadMessages, gid) && db.get_group_members(gid).await?.contains(&claims.id))
+}
+
+/// Whether the user (or bot) some claims came from can read a channel's messages and see
+/// its members: they have to be able to see its group (see `can_view`), and private
+/// channels are only for their members, the group's owner and its admins
+pub async fn can_read(db: &dyn Database, claims: &Claims, cid: i64) -> Result<bool> {
+    let channel = db.get_channel(cid).await?;
+    if !claims.allows(ScopeKind::ReadMessages, channel.group) {
+        return Ok(false);
+    }
+    let group = db.get_group(channel.group).await?;
+    Ok(group.members.contains(&claims.id)
+        && (!channel.private
+            || channel.members.contains(&claims.id)
+            || group.owner == claims.id
+            || group.admin.contains(&claims.id)))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::db::InMemory;
+
+    #[tokio::test]
+    async fn test_member_permissions() {
+        let db = InMemory::new();
+        db.create_group(1, 10, "test".to_string(), false).await.unwrap();
+        for uid in [20, 30, 40] {
+            db.add_group_member(1, uid).await.unwrap();
+        }
+        db.add_group_admin(1, 20).await.unwrap();
+        assert_eq!(member_permissions(&db, 1, 10).await.unwrap(), Permissions::all());
+        assert_eq!(member_permissions(&db, 1, 20).await.unwrap(), Permissions::all());
+        assert_eq!(member_permissions(&db, 1, 30).await.unwrap(), Permissions::member_default());
+        assert_eq!(member_permissions(&db, 1, 50).await.unwrap(), Permissions::NONE);
+
+        // Roles add to the default role, which can be changed too
+        let mods = Role { id: 2, group: 1, name: "mods".to_string(), permissions: vec![Permission::DeleteMessages] };
+        db.set_role(mods).await.unwrap();
+        db.add_member_role(1, 30, 2).await.unwrap();
+        db.set_role(Role { permissions: vec![Permission::SendMessages], ..Role::group_default(1) }).await.unwrap();
+        let expected: Permissions = [Permission::DeleteMessages, Permission::SendMessages].into_iter().collect();
+        assert_eq!(member_permissions(&db, 1, 30).await.unwrap(), expected);
+        assert_eq!(member_permissions(&db, 1, 40).await.unwrap(), Permission::SendMessages.into());
+
+        db.delete_role(2).await.unwrap();
+        assert_eq!(db.get_member_roles(1, 30).await.unwrap(), Vec::<i64>::new());
+        assert_eq!(member_permissions(&db, 1, 30).await.unwrap(), Permission::SendMessages.into());
+
+        // Timeouts take everything away until they expire, even from admins
+        let until = chrono::Utc::now().timestamp() + 60;
+        for uid in [10, 20, 30] {
+            db.set_timeout(Timeout { group: 1, user: uid, reason: String::new(), until }).await.unwrap();
+        }
+        db.set_timeout(Timeout { group: 1, user: 40, reason: String::new(), until: until - 120 }).await.unwrap();
+        assert_eq!(member_permissions(&db, 1, 10).await.unwrap(), Permissions::all());
+        assert_eq!(member_permissions(&db, 1, 20).await.unwrap(), Permissions::NONE);
+        assert_eq!(member_permissions(&db, 1, 30).await.unwrap(), Permissions::NONE);
+        assert_eq!(member_permissions(&db, 1, 40).await.unwrap(), Permission::SendMessages.into());
+    }
+
+    #[tokio::test]
+    async fn test_channel_permissions() {
+        let db = InMemory::new();
+        db.create_group(1, 10, "test".to_string(), false).await.unwrap();
+        db.create_channel(3, 1, 10, "announcements".to_string()).await.unwrap();
+        for uid in [20, 30, 40] {
+            db.add_group_member(1, uid).await.unwrap();
+        }
+        db.add_group_admin(1, 20).await.unwrap();
+        let posters = Role { id: 2, group: 1, name: "posters".to_string(), permissions: vec![] };
+        db.set_role(posters).await.unwrap();
+        db.add_member_role(1, 30, 2).await.unwrap();
+        db.add_member_role(1, 40, 2).await.unwrap();
+        assert_eq!(member_channel_permissions(&db, 3, 30).await.unwrap(), Permissions::member_default());
+
+        // Only posters can post, apart from one of them, and admins aren't affected
+        let over = |target, allow, deny| ChannelOverride { channel: 3, target, allow, deny };
+        db.set_channel_override(over(1, vec![], vec![Permission::SendMessages, Permission::CreateThreads])).await.unwrap();
+        db.set_channel_override(over(2, vec![Permission::SendMessages], vec![])).await.unwrap();
+        db.set_channel_override(over(40, vec![], vec![Permission::SendMessages])).await.unwrap();
+        let expected: Permissions = [Permission::SendMessages, Permission::MentionEveryone].into_iter().collect();
+        assert_eq!(member_channel_permissions(&db, 3, 30).await.unwrap(), expected);
+        assert_eq!(member_channel_permissions(&db, 3, 40).await.unwrap(), Permission::MentionEveryone.into());
+        assert_eq!(member_channel_permissions(&db, 3, 20).await.unwrap(), Permissions::all());
+        assert_eq!(member_channel_permissions(&db, 3, 50).await.unwrap(), Permissions::NONE);
+        // ...and nothing changes in the rest of the group
+        assert_eq!(member_permissions(&db, 1, 40).await.unwrap(), Permissions::member_default());
+
+        db.delete_channel_override(3, 40).await.unwrap();
+        assert_eq!(member_channel_permissions(&db, 3, 40).await.unwrap(), expected);
+
+        // Private channels are only for their members, apart from the owner and admins
+        db.set_channel_private(3, true).await.unwrap();
+        db.add_channel_member(3, 40).await.unwrap();
+        assert_eq!(member_channel_permissions(&db, 3, 30).await.unwrap(), Permissions::NONE);
+        assert_eq!(member_channel_permissions(&db, 3, 40).await.unwrap(), expected);
+        assert_eq!(member_channel_permissions(&db, 3, 20).await.unwrap(), Permissions::all());
+        assert_eq!(member_channel_permissions(&db, 3, 10).await.unwrap(), Permissions::all());
+        db.delete_channel(3).await.unwrap();
+        assert_eq!(db.get_channel_overrides(3).await.unwrap(), Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_can_read() {
+        let db = InMemory::new();
+        db.create_group(1, 10, "test".to_string(), false).await.unwrap();
+        db.create_channel(3, 1, 10, "secret".to_string()).await.unwrap();
+        for uid in [20, 30, 40] {
+            db.add_group_member(1, uid).await.unwrap();
+        }
+        db.add_group_admin(1, 20).await.unwrap();
+        db.set_channel_private(3, true).await.unwrap();
+        db.add_channel_member(3, 40).await.unwrap();
+        let claims = |id, scopes| Claims { id, sid: 0, exp: chrono::Local::now(), scopes };
+
+        // Private channels are only for their members, the owner and admins
+        for (uid, expected) in [(10, true), (20, true), (30, false), (40, true), (50, false)] {
+            assert_eq!(can_read(&db, &claims(uid, None), 3).await.unwrap(), expected, "{uid}");
+        }
+        assert!(can_view(&db, &claims(30, None), 1).await.unwrap());
+        assert!(!can_view(&db, &claims(50, None), 1).await.unwrap());
+
+        // ...and bots need a scope for the group too
+        let read = Scope { kind: ScopeKind::ReadMessages, group: 1 };
+        let send = Scope { kind: ScopeKind::SendMessages, group: 1 };
+        assert!(can_read(&db, &claims(40, Some(vec![read])), 3).await.unwrap());
+        assert!(!can_read(&db, &claims(40, Some(vec![send])), 3).await.unwrap());
+        assert!(!can_view(&db, &claims(40, Some(vec![])), 1).await.unwrap());
+    }
+}
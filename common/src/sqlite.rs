@@ -0,0 +1,1789 @@
+use async_trait::async_trait;
+use rusqlite::{params, params_from_iter, Connection, ErrorCode, OptionalExtension, TransactionBehavior};
+use std::sync::{Arc, Mutex};
+use crate::db::{invite_not_found, not_found, taken_if_conflict, AuditQuery, AuthSession, BotToken, Database, DbError, DirectoryQuery, IdType, Identity, Result, UserTotp};
+use crate::models::*;
+use crate::names::{email_key, prefix_range, username_key};
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        use rusqlite::Error::*;
+        match e {
+            QueryReturnedNoRows => DbError::NotFound(e.to_string()),
+            SqliteFailure(err, _) if err.code == ErrorCode::ConstraintViolation => {
+                DbError::Conflict(e.to_string())
+            }
+            FromSqlConversionFailure(..) | IntegralValueOutOfRange(..)
+            | InvalidColumnType(..) | Utf8Error(_) => DbError::Serialization(e.to_string()),
+            _ => DbError::Backend(e.to_string()),
+        }
+    }
+}
+
+/// SQLite backend struct
+///
+/// Unlike the Cassandra tables, the sets of IDs (members, admins, channels...) are
+/// stored as join tables so that deleting a user/group/channel cleans up after itself.
+#[derive(Clone)]
+pub struct Sqlite {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Sqlite {
+    /// Open (or create) the database file and create tables
+    ///
+    /// Arguments:
+    /// - `path`: the SQLite file to use. `chatterbox` should be pointed at the same one.
+    pub fn new(path: &str) -> Self {
+        let mut conn = Connection::open(path).unwrap();
+        // Both services write to the file, so wait on each other's locks instead of failing
+        conn.busy_timeout(std::time::Duration::from_secs(5)).unwrap();
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA foreign_keys = ON;
+
+             CREATE TABLE IF NOT EXISTS users (
+                 id INTEGER PRIMARY KEY,
+                 name TEXT NOT NULL,
+                 email TEXT NOT NULL,
+                 hash TEXT NOT NULL
+             );
+
+             CREATE TABLE IF NOT EXISTS groups (
+                 id INTEGER PRIMARY KEY,
+                 name TEXT NOT NULL,
+                 owner INTEGER NOT NULL,
+                 is_dm INTEGER NOT NULL
+             );
+
+             CREATE TABLE IF NOT EXISTS channels (
+                 id INTEGER PRIMARY KEY,
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 name TEXT NOT NULL,
+                 private INTEGER NOT NULL
+             );
+
+             CREATE TABLE IF NOT EXISTS group_members (
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 PRIMARY KEY (group_id, user_id)
+             );
+
+             CREATE TABLE IF NOT EXISTS group_admins (
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 PRIMARY KEY (group_id, user_id)
+             );
+
+             -- permissions is a bitset, see `Permission::bit`
+             CREATE TABLE IF NOT EXISTS roles (
+                 id INTEGER PRIMARY KEY,
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 name TEXT NOT NULL,
+                 permissions INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS roles_by_group ON roles (group_id);
+
+             CREATE TABLE IF NOT EXISTS member_roles (
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 role_id INTEGER NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+                 PRIMARY KEY (group_id, user_id, role_id)
+             );
+
+             -- expires and until are Unix timestamps; a ban without expires is for good
+             CREATE TABLE IF NOT EXISTS bans (
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 reason TEXT NOT NULL,
+                 expires INTEGER,
+                 PRIMARY KEY (group_id, user_id)
+             );
+
+             CREATE TABLE IF NOT EXISTS timeouts (
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 reason TEXT NOT NULL,
+                 until INTEGER NOT NULL,
+                 PRIMARY KEY (group_id, user_id)
+             );
+
+             -- How groups are listed in the directory; tags is space-separated
+             CREATE TABLE IF NOT EXISTS group_profiles (
+                 group_id INTEGER PRIMARY KEY REFERENCES groups(id) ON DELETE CASCADE,
+                 discoverable INTEGER NOT NULL,
+                 requires_approval INTEGER NOT NULL,
+                 description TEXT NOT NULL,
+                 tags TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS group_profiles_discoverable ON group_profiles (discoverable, group_id);
+
+             -- When each member joined, in milliseconds; members from before this have no row
+             CREATE TABLE IF NOT EXISTS member_joins (
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 joined INTEGER NOT NULL,
+                 PRIMARY KEY (group_id, user_id)
+             );
+
+             CREATE TABLE IF NOT EXISTS join_requests (
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 message TEXT NOT NULL,
+                 timestamp INTEGER NOT NULL,
+                 PRIMARY KEY (group_id, user_id)
+             );
+
+             -- actor and target aren't references, so entries outlive what they're about
+             CREATE TABLE IF NOT EXISTS audit_log (
+                 id INTEGER PRIMARY KEY,
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 actor INTEGER NOT NULL,
+                 action TEXT NOT NULL,
+                 target INTEGER NOT NULL,
+                 timestamp INTEGER NOT NULL,
+                 reason TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS audit_log_group ON audit_log (group_id, id);
+
+             -- channel and creator aren't references: the invite outlives them, but not its group
+             CREATE TABLE IF NOT EXISTS invites (
+                 code TEXT PRIMARY KEY,
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 channel INTEGER,
+                 creator INTEGER NOT NULL,
+                 expires INTEGER,
+                 max_uses INTEGER,
+                 uses INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS invites_group ON invites (group_id);
+
+             CREATE TABLE IF NOT EXISTS group_channels (
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 channel_id INTEGER NOT NULL REFERENCES channels(id) ON DELETE CASCADE,
+                 PRIMARY KEY (group_id, channel_id)
+             );
+
+             CREATE TABLE IF NOT EXISTS channel_members (
+                 channel_id INTEGER NOT NULL REFERENCES channels(id) ON DELETE CASCADE,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 PRIMARY KEY (channel_id, user_id)
+             );
+
+             -- allow and deny are bitsets too; target is a role or user, so there's no reference
+             CREATE TABLE IF NOT EXISTS channel_overrides (
+                 channel_id INTEGER NOT NULL REFERENCES channels(id) ON DELETE CASCADE,
+                 target INTEGER NOT NULL,
+                 allow INTEGER NOT NULL,
+                 deny INTEGER NOT NULL,
+                 PRIMARY KEY (channel_id, target)
+             );
+
+             CREATE TABLE IF NOT EXISTS user_groups (
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 PRIMARY KEY (user_id, group_id)
+             );
+
+             CREATE TABLE IF NOT EXISTS user_dms (
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 group_id INTEGER NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                 PRIMARY KEY (user_id, group_id)
+             );
+
+             CREATE TABLE IF NOT EXISTS messages (
+                 id INTEGER PRIMARY KEY,
+                 channel INTEGER NOT NULL REFERENCES channels(id) ON DELETE CASCADE,
+                 author INTEGER NOT NULL,
+                 content TEXT NOT NULL,
+                 thread INTEGER
+             );
+             CREATE INDEX IF NOT EXISTS messages_by_channel ON messages (channel, id DESC);
+
+             CREATE TABLE IF NOT EXISTS sessions (
+                 id INTEGER PRIMARY KEY,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 refresh_hash TEXT NOT NULL,
+                 expires INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS sessions_by_user ON sessions (user_id);
+
+             CREATE TABLE IF NOT EXISTS revoked_sessions (
+                 id INTEGER PRIMARY KEY,
+                 until INTEGER NOT NULL
+             );
+
+             -- recovery_codes is space-separated
+             CREATE TABLE IF NOT EXISTS user_totp (
+                 user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+                 secret TEXT NOT NULL,
+                 enabled INTEGER NOT NULL,
+                 last_step INTEGER NOT NULL,
+                 recovery_codes TEXT NOT NULL
+             );
+
+             CREATE TABLE IF NOT EXISTS bots (
+                 id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+                 owner INTEGER NOT NULL REFERENCES users(id)
+             );
+             CREATE INDEX IF NOT EXISTS bots_by_owner ON bots (owner);
+
+             -- scopes is space-separated, see `Scope::join`
+             CREATE TABLE IF NOT EXISTS api_tokens (
+                 id INTEGER PRIMARY KEY,
+                 bot INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 name TEXT NOT NULL,
+                 hash TEXT NOT NULL,
+                 scopes TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS api_tokens_by_bot ON api_tokens (bot);
+
+             CREATE TABLE IF NOT EXISTS verified_emails (
+                 user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+                 email TEXT NOT NULL
+             );
+
+             CREATE TABLE IF NOT EXISTS identities (
+                 issuer TEXT NOT NULL,
+                 subject TEXT NOT NULL,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                 PRIMARY KEY (issuer, subject)
+             );
+             CREATE INDEX IF NOT EXISTS identities_by_user ON identities (user_id);
+
+             -- Claimed username and email keys, see `names`
+             CREATE TABLE IF NOT EXISTS usernames (
+                 key TEXT PRIMARY KEY,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE
+             );
+             CREATE INDEX IF NOT EXISTS usernames_by_user ON usernames (user_id);
+
+             CREATE TABLE IF NOT EXISTS emails (
+                 key TEXT PRIMARY KEY,
+                 user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE
+             );
+             CREATE INDEX IF NOT EXISTS emails_by_user ON emails (user_id);"
+        ).unwrap();
+        backfill_names(&mut conn).unwrap();
+        Self { conn: Arc::new(Mutex::new(conn)) }
+    }
+
+    /// Run a closure against the connection on the blocking thread pool.
+    ///
+    /// rusqlite is synchronous, so this keeps queries off of the async worker threads.
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .map_err(|e| DbError::Backend(e.to_string()))?
+    }
+
+    /// Run a statement that changes rows, returning `NotFound` if it didn't change any
+    ///
+    /// Arguments:
+    /// - `table`: the table being changed (for the error message)
+    /// - `id`: the id of the row being changed
+    /// - `sql`: the statement, with `id` as its first parameter
+    /// - `value`: the statement's second parameter
+    async fn update<V>(&self, table: &'static str, id: i64, sql: &'static str, value: V) -> Result<()>
+    where
+        V: rusqlite::ToSql + Send + 'static,
+    {
+        self.run(move |conn| match conn.execute(sql, params![id, value])? {
+            0 => Err(not_found(table, id)),
+            _ => Ok(()),
+        }).await
+    }
+
+    /// Run a statement that doesn't care whether any rows were changed
+    ///
+    /// Arguments:
+    /// - `sql`: the statement to run
+    /// - `ids`: the IDs to bind to the statement's parameters, in order
+    async fn exec<const N: usize>(&self, sql: &'static str, ids: [i64; N]) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(sql, params_from_iter(ids))?;
+            Ok(())
+        }).await
+    }
+
+    /// Get the set of IDs belonging to a row in a join table
+    ///
+    /// Arguments:
+    /// - `parent`: the table the row belongs to, or `None` if a missing row is fine
+    /// - `sql`: the query selecting the IDs, with the row's `id` as its only parameter
+    /// - `id`: the id of the row to get the set for
+    async fn get_set(&self, parent: Option<&'static str>, sql: &'static str, id: i64) -> Result<Vec<i64>> {
+        self.run(move |conn| {
+            if let Some(table) = parent {
+                if !exists(conn, table, id)? {
+                    return Err(not_found(table, id));
+                }
+            }
+            query_set(conn, sql, id)
+        }).await
+    }
+}
+
+/// Claim a username and email for a user, releasing the ones they had. Returns
+/// `Conflict` if someone else has either; run it in a transaction so that nothing is
+/// claimed when that happens.
+fn claim_names(conn: &Connection, id: i64, name: &str, email: &str) -> Result<()> {
+    conn.execute("DELETE FROM usernames WHERE user_id = ?1", params![id])?;
+    conn.execute("DELETE FROM emails WHERE user_id = ?1", params![id])?;
+    conn.execute("INSERT INTO usernames (key, user_id) VALUES (?1, ?2)", params![username_key(name), id])
+        .map_err(|e| taken_if_conflict(e.into(), "username", name))?;
+    if !email.is_empty() {
+        conn.execute("INSERT INTO emails (key, user_id) VALUES (?1, ?2)", params![email_key(email), id])
+            .map_err(|e| taken_if_conflict(e.into(), "email", email))?;
+    }
+    Ok(())
+}
+
+/// The version of `username_key` the claimed usernames were made with, kept in the
+/// database's `user_version`. Bump it whenever `username_key` changes, so they're made
+/// again with the new one.
+const NAME_KEYS_VERSION: i32 = 1;
+
+/// Claim the names of users from before they were unique (or from before the keys were
+/// last changed). Where several of them share a name (or email), whoever was made first
+/// keeps it, and the others can't be found by it until they change theirs.
+fn backfill_names(conn: &mut Connection) -> Result<()> {
+    // Immediate, so the other service can't claim names between the checks and the inserts
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: i32 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version < NAME_KEYS_VERSION {
+        tx.execute("DELETE FROM usernames", [])?;
+        tx.pragma_update(None, "user_version", NAME_KEYS_VERSION)?;
+    }
+    let users = tx.prepare(
+        "SELECT id, name, email FROM users WHERE id NOT IN (SELECT user_id FROM usernames) ORDER BY id"
+    )?
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (id, name, email) in users {
+        tx.execute("INSERT OR IGNORE INTO usernames (key, user_id) VALUES (?1, ?2)", params![username_key(&name), id])?;
+        if !email.is_empty() {
+            tx.execute("INSERT OR IGNORE INTO emails (key, user_id) VALUES (?1, ?2)", params![email_key(&email), id])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Check whether a row with the given ID exists
+fn exists(conn: &Connection, table: &str, id: i64) -> Result<bool> {
+    Ok(conn.query_row(
+        &format!("SELECT EXISTS(SELECT 1 FROM {table} WHERE id = ?1)"),
+        params![id],
+        |row| row.get(0),
+    )?)
+}
+
+/// Collect the first column of a query returning IDs
+fn query_set(conn: &Connection, sql: &str, id: i64) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare_cached(sql)?;
+    let ids = stmt.query_map(params![id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+    Ok(ids)
+}
+
+/// Convert a row of `SELECT id, channel, author, content, thread FROM messages`
+fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<Message> {
+    Ok(Message {
+        id: row.get(0)?,
+        channel: row.get(1)?,
+        author: row.get(2)?,
+        content: row.get(3)?,
+        thread: row.get(4)?,
+    })
+}
+
+/// Convert a row of `SELECT id, group_id, name, permissions FROM roles`
+fn role_from_row(row: &rusqlite::Row) -> rusqlite::Result<Role> {
+    Ok(Role {
+        id: row.get(0)?,
+        group: row.get(1)?,
+        name: row.get(2)?,
+        permissions: Permissions(row.get(3)?).list(),
+    })
+}
+
+/// Convert a row of `SELECT code, group_id, channel, creator, expires, max_uses, uses FROM invites`
+fn invite_from_row(row: &rusqlite::Row) -> rusqlite::Result<Invite> {
+    Ok(Invite {
+        code: row.get(0)?,
+        group: row.get(1)?,
+        channel: row.get(2)?,
+        creator: row.get(3)?,
+        expires: row.get(4)?,
+        max_uses: row.get(5)?,
+        uses: row.get(6)?,
+    })
+}
+
+#[async_trait]
+impl Database for Sqlite {
+    async fn valid_id(&self, kind: IdType, id: i64) -> Result<bool> {
+        let table = match kind {
+            IdType::User => "users",
+            IdType::Group => "groups",
+            IdType::Channel => "channels",
+            IdType::Message => "messages",
+        };
+        self.run(move |conn| exists(conn, table, id)).await
+    }
+
+    async fn create_user(&self, id: i64, name: String, email: String, hash: String) -> Result<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO users (id, name, email, hash) VALUES (?1, ?2, ?3, ?4)",
+                params![id, name, email, hash],
+            )?;
+            claim_names(&tx, id, &name, &email)?;
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_user(&self, id: i64) -> Result<User> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT name, email FROM users WHERE id = ?1",
+                params![id],
+                |row| Ok(User { id, username: row.get(0)?, email: row.get(1)? }),
+            ).optional()?.ok_or_else(|| not_found("users", id))
+        }).await
+    }
+
+    async fn get_user_hash(&self, id: i64) -> Result<String> {
+        self.run(move |conn| {
+            conn.query_row("SELECT hash FROM users WHERE id = ?1", params![id], |row| row.get(0))
+                .optional()?.ok_or_else(|| not_found("users", id))
+        }).await
+    }
+
+    async fn set_user_hash(&self, id: i64, hash: String) -> Result<()> {
+        self.update("users", id, "UPDATE users SET hash = ?2 WHERE id = ?1", hash).await
+    }
+
+    async fn update_user(&self, id: i64, name: String, email: String) -> Result<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            if tx.execute("UPDATE users SET name = ?2, email = ?3 WHERE id = ?1", params![id, name, email])? == 0 {
+                return Err(not_found("users", id));
+            }
+            claim_names(&tx, id, &name, &email)?;
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_user_by_name(&self, name: String) -> Result<i64> {
+        self.run(move |conn| {
+            conn.query_row("SELECT user_id FROM usernames WHERE key = ?1", params![username_key(&name)], |row| row.get(0))
+                .optional()?.ok_or_else(|| DbError::NotFound(format!("no user named {name}")))
+        }).await
+    }
+
+    async fn get_user_by_email(&self, email: String) -> Result<i64> {
+        self.run(move |conn| {
+            conn.query_row("SELECT user_id FROM emails WHERE key = ?1", params![email_key(&email)], |row| row.get(0))
+                .optional()?.ok_or_else(|| DbError::NotFound(format!("no user with email {email}")))
+        }).await
+    }
+
+    async fn search_users(&self, prefix: String, limit: u64) -> Result<Vec<User>> {
+        self.run(move |conn| {
+            let (start, end) = prefix_range(&prefix);
+            let mut stmt = conn.prepare_cached(
+                "SELECT users.id, users.name, users.email FROM usernames \
+                 JOIN users ON users.id = usernames.user_id \
+                 WHERE usernames.key >= ?1 AND usernames.key < ?2 ORDER BY usernames.key LIMIT ?3"
+            )?;
+            let users = stmt.query_map(params![start, end, limit as i64], |row| {
+                Ok(User { id: row.get(0)?, username: row.get(1)?, email: row.get(2)? })
+            })?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(users)
+        }).await
+    }
+
+    async fn delete_user(&self, id: i64) -> Result<()> {
+        self.exec("DELETE FROM users WHERE id = ?1", [id]).await
+    }
+
+    async fn get_verified_email(&self, id: i64) -> Result<Option<String>> {
+        self.run(move |conn| {
+            Ok(conn.query_row(
+                "SELECT email FROM verified_emails WHERE user_id = ?1", params![id], |row| row.get(0)
+            ).optional()?)
+        }).await
+    }
+
+    async fn set_verified_email(&self, id: i64, email: String) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO verified_emails (user_id, email) VALUES (?1, ?2) \
+                 ON CONFLICT (user_id) DO UPDATE SET email = excluded.email",
+                params![id, email],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_group(&self, id: i64) -> Result<Group> {
+        self.run(move |conn| {
+            let (name, owner, is_dm, discoverable, requires_approval, description, tags) = conn.query_row(
+                "SELECT name, owner, is_dm, coalesce(discoverable, 0), coalesce(requires_approval, 1), \
+                 coalesce(description, ''), coalesce(tags, '') \
+                 FROM groups LEFT JOIN group_profiles ON group_id = id WHERE id = ?1",
+                params![id],
+                |row| Ok((
+                    row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get::<_, String>(6)?,
+                )),
+            ).optional()?.ok_or_else(|| not_found("groups", id))?;
+            Ok(Group {
+                id,
+                name,
+                members: query_set(conn, "SELECT user_id FROM group_members WHERE group_id = ?1 ORDER BY user_id", id)?,
+                channels: query_set(conn, "SELECT channel_id FROM group_channels WHERE group_id = ?1 ORDER BY channel_id", id)?,
+                admin: query_set(conn, "SELECT user_id FROM group_admins WHERE group_id = ?1 ORDER BY user_id", id)?,
+                owner,
+                is_dm,
+                discoverable,
+                requires_approval,
+                description,
+                tags: tags.split_whitespace().map(str::to_string).collect(),
+            })
+        }).await
+    }
+
+    async fn create_group(&self, gid: i64, uid: i64, name: String, dm: bool) -> Result<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO groups (id, name, owner, is_dm) VALUES (?1, ?2, ?3, ?4)",
+                params![gid, name, uid, dm],
+            )?;
+            tx.execute(
+                "INSERT INTO group_members (group_id, user_id) VALUES (?1, ?2)",
+                params![gid, uid],
+            )?;
+            tx.execute(
+                "INSERT INTO member_joins (group_id, user_id, joined) VALUES (?1, ?2, ?3)",
+                params![gid, uid, chrono::Utc::now().timestamp_millis()],
+            )?;
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn delete_group(&self, id: i64) -> Result<()> {
+        self.exec("DELETE FROM groups WHERE id = ?1", [id]).await
+    }
+
+    async fn update_group(&self, id: i64, name: String) -> Result<()> {
+        self.update("groups", id, "UPDATE groups SET name = ?2 WHERE id = ?1", name).await
+    }
+
+    async fn get_group_members(&self, gid: i64) -> Result<Vec<i64>> {
+        self.get_set(Some("groups"), "SELECT user_id FROM group_members WHERE group_id = ?1 ORDER BY user_id", gid).await
+    }
+
+    async fn add_group_member(&self, gid: i64, uid: i64) -> Result<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            let added = tx.execute(
+                "INSERT OR IGNORE INTO group_members (group_id, user_id) VALUES (?1, ?2)",
+                params![gid, uid],
+            )?;
+            if added > 0 {
+                tx.execute(
+                    "INSERT OR REPLACE INTO member_joins (group_id, user_id, joined) VALUES (?1, ?2, ?3)",
+                    params![gid, uid, chrono::Utc::now().timestamp_millis()],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn remove_group_member(&self, gid: i64, uid: i64) -> Result<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM group_members WHERE group_id = ?1 AND user_id = ?2", params![gid, uid])?;
+            tx.execute("DELETE FROM member_joins WHERE group_id = ?1 AND user_id = ?2", params![gid, uid])?;
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_member_joined(&self, gid: i64, uid: i64) -> Result<i64> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT coalesce(j.joined, 0) FROM group_members m \
+                 LEFT JOIN member_joins j ON j.group_id = m.group_id AND j.user_id = m.user_id \
+                 WHERE m.group_id = ?1 AND m.user_id = ?2",
+                params![gid, uid],
+                |row| row.get(0),
+            ).optional()?.ok_or_else(|| not_found("group_members", uid))
+        }).await
+    }
+
+    async fn get_group_channels(&self, gid: i64) -> Result<Vec<i64>> {
+        self.get_set(Some("groups"), "SELECT channel_id FROM group_channels WHERE group_id = ?1 ORDER BY channel_id", gid).await
+    }
+
+    async fn add_group_channel(&self, gid: i64, cid: i64) -> Result<()> {
+        self.exec("INSERT OR IGNORE INTO group_channels (group_id, channel_id) VALUES (?1, ?2)", [gid, cid]).await
+    }
+
+    async fn remove_group_channel(&self, gid: i64, cid: i64) -> Result<()> {
+        self.exec("DELETE FROM group_channels WHERE group_id = ?1 AND channel_id = ?2", [gid, cid]).await
+    }
+
+    async fn get_group_admin(&self, gid: i64) -> Result<Vec<i64>> {
+        self.get_set(Some("groups"), "SELECT user_id FROM group_admins WHERE group_id = ?1 ORDER BY user_id", gid).await
+    }
+
+    async fn add_group_admin(&self, gid: i64, uid: i64) -> Result<()> {
+        self.exec("INSERT OR IGNORE INTO group_admins (group_id, user_id) VALUES (?1, ?2)", [gid, uid]).await
+    }
+
+    async fn remove_group_admin(&self, gid: i64, uid: i64) -> Result<()> {
+        self.exec("DELETE FROM group_admins WHERE group_id = ?1 AND user_id = ?2", [gid, uid]).await
+    }
+
+    async fn get_group_owner(&self, gid: i64) -> Result<i64> {
+        self.run(move |conn| {
+            conn.query_row("SELECT owner FROM groups WHERE id = ?1", params![gid], |row| row.get(0))
+                .optional()?.ok_or_else(|| not_found("groups", gid))
+        }).await
+    }
+
+    async fn set_group_owner(&self, gid: i64, uid: i64) -> Result<()> {
+        self.update("groups", gid, "UPDATE groups SET owner = ?2 WHERE id = ?1", uid).await
+    }
+
+    async fn is_group_dm(&self, gid: i64) -> Result<bool> {
+        self.run(move |conn| {
+            conn.query_row("SELECT is_dm FROM groups WHERE id = ?1", params![gid], |row| row.get(0))
+                .optional()?.ok_or_else(|| not_found("groups", gid))
+        }).await
+    }
+
+    async fn set_group_profile(&self, gid: i64, discoverable: bool, requires_approval: bool, description: String, tags: Vec<String>) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO group_profiles (group_id, discoverable, requires_approval, description, tags) \
+                 VALUES (?1, ?2, ?3, ?4, ?5) \
+                 ON CONFLICT (group_id) DO UPDATE SET discoverable = excluded.discoverable, \
+                 requires_approval = excluded.requires_approval, description = excluded.description, \
+                 tags = excluded.tags",
+                params![gid, discoverable, requires_approval, description, tags.join(" ")],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn search_groups(&self, query: DirectoryQuery) -> Result<Vec<Group>> {
+        // Paging is done here, and the rest of the filters by `query.matches`
+        let after = query.after.unwrap_or(i64::MIN);
+        let ids = self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT group_id FROM group_profiles WHERE discoverable AND group_id > ?1 ORDER BY group_id"
+            )?;
+            let ids = stmt.query_map(params![after], |row| row.get(0))?.collect::<rusqlite::Result<Vec<i64>>>()?;
+            Ok(ids)
+        }).await?;
+        let mut groups = Vec::new();
+        for id in ids {
+            let group = self.get_group(id).await?;
+            if query.matches(&group) {
+                groups.push(group);
+                if groups.len() as u64 >= query.limit {
+                    break;
+                }
+            }
+        }
+        Ok(groups)
+    }
+
+    async fn set_role(&self, role: Role) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO roles (id, group_id, name, permissions) VALUES (?1, ?2, ?3, ?4) \
+                 ON CONFLICT (id) DO UPDATE SET name = excluded.name, permissions = excluded.permissions",
+                params![role.id, role.group, role.name, role.permission_set().0],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_role(&self, id: i64) -> Result<Role> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT id, group_id, name, permissions FROM roles WHERE id = ?1",
+                params![id],
+                role_from_row,
+            ).optional()?.ok_or_else(|| not_found("roles", id))
+        }).await
+    }
+
+    async fn get_group_roles(&self, gid: i64) -> Result<Vec<Role>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT id, group_id, name, permissions FROM roles WHERE group_id = ?1 ORDER BY id"
+            )?;
+            let roles = stmt.query_map(params![gid], role_from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(roles)
+        }).await
+    }
+
+    async fn delete_role(&self, id: i64) -> Result<()> {
+        self.exec("DELETE FROM roles WHERE id = ?1", [id]).await
+    }
+
+    async fn get_member_roles(&self, gid: i64, uid: i64) -> Result<Vec<i64>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT role_id FROM member_roles WHERE group_id = ?1 AND user_id = ?2 ORDER BY role_id"
+            )?;
+            let ids = stmt.query_map(params![gid, uid], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<i64>>>()?;
+            Ok(ids)
+        }).await
+    }
+
+    async fn add_member_role(&self, gid: i64, uid: i64, rid: i64) -> Result<()> {
+        self.exec(
+            "INSERT OR IGNORE INTO member_roles (group_id, user_id, role_id) VALUES (?1, ?2, ?3)", [gid, uid, rid]
+        ).await
+    }
+
+    async fn remove_member_role(&self, gid: i64, uid: i64, rid: i64) -> Result<()> {
+        self.exec(
+            "DELETE FROM member_roles WHERE group_id = ?1 AND user_id = ?2 AND role_id = ?3", [gid, uid, rid]
+        ).await
+    }
+
+    async fn set_ban(&self, ban: Ban) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO bans (group_id, user_id, reason, expires) VALUES (?1, ?2, ?3, ?4) \
+                 ON CONFLICT (group_id, user_id) DO UPDATE SET reason = excluded.reason, expires = excluded.expires",
+                params![ban.group, ban.user, ban.reason, ban.expires],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_ban(&self, gid: i64, uid: i64) -> Result<Ban> {
+        let now = chrono::Utc::now().timestamp();
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT reason, expires FROM bans \
+                 WHERE group_id = ?1 AND user_id = ?2 AND (expires IS NULL OR expires > ?3)",
+                params![gid, uid, now],
+                |row| Ok(Ban { group: gid, user: uid, reason: row.get(0)?, expires: row.get(1)? }),
+            ).optional()?.ok_or_else(|| not_found("bans", uid))
+        }).await
+    }
+
+    async fn get_group_bans(&self, gid: i64) -> Result<Vec<Ban>> {
+        let now = chrono::Utc::now().timestamp();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT user_id, reason, expires FROM bans \
+                 WHERE group_id = ?1 AND (expires IS NULL OR expires > ?2) ORDER BY user_id"
+            )?;
+            let bans = stmt.query_map(params![gid, now], |row| Ok(Ban {
+                group: gid,
+                user: row.get(0)?,
+                reason: row.get(1)?,
+                expires: row.get(2)?,
+            }))?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(bans)
+        }).await
+    }
+
+    async fn delete_ban(&self, gid: i64, uid: i64) -> Result<()> {
+        self.exec("DELETE FROM bans WHERE group_id = ?1 AND user_id = ?2", [gid, uid]).await
+    }
+
+    async fn set_timeout(&self, timeout: Timeout) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO timeouts (group_id, user_id, reason, until) VALUES (?1, ?2, ?3, ?4) \
+                 ON CONFLICT (group_id, user_id) DO UPDATE SET reason = excluded.reason, until = excluded.until",
+                params![timeout.group, timeout.user, timeout.reason, timeout.until],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_timeout(&self, gid: i64, uid: i64) -> Result<Timeout> {
+        let now = chrono::Utc::now().timestamp();
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT reason, until FROM timeouts WHERE group_id = ?1 AND user_id = ?2 AND until > ?3",
+                params![gid, uid, now],
+                |row| Ok(Timeout { group: gid, user: uid, reason: row.get(0)?, until: row.get(1)? }),
+            ).optional()?.ok_or_else(|| not_found("timeouts", uid))
+        }).await
+    }
+
+    async fn get_group_timeouts(&self, gid: i64) -> Result<Vec<Timeout>> {
+        let now = chrono::Utc::now().timestamp();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT user_id, reason, until FROM timeouts WHERE group_id = ?1 AND until > ?2 ORDER BY user_id"
+            )?;
+            let timeouts = stmt.query_map(params![gid, now], |row| Ok(Timeout {
+                group: gid,
+                user: row.get(0)?,
+                reason: row.get(1)?,
+                until: row.get(2)?,
+            }))?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(timeouts)
+        }).await
+    }
+
+    async fn delete_timeout(&self, gid: i64, uid: i64) -> Result<()> {
+        self.exec("DELETE FROM timeouts WHERE group_id = ?1 AND user_id = ?2", [gid, uid]).await
+    }
+
+    async fn add_audit_entry(&self, entry: AuditEntry) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO audit_log (id, group_id, actor, action, target, timestamp, reason) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    entry.id, entry.group, entry.actor, entry.action.as_str(),
+                    entry.target, entry.timestamp, entry.reason
+                ],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_audit_log(&self, gid: i64, query: AuditQuery) -> Result<Vec<AuditEntry>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT id, actor, action, target, timestamp, reason FROM audit_log \
+                 WHERE group_id = ?1 AND (?2 IS NULL OR id < ?2) AND (?3 IS NULL OR actor = ?3) \
+                 AND (?4 IS NULL OR target = ?4) AND (?5 IS NULL OR action = ?5) \
+                 ORDER BY id DESC LIMIT ?6"
+            )?;
+            let action = query.action.map(|action| action.as_str());
+            let limit = i64::try_from(query.limit).unwrap_or(i64::MAX);
+            let rows = stmt.query_map(
+                params![gid, query.before, query.actor, query.target, action, limit],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?, row.get(3)?, row.get(4)?, row.get(5)?)),
+            )?.collect::<rusqlite::Result<Vec<_>>>()?;
+            // Skip actions this version doesn't know about
+            Ok(rows.into_iter()
+                .filter_map(|(id, actor, action, target, timestamp, reason)| Some(AuditEntry {
+                    id,
+                    group: gid,
+                    actor,
+                    action: AuditAction::parse(&action)?,
+                    target,
+                    timestamp,
+                    reason,
+                }))
+                .collect())
+        }).await
+    }
+
+    async fn delete_audit_log(&self, gid: i64) -> Result<()> {
+        self.exec("DELETE FROM audit_log WHERE group_id = ?1", [gid]).await
+    }
+
+    async fn create_invite(&self, invite: Invite) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO invites (code, group_id, channel, creator, expires, max_uses, uses) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    invite.code, invite.group, invite.channel, invite.creator,
+                    invite.expires, invite.max_uses, invite.uses
+                ],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_invite(&self, code: String) -> Result<Invite> {
+        let now = chrono::Utc::now().timestamp();
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT code, group_id, channel, creator, expires, max_uses, uses FROM invites \
+                 WHERE code = ?1 AND (expires IS NULL OR expires > ?2) AND (max_uses IS NULL OR uses < max_uses)",
+                params![code, now],
+                invite_from_row,
+            ).optional()?.ok_or_else(|| invite_not_found(&code))
+        }).await
+    }
+
+    async fn get_group_invites(&self, gid: i64) -> Result<Vec<Invite>> {
+        let now = chrono::Utc::now().timestamp();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT code, group_id, channel, creator, expires, max_uses, uses FROM invites \
+                 WHERE group_id = ?1 AND (expires IS NULL OR expires > ?2) AND (max_uses IS NULL OR uses < max_uses) \
+                 ORDER BY code"
+            )?;
+            let invites = stmt.query_map(params![gid, now], invite_from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(invites)
+        }).await
+    }
+
+    async fn use_invite(&self, code: String) -> Result<Invite> {
+        let now = chrono::Utc::now().timestamp();
+        self.run(move |conn| {
+            conn.query_row(
+                "UPDATE invites SET uses = uses + 1 \
+                 WHERE code = ?1 AND (expires IS NULL OR expires > ?2) AND (max_uses IS NULL OR uses < max_uses) \
+                 RETURNING code, group_id, channel, creator, expires, max_uses, uses",
+                params![code, now],
+                invite_from_row,
+            ).optional()?.ok_or_else(|| invite_not_found(&code))
+        }).await
+    }
+
+    async fn delete_invite(&self, code: String) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute("DELETE FROM invites WHERE code = ?1", params![code])?;
+            Ok(())
+        }).await
+    }
+
+    async fn set_join_request(&self, request: JoinRequest) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO join_requests (group_id, user_id, message, timestamp) VALUES (?1, ?2, ?3, ?4) \
+                 ON CONFLICT (group_id, user_id) DO UPDATE SET message = excluded.message, timestamp = excluded.timestamp",
+                params![request.group, request.user, request.message, request.timestamp],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_join_request(&self, gid: i64, uid: i64) -> Result<JoinRequest> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT message, timestamp FROM join_requests WHERE group_id = ?1 AND user_id = ?2",
+                params![gid, uid],
+                |row| Ok(JoinRequest { group: gid, user: uid, message: row.get(0)?, timestamp: row.get(1)? }),
+            ).optional()?.ok_or_else(|| not_found("join_requests", uid))
+        }).await
+    }
+
+    async fn get_join_requests(&self, gid: i64) -> Result<Vec<JoinRequest>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT user_id, message, timestamp FROM join_requests WHERE group_id = ?1 ORDER BY timestamp, user_id"
+            )?;
+            let requests = stmt.query_map(params![gid], |row| Ok(JoinRequest {
+                group: gid,
+                user: row.get(0)?,
+                message: row.get(1)?,
+                timestamp: row.get(2)?,
+            }))?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(requests)
+        }).await
+    }
+
+    async fn delete_join_request(&self, gid: i64, uid: i64) -> Result<()> {
+        self.exec("DELETE FROM join_requests WHERE group_id = ?1 AND user_id = ?2", [gid, uid]).await
+    }
+
+    async fn get_channel(&self, id: i64) -> Result<Channel> {
+        self.run(move |conn| {
+            let (group, name, private) = conn.query_row(
+                "SELECT group_id, name, private FROM channels WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            ).optional()?.ok_or_else(|| not_found("channels", id))?;
+            Ok(Channel {
+                id,
+                name,
+                group,
+                members: query_set(conn, "SELECT user_id FROM channel_members WHERE channel_id = ?1 ORDER BY user_id", id)?,
+                private,
+            })
+        }).await
+    }
+
+    async fn create_channel(&self, cid: i64, gid: i64, uid: i64, name: String) -> Result<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO channels (id, group_id, name, private) VALUES (?1, ?2, ?3, FALSE)",
+                params![cid, gid, name],
+            )?;
+            tx.execute(
+                "INSERT INTO channel_members (channel_id, user_id) VALUES (?1, ?2)",
+                params![cid, uid],
+            )?;
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn delete_channel(&self, id: i64) -> Result<()> {
+        self.exec("DELETE FROM channels WHERE id = ?1", [id]).await
+    }
+
+    async fn update_channel(&self, id: i64, name: String) -> Result<()> {
+        self.update("channels", id, "UPDATE channels SET name = ?2 WHERE id = ?1", name).await
+    }
+
+    async fn get_channel_members(&self, cid: i64) -> Result<Vec<i64>> {
+        self.get_set(Some("channels"), "SELECT user_id FROM channel_members WHERE channel_id = ?1 ORDER BY user_id", cid).await
+    }
+
+    async fn add_channel_member(&self, cid: i64, uid: i64) -> Result<()> {
+        self.exec("INSERT OR IGNORE INTO channel_members (channel_id, user_id) VALUES (?1, ?2)", [cid, uid]).await
+    }
+
+    async fn remove_channel_member(&self, cid: i64, uid: i64) -> Result<()> {
+        self.exec("DELETE FROM channel_members WHERE channel_id = ?1 AND user_id = ?2", [cid, uid]).await
+    }
+
+    async fn is_channel_member(&self, cid: i64, uid: i64) -> Result<bool> {
+        self.run(move |conn| {
+            if !exists(conn, "channels", cid)? {
+                return Err(not_found("channels", cid));
+            }
+            Ok(conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM channel_members WHERE channel_id = ?1 AND user_id = ?2)",
+                params![cid, uid],
+                |row| row.get(0),
+            )?)
+        }).await
+    }
+
+    async fn is_channel_private(&self, id: i64) -> Result<bool> {
+        self.run(move |conn| {
+            conn.query_row("SELECT private FROM channels WHERE id = ?1", params![id], |row| row.get(0))
+                .optional()?.ok_or_else(|| not_found("channels", id))
+        }).await
+    }
+
+    async fn set_channel_private(&self, id: i64, value: bool) -> Result<bool> {
+        self.update("channels", id, "UPDATE channels SET private = ?2 WHERE id = ?1", value).await?;
+        Ok(value)
+    }
+
+    async fn get_channel_overrides(&self, cid: i64) -> Result<Vec<ChannelOverride>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT target, allow, deny FROM channel_overrides WHERE channel_id = ?1 ORDER BY target"
+            )?;
+            let overrides = stmt.query_map(params![cid], |row| Ok(ChannelOverride {
+                channel: cid,
+                target: row.get(0)?,
+                allow: Permissions(row.get(1)?).list(),
+                deny: Permissions(row.get(2)?).list(),
+            }))?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(overrides)
+        }).await
+    }
+
+    async fn set_channel_override(&self, over: ChannelOverride) -> Result<()> {
+        let (allow, deny) = (over.allowed(), over.denied());
+        self.exec(
+            "INSERT INTO channel_overrides (channel_id, target, allow, deny) VALUES (?1, ?2, ?3, ?4) \
+             ON CONFLICT (channel_id, target) DO UPDATE SET allow = excluded.allow, deny = excluded.deny",
+            [over.channel, over.target, allow.0, deny.0],
+        ).await
+    }
+
+    async fn delete_channel_override(&self, cid: i64, target: i64) -> Result<()> {
+        self.exec("DELETE FROM channel_overrides WHERE channel_id = ?1 AND target = ?2", [cid, target]).await
+    }
+
+    async fn create_user_dms(&self, id: i64) -> Result<()> {
+        // There's no row to create: a user's DMs are just the rows in `user_dms`
+        self.delete_user_dms(id).await
+    }
+
+    async fn get_user_dms(&self, id: i64) -> Result<Vec<i64>> {
+        self.get_set(None, "SELECT group_id FROM user_dms WHERE user_id = ?1 ORDER BY group_id", id).await
+    }
+
+    async fn add_user_dm(&self, uid: i64, gid: i64) -> Result<()> {
+        self.exec("INSERT OR IGNORE INTO user_dms (user_id, group_id) VALUES (?1, ?2)", [uid, gid]).await
+    }
+
+    async fn remove_user_dm(&self, uid: i64, gid: i64) -> Result<()> {
+        self.exec("DELETE FROM user_dms WHERE user_id = ?1 AND group_id = ?2", [uid, gid]).await
+    }
+
+    async fn create_session(&self, session: AuthSession) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO sessions (id, user_id, refresh_hash, expires) VALUES (?1, ?2, ?3, ?4)",
+                params![session.id, session.user, session.refresh_hash, session.expires],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_session(&self, id: i64) -> Result<AuthSession> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT user_id, refresh_hash, expires FROM sessions WHERE id = ?1",
+                params![id],
+                |row| Ok(AuthSession { id, user: row.get(0)?, refresh_hash: row.get(1)?, expires: row.get(2)? }),
+            ).optional()?.ok_or_else(|| not_found("sessions", id))
+        }).await
+    }
+
+    async fn update_session(&self, id: i64, refresh_hash: String, expires: i64) -> Result<()> {
+        self.run(move |conn| {
+            match conn.execute(
+                "UPDATE sessions SET refresh_hash = ?2, expires = ?3 WHERE id = ?1",
+                params![id, refresh_hash, expires],
+            )? {
+                0 => Err(not_found("sessions", id)),
+                _ => Ok(()),
+            }
+        }).await
+    }
+
+    async fn delete_session(&self, id: i64) -> Result<()> {
+        self.exec("DELETE FROM sessions WHERE id = ?1", [id]).await
+    }
+
+    async fn get_user_sessions(&self, uid: i64) -> Result<Vec<i64>> {
+        self.get_set(None, "SELECT id FROM sessions WHERE user_id = ?1 ORDER BY id", uid).await
+    }
+
+    async fn revoke_session(&self, id: i64, until: i64) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM revoked_sessions WHERE until <= ?1", params![now])?;
+            tx.execute(
+                "INSERT INTO revoked_sessions (id, until) VALUES (?1, ?2) \
+                 ON CONFLICT (id) DO UPDATE SET until = MAX(until, excluded.until)",
+                params![id, until],
+            )?;
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn is_session_revoked(&self, id: i64) -> Result<bool> {
+        let now = chrono::Utc::now().timestamp();
+        self.run(move |conn| {
+            Ok(conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM revoked_sessions WHERE id = ?1 AND until > ?2)",
+                params![id, now],
+                |row| row.get(0),
+            )?)
+        }).await
+    }
+
+    async fn get_totp(&self, uid: i64) -> Result<UserTotp> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT secret, enabled, last_step, recovery_codes FROM user_totp WHERE user_id = ?1",
+                params![uid],
+                |row| Ok(UserTotp {
+                    secret: row.get(0)?,
+                    enabled: row.get(1)?,
+                    last_step: row.get(2)?,
+                    recovery_codes: row.get::<_, String>(3)?.split_whitespace().map(str::to_string).collect(),
+                }),
+            ).optional()?.ok_or_else(|| not_found("user_totp", uid))
+        }).await
+    }
+
+    async fn set_totp(&self, uid: i64, totp: UserTotp) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO user_totp (user_id, secret, enabled, last_step, recovery_codes) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![uid, totp.secret, totp.enabled, totp.last_step, totp.recovery_codes.join(" ")],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn delete_totp(&self, uid: i64) -> Result<()> {
+        self.exec("DELETE FROM user_totp WHERE user_id = ?1", [uid]).await
+    }
+
+    async fn create_bot(&self, id: i64, owner: i64) -> Result<()> {
+        self.exec("INSERT INTO bots (id, owner) VALUES (?1, ?2)", [id, owner]).await
+    }
+
+    async fn get_bot_owner(&self, id: i64) -> Result<i64> {
+        self.run(move |conn| {
+            conn.query_row("SELECT owner FROM bots WHERE id = ?1", params![id], |row| row.get(0))
+                .optional()?.ok_or_else(|| not_found("bots", id))
+        }).await
+    }
+
+    async fn get_user_bots(&self, owner: i64) -> Result<Vec<i64>> {
+        self.get_set(None, "SELECT id FROM bots WHERE owner = ?1 ORDER BY id", owner).await
+    }
+
+    async fn create_api_token(&self, token: BotToken) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO api_tokens (id, bot, name, hash, scopes) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![token.id, token.bot, token.name, token.hash, Scope::join(&token.scopes)],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_api_token(&self, id: i64) -> Result<BotToken> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT bot, name, hash, scopes FROM api_tokens WHERE id = ?1",
+                params![id],
+                |row| Ok(BotToken {
+                    id,
+                    bot: row.get(0)?,
+                    name: row.get(1)?,
+                    hash: row.get(2)?,
+                    scopes: Scope::split(&row.get::<_, String>(3)?),
+                }),
+            ).optional()?.ok_or_else(|| not_found("api_tokens", id))
+        }).await
+    }
+
+    async fn get_bot_tokens(&self, bot: i64) -> Result<Vec<BotToken>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT id, name, hash, scopes FROM api_tokens WHERE bot = ?1 ORDER BY id"
+            )?;
+            let tokens = stmt.query_map(params![bot], |row| Ok(BotToken {
+                id: row.get(0)?,
+                bot,
+                name: row.get(1)?,
+                hash: row.get(2)?,
+                scopes: Scope::split(&row.get::<_, String>(3)?),
+            }))?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(tokens)
+        }).await
+    }
+
+    async fn delete_api_token(&self, id: i64) -> Result<()> {
+        self.exec("DELETE FROM api_tokens WHERE id = ?1", [id]).await
+    }
+
+    async fn link_identity(&self, uid: i64, identity: Identity) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO identities (issuer, subject, user_id) VALUES (?1, ?2, ?3)",
+                params![identity.issuer, identity.subject, uid],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_identity_user(&self, identity: Identity) -> Result<i64> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT user_id FROM identities WHERE issuer = ?1 AND subject = ?2",
+                params![identity.issuer, identity.subject],
+                |row| row.get(0),
+            ).optional()?
+                .ok_or_else(|| DbError::NotFound(format!("identity {} not found", identity.subject)))
+        }).await
+    }
+
+    async fn get_user_identities(&self, uid: i64) -> Result<Vec<Identity>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT issuer, subject FROM identities WHERE user_id = ?1 ORDER BY issuer, subject"
+            )?;
+            let identities = stmt.query_map(params![uid], |row| Ok(Identity {
+                issuer: row.get(0)?,
+                subject: row.get(1)?,
+            }))?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(identities)
+        }).await
+    }
+
+    async fn delete_user_dms(&self, id: i64) -> Result<()> {
+        self.exec("DELETE FROM user_dms WHERE user_id = ?1", [id]).await
+    }
+
+    async fn create_user_groups(&self, id: i64) -> Result<()> {
+        // There's no row to create: a user's groups are just the rows in `user_groups`
+        self.delete_user_groups(id).await
+    }
+
+    async fn get_user_groups(&self, id: i64) -> Result<Vec<i64>> {
+        self.get_set(None, "SELECT group_id FROM user_groups WHERE user_id = ?1 ORDER BY group_id", id).await
+    }
+
+    async fn add_user_group(&self, uid: i64, gid: i64) -> Result<()> {
+        self.exec("INSERT OR IGNORE INTO user_groups (user_id, group_id) VALUES (?1, ?2)", [uid, gid]).await
+    }
+
+    async fn remove_user_group(&self, uid: i64, gid: i64) -> Result<()> {
+        self.exec("DELETE FROM user_groups WHERE user_id = ?1 AND group_id = ?2", [uid, gid]).await
+    }
+
+    async fn delete_user_groups(&self, id: i64) -> Result<()> {
+        self.exec("DELETE FROM user_groups WHERE user_id = ?1", [id]).await
+    }
+
+    async fn get_messages(&self, cid: i64, num: u64) -> Result<Vec<Message>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT id, channel, author, content, thread FROM messages \
+                 WHERE channel = ?1 ORDER BY id DESC LIMIT ?2"
+            )?;
+            let messages = stmt.query_map(params![cid, num as i64], message_from_row)?
+                .collect::<rusqlite::Result<Vec<Message>>>()?;
+            Ok(messages)
+        }).await
+    }
+
+    async fn create_message(&self, msg: Message) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO messages (id, channel, author, content, thread) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![msg.id, msg.channel, msg.author, msg.content, msg.thread],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_message(&self, id: i64) -> Result<Message> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT id, channel, author, content, thread FROM messages WHERE id = ?1",
+                params![id],
+                message_from_row,
+            ).optional()?.ok_or_else(|| not_found("messages", id))
+        }).await
+    }
+
+    async fn delete_message(&self, id: i64) -> Result<()> {
+        self.exec("DELETE FROM messages WHERE id = ?1", [id]).await
+    }
+
+    async fn set_thread(&self, id: i64, cid: i64) -> Result<()> {
+        self.update("messages", id, "UPDATE messages SET thread = ?2 WHERE id = ?1", cid).await
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_membership_tables() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        db.create_user(2, "b".to_string(), "b@example.com".to_string(), String::new()).await.unwrap();
+        db.create_group(10, 1, "test".to_string(), false).await.unwrap();
+        db.add_group_member(10, 2).await.unwrap();
+        let joined = db.get_member_joined(10, 2).await.unwrap();
+        db.add_group_member(10, 2).await.unwrap();
+        // Being added again doesn't change when you joined
+        assert_eq!(db.get_member_joined(10, 2).await.unwrap(), joined);
+        assert!(db.get_member_joined(10, 1).await.unwrap() <= joined);
+        db.create_channel(20, 10, 1, "main".to_string()).await.unwrap();
+        db.add_group_channel(10, 20).await.unwrap();
+        db.add_user_group(2, 10).await.unwrap();
+
+        let group = db.get_group(10).await.unwrap();
+        assert_eq!(group.members, vec![1, 2]);
+        assert_eq!(group.channels, vec![20]);
+        assert_eq!(group.admin, Vec::<i64>::new());
+        db.set_group_owner(10, 2).await.unwrap();
+        assert_eq!(db.get_group_owner(10).await.unwrap(), 2);
+        db.set_group_owner(10, 1).await.unwrap();
+        assert!(matches!(db.set_group_owner(11, 1).await, Err(DbError::NotFound(_))));
+
+        // Deleting the user takes their memberships with them
+        db.delete_user(2).await.unwrap();
+        assert_eq!(db.get_group_members(10).await.unwrap(), vec![1]);
+        assert_eq!(db.get_user_groups(2).await.unwrap(), Vec::<i64>::new());
+        assert!(matches!(db.get_member_joined(10, 2).await, Err(DbError::NotFound(_))));
+
+        // ...and deleting the group takes its channels
+        db.delete_group(10).await.unwrap();
+        assert!(!db.valid_id(IdType::Channel, 20).await.unwrap());
+        assert!(matches!(db.get_group_members(10).await, Err(DbError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_roles() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        db.create_group(10, 1, "test".to_string(), false).await.unwrap();
+        let role = Role { id: 30, group: 10, name: "mods".to_string(), permissions: vec![Permission::ManageChannels] };
+        db.set_role(role.clone()).await.unwrap();
+        db.set_role(Role { name: "moderators".to_string(), ..role.clone() }).await.unwrap();
+        assert_eq!(db.get_role(30).await.unwrap().name, "moderators");
+        assert!(matches!(db.get_role(10).await, Err(DbError::NotFound(_))));
+
+        db.add_member_role(10, 1, 30).await.unwrap();
+        db.add_member_role(10, 1, 30).await.unwrap();
+        assert_eq!(db.get_member_roles(10, 1).await.unwrap(), vec![30]);
+        db.remove_member_role(10, 1, 30).await.unwrap();
+        assert_eq!(db.get_member_roles(10, 1).await.unwrap(), Vec::<i64>::new());
+
+        // Deleting a role unassigns it, and deleting the group takes its roles
+        db.add_member_role(10, 1, 30).await.unwrap();
+        db.delete_role(30).await.unwrap();
+        assert_eq!(db.get_member_roles(10, 1).await.unwrap(), Vec::<i64>::new());
+        db.set_role(role).await.unwrap();
+        db.delete_group(10).await.unwrap();
+        assert!(matches!(db.get_role(30).await, Err(DbError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_channel_overrides() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        db.create_group(10, 1, "test".to_string(), false).await.unwrap();
+        db.create_channel(20, 10, 1, "main".to_string()).await.unwrap();
+        let over = ChannelOverride { channel: 20, target: 10, allow: vec![], deny: vec![Permission::SendMessages] };
+        db.set_channel_override(over.clone()).await.unwrap();
+        let over = ChannelOverride { allow: vec![Permission::CreateThreads], ..over };
+        db.set_channel_override(over.clone()).await.unwrap();
+        assert_eq!(db.get_channel_overrides(20).await.unwrap(), vec![over]);
+        db.delete_channel_override(20, 10).await.unwrap();
+        assert_eq!(db.get_channel_overrides(20).await.unwrap(), Vec::new());
+
+        // Deleting the channel takes its overrides with it
+        db.set_channel_override(ChannelOverride { channel: 20, target: 1, allow: vec![], deny: vec![] }).await.unwrap();
+        db.delete_channel(20).await.unwrap();
+        assert_eq!(db.get_channel_overrides(20).await.unwrap(), Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_bans() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        db.create_user(2, "b".to_string(), "b@example.com".to_string(), String::new()).await.unwrap();
+        db.create_group(10, 1, "test".to_string(), false).await.unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let ban = Ban { group: 10, user: 2, reason: "spam".to_string(), expires: None };
+        db.set_ban(ban.clone()).await.unwrap();
+        assert_eq!(db.get_ban(10, 2).await.unwrap(), ban);
+        assert_eq!(db.get_group_bans(10).await.unwrap(), vec![ban.clone()]);
+
+        // Expired bans and timeouts are as good as lifted
+        db.set_ban(Ban { expires: Some(now - 1), ..ban }).await.unwrap();
+        assert!(matches!(db.get_ban(10, 2).await, Err(DbError::NotFound(_))));
+        assert_eq!(db.get_group_bans(10).await.unwrap(), Vec::new());
+        let timeout = Timeout { group: 10, user: 2, reason: String::new(), until: now + 60 };
+        db.set_timeout(timeout.clone()).await.unwrap();
+        assert_eq!(db.get_group_timeouts(10).await.unwrap(), vec![timeout.clone()]);
+        db.set_timeout(Timeout { until: now - 1, ..timeout.clone() }).await.unwrap();
+        assert!(matches!(db.get_timeout(10, 2).await, Err(DbError::NotFound(_))));
+
+        db.set_timeout(timeout).await.unwrap();
+        db.delete_timeout(10, 2).await.unwrap();
+        assert!(matches!(db.get_timeout(10, 2).await, Err(DbError::NotFound(_))));
+        db.delete_ban(10, 2).await.unwrap();
+        db.set_ban(Ban { group: 10, user: 2, reason: String::new(), expires: None }).await.unwrap();
+        db.delete_user(2).await.unwrap();
+        assert_eq!(db.get_group_bans(10).await.unwrap(), Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        db.create_group(10, 1, "test".to_string(), false).await.unwrap();
+        let entry = |id, action, target| AuditEntry {
+            id, group: 10, actor: 1, action, target, timestamp: id, reason: String::new()
+        };
+        let (a, b, c) = (entry(100, AuditAction::AddMember, 2), entry(101, AuditAction::Ban, 2), entry(102, AuditAction::AddMember, 3));
+        for e in [&a, &b, &c] {
+            db.add_audit_entry(e.clone()).await.unwrap();
+        }
+        let query = AuditQuery { before: None, actor: None, target: None, action: None, limit: 10 };
+        assert_eq!(db.get_audit_log(10, query.clone()).await.unwrap(), vec![c.clone(), b.clone(), a.clone()]);
+        assert_eq!(db.get_audit_log(10, AuditQuery { before: Some(102), limit: 1, ..query.clone() }).await.unwrap(), vec![b.clone()]);
+        let by_action = AuditQuery { action: Some(AuditAction::AddMember), ..query.clone() };
+        assert_eq!(db.get_audit_log(10, by_action).await.unwrap(), vec![c, a.clone()]);
+        let by_target = AuditQuery { target: Some(2), actor: Some(1), ..query.clone() };
+        assert_eq!(db.get_audit_log(10, by_target).await.unwrap(), vec![b, a]);
+
+        // Entries go with their group, and nothing else
+        db.delete_user(1).await.unwrap();
+        assert_eq!(db.get_audit_log(10, query.clone()).await.unwrap().len(), 3);
+        db.delete_group(10).await.unwrap();
+        assert_eq!(db.get_audit_log(10, query).await.unwrap(), Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_invites() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        db.create_group(10, 1, "test".to_string(), false).await.unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let invite = Invite {
+            code: "abc".to_string(), group: 10, channel: None, creator: 1, expires: None, max_uses: Some(2), uses: 0
+        };
+        db.create_invite(invite.clone()).await.unwrap();
+        assert_eq!(db.get_invite("abc".to_string()).await.unwrap(), invite);
+        assert!(matches!(db.create_invite(invite.clone()).await, Err(DbError::Conflict(_))));
+
+        // Invites that have run out of uses or expired are as good as deleted
+        assert_eq!(db.use_invite("abc".to_string()).await.unwrap().uses, 1);
+        assert_eq!(db.use_invite("abc".to_string()).await.unwrap().uses, 2);
+        assert!(matches!(db.use_invite("abc".to_string()).await, Err(DbError::NotFound(_))));
+        assert!(matches!(db.get_invite("abc".to_string()).await, Err(DbError::NotFound(_))));
+        let expired = Invite { code: "def".to_string(), expires: Some(now - 1), max_uses: None, ..invite.clone() };
+        db.create_invite(expired).await.unwrap();
+        assert_eq!(db.get_group_invites(10).await.unwrap(), Vec::new());
+
+        let forever = Invite { code: "ghi".to_string(), channel: Some(20), max_uses: None, ..invite };
+        db.create_invite(forever.clone()).await.unwrap();
+        assert_eq!(db.get_group_invites(10).await.unwrap(), vec![forever]);
+        db.delete_invite("ghi".to_string()).await.unwrap();
+        assert_eq!(db.get_group_invites(10).await.unwrap(), Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_group_directory() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        db.create_user(2, "b".to_string(), "b@example.com".to_string(), String::new()).await.unwrap();
+        for (gid, name) in [(10, "Rust Users"), (11, "rustaceans"), (12, "Hidden Rust")] {
+            db.create_group(gid, 1, name.to_string(), false).await.unwrap();
+        }
+        let group = db.get_group(10).await.unwrap();
+        assert!(!group.discoverable && group.description.is_empty() && group.tags.is_empty());
+        assert!(group.requires_approval);
+        let tags = vec!["rust".to_string(), "programming".to_string()];
+        db.set_group_profile(10, true, false, "All about Rust".to_string(), tags.clone()).await.unwrap();
+        db.set_group_profile(11, true, true, String::new(), vec!["crabs".to_string()]).await.unwrap();
+        db.set_group_profile(12, false, true, String::new(), tags.clone()).await.unwrap();
+        let group = db.get_group(10).await.unwrap();
+        assert_eq!((group.discoverable, group.description, group.tags), (true, "All about Rust".to_string(), tags));
+        assert!(!group.requires_approval);
+
+        let search = |name: Option<&str>, tag: Option<&str>, after| {
+            let query = DirectoryQuery { after, name: name.map(str::to_string), tag: tag.map(str::to_string), limit: 10 };
+            let db = &db;
+            async move { db.search_groups(query).await.unwrap().iter().map(|g| g.id).collect::<Vec<_>>() }
+        };
+        assert_eq!(search(Some("RUST"), None, None).await, vec![10, 11]);
+        assert_eq!(search(None, Some("rust"), None).await, vec![10]);
+        assert_eq!(search(None, None, Some(10)).await, vec![11]);
+
+        db.set_join_request(JoinRequest { group: 10, user: 2, message: "hi".to_string(), timestamp: 5 }).await.unwrap();
+        let request = JoinRequest { group: 10, user: 2, message: "hello".to_string(), timestamp: 6 };
+        db.set_join_request(request.clone()).await.unwrap();
+        assert_eq!(db.get_join_request(10, 2).await.unwrap(), request);
+        assert_eq!(db.get_join_requests(10).await.unwrap(), vec![request]);
+        db.delete_join_request(10, 2).await.unwrap();
+        assert!(matches!(db.get_join_request(10, 2).await, Err(DbError::NotFound(_))));
+
+        // Requests and listings go with their group
+        db.set_join_request(JoinRequest { group: 11, user: 2, message: String::new(), timestamp: 7 }).await.unwrap();
+        db.delete_group(11).await.unwrap();
+        assert_eq!(db.get_join_requests(11).await.unwrap(), Vec::new());
+        assert_eq!(search(None, None, None).await, vec![10]);
+    }
+
+    #[tokio::test]
+    async fn test_messages() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        db.create_group(10, 1, "test".to_string(), false).await.unwrap();
+        db.create_channel(20, 10, 1, "main".to_string()).await.unwrap();
+        for id in 1..=3 {
+            db.create_message(Message { id, channel: 20, author: 1, content: "hi".to_string(), thread: None })
+                .await.unwrap();
+        }
+        assert!(db.is_channel_member(20, 1).await.unwrap());
+        assert!(!db.is_channel_member(20, 2).await.unwrap());
+
+        let ids: Vec<i64> = db.get_messages(20, 2).await.unwrap().iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![3, 2]);
+        db.set_thread(2, 30).await.unwrap();
+        assert_eq!(db.get_message(2).await.unwrap().thread, Some(30));
+        assert!(matches!(db.set_thread(9, 30).await, Err(DbError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        let session = AuthSession { id: 5, user: 1, refresh_hash: "abc".to_string(), expires: 100 };
+        db.create_session(session.clone()).await.unwrap();
+        assert_eq!(db.get_session(5).await.unwrap(), session);
+        db.update_session(5, "def".to_string(), 200).await.unwrap();
+        assert_eq!(db.get_session(5).await.unwrap().expires, 200);
+        assert_eq!(db.get_user_sessions(1).await.unwrap(), vec![5]);
+
+        let now = chrono::Utc::now().timestamp();
+        db.revoke_session(5, now + 60).await.unwrap();
+        db.revoke_session(6, now - 60).await.unwrap();
+        assert!(db.is_session_revoked(5).await.unwrap());
+        assert!(!db.is_session_revoked(6).await.unwrap());
+
+        db.delete_user(1).await.unwrap();
+        assert!(matches!(db.get_session(5).await, Err(DbError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_totp() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        assert!(matches!(db.get_totp(1).await, Err(DbError::NotFound(_))));
+
+        let mut totp = UserTotp { secret: "ABC".to_string(), enabled: false, last_step: 0, recovery_codes: vec![] };
+        db.set_totp(1, totp.clone()).await.unwrap();
+        assert_eq!(db.get_totp(1).await.unwrap(), totp);
+        totp.enabled = true;
+        totp.recovery_codes = vec!["aa".to_string(), "bb".to_string()];
+        db.set_totp(1, totp.clone()).await.unwrap();
+        assert_eq!(db.get_totp(1).await.unwrap(), totp);
+
+        db.delete_user(1).await.unwrap();
+        assert!(matches!(db.get_totp(1).await, Err(DbError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_bots() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        db.create_user(2, "b".to_string(), String::new(), String::new()).await.unwrap();
+        db.create_bot(2, 1).await.unwrap();
+        assert_eq!(db.get_bot_owner(2).await.unwrap(), 1);
+        assert!(matches!(db.get_bot_owner(1).await, Err(DbError::NotFound(_))));
+        assert_eq!(db.get_user_bots(1).await.unwrap(), vec![2]);
+
+        let token = BotToken {
+            id: 5,
+            bot: 2,
+            name: "t".to_string(),
+            hash: "abc".to_string(),
+            scopes: vec![
+                Scope { kind: ScopeKind::ReadMessages, group: 10 },
+                Scope { kind: ScopeKind::ManageChannels, group: 11 },
+            ],
+        };
+        db.create_api_token(token.clone()).await.unwrap();
+        assert_eq!(db.get_api_token(5).await.unwrap(), token);
+        assert_eq!(db.get_bot_tokens(2).await.unwrap(), vec![token]);
+        db.delete_api_token(5).await.unwrap();
+        assert!(matches!(db.get_api_token(5).await, Err(DbError::NotFound(_))));
+
+        // A user's bots have to go before they do
+        assert!(matches!(db.delete_user(1).await, Err(DbError::Conflict(_))));
+        db.delete_user(2).await.unwrap();
+        assert_eq!(db.get_user_bots(1).await.unwrap(), Vec::<i64>::new());
+        db.delete_user(1).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_identities() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        db.create_user(2, "b".to_string(), "b@example.com".to_string(), String::new()).await.unwrap();
+        let identity = Identity { issuer: "https://idp.example.com".to_string(), subject: "abc".to_string() };
+        assert!(matches!(db.get_identity_user(identity.clone()).await, Err(DbError::NotFound(_))));
+        db.link_identity(1, identity.clone()).await.unwrap();
+        assert_eq!(db.get_identity_user(identity.clone()).await.unwrap(), 1);
+        assert_eq!(db.get_user_identities(1).await.unwrap(), vec![identity.clone()]);
+        assert!(matches!(db.link_identity(2, identity.clone()).await, Err(DbError::Conflict(_))));
+
+        db.delete_user(1).await.unwrap();
+        assert!(matches!(db.get_identity_user(identity).await, Err(DbError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_verified_email() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "a".to_string(), "a@example.com".to_string(), String::new()).await.unwrap();
+        assert_eq!(db.get_verified_email(1).await.unwrap(), None);
+        db.set_verified_email(1, "a@example.com".to_string()).await.unwrap();
+        db.set_verified_email(1, "b@example.com".to_string()).await.unwrap();
+        assert_eq!(db.get_verified_email(1).await.unwrap(), Some("b@example.com".to_string()));
+
+        db.delete_user(1).await.unwrap();
+        assert_eq!(db.get_verified_email(1).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_names() {
+        let db = Sqlite::new(":memory:");
+        db.create_user(1, "Fred".to_string(), "fred@example.com".to_string(), String::new()).await.unwrap();
+        db.create_user(2, "freda".to_string(), String::new(), String::new()).await.unwrap();
+        db.create_user(3, "bot".to_string(), String::new(), String::new()).await.unwrap();
+        assert!(matches!(db.create_user(4, "FRED".to_string(), "x@example.com".to_string(), String::new()).await, Err(DbError::Conflict(_))));
+        assert!(matches!(db.create_user(4, "x".to_string(), "Fred@Example.com".to_string(), String::new()).await, Err(DbError::Conflict(_))));
+        assert!(!db.valid_id(IdType::User, 4).await.unwrap());
+
+        assert_eq!(db.get_user_by_name("fRED".to_string()).await.unwrap(), 1);
+        assert_eq!(db.get_user_by_email("FRED@example.com".to_string()).await.unwrap(), 1);
+        let found: Vec<i64> = db.search_users("Fr".to_string(), 10).await.unwrap().iter().map(|u| u.id).collect();
+        assert_eq!(found, vec![1, 2]);
+
+        assert!(matches!(db.update_user(1, "freda".to_string(), "fred@example.com".to_string()).await, Err(DbError::Conflict(_))));
+        assert_eq!(db.get_user(1).await.unwrap().username, "Fred");
+        db.update_user(1, "frederick".to_string(), "new@example.com".to_string()).await.unwrap();
+        assert!(matches!(db.get_user_by_name("fred".to_string()).await, Err(DbError::NotFound(_))));
+        assert!(matches!(db.get_user_by_email("fred@example.com".to_string()).await, Err(DbError::NotFound(_))));
+        db.delete_user(1).await.unwrap();
+        assert!(matches!(db.get_user_by_name("frederick".to_string()).await, Err(DbError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_backfill_names() {
+        let path = std::env::temp_dir().join(format!("bsk-backfill-{}.db", crate::gen_id()));
+        let path = path.to_str().unwrap();
+        {
+            // Users from before names were claimed, two of them sharing one
+            let conn = Connection::open(path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL, email TEXT NOT NULL, hash TEXT NOT NULL);
+                 INSERT INTO users VALUES (1, 'Fred', 'fred@example.com', ''), (2, 'fred', 'other@example.com', '');"
+            ).unwrap();
+        }
+        let db = Sqlite::new(path);
+        assert_eq!(db.get_user_by_name("FRED".to_string()).await.unwrap(), 1);
+        assert_eq!(db.get_user_by_email("other@example.com".to_string()).await.unwrap(), 2);
+        db.update_user(2, "freddie".to_string(), "other@example.com".to_string()).await.unwrap();
+        assert_eq!(db.get_user_by_name("freddie".to_string()).await.unwrap(), 2);
+        drop(db);
+        {
+            // Claims made with an older `username_key`, which are made again
+            let conn = Connection::open(path).unwrap();
+            conn.execute_batch(
+                "INSERT INTO users VALUES (3, 'paypal', 'p@example.com', ''), (4, 'paypa1', 'q@example.com', '');
+                 DELETE FROM usernames;
+                 INSERT INTO usernames VALUES ('fred', 1), ('freddie', 2), ('paypal', 3), ('paypa1', 4);
+                 PRAGMA user_version = 0;"
+            ).unwrap();
+        }
+        let db = Sqlite::new(path);
+        assert_eq!(db.get_user_by_name("FREDDIE".to_string()).await.unwrap(), 2);
+        assert_eq!(db.get_user_by_name("paypa1".to_string()).await.unwrap(), 3);
+        drop(db);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{path}{suffix}"));
+        }
+    }
+}
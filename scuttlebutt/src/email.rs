@@ -0,0 +1,199 @@
+//! Email verification and password resets.
+//!
+//! Both work by mailing the user a token signed with the server keys. A verification
+//! token is tied to the address it was sent to, so it stops working if the user changes
+//! their email again before using it. A reset token is tied to the user's current
+//! password hash, so it stops working once the password changes, including by using it.
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::mailer::Email;
+
+/// The longest email address accepted, in bytes (RFC 5321's limit on a path)
+pub const MAX_EMAIL_LEN: usize = 254;
+
+/// How long verification tokens are valid for, in seconds
+pub const VERIFY_LIFETIME: i64 = 24 * 60 * 60;
+
+/// How long password reset tokens are valid for, in seconds
+pub const RESET_LIFETIME: i64 = 60 * 60;
+
+/// Whether an email address looks deliverable: a non-empty local part, an `@` and a
+/// domain with a dot in it, and no whitespace. Anything stricter is better left to
+/// actually sending mail to it.
+///
+/// Arguments:
+/// - `email`: the address, as sent by the user
+pub fn valid_email(email: &str) -> bool {
+    let (local, domain) = match email.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    email.len() <= MAX_EMAIL_LEN
+        && !local.is_empty()
+        && !local.contains('@')
+        && domain.split('.').count() > 1
+        && domain.split('.').all(|label| !label.is_empty())
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Claims of the tokens mailed out for verifying an address or resetting a password.
+///
+/// `purpose` keeps these from being mixed up with other tokens signed with the same
+/// keys, and since there's no `sid` they can't be used as access tokens.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailClaims {
+    pub id: i64,
+    pub purpose: String,
+    // What the token is tied to: the address being verified, or a fingerprint of the
+    // password hash being reset
+    pub check: String,
+    pub exp: DateTime<Local>,
+}
+
+impl EmailClaims {
+    const VERIFY: &'static str = "verify_email";
+    const RESET: &'static str = "reset_password";
+
+    /// Make the claims for verifying a user's address, expiring after `VERIFY_LIFETIME`
+    ///
+    /// Arguments:
+    /// - `id`: the user's ID
+    /// - `email`: the address being verified
+    pub fn verification(id: i64, email: &str) -> Self {
+        Self {
+            id,
+            purpose: Self::VERIFY.to_string(),
+            check: email.to_string(),
+            exp: Local::now() + chrono::Duration::seconds(VERIFY_LIFETIME),
+        }
+    }
+
+    /// Make the claims for resetting a user's password, expiring after `RESET_LIFETIME`
+    ///
+    /// Arguments:
+    /// - `id`: the user's ID
+    /// - `hash`: the user's current password hash
+    pub fn reset(id: i64, hash: &str) -> Self {
+        Self {
+            id,
+            purpose: Self::RESET.to_string(),
+            check: fingerprint(hash),
+            exp: Local::now() + chrono::Duration::seconds(RESET_LIFETIME),
+        }
+    }
+
+    /// Whether these claims verify the given address, and haven't expired
+    pub fn verifies(&self, email: &str) -> bool {
+        self.purpose == Self::VERIFY && self.check == email && self.exp > Local::now()
+    }
+
+    /// Whether these claims allow resetting a password with the given current hash,
+    /// and haven't expired
+    pub fn resets(&self, hash: &str) -> bool {
+        self.purpose == Self::RESET && self.check == fingerprint(hash) && self.exp > Local::now()
+    }
+}
+
+/// A fingerprint of a password hash, so reset tokens don't carry the hash itself
+fn fingerprint(hash: &str) -> String {
+    hex::encode(Sha256::digest(hash.as_bytes()))
+}
+
+/// The email asking a user to verify their address
+///
+/// Arguments:
+/// - `to`: the address to verify
+/// - `name`: the user's name
+/// - `token`: the signed `EmailClaims::verification`
+pub fn verification_email(to: &str, name: &str, token: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Hi {name},\n\n\
+             Use this token to verify your email address for Blatherskite:\n\n\
+             {token}\n\n\
+             It expires in {} hours. If you didn't sign up, you can ignore this email.\n",
+            VERIFY_LIFETIME / 3600
+        ),
+    }
+}
+
+/// The email with a token for resetting a user's password
+///
+/// Arguments:
+/// - `to`: the user's address
+/// - `name`: the user's name
+/// - `token`: the signed `EmailClaims::reset`
+pub fn reset_email(to: &str, name: &str, token: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hi {name},\n\n\
+             Use this token to reset your Blatherskite password:\n\n\
+             {token}\n\n\
+             It expires in {} minutes. If you didn't ask to reset your password, you can \
+             ignore this email.\n",
+            RESET_LIFETIME / 60
+        ),
+    }
+}
+
+/// The token in an email made by `verification_email` or `reset_email`. Counted from
+/// the end, since the user's name could have blank lines in it.
+pub fn token_in(email: &Email) -> Option<&str> {
+    email.body.rsplit("\n\n").nth(1)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_valid_email() {
+        for email in ["fred@example.com", "fred.smith+bsk@mail.example.co.uk"] {
+            assert!(valid_email(email), "{email}");
+        }
+        for email in [
+            "", "fred", "fred@", "@example.com", "fred@localhost", "fred@example.", "fred@.com",
+            "fred smith@example.com", "fred@exa\tmple.com", "fred@@example.com",
+        ] {
+            assert!(!valid_email(email), "{email}");
+        }
+        let long = format!("{}@example.com", "a".repeat(MAX_EMAIL_LEN));
+        assert!(!valid_email(&long));
+        assert!(valid_email(&long[long.len() - MAX_EMAIL_LEN..]));
+    }
+
+    #[test]
+    fn test_verification_claims() {
+        let claims = EmailClaims::verification(1, "fred@example.com");
+        assert!(claims.verifies("fred@example.com"));
+        assert!(!claims.verifies("other@example.com"));
+        assert!(!claims.resets("fred@example.com"));
+        let expired = EmailClaims { exp: Local::now() - chrono::Duration::seconds(1), ..claims };
+        assert!(!expired.verifies("fred@example.com"));
+    }
+
+    #[test]
+    fn test_reset_claims() {
+        let claims = EmailClaims::reset(1, "$argon2id$old");
+        assert!(claims.resets("$argon2id$old"));
+        assert!(!claims.resets("$argon2id$new"));
+        assert!(!claims.verifies(&claims.check));
+        assert!(!claims.check.contains("argon2id"));
+        let expired = EmailClaims { exp: Local::now() - chrono::Duration::seconds(1), ..claims };
+        assert!(!expired.resets("$argon2id$old"));
+    }
+
+    #[test]
+    fn test_templates() {
+        let email = verification_email("fred@example.com", "fred", "a.b.c");
+        assert_eq!(email.to, "fred@example.com");
+        assert_eq!(token_in(&email), Some("a.b.c"));
+        assert_eq!(token_in(&reset_email("fred@example.com", "fred", "d.e.f")), Some("d.e.f"));
+    }
+}
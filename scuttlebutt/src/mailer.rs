@@ -0,0 +1,175 @@
+//! Sending email. Handlers only see the `Mailer` trait, so where mail actually goes is
+//! picked at startup (see `from_env`):
+//! - `SmtpMailer` sends it through an SMTP server, for real deployments
+//! - `FileMailer` appends it to a file as JSON lines, for tests and local development
+//! - `LogMailer` just logs it, which is the default so nothing is sent by accident
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use std::{io::Write, path::PathBuf, sync::{Arc, Mutex}};
+
+/// A plain text email to one recipient
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Something that can send emails
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    /// Send an email, returning a description of what went wrong if it couldn't be
+    async fn send(&self, email: Email) -> Result<(), String>;
+}
+
+/// Pick a mailer from the environment:
+/// - `BSK_SMTP_HOST` sends through that server (on `BSK_SMTP_PORT`, 587 by default),
+///   logging in with `BSK_SMTP_USERNAME` and `BSK_SMTP_PASSWORD` if they're set, from the
+///   address in `BSK_MAIL_FROM`
+/// - otherwise `BSK_MAIL_FILE` appends emails to that file
+/// - otherwise they're only logged
+pub fn from_env() -> Result<Arc<dyn Mailer>, String> {
+    if let Ok(host) = std::env::var("BSK_SMTP_HOST") {
+        let var = |name: &str| std::env::var(name).map_err(|_| format!("{name} must be set along with BSK_SMTP_HOST"));
+        let port = match std::env::var("BSK_SMTP_PORT") {
+            Ok(port) => port.parse().map_err(|_| format!("invalid BSK_SMTP_PORT {port}"))?,
+            Err(_) => 587,
+        };
+        let credentials = match std::env::var("BSK_SMTP_USERNAME") {
+            Ok(username) => Some((username, var("BSK_SMTP_PASSWORD")?)),
+            Err(_) => None,
+        };
+        Ok(Arc::new(SmtpMailer::new(&host, port, credentials, &var("BSK_MAIL_FROM")?)?))
+    } else if let Ok(path) = std::env::var("BSK_MAIL_FILE") {
+        Ok(Arc::new(FileMailer::new(path)))
+    } else {
+        Ok(Arc::new(LogMailer))
+    }
+}
+
+/// Sends emails through an SMTP server
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    /// Arguments:
+    /// - `host`: the server's hostname
+    /// - `port`: the port to connect to. Port 465 uses TLS from the start, and any other
+    ///   has to support upgrading to it with STARTTLS.
+    /// - `credentials`: the username and password to log in with, if the server needs them
+    /// - `from`: the address to send from, optionally with a name (`Name <address>`)
+    pub fn new(host: &str, port: u16, credentials: Option<(String, String)>, from: &str) -> Result<Self, String> {
+        let builder = match port {
+            465 => AsyncSmtpTransport::<Tokio1Executor>::relay(host),
+            _ => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host),
+        };
+        let mut builder = builder.map_err(|e| format!("invalid SMTP host {host}: {e}"))?.port(port);
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            from: from.parse().map_err(|e| format!("invalid sender address {from}: {e}"))?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), String> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse().map_err(|e| format!("invalid address {}: {e}", email.to))?)
+            .subject(email.subject)
+            .body(email.body)
+            .map_err(|e| e.to_string())?;
+        self.transport.send(message).await.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// Appends emails to a file, one JSON object per line
+pub struct FileMailer {
+    path: PathBuf,
+    // Keeps concurrent sends from interleaving their lines
+    lock: Mutex<()>,
+}
+
+impl FileMailer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), lock: Mutex::new(()) }
+    }
+
+    /// Read back every email sent to the file, oldest first
+    pub fn read(&self) -> Result<Vec<Email>, String> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.to_string()),
+        };
+        contents.lines()
+            .map(|line| serde_json::from_str(line).map_err(|e| e.to_string()))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), String> {
+        let line = serde_json::to_string(&email).map_err(|e| e.to_string())?;
+        let _guard = self.lock.lock().unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| e.to_string())?;
+        writeln!(file, "{line}").map_err(|e| e.to_string())
+    }
+}
+
+/// Logs emails instead of sending them
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<(), String> {
+        log::info!("Not sending email to {} (no mailer set up): {}\n{}", email.to, email.subject, email.body);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_file_mailer() {
+        let path = std::env::temp_dir().join(format!("bsk-mail-{}.jsonl", common::gen_id()));
+        let mailer = FileMailer::new(&path);
+        assert_eq!(mailer.read().unwrap(), vec![]);
+        let email = |to: &str| Email {
+            to: to.to_string(),
+            subject: "Hi".to_string(),
+            body: "Line one\nLine two".to_string(),
+        };
+        mailer.send(email("a@example.com")).await.unwrap();
+        mailer.send(email("b@example.com")).await.unwrap();
+        assert_eq!(mailer.read().unwrap(), vec![email("a@example.com"), email("b@example.com")]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_smtp_settings() {
+        let credentials = Some(("user".to_string(), "password".to_string()));
+        assert!(SmtpMailer::new("smtp.example.com", 587, credentials, "Blatherskite <noreply@example.com>").is_ok());
+        assert!(SmtpMailer::new("smtp.example.com", 465, None, "noreply@example.com").is_ok());
+        assert!(SmtpMailer::new("smtp.example.com", 587, None, "not an address").is_err());
+    }
+}
@@ -1,37 +1,37 @@
-use chrono::{DateTime, Duration, Local, Utc};
-use hmac::{Hmac, digest::typenum::array};
-use jwt::{SignWithKey, VerifyWithKey};
+use chrono::{Duration, Local, Utc};
 use poem::{
     listener::TcpListener, web::Data, EndpointExt, Request, Result,
     Route, Server,
 };
 use poem_openapi::{
     auth::ApiKey,
-    param::Query,
+    param::{Path, Query},
     payload::{Json, PlainText},
     *,
 };
-use std::sync::Mutex;
 use rand::{distributions::Alphanumeric, Rng};
-use rustflake::Snowflake;
-use serde::{Deserialize, Serialize};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
 
 pub mod responses;
 pub use responses::*;
 
-pub mod db;
-pub use db::*;
+pub mod password;
+use password::{hash_password_blocking, valid_password, verify_password_blocking, Verified};
 
-type ServerKey = Hmac<Sha256>;
+pub mod totp;
+use totp::ChallengeClaims;
 
-/// Struct representing the ID of the authorized users and the expiration date of the token
-/// The serialized form of this struct forms the content portion of the JWT returned by /login
-#[derive(Serialize, Deserialize)]
-struct Claims {
-    id: i64,
-    exp: DateTime<Local>,
-}
+pub mod oidc;
+use oidc::{OidcConfig, Provider, StateClaims};
+
+pub mod mailer;
+use mailer::{LogMailer, Mailer};
+
+pub mod email;
+use email::{valid_email, EmailClaims};
+
+use common::*;
 
 /// API key authorization scheme
 #[derive(SecurityScheme)]
@@ -43,91 +43,703 @@ struct Claims {
 )]
 struct Authorization(Claims);
 
+/// Authorization scheme for the endpoints bots can use too: also accepts bots'
+/// API tokens, whose scopes the endpoint has to check (see `Claims::allows`)
+#[derive(SecurityScheme)]
+#[oai(
+    type = "api_key",
+    key_name = "Authorization",
+    in = "header",
+    checker = "scoped_api_checker"
+)]
+struct ScopedAuthorization(Claims);
+
 /// Check if a user has supplied a valid authorization token.
 ///
-/// Returns None if the token was invalid or if it fails to parse the given token
-/// (which will then be handled by Poem to throw a 401), otherwise returns the
-/// Claims struct.
+/// Returns None if the token was invalid, if it fails to parse the given token
+/// or if it's a bot's API token (which will then be handled by Poem to throw a 401),
+/// otherwise returns the Claims struct.
 async fn api_checker(req: &Request, api_key: ApiKey) -> Option<Claims> {
-    let encoded_claims_str = match api_key.key.split(".").nth(1) {
-        None => return None,
-        Some(s) => s,
-    };
-    let claims_str = match base64::decode(encoded_claims_str) {
-        Err(_) => return None,
-        Ok(s) => s,
-    };
-    let claims: Claims = match serde_json::from_str(&String::from_utf8(claims_str).unwrap()) {
-        Err(_) => return None,
-        Ok(c) => c
-    };          
-    if claims.exp < Local::now() {
-        return None;
-    }    
-    let server_key = req.data::<ServerKey>().unwrap(); // get server secret
-    VerifyWithKey::<Claims>::verify_with_key(api_key.key.as_str(), server_key).ok()
+    scoped_api_checker(req, api_key).await.filter(|claims| !claims.is_bot())
+}
+
+/// Check if a user or bot has supplied a valid authorization token.
+///
+/// Like `api_checker`, but accepts bots' API tokens too.
+async fn scoped_api_checker(req: &Request, api_key: ApiKey) -> Option<Claims> {
+    let keys = req.data::<ServerKeys>().unwrap(); // get server secrets
+    let db = req.data::<Arc<dyn Database>>().unwrap();
+    authenticate(keys, db.as_ref(), &api_key.key).await
+}
+
+/// How long access tokens are valid for, in seconds. They're checked against the
+/// revocation list, which only has to remember a revoked session for this long.
+const ACCESS_TOKEN_LIFETIME: i64 = 15 * 60;
+
+/// How long refresh tokens are valid for, in seconds
+const REFRESH_TOKEN_LIFETIME: i64 = 30 * 24 * 60 * 60;
+
+/// Make a new refresh token for a session.
+///
+/// Returns the token, which looks like `<session id>.<secret>`, and the hash of the
+/// secret to store in the session.
+fn make_refresh_token(sid: i64) -> (String, String) {
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    (format!("{sid}.{secret}"), hex::encode(Sha256::digest(secret.as_bytes())))
+}
+
+/// Make a new API token for a bot.
+///
+/// Returns the token, which looks like `bsk_<token id>_<secret>`, and the hash of the
+/// secret to store with it.
+fn make_api_token(id: i64) -> (String, String) {
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    (format!("{API_TOKEN_PREFIX}{id}_{secret}"), common::auth::hash_api_secret(&secret))
+}
+
+/// Make a new code for an invite to a group
+fn make_invite_code() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(char::from)
+        .collect()
+}
+
+/// The most users `/user/search` (or groups `/group/directory`) returns at once
+const MAX_SEARCH_RESULTS: u64 = 50;
+
+/// The longest reason a moderation action can be given, in characters
+const MAX_REASON_LEN: usize = 512;
+
+/// The longest a member can be timed out for, in seconds
+const MAX_TIMEOUT: i64 = 28 * 24 * 60 * 60;
+
+/// The most audit log entries `/group/audit` returns at once
+const MAX_AUDIT_ENTRIES: u64 = 100;
+
+/// The longest description a group can have in the directory, in characters
+const MAX_DESCRIPTION_LEN: usize = 1000;
+
+/// The most tags a group can have
+const MAX_TAGS: usize = 10;
+
+/// The longest a group's tag can be, in characters
+const MAX_TAG_LEN: usize = 32;
+
+/// The longest message a request to join a group can have, in characters
+const MAX_JOIN_MESSAGE_LEN: usize = 512;
+
+/// Check the tags a group is given, lowercasing them and dropping duplicates. Tags can
+/// only have letters, digits and dashes.
+fn clean_tags(tags: Vec<String>) -> std::result::Result<Vec<String>, String> {
+    let mut cleaned: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tag.chars().count() > MAX_TAG_LEN {
+            return Err(format!("Tags have to be between 1 and {MAX_TAG_LEN} characters long"));
+        } else if !tag.chars().all(|c| c.is_alphanumeric() || c == '-') {
+            return Err("Tags can only have letters, digits and dashes".to_string());
+        } else if !cleaned.contains(&tag) {
+            cleaned.push(tag);
+        }
+    }
+    match cleaned.len() > MAX_TAGS {
+        true => Err(format!("Groups can't have more than {MAX_TAGS} tags")),
+        false => Ok(cleaned),
+    }
+}
+
+/// Check the reason given for a moderation action, trimming it. No reason is an empty one.
+fn clean_reason(reason: Option<String>) -> std::result::Result<String, String> {
+    let reason = reason.unwrap_or_default().trim().to_string();
+    match reason.chars().count() > MAX_REASON_LEN {
+        true => Err(format!("Reasons can't be longer than {MAX_REASON_LEN} characters")),
+        false => Ok(reason),
+    }
 }
 
 /// Wrapper struct for the API functions
 struct Api {
-    // The backend.
-    db: Box<dyn Database>,  
+    // The backend. Also shared with `api_checker` through the request data.
+    db: Arc<dyn Database>,  
+    // The OpenID Connect provider users can log in with, if one is set up
+    oidc: Option<Provider>,
+    // Sends verification and password reset emails
+    mailer: Arc<dyn Mailer>,
+    // How long names can be (see `NameLimits::clean`)
+    names: NameLimits,
 }
 
-/// Generates a unique i64 for ID generation
-// FIXME: Very bad performance - acts as a chokehold for parallelism since
-// every request that sends a message / makes a channel / etc. has to contest
-// a global mutex.
-pub fn gen_id() -> i64 {
-    static STATE: Mutex<Option<Snowflake>> = Mutex::new(None);
-
-    STATE
-        .lock()
-        .unwrap()
-        .get_or_insert_with(|| Snowflake::default())
-        .generate()
+/// Unwrap a database result, or return early from the handler with the error
+/// converted into its response type (see `from_db_error!` in `responses.rs`).
+macro_rules! db_try {
+    ($e:expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(e) => return e.into(),
+        }
+    };
 }
 
 #[OpenApi]
 #[allow(unused_variables)]
 impl Api {
-    fn new(db: Box<dyn Database>) -> Api {
-        Api { db }
+    fn new(db: Arc<dyn Database>) -> Api {
+        Api { db, oidc: None, mailer: Arc::new(LogMailer), names: NameLimits::default() }
+    }
+
+    /// Let users log in with an OpenID Connect provider
+    fn with_oidc(self, provider: Provider) -> Api {
+        Api { oidc: Some(provider), ..self }
+    }
+
+    /// Send emails with the given mailer, rather than just logging them
+    fn with_mailer(self, mailer: Arc<dyn Mailer>) -> Api {
+        Api { mailer, ..self }
+    }
+
+    /// Allow names up to the given lengths, rather than the defaults
+    fn with_name_limits(self, names: NameLimits) -> Api {
+        Api { names, ..self }
+    }
+
+    /// Sign a new access token for a session, and bundle it with the session's refresh token
+    fn __tokens(&self, keys: &ServerKeys, uid: i64, sid: i64, refresh_token: String) -> Result<Tokens, jwt::Error> {
+        let access_token = keys.sign(&Claims {
+            id: uid,
+            sid,
+            exp: Local::now() + Duration::seconds(ACCESS_TOKEN_LIFETIME),
+            scopes: None,
+        })?;
+        Ok(Tokens { access_token, refresh_token, expires_in: ACCESS_TOKEN_LIFETIME })
+    }
+
+    /// Find a user by their username or email (anything with an `@` in it), ignoring case
+    async fn __find_user(&self, user: &str) -> db::Result<i64> {
+        match user.contains('@') {
+            true => self.db.get_user_by_email(user.to_string()).await,
+            false => self.db.get_user_by_name(user.to_string()).await,
+        }
+    }
+
+    /// Start a new session for a user who's logged in, returning its tokens
+    async fn __start_session(&self, keys: &ServerKeys, uid: i64) -> Result<Tokens, String> {
+        let sid = gen_id();
+        let (refresh_token, refresh_hash) = make_refresh_token(sid);
+        self.db.create_session(AuthSession {
+            id: sid,
+            user: uid,
+            refresh_hash,
+            expires: Utc::now().timestamp() + REFRESH_TOKEN_LIFETIME,
+        }).await.map_err(|e| e.to_string())?;
+        self.__tokens(keys, uid, sid, refresh_token).map_err(|e| e.to_string())
+    }
+
+    /// Check a second factor for a user with two-factor authentication on: either a TOTP
+    /// code, or one of their recovery codes (which is then used up).
+    ///
+    /// Returns false if the code is wrong, or if the user doesn't have 2FA on.
+    async fn __check_second_factor(&self, uid: i64, code: &str) -> db::Result<bool> {
+        let mut totp = match self.db.get_totp(uid).await {
+            Ok(totp) if totp.enabled => totp,
+            Ok(_) | Err(DbError::NotFound(_)) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if let Some(step) = totp::check_code(&totp.secret, code, totp.last_step, Utc::now().timestamp()) {
+            totp.last_step = step;
+        } else {
+            let hash = totp::hash_recovery_code(code);
+            match totp.recovery_codes.iter().position(|c| *c == hash) {
+                Some(i) => { totp.recovery_codes.remove(i); }
+                None => return Ok(false),
+            }
+        }
+        self.db.set_totp(uid, totp).await?;
+        Ok(true)
+    }
+
+    /// End a session: its refresh token stops working, and the access tokens issued
+    /// for it are refused through the revocation list until they expire.
+    async fn __revoke_session(&self, sid: i64) -> db::Result<()> {
+        self.db.delete_session(sid).await?;
+        self.db.revoke_session(sid, Utc::now().timestamp() + ACCESS_TOKEN_LIFETIME).await
+    }
+
+    /// End every one of a user's sessions
+    async fn __revoke_user_sessions(&self, uid: i64) -> db::Result<()> {
+        for sid in self.db.get_user_sessions(uid).await? {
+            self.__revoke_session(sid).await?;
+        }
+        Ok(())
+    }
+
+    /// Delete a user (or bot) along with their sessions, and remove them from every group,
+    /// handing over the ones they own (see `__leave_group`)
+    async fn __delete_user(&self, uid: i64) -> db::Result<()> {
+        self.__revoke_user_sessions(uid).await?;
+        // Before the user goes, since some backends take their memberships with them
+        for group in self.db.get_user_groups(uid).await? {
+            self.__leave_group(group, uid).await?;
+        }
+        for dm in self.db.get_user_dms(uid).await? {
+            self.__leave_group(dm, uid).await?;
+        }
+        self.db.delete_user(uid).await?;
+        self.db.delete_user_groups(uid).await
+    }
+
+    /// Whether a user is a bot
+    async fn __is_bot(&self, uid: i64) -> db::Result<bool> {
+        match self.db.get_bot_owner(uid).await {
+            Ok(_) => Ok(true),
+            Err(DbError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether a bot exists and belongs to a user
+    async fn __owns_bot(&self, uid: i64, bot: i64) -> db::Result<bool> {
+        match self.db.get_bot_owner(bot).await {
+            Ok(owner) => Ok(owner == uid),
+            Err(DbError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the user (or bot) some claims came from can see a group and its members
+    /// (see `roles::can_view`)
+    async fn __can_view(&self, claims: &Claims, gid: i64) -> db::Result<bool> {
+        roles::can_view(self.db.as_ref(), claims, gid).await
+    }
+
+    /// Whether the user (or bot) some claims came from can read a channel's messages and
+    /// see its members (see `roles::can_read`)
+    async fn __can_read(&self, claims: &Claims, cid: i64) -> db::Result<bool> {
+        roles::can_read(self.db.as_ref(), claims, cid).await
+    }
+
+    /// Whether the user (or bot) some claims came from has a permission in a group (see
+    /// `roles::permissions`). Every endpoint checks permissions through this, apart from
+    /// the few things only a group's owner can do, which no role can allow.
+    async fn __authorize(&self, claims: &Claims, gid: i64, permission: Permission) -> db::Result<bool> {
+        roles::authorize(self.db.as_ref(), claims, gid, permission).await
+    }
+
+    /// Whether the user (or bot) some claims came from has a permission in a channel, once
+    /// the channel's overrides are applied
+    async fn __authorize_channel(&self, claims: &Claims, cid: i64, permission: Permission) -> db::Result<bool> {
+        roles::authorize_channel(self.db.as_ref(), claims, cid, permission).await
+    }
+
+    /// Whether some claims allow setting or deleting a channel's override: that needs
+    /// `manage_channels` in the channel, and every permission the override allows or denies
+    async fn __can_manage_override(&self, claims: &Claims, over: &ChannelOverride) -> db::Result<bool> {
+        let permissions = roles::channel_permissions(self.db.as_ref(), claims, over.channel).await?;
+        Ok(permissions.contains(Permission::ManageChannels)
+            && permissions.contains_all(over.allowed().union(over.denied())))
+    }
+
+    /// Whether some claims allow doing something to a user that needs a permission, like
+    /// removing or banning them: nobody can do it to the owner, and only the owner and
+    /// admins can do it to admins
+    async fn __can_moderate(&self, claims: &Claims, gid: i64, uid: i64, permission: Permission) -> db::Result<bool> {
+        let group = self.db.get_group(gid).await?;
+        Ok(self.__authorize(claims, gid, permission).await?
+            && group.owner != uid
+            && (!group.admin.contains(&uid) || group.owner == claims.id || group.admin.contains(&claims.id)))
+    }
+
+    /// Add an entry to a group's audit log, saying that a user (the actor) did something
+    /// to a target (see `AuditEntry`)
+    async fn __audit(&self, actor: i64, gid: i64, action: AuditAction, target: i64, reason: String) -> db::Result<()> {
+        self.db.add_audit_entry(AuditEntry {
+            id: gen_id(),
+            group: gid,
+            actor,
+            action,
+            target,
+            timestamp: Utc::now().timestamp(),
+            reason,
+        }).await
+    }
+
+    /// Whether a user is banned from a group
+    async fn __is_banned(&self, gid: i64, uid: i64) -> db::Result<bool> {
+        match self.db.get_ban(gid, uid).await {
+            Ok(_) => Ok(true),
+            Err(DbError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether some claims allow creating, changing, deleting or handing out a role: that
+    /// needs `manage_roles`, and every permission the role has
+    async fn __can_manage_role(&self, claims: &Claims, role: &Role) -> db::Result<bool> {
+        let permissions = roles::permissions(self.db.as_ref(), claims, role.group).await?;
+        Ok(permissions.contains(Permission::ManageRoles) && permissions.contains_all(role.permission_set()))
+    }
+
+    /// Get a role, including a group's default role when it hasn't been changed
+    async fn __get_role(&self, id: i64) -> db::Result<Role> {
+        match self.db.valid_id(IdType::Group, id).await? {
+            true => roles::default_role(self.db.as_ref(), id).await,
+            false => self.db.get_role(id).await,
+        }
+    }
+
+    /// Get the roles in a group, starting with its default role
+    async fn __group_roles(&self, gid: i64) -> db::Result<Vec<Role>> {
+        let mut all = vec![roles::default_role(self.db.as_ref(), gid).await?];
+        all.extend(self.db.get_group_roles(gid).await?.into_iter().filter(|role| role.id != gid));
+        Ok(all)
+    }
+
+    /// Start logging in (or linking an identity) through the OpenID Connect provider
+    fn __start_oidc(&self, keys: &ServerKeys, link: Option<i64>) -> OidcStartResponse {
+        use OidcStartResponse::*;
+        let provider = match &self.oidc {
+            Some(provider) => provider,
+            None => return NotFound,
+        };
+        let claims = StateClaims::new(link);
+        let state = match keys.sign(&claims) {
+            Ok(state) => state,
+            Err(e) => return InternalError(PlainText(e.to_string())),
+        };
+        match provider.authorization_url(&state, &claims.nonce) {
+            Ok(url) => Success(Json(OidcRedirect { url, state })),
+            Err(e) => InternalError(PlainText(e)),
+        }
+    }
+
+    /// Make a user for an identity the first time it logs in.
+    ///
+    /// If their name is taken, they get a number on the end of it. If their email is
+    /// taken, they're made without one, rather than being let into someone else's account.
+    async fn __make_oidc_user(&self, token: &oidc::IdToken) -> db::Result<i64> {
+        let id = gen_id();
+        // Leave room for the number, in case it's needed
+        let max = self.names.user.saturating_sub(4).max(1);
+        let base: String = match self.names.clean(NameKind::User, &token.username()) {
+            Ok(name) => name.chars().take(max).collect(),
+            Err(_) => "user".to_string(),
+        };
+        let mut name = base.clone();
+        while self.db.get_user_by_name(name.clone()).await.is_ok() {
+            name = format!("{base}{}", rand::thread_rng().gen_range(1000..10000));
+        }
+        let mut email = token.email.clone().unwrap_or_default();
+        if !email.is_empty() && self.db.get_user_by_email(email.clone()).await.is_ok() {
+            email = String::new();
+        }
+        // No password hash, so they can only log in through the provider (until they set one)
+        self.db.create_user(id, name, email.clone(), String::new()).await?;
+        self.db.create_user_groups(id).await?;
+        self.db.create_user_dms(id).await?;
+        if let Err(e) = self.db.link_identity(id, token.identity()).await {
+            // Someone else got there first
+            self.__delete_user(id).await?;
+            return Err(e);
+        }
+        if !email.is_empty() && token.email_verified == Some(true) {
+            self.db.set_verified_email(id, email).await?;
+        }
+        Ok(id)
+    }
+
+    /// Mail a user a token to verify their email address with
+    async fn __send_verification(&self, keys: &ServerKeys, user: &User) -> Result<(), String> {
+        let token = keys.sign(&EmailClaims::verification(user.id, &user.email)).map_err(|e| e.to_string())?;
+        self.mailer.send(email::verification_email(&user.email, &user.username, &token)).await
+    }
+
+    /// Make a member of a group its owner. Owners can't be timed out, so any timeout they
+    /// had is lifted.
+    async fn __set_group_owner(&self, gid: i64, uid: i64) -> db::Result<()> {
+        self.db.set_group_owner(gid, uid).await?;
+        self.db.delete_timeout(gid, uid).await
     }
 
-    fn __remove_group_member(&self, gid: i64, uid: i64) {
-        self.db.remove_group_member(gid, uid).unwrap();
-        let channels = self.db.get_group_channels(gid).unwrap();        
+    /// Choose who a group goes to when its owner leaves: the admin, or failing that the
+    /// member, who has been in the group the longest (only members count, so an admin who
+    /// has since left is passed over) (members who joined at the same time
+    /// go by who's been a user longer, as IDs go up over time). Bots can't own groups, so
+    /// they're passed over.
+    async fn __successor(&self, group: &Group) -> db::Result<Option<i64>> {
+        for candidates in [&group.admin, &group.members] {
+            let mut joined = Vec::with_capacity(candidates.len());
+            for &uid in candidates.iter().filter(|&&uid| uid != group.owner && group.members.contains(&uid)) {
+                joined.push((self.db.get_member_joined(group.id, uid).await?, uid));
+            }
+            joined.sort_unstable();
+            for (_, uid) in joined {
+                if !self.__is_bot(uid).await? {
+                    return Ok(Some(uid));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Delete a group along with its channels (and their threads), roles, bans, timeouts,
+    /// invites, join requests and audit log
+    async fn __delete_group(&self, gid: i64) -> db::Result<()> {
+        let group = self.db.get_group(gid).await?;
+        for member in group.members {
+            match group.is_dm {
+                false => self.db.remove_user_group(member, gid).await?,
+                true => self.db.remove_user_dm(member, gid).await?,
+            }
+        }
+        for channel in group.channels {
+            self.db.delete_channel(channel).await?;
+        }
+        for role in self.db.get_group_roles(gid).await? {
+            self.db.delete_role(role.id).await?;
+        }
+        for ban in self.db.get_group_bans(gid).await? {
+            self.db.delete_ban(gid, ban.user).await?;
+        }
+        for timeout in self.db.get_group_timeouts(gid).await? {
+            self.db.delete_timeout(gid, timeout.user).await?;
+        }
+        for invite in self.db.get_group_invites(gid).await? {
+            self.db.delete_invite(invite.code).await?;
+        }
+        for request in self.db.get_join_requests(gid).await? {
+            self.db.delete_join_request(gid, request.user).await?;
+        }
+        self.db.delete_audit_log(gid).await?;
+        self.db.delete_group(gid).await
+    }
+
+    /// Take a user out of a group they're leaving (or that's losing them with their
+    /// account). If they own it, it's handed to their successor (see `__successor`), or
+    /// deleted if there's nobody to hand it to.
+    async fn __leave_group(&self, gid: i64, uid: i64) -> db::Result<()> {
+        let group = self.db.get_group(gid).await?;
+        if group.owner == uid {
+            match self.__successor(&group).await? {
+                Some(owner) => {
+                    self.__set_group_owner(gid, owner).await?;
+                    self.__audit(uid, gid, AuditAction::TransferOwnership, owner, String::new()).await?;
+                }
+                None => return self.__delete_group(gid).await,
+            }
+        }
+        self.__remove_group_member(gid, uid).await
+    }
+
+    /// Add a user to a group and its public channels, dropping any request they'd made
+    /// to join it
+    async fn __add_group_member(&self, gid: i64, uid: i64) -> db::Result<()> {
+        self.db.add_group_member(gid, uid).await?;
+        self.db.delete_join_request(gid, uid).await?;
+        for channel in self.db.get_group_channels(gid).await? {
+            if self.db.is_channel_private(channel).await? { continue; }
+            self.db.add_channel_member(channel, uid).await?;
+        }
+        match self.db.is_group_dm(gid).await? {
+            false => self.db.add_user_group(uid, gid).await,
+            true => self.db.add_user_dm(uid, gid).await,
+        }
+    }
+
+    async fn __remove_group_member(&self, gid: i64, uid: i64) -> db::Result<()> {
+        self.db.remove_group_member(gid, uid).await?;
+        self.db.remove_group_admin(gid, uid).await?;
+        for role in self.db.get_member_roles(gid, uid).await? {
+            self.db.remove_member_role(gid, uid, role).await?;
+        }
+        let channels = self.db.get_group_channels(gid).await?;
         for channel in channels {
-            self.db.remove_channel_member(channel, uid).unwrap();
+            self.db.remove_channel_member(channel, uid).await?;
+        }
+        match self.db.is_group_dm(gid).await? {
+            false => self.db.remove_user_group(uid, gid).await,
+            true => self.db.remove_user_dm(uid, gid).await,
         }
-        self.db.remove_user_group(uid, gid).unwrap();
     }
 
     #[oai(path = "/login", method = "post")]
-    /// Log in as a user. Returns an authentication token given id and hash.
+    /// Log in as a user. Returns authentication tokens given a username or email and
+    /// password.
+    ///
+    /// Expects the user's password to be given in the request body.
+    /// Checks the password, then starts a session: the returned access token is a
+    /// JWT signed with a server secret key, and the refresh token can be traded for new
+    /// tokens at `/refresh` once it expires.
     ///
-    /// Expects hash of user's password to be given in the request body.
-    /// Checks validity of hash, then signs JWT with a server secret key.
-    async fn login(&self, key: Data<&ServerKey>, id: Query<i64>, hash: PlainText<String>) -> LoginResponse {
+    /// If the user has two-factor authentication on, returns a challenge token to pass
+    /// to `/login/totp` with a code instead.
+    async fn login(&self, keys: Data<&ServerKeys>, user: Query<String>, password: PlainText<String>) -> LoginResponse {
         use LoginResponse::*;
-        if hash.0.len() != 64 {
+        if !valid_password(&password.0) {
             return BadRequest;
-        } else if !self.db.valid_id(IdType::User, id.0).unwrap() {
-            return NotFound;
         }
-        let db_hash = self.db.get_user_hash(id.0).unwrap();
-        if hex::decode(db_hash.clone()).unwrap() != hex::decode(hash.0.clone()).unwrap() {
-            
-            Unauthorized
-        } else {
-            let token = Claims {
-                id: id.0,
-                exp: Local::now() + Duration::days(1),
+        let id = Query(db_try!(self.__find_user(&user.0).await));
+        let db_hash = db_try!(self.db.get_user_hash(id.0).await);
+        match verify_password_blocking(password.0.clone(), db_hash).await {
+            Verified::No => return Unauthorized,
+            Verified::Yes => {}
+            // Legacy SHA-256 hashes (and ones with outdated parameters) get replaced now,
+            // the only time we have the password to hash
+            Verified::NeedsRehash => match hash_password_blocking(password.0).await {
+                Ok(hash) => db_try!(self.db.set_user_hash(id.0, hash).await),
+                Err(e) => return InternalError(PlainText(e)),
+            },
+        }
+        match self.db.get_totp(id.0).await {
+            Ok(totp) if totp.enabled => {
+                return match keys.sign(&ChallengeClaims::new(id.0)) {
+                    Ok(challenge_token) => TwoFactorRequired(Json(TotpChallenge {
+                        challenge_token,
+                        expires_in: totp::CHALLENGE_LIFETIME,
+                    })),
+                    Err(e) => InternalError(PlainText(e.to_string())),
+                };
+            }
+            Ok(_) | Err(DbError::NotFound(_)) => {}
+            Err(e) => return e.into(),
+        }
+        match self.__start_session(keys.0, id.0).await {
+            Ok(tokens) => Success(Json(tokens)),
+            Err(e) => InternalError(PlainText(e)),
+        }
+    }
+
+    #[oai(path = "/login/totp", method = "post")]
+    /// Second step of logging in as a user with two-factor authentication on.
+    ///
+    /// Expects the challenge token returned by `/login`, and either a code from the user's
+    /// authenticator app or one of their recovery codes (each of which only works once).
+    /// Returns the same tokens as `/login`.
+    async fn login_totp(&self, keys: Data<&ServerKeys>, login: Json<TotpLogin>) -> TotpLoginResponse {
+        use TotpLoginResponse::*;
+        let claims = match keys.decode::<ChallengeClaims>(&login.0.challenge_token) {
+            Some(claims) if claims.valid() => claims,
+            _ => return Unauthorized,
+        };
+        if !db_try!(self.__check_second_factor(claims.id, &login.0.code).await) {
+            return Unauthorized;
+        }
+        match self.__start_session(keys.0, claims.id).await {
+            Ok(tokens) => Success(Json(tokens)),
+            Err(e) => InternalError(PlainText(e)),
+        }
+    }
+
+    #[oai(path = "/oidc/login", method = "get")]
+    /// Start logging in through the OpenID Connect provider.
+    ///
+    /// Returns the URL to send the user to, and the state the provider will send them
+    /// back with (check it matches). Pass the code it sends back, along with the state,
+    /// to `/oidc/callback`.
+    /// Does not require any authorization.
+    async fn oidc_login(&self, keys: Data<&ServerKeys>) -> OidcStartResponse {
+        self.__start_oidc(keys.0, None)
+    }
+
+    #[oai(path = "/oidc/callback", method = "post")]
+    /// Finish logging in through the OpenID Connect provider.
+    ///
+    /// Expects the code and state the provider sent the user back with. Logs in as the
+    /// user the identity is linked to, making a new user the first time, and returns the
+    /// same tokens as `/login`.
+    async fn oidc_callback(&self, keys: Data<&ServerKeys>, callback: Json<OidcCallback>) -> OidcLoginResponse {
+        use OidcLoginResponse::*;
+        let provider = match &self.oidc {
+            Some(provider) => provider,
+            None => return NotFound,
+        };
+        let state = match keys.decode::<StateClaims>(&callback.0.state) {
+            Some(state) if state.valid() => state,
+            _ => return Unauthorized,
+        };
+        let token = match provider.exchange(&callback.0.code, &state.nonce).await {
+            Ok(token) => token,
+            Err(_) => return Unauthorized,
+        };
+        let linked = match self.db.get_identity_user(token.identity()).await {
+            Ok(uid) => Some(uid),
+            Err(DbError::NotFound(_)) => None,
+            Err(e) => return e.into(),
+        };
+        let uid = match (state.link, linked) {
+            (Some(uid), Some(linked)) if uid != linked => return Conflict,
+            (_, Some(linked)) => linked,
+            (Some(uid), None) => {
+                if !db_try!(self.db.valid_id(IdType::User, uid).await) {
+                    return Unauthorized;
+                }
+                match self.db.link_identity(uid, token.identity()).await {
+                    Ok(()) => uid,
+                    Err(DbError::Conflict(_)) => return Conflict,
+                    Err(e) => return e.into(),
+                }
             }
-            .sign_with_key(key.0);
-            Success(PlainText(token.unwrap()))
+            (None, None) => db_try!(self.__make_oidc_user(&token).await),
+        };
+        match self.__start_session(keys.0, uid).await {
+            Ok(tokens) => Success(Json(tokens)),
+            Err(e) => InternalError(PlainText(e)),
+        }
+    }
+
+    #[oai(path = "/refresh", method = "post")]
+    /// Get new tokens for a session, given its refresh token in the request body.
+    ///
+    /// Refresh tokens can only be used once: the response includes the one to use next
+    /// time. Using an old one again ends the session, since it means it was leaked.
+    async fn refresh(&self, keys: Data<&ServerKeys>, token: PlainText<String>) -> RefreshResponse {
+        use RefreshResponse::*;
+        let (sid, secret) = match token.0.split_once('.') {
+            Some((sid, secret)) => match sid.parse::<i64>() {
+                Ok(sid) => (sid, secret),
+                Err(_) => return Unauthorized,
+            },
+            None => return Unauthorized,
+        };
+        let session = match self.db.get_session(sid).await {
+            Ok(session) => session,
+            Err(DbError::NotFound(_)) => return Unauthorized,
+            Err(e) => return e.into(),
+        };
+        if hex::encode(Sha256::digest(secret.as_bytes())) != session.refresh_hash
+            || session.expires < Utc::now().timestamp() {
+            db_try!(self.__revoke_session(sid).await);
+            return Unauthorized;
+        }
+        let (refresh_token, refresh_hash) = make_refresh_token(sid);
+        let expires = Utc::now().timestamp() + REFRESH_TOKEN_LIFETIME;
+        db_try!(self.db.update_session(sid, refresh_hash, expires).await);
+        match self.__tokens(keys.0, session.user, sid, refresh_token) {
+            Ok(tokens) => Success(Json(tokens)),
+            Err(e) => InternalError(PlainText(e.to_string())),
+        }
+    }
+
+    #[oai(path = "/logout", method = "post")]
+    /// Log out, ending the session your token belongs to.
+    ///
+    /// With `all` set, ends every one of your sessions instead.
+    async fn logout(&self, auth: Authorization, all: Query<Option<bool>>) -> GenericResponse {
+        use GenericResponse::*;
+        if all.0.unwrap_or(false) {
+            db_try!(self.__revoke_user_sessions(auth.0.id).await);
+        } else {
+            db_try!(self.__revoke_session(auth.0.sid).await);
         }
+        Success
     }
 
     #[oai(path = "/user", method = "get")]
@@ -136,321 +748,1165 @@ impl Api {
     /// Does not require any authorization.
     async fn get_user(&self, id: Query<i64>) -> UserResponse {
         use UserResponse::*;
-        if !self.db.valid_id(IdType::User, id.0).unwrap() { return NotFound; }
-        match self.db.get_user(id.0) {
+        if !db_try!(self.db.valid_id(IdType::User, id.0).await) { return NotFound; }
+        match self.db.get_user(id.0).await {
             Ok(user) => Success(Json(user)),
             Err(e) => InternalError(PlainText(e.to_string()))
         }
     }
 
+    #[oai(path = "/user/search", method = "get")]
+    /// Find users whose usernames start with `prefix`, ignoring case, in order of username.
+    ///
+    /// Returns at most `limit` of them (20 by default, and no more than 50).
+    async fn search_users(&self, auth: Authorization, prefix: Query<String>, limit: Query<Option<u64>>) -> SearchResponse {
+        use SearchResponse::*;
+        if prefix.0.is_empty() {
+            return BadRequest(PlainText("Empty string not allowed for prefix".to_string()));
+        }
+        let limit = limit.0.unwrap_or(20).clamp(1, MAX_SEARCH_RESULTS);
+        Success(Json(db_try!(self.db.search_users(prefix.0, limit).await)))
+    }
+
     #[oai(path = "/user", method = "post")]
     /// Create a new user.
     ///
-    /// Expects hash of user's password to be given in the request body.
+    /// Expects the user's password to be given in the request body. Mails them a token
+    /// to verify their email address with at `/user/email/verification`.
     /// Does not require any authorization.
-    async fn make_user(&self, name: Query<String>, email: Query<String>, hash: PlainText<String>) -> CreateUserResponse {       
+    async fn make_user(&self, keys: Data<&ServerKeys>, name: Query<String>, email: Query<String>, password: PlainText<String>) -> CreateUserResponse {       
         use CreateUserResponse::*;
-        if hash.0.len() != 64 {
-            return BadRequest(PlainText("Invalid hash provided.".to_string()));
-        }
-        
-        // name cleaning:
-        let disallowed_chars: [u32; 53] = [0x202e,0x0009,0x00AD,0x034F,0x061C,0x115F,0x1160,0x17B4,0x17B5,0x180E,0x2000,0x2001,0x2002,0x2003,0x2004,0x2005,0x2006,0x2007,0x2008,0x2009,0x200A,0x200B,0x200C,0x200D,0x200E,0x200F,0x202F,0x205F,0x2060,0x2061,0x2062,0x2063,0x2064,0x206A,0x206B,0x206C,0x206D,0x206E,0x206F,0x3000,0x2800,0x3164,0xFEFF,0xFFA0,0x1D159,0x1D173,0x1D174,0x1D175,0x1D176,0x1D177,0x1D178,0x1D179,0x1D17A];
-        let name_chars = name.chars();
-        let mut fixed_name = String::from("");
-        let mut index = 0;
-        let mut to_keep:[bool; 32] = [true; 32]; //assuming 32 is the max name size - TODO: figure out how to not hardcode this in rust
-        for i in name_chars{
-            for j in 0..disallowed_chars.len(){
-                if i == char::from_u32(disallowed_chars[j]).unwrap() {
-                    to_keep[index] = false;
-                }; 
-            };
-            if to_keep[index]{
-                fixed_name.push(i)
-            };
-                index += 1;
+        if !valid_password(&password.0) {
+            return BadRequest(PlainText("Invalid password provided.".to_string()));
+        } else if !valid_email(&email.0) {
+            return BadRequest(PlainText("Invalid email provided.".to_string()));
+        }
+        let name = match self.names.clean(NameKind::User, &name.0) {
+            Ok(name) => name,
+            Err(e) => return BadRequest(PlainText(e)),
         };
 
-
+        let hash = match hash_password_blocking(password.0).await {
+            Ok(hash) => hash,
+            Err(e) => return InternalError(PlainText(e)),
+        };
         let id = gen_id();
-        self.db.create_user(id, name.0.clone(), email.0.clone(), hash.0).unwrap();
-        self.db.create_user_groups(id).unwrap();
-        self.db.create_user_dms(id).unwrap();
-        Success(Json(User {
+        match self.db.create_user(id, name.clone(), email.0.clone(), hash).await {
+            Ok(()) => {}
+            Err(DbError::Conflict(e)) => return Conflict(PlainText(e)),
+            Err(e) => return e.into(),
+        }
+        db_try!(self.db.create_user_groups(id).await);
+        db_try!(self.db.create_user_dms(id).await);
+        let user = User {
             id,
-            username: name.0,
+            username: name,
             email: email.0,
-        }))
+        };
+        // They can ask for another at `/user/email/verify`, so this doesn't fail the signup
+        if let Err(e) = self.__send_verification(keys.0, &user).await {
+            log::warn!("Couldn't send verification email to user {id}: {e}");
+        }
+        Success(Json(user))
     }
 
     #[oai(path = "/user", method = "put")]
     /// Update your name and email.
-    async fn update_user(&self, auth: Authorization, name: Query<String>, email: Query<String>) -> GenericResponse {
+    ///
+    /// Usernames and emails are unique, ignoring case. Changing your email unverifies it,
+    /// and mails a token to verify the new one with.
+    async fn update_user(&self, auth: Authorization, keys: Data<&ServerKeys>, name: Query<String>, email: Query<String>) -> GenericResponse {
         use GenericResponse::*;
-        self.db.update_user(auth.0.id, name.0, email.0).unwrap();
+        if !valid_email(&email.0) {
+            return BadRequest(PlainText("Invalid email provided.".to_string()));
+        }
+        let name = match self.names.clean(NameKind::User, &name.0) {
+            Ok(name) => name,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        let old = db_try!(self.db.get_user(auth.0.id).await);
+        match self.db.update_user(auth.0.id, name.clone(), email.0.clone()).await {
+            Ok(()) => {}
+            Err(DbError::Conflict(e)) => return Conflict(PlainText(e)),
+            Err(e) => return e.into(),
+        }
+        if email.0 != old.email {
+            let user = User { id: auth.0.id, username: name, email: email.0 };
+            if let Err(e) = self.__send_verification(keys.0, &user).await {
+                log::warn!("Couldn't send verification email to user {}: {e}", user.id);
+            }
+        }
         Success
     }
 
-    #[oai(path = "/user", method = "delete")]
-    /// Delete your user.
+    #[oai(path = "/user/email", method = "get")]
+    /// Get your email address, and whether you've verified it
+    async fn get_email(&self, auth: Authorization) -> EmailStatusResponse {
+        use EmailStatusResponse::*;
+        let user = db_try!(self.db.get_user(auth.0.id).await);
+        let verified = db_try!(self.db.get_verified_email(auth.0.id).await);
+        Success(Json(EmailStatus {
+            verified: verified.as_deref() == Some(user.email.as_str()),
+            email: user.email,
+        }))
+    }
+
+    #[oai(path = "/user/email/verify", method = "post")]
+    /// Mail yourself another token to verify your email address with
+    async fn resend_verification(&self, auth: Authorization, keys: Data<&ServerKeys>) -> GenericResponse {
+        use GenericResponse::*;
+        let user = db_try!(self.db.get_user(auth.0.id).await);
+        if db_try!(self.db.get_verified_email(auth.0.id).await).as_deref() == Some(user.email.as_str()) {
+            return BadRequest(PlainText("Email already verified.".to_string()));
+        }
+        match self.__send_verification(keys.0, &user).await {
+            Ok(()) => Success,
+            Err(e) => InternalError(PlainText(e)),
+        }
+    }
+
+    #[oai(path = "/user/email/verification", method = "post")]
+    /// Verify an email address, given the token mailed to it in the request body.
     ///
-    /// Has the side effects of removing your user from every group, channel, or DM
-    /// it is a member of.    
-    async fn delete_user(&self, auth: Authorization) -> DeleteResponse {
-        use DeleteResponse::*;
-        self.db.delete_user(auth.0.id).unwrap();        
-        for group in self.db.get_user_groups(auth.0.id).unwrap() {
-            self.__remove_group_member(group, auth.0.id);
+    /// The token stops working if the user's email has changed since it was sent.
+    /// Does not require any authorization.
+    async fn verify_email(&self, keys: Data<&ServerKeys>, token: PlainText<String>) -> GenericResponse {
+        use GenericResponse::*;
+        let claims = match keys.decode::<EmailClaims>(&token.0) {
+            Some(claims) => claims,
+            None => return Unauthorized,
+        };
+        let user = match self.db.get_user(claims.id).await {
+            Ok(user) => user,
+            Err(DbError::NotFound(_)) => return Unauthorized,
+            Err(e) => return e.into(),
+        };
+        if !claims.verifies(&user.email) {
+            return Unauthorized;
+        }
+        db_try!(self.db.set_verified_email(user.id, user.email).await);
+        Success
+    }
+
+    #[oai(path = "/password/reset", method = "post")]
+    /// Ask for a password reset, given a username or email: mails the user a token to set
+    /// a new password with at `PUT /password/reset`.
+    ///
+    /// Succeeds whether or not the user exists. Users without an email address (like
+    /// bots) can't reset their password.
+    /// Does not require any authorization.
+    async fn request_password_reset(&self, keys: Data<&ServerKeys>, user: Query<String>) -> GenericResponse {
+        use GenericResponse::*;
+        let id = match self.__find_user(&user.0).await {
+            Ok(id) => id,
+            Err(DbError::NotFound(_)) => return Success,
+            Err(e) => return e.into(),
+        };
+        let user = db_try!(self.db.get_user(id).await);
+        if !valid_email(&user.email) {
+            return Success;
+        }
+        let hash = db_try!(self.db.get_user_hash(id).await);
+        let token = match keys.sign(&EmailClaims::reset(user.id, &hash)) {
+            Ok(token) => token,
+            Err(e) => return InternalError(PlainText(e.to_string())),
+        };
+        match self.mailer.send(email::reset_email(&user.email, &user.username, &token)).await {
+            Ok(()) => Success,
+            Err(e) => InternalError(PlainText(e)),
+        }
+    }
+
+    #[oai(path = "/password/reset", method = "put")]
+    /// Set a new password with the token from a password reset email.
+    ///
+    /// Tokens only work once, and not at all if the password has changed since they were
+    /// sent. Ends all of the user's sessions.
+    /// Does not require any authorization.
+    async fn reset_password(&self, keys: Data<&ServerKeys>, reset: Json<PasswordReset>) -> GenericResponse {
+        use GenericResponse::*;
+        let PasswordReset { token, password } = reset.0;
+        if !valid_password(&password) {
+            return BadRequest(PlainText("Invalid password provided.".to_string()));
+        }
+        let claims = match keys.decode::<EmailClaims>(&token) {
+            Some(claims) => claims,
+            None => return Unauthorized,
+        };
+        let db_hash = match self.db.get_user_hash(claims.id).await {
+            Ok(hash) => hash,
+            Err(DbError::NotFound(_)) => return Unauthorized,
+            Err(e) => return e.into(),
+        };
+        if !claims.resets(&db_hash) {
+            return Unauthorized;
+        }
+        let hash = match hash_password_blocking(password).await {
+            Ok(hash) => hash,
+            Err(e) => return InternalError(PlainText(e)),
+        };
+        db_try!(self.db.set_user_hash(claims.id, hash).await);
+        db_try!(self.__revoke_user_sessions(claims.id).await);
+        Success
+    }
+
+    #[oai(path = "/user/password", method = "put")]
+    /// Change your password.
+    ///
+    /// Expects your current and new passwords. Ends all of your
+    /// sessions, including the current one, so you'll have to log in again.
+    async fn change_password(&self, auth: Authorization, change: Json<PasswordChange>) -> GenericResponse {
+        use GenericResponse::*;
+        let PasswordChange { old, new } = change.0;
+        if !valid_password(&new) {
+            return BadRequest(PlainText("Invalid password provided.".to_string()));
+        }
+        let db_hash = db_try!(self.db.get_user_hash(auth.0.id).await);
+        if verify_password_blocking(old, db_hash).await == Verified::No {
+            return Unauthorized;
+        }
+        let hash = match hash_password_blocking(new).await {
+            Ok(hash) => hash,
+            Err(e) => return InternalError(PlainText(e)),
+        };
+        db_try!(self.db.set_user_hash(auth.0.id, hash).await);
+        db_try!(self.__revoke_user_sessions(auth.0.id).await);
+        Success
+    }
+
+    #[oai(path = "/user/oidc", method = "post")]
+    /// Start linking an identity from the OpenID Connect provider to your user, so you
+    /// can log in with it.
+    ///
+    /// Works like `/oidc/login`: finishing at `/oidc/callback` links the identity and
+    /// logs you in.
+    async fn link_oidc(&self, auth: Authorization, keys: Data<&ServerKeys>) -> OidcStartResponse {
+        self.__start_oidc(keys.0, Some(auth.0.id))
+    }
+
+    #[oai(path = "/user/totp", method = "post")]
+    /// Start turning on two-factor authentication.
+    ///
+    /// Returns a new TOTP secret for your authenticator app. Logging in won't need codes
+    /// until the secret is confirmed with `PUT /user/totp`.
+    async fn enroll_totp(&self, auth: Authorization) -> TotpEnrollResponse {
+        use TotpEnrollResponse::*;
+        match self.db.get_totp(auth.0.id).await {
+            Ok(totp) if totp.enabled => return Conflict,
+            Ok(_) | Err(DbError::NotFound(_)) => {}
+            Err(e) => return e.into(),
+        }
+        let user = db_try!(self.db.get_user(auth.0.id).await);
+        let secret = totp::generate_secret();
+        db_try!(self.db.set_totp(auth.0.id, UserTotp {
+            secret: secret.clone(),
+            enabled: false,
+            last_step: 0,
+            recovery_codes: Vec::new(),
+        }).await);
+        Success(Json(TotpEnrollment {
+            uri: totp::provisioning_uri(&secret, &user.username),
+            secret,
+        }))
+    }
+
+    #[oai(path = "/user/totp", method = "put")]
+    /// Finish turning on two-factor authentication.
+    ///
+    /// Expects a code from your authenticator app in the request body, to show it was set
+    /// up with the secret from `POST /user/totp`. Returns your recovery codes.
+    async fn confirm_totp(&self, auth: Authorization, code: PlainText<String>) -> TotpConfirmResponse {
+        use TotpConfirmResponse::*;
+        let mut totp = db_try!(self.db.get_totp(auth.0.id).await);
+        if totp.enabled {
+            return Conflict;
+        }
+        totp.last_step = match totp::check_code(&totp.secret, &code.0, totp.last_step, Utc::now().timestamp()) {
+            Some(step) => step,
+            None => return Unauthorized,
+        };
+        let codes = totp::generate_recovery_codes();
+        totp.recovery_codes = codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+        totp.enabled = true;
+        db_try!(self.db.set_totp(auth.0.id, totp).await);
+        Success(Json(RecoveryCodes { codes }))
+    }
+
+    #[oai(path = "/user/totp", method = "delete")]
+    /// Turn off two-factor authentication.
+    ///
+    /// Expects a code from your authenticator app, or one of your recovery codes, in the
+    /// request body.
+    async fn disable_totp(&self, auth: Authorization, code: PlainText<String>) -> GenericResponse {
+        use GenericResponse::*;
+        match self.db.get_totp(auth.0.id).await {
+            Ok(totp) if totp.enabled => {}
+            Ok(_) | Err(DbError::NotFound(_)) => {
+                return NotFound(PlainText("Two-factor authentication is off.".to_string()));
+            }
+            Err(e) => return e.into(),
+        }
+        if !db_try!(self.__check_second_factor(auth.0.id, &code.0).await) {
+            return Unauthorized;
+        }
+        db_try!(self.db.delete_totp(auth.0.id).await);
+        Success
+    }
+
+    #[oai(path = "/user", method = "delete")]
+    /// Delete your user.
+    ///
+    /// Has the side effects of removing your user from every group, channel, or DM
+    /// it is a member of, of ending all of its sessions, and of deleting your bots.
+    async fn delete_user(&self, auth: Authorization) -> DeleteResponse {
+        use DeleteResponse::*;
+        for bot in db_try!(self.db.get_user_bots(auth.0.id).await) {
+            db_try!(self.__delete_user(bot).await);
+        }
+        db_try!(self.__delete_user(auth.0.id).await);
+        Success
+    }
+
+    #[oai(path = "/bot", method = "post")]
+    /// Create a bot.
+    ///
+    /// Bots are users that belong to you and can't log in: they authenticate with the
+    /// API tokens you make for them at `/bot/tokens` instead. Like any other user, they
+    /// have to be added to a group before they can do anything in it.
+    async fn make_bot(&self, auth: Authorization, name: Query<String>) -> CreateUserResponse {
+        use CreateUserResponse::*;
+        let name = match self.names.clean(NameKind::User, &name.0) {
+            Ok(name) => name,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        let id = gen_id();
+        // No password hash, so logging in as a bot always fails
+        match self.db.create_user(id, name.clone(), String::new(), String::new()).await {
+            Ok(()) => {}
+            Err(DbError::Conflict(e)) => return Conflict(PlainText(e)),
+            Err(e) => return e.into(),
+        }
+        db_try!(self.db.create_user_groups(id).await);
+        db_try!(self.db.create_user_dms(id).await);
+        db_try!(self.db.create_bot(id, auth.0.id).await);
+        Success(Json(User {
+            id,
+            username: name,
+            email: String::new(),
+        }))
+    }
+
+    #[oai(path = "/bots", method = "get")]
+    /// Get your bots.
+    async fn get_bots(&self, auth: Authorization) -> BotsResponse {
+        use BotsResponse::*;
+        let bots = db_try!(self.db.get_user_bots(auth.0.id).await);
+        let mut users = Vec::with_capacity(bots.len());
+        for bot in bots {
+            users.push(db_try!(self.db.get_user(bot).await));
+        }
+        Success(Json(users))
+    }
+
+    #[oai(path = "/bot", method = "delete")]
+    /// Delete one of your bots, along with its API tokens.
+    async fn delete_bot(&self, auth: Authorization, id: Query<i64>) -> DeleteResponse {
+        use DeleteResponse::*;
+        if !db_try!(self.__owns_bot(auth.0.id, id.0).await) {
+            return NotFound(PlainText("Bot not found".to_string()));
+        }
+        db_try!(self.__delete_user(id.0).await);
+        Success
+    }
+
+    #[oai(path = "/bot/tokens", method = "post")]
+    /// Make an API token for one of your bots.
+    ///
+    /// Expects the scopes the token is allowed (what it can do, and in which group) in
+    /// the request body. Returns the token, which won't be shown again: use it in the
+    /// `Authorization` header like a JWT.
+    async fn make_api_token(&self, auth: Authorization, bot: Query<i64>, name: Query<String>, scopes: Json<Vec<Scope>>) -> ApiTokenResponse {
+        use ApiTokenResponse::*;
+        if name.0.is_empty() {
+            return BadRequest(PlainText("Empty string not allowed for name".to_string()));
+        } else if scopes.0.is_empty() {
+            return BadRequest(PlainText("At least one scope is required".to_string()));
+        } else if !db_try!(self.__owns_bot(auth.0.id, bot.0).await) {
+            return NotFound(PlainText("Bot not found".to_string()));
+        }
+        for scope in &scopes.0 {
+            if !db_try!(self.db.valid_id(IdType::Group, scope.group).await) {
+                return NotFound(PlainText(format!("Group {} not found", scope.group)));
+            }
+        }
+        let id = gen_id();
+        let (token, hash) = make_api_token(id);
+        db_try!(self.db.create_api_token(BotToken {
+            id,
+            bot: bot.0,
+            name: name.0.clone(),
+            hash,
+            scopes: scopes.0.clone(),
+        }).await);
+        Success(Json(ApiToken {
+            id,
+            bot: bot.0,
+            name: name.0,
+            scopes: scopes.0,
+            token: Some(token),
+        }))
+    }
+
+    #[oai(path = "/bot/tokens", method = "get")]
+    /// Get the API tokens of one of your bots (without the tokens themselves).
+    async fn get_api_tokens(&self, auth: Authorization, bot: Query<i64>) -> ApiTokensResponse {
+        use ApiTokensResponse::*;
+        if !db_try!(self.__owns_bot(auth.0.id, bot.0).await) {
+            return NotFound;
+        }
+        let tokens = db_try!(self.db.get_bot_tokens(bot.0).await);
+        Success(Json(tokens.into_iter().map(|t| ApiToken {
+            id: t.id,
+            bot: t.bot,
+            name: t.name,
+            scopes: t.scopes,
+            token: None,
+        }).collect()))
+    }
+
+    #[oai(path = "/bot/tokens", method = "delete")]
+    /// Delete one of your bots' API tokens. It stops working straight away.
+    async fn delete_api_token(&self, auth: Authorization, bot: Query<i64>, id: Query<i64>) -> DeleteResponse {
+        use DeleteResponse::*;
+        if !db_try!(self.__owns_bot(auth.0.id, bot.0).await) {
+            return NotFound(PlainText("Bot not found".to_string()));
+        }
+        match self.db.get_api_token(id.0).await {
+            Ok(token) if token.bot == bot.0 => {}
+            Ok(_) | Err(DbError::NotFound(_)) => return NotFound(PlainText("Token not found".to_string())),
+            Err(e) => return e.into(),
+        }
+        db_try!(self.db.delete_api_token(id.0).await);
+        Success
+    }
+
+    #[oai(path = "/user/groups", method = "get")]
+    /// Get all groups accessible to you.
+    async fn get_groups(&self, auth: ScopedAuthorization) -> GroupsResponse {
+        use GroupsResponse::*;
+        let groups = db_try!(self.db.get_user_groups(auth.0.id).await);
+        let mut group_vec = Vec::with_capacity(groups.len());
+        for group in groups {
+            group_vec.push(db_try!(self.db.get_group(group).await));
+        }
+        Success(Json(group_vec))
+    }
+
+    #[oai(path = "/user/dms", method = "get")]
+    /// Get all DMs accessible to you.
+    async fn get_dms(&self, auth: Authorization) -> GroupsResponse {
+        use GroupsResponse::*;
+        let groups = db_try!(self.db.get_user_dms(auth.0.id).await);
+        let mut group_vec = Vec::with_capacity(groups.len());
+        for group in groups {
+            group_vec.push(db_try!(self.db.get_group(group).await));
+        }
+        Success(Json(group_vec))
+    }
+
+    
+    #[oai(path = "/user/groups", method = "delete")]
+    /// Leave a group accessible to you
+    ///
+    /// If you own the group, it goes to the admin who has been in the group the longest, or
+    /// failing that the member who has. If you're the only one left, it's deleted.
+    async fn leave_group(&self, auth: Authorization, gid: Query<i64>) -> GenericResponse {
+        use GenericResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        }
+        db_try!(self.__leave_group(gid.0, auth.0.id).await);
+        Success
+    }
+
+    #[oai(path = "/group", method = "get")]
+    /// Gets the group with the given ID
+    async fn get_group(&self, auth: ScopedAuthorization, id: Query<i64>) -> GroupResponse {
+        use GroupResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, id.0).await) ||
+           !db_try!(self.__can_view(&auth.0, id.0).await)
+        {
+            return NotFound;
+        }
+        Success(Json(db_try!(self.db.get_group(id.0).await)))
+    }
+
+    #[oai(path = "/group", method = "post")]
+    /// Create a new group.
+    ///
+    /// The group created...
+    /// - will have a default public "main" channel
+    /// - will have your user as the owner
+    /// - will have your user as an admin 
+    async fn make_group(&self, auth: Authorization, name: Query<String>) -> CreateGroupResponse {
+        use CreateGroupResponse::*;
+        let gid = gen_id();
+        let name = match self.names.clean(NameKind::Group, &name.0) {
+            Ok(name) => name,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        db_try!(self.db.create_group(gid, auth.0.id, name.clone(), false).await);
+        db_try!(self.db.add_user_group(auth.0.id, gid).await);
+        db_try!(self.db.add_group_admin(gid, auth.0.id).await);
+        let cid = gen_id();
+        db_try!(self.db.create_channel(cid, gid, auth.0.id, String::from("main")).await);
+        db_try!(self.db.add_group_channel(gid, cid).await);
+        Success(Json(Group {
+            id: gid,
+            name,
+            members: vec![auth.0.id],
+            channels: vec![cid],
+            admin: vec![auth.0.id],
+            owner: auth.0.id,
+            is_dm: false,
+            discoverable: false,
+            requires_approval: true,
+            description: String::new(),
+            tags: vec![],
+        }))
+    }
+    
+    #[oai(path = "/dm", method = "post")]
+    /// Create a new DM with a user `uid`.
+    ///
+    /// The group created...
+    /// - will have the `is_dm` attribute set to true.
+    /// - will have only one channel "main" with you and `uid`
+    /// - will have no owner or admins
+    async fn make_dm(&self, auth: Authorization, uid: Query<i64>) -> CreateGroupResponse {       
+        use CreateGroupResponse::*;
+        if !db_try!(self.db.valid_id(IdType::User, uid.0).await) {
+            return NotFound;
+        }
+        let gid = gen_id();
+        db_try!(self.db.create_group(gid, auth.0.id, String::from(""), true).await);
+        db_try!(self.db.add_group_member(gid, uid.0).await);
+        db_try!(self.db.add_user_dm(auth.0.id, gid).await);
+        db_try!(self.db.add_user_dm(uid.0, gid).await);
+        let cid = gen_id();
+        db_try!(self.db.create_channel(cid, gid, auth.0.id, String::from("main")).await);
+        db_try!(self.db.add_group_channel(gid, cid).await);
+        db_try!(self.db.add_channel_member(cid, uid.0).await);
+        Success(Json(Group {
+            id: gid,
+            name: String::from(""),
+            members: vec![auth.0.id, uid.0],
+            channels: vec![cid],
+            admin: vec![],
+            owner: auth.0.id,
+            is_dm: true,
+            discoverable: false,
+            requires_approval: true,
+            description: String::new(),
+            tags: vec![],
+        }))
+    }
+    
+    #[oai(path = "/group", method = "put")]
+    /// Update the name of an existing group.
+    ///
+    /// Only authorized for members with the `manage_group` permission.
+    async fn update_group(&self, auth: Authorization, id: Query<i64>, name: Query<String>) -> GenericResponse {
+        use GenericResponse::*;
+        let name = match self.names.clean(NameKind::Group, &name.0) {
+            Ok(name) => name,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Group, id.0).await) {
+            return NotFound(PlainText("Didn't find group or experienced database error.".to_string()));
+        } else if !db_try!(self.__authorize(&auth.0, id.0, Permission::ManageGroup).await) {
+            return Unauthorized;
+        }
+        db_try!(self.db.update_group(id.0, name).await);
+        db_try!(self.__audit(auth.0.id, id.0, AuditAction::UpdateGroup, id.0, String::new()).await);
+        Success
+    }
+
+    #[oai(path = "/group", method = "delete")]
+    /// Delete a group. 
+    /// 
+    /// Only authorized for the owner of a group.
+    async fn delete_group(&self, auth: Authorization, id: Query<i64>) -> DeleteResponse {
+        use DeleteResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, id.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if db_try!(self.db.get_group_owner(id.0).await) != auth.0.id {
+            return Unauthorized;
+        }
+        db_try!(self.__delete_group(id.0).await);
+        Success
+    }
+
+    #[oai(path = "/group/owner", method = "put")]
+    /// Hand a group over to one of its members, who becomes its owner. You stay a member
+    /// (and an admin, if you're one).
+    ///
+    /// Only authorized for the owner of a group. Bots can't own groups.
+    async fn transfer_group(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>) -> GenericResponse {
+        use GenericResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        }
+        let group = db_try!(self.db.get_group(gid.0).await);
+        if group.owner != auth.0.id {
+            return Unauthorized;
+        } else if !group.members.contains(&uid.0) {
+            return NotFound(PlainText("User not found in group".to_string()));
+        } else if db_try!(self.__is_bot(uid.0).await) {
+            return BadRequest(PlainText("Bots can't own groups".to_string()));
+        }
+        db_try!(self.__set_group_owner(gid.0, uid.0).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::TransferOwnership, uid.0, String::new()).await);
+        Success
+    }
+
+    #[oai(path = "/group/members", method = "get")]
+    /// Get the members of the specified group.
+    ///
+    /// No specific order for the list is guaranteed.
+    ///
+    /// Only authorized for members of the group.
+    async fn get_group_members(&self, auth: Authorization, id: Query<i64>) -> MembersResponse {
+        use MembersResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, id.0).await) ||
+           !db_try!(self.__can_view(&auth.0, id.0).await)
+        {
+            return NotFound;
+        }
+        let members = db_try!(self.db.get_group_members(id.0).await);
+        let mut users = Vec::with_capacity(members.len());
+        for member in members {
+            users.push(db_try!(self.db.get_user(member).await));
+        }
+        Success(Json(users))     
+    }
+
+    #[oai(path = "/group/members", method = "put")]
+    /// Add a member to an existing group
+    ///
+    /// Only authorized for members with the `manage_members` permission, and users banned
+    /// from the group can't be added.
+    /// Has the side effect of adding that member to all public channels.
+    async fn add_group_member(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>) -> GenericResponse {
+        use GenericResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if !db_try!(self.db.valid_id(IdType::User, uid.0).await) {
+            return NotFound(PlainText("User not found".to_string()));
+        } else if !db_try!(self.__authorize(&auth.0, gid.0, Permission::ManageMembers).await) {
+            return Unauthorized;
+        } else if db_try!(self.__is_banned(gid.0, uid.0).await) {
+            return Conflict(PlainText("User is banned from the group".to_string()));
+        }
+        db_try!(self.__add_group_member(gid.0, uid.0).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::AddMember, uid.0, String::new()).await);
+        Success
+    }
+
+    #[oai(path = "/group/members", method = "delete")]
+    /// Remove a member from an existing group
+    ///
+    /// Only authorized for members with the `manage_members` permission, and only admins
+    /// can remove other admins.
+    /// Attempting to remove the owner from their group will always be unauthorized.
+    /// 
+    /// Has the side effect of removing the member from all channels. The `reason`, if given,
+    /// goes in the group's audit log.
+    async fn remove_group_member(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>, reason: Query<Option<String>>) -> DeleteResponse {
+        use DeleteResponse::*;
+        let reason = match clean_reason(reason.0) {
+            Ok(reason) => reason,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()))
+        } else if !db_try!(self.db.valid_id(IdType::User, uid.0).await) {
+            return NotFound(PlainText("User not found".to_string()))
+        } else if !db_try!(self.__can_moderate(&auth.0, gid.0, uid.0, Permission::ManageMembers).await) {
+            return Unauthorized;
+        }
+        db_try!(self.__remove_group_member(gid.0, uid.0).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::RemoveMember, uid.0, reason).await);
+        Success
+    }
+
+    #[oai(path = "/group/invites", method = "get")]
+    /// Get the invites to a group that can still be accepted.
+    ///
+    /// Only authorized for members with the `manage_members` permission.
+    async fn get_invites(&self, auth: Authorization, gid: Query<i64>) -> InvitesResponse {
+        use InvitesResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if !db_try!(self.__authorize(&auth.0, gid.0, Permission::ManageMembers).await) {
+            return Unauthorized;
+        }
+        Success(Json(db_try!(self.db.get_group_invites(gid.0).await)))
+    }
+
+    #[oai(path = "/group/invites", method = "post")]
+    /// Make an invite to a group, which anyone with its code can accept until it `expires`
+    /// (a Unix timestamp) or has been accepted `max_uses` times. An invite to a `channel`
+    /// adds whoever accepts it to that channel too, even if it's private.
+    ///
+    /// Only authorized for members with the `manage_members` permission, who also need the
+    /// `manage_channels` permission in the channel to invite people to a private one. DMs
+    /// can't have invites.
+    async fn make_invite(&self, auth: Authorization, gid: Query<i64>, channel: Query<Option<i64>>, expires: Query<Option<i64>>, max_uses: Query<Option<i64>>) -> InviteResponse {
+        use InviteResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if expires.0.is_some_and(|expires| expires <= Utc::now().timestamp()) {
+            return BadRequest(PlainText("Invites can't expire in the past".to_string()));
+        } else if max_uses.0.is_some_and(|max_uses| max_uses < 1) {
+            return BadRequest(PlainText("Invites have to be usable at least once".to_string()));
+        }
+        let group = db_try!(self.db.get_group(gid.0).await);
+        if group.is_dm {
+            return BadRequest(PlainText("DMs can't have invites".to_string()));
+        } else if !db_try!(self.__authorize(&auth.0, gid.0, Permission::ManageMembers).await) {
+            return Unauthorized;
+        }
+        if let Some(cid) = channel.0 {
+            if !group.channels.contains(&cid) {
+                return NotFound(PlainText("Channel not found in group".to_string()));
+            } else if db_try!(self.db.is_channel_private(cid).await) &&
+                      !db_try!(self.__authorize_channel(&auth.0, cid, Permission::ManageChannels).await)
+            {
+                return Unauthorized;
+            }
+        }
+        let invite = Invite {
+            code: make_invite_code(),
+            group: gid.0,
+            channel: channel.0,
+            creator: auth.0.id,
+            expires: expires.0,
+            max_uses: max_uses.0,
+            uses: 0,
+        };
+        db_try!(self.db.create_invite(invite.clone()).await);
+        let target = invite.channel.unwrap_or(invite.group);
+        db_try!(self.__audit(auth.0.id, invite.group, AuditAction::CreateInvite, target, String::new()).await);
+        Success(Json(invite))
+    }
+
+    #[oai(path = "/group/invites", method = "delete")]
+    /// Delete an invite to a group, so it can't be accepted any more.
+    ///
+    /// Only authorized for members with the `manage_members` permission.
+    async fn delete_invite(&self, auth: Authorization, code: Query<String>) -> DeleteResponse {
+        use DeleteResponse::*;
+        let invite = db_try!(self.db.get_invite(code.0).await);
+        if !db_try!(self.__authorize(&auth.0, invite.group, Permission::ManageMembers).await) {
+            return Unauthorized;
+        }
+        db_try!(self.db.delete_invite(invite.code).await);
+        db_try!(self.__audit(auth.0.id, invite.group, AuditAction::DeleteInvite, invite.creator, String::new()).await);
+        Success
+    }
+
+    #[oai(path = "/invite/:code/accept", method = "post")]
+    /// Accept an invite, joining its group along with the group's public channels (and the
+    /// invite's channel, if it has one). Returns the invite.
+    ///
+    /// Users banned from the group can't join it, and accepting an invite to a group you're
+    /// already in doesn't use it up.
+    async fn accept_invite(&self, auth: Authorization, code: Path<String>) -> InviteResponse {
+        use InviteResponse::*;
+        let invite = db_try!(self.db.get_invite(code.0).await);
+        if db_try!(self.db.get_group_members(invite.group).await).contains(&auth.0.id) {
+            return Conflict(PlainText("Already a member of the group".to_string()));
+        } else if db_try!(self.__is_banned(invite.group, auth.0.id).await) {
+            return Conflict(PlainText("User is banned from the group".to_string()));
+        }
+        let invite = db_try!(self.db.use_invite(invite.code).await);
+        db_try!(self.__add_group_member(invite.group, auth.0.id).await);
+        if let Some(cid) = invite.channel {
+            // The channel may have been deleted since
+            if db_try!(self.db.valid_id(IdType::Channel, cid).await) {
+                db_try!(self.db.add_channel_member(cid, auth.0.id).await);
+            }
+        }
+        db_try!(self.__audit(auth.0.id, invite.group, AuditAction::AcceptInvite, invite.creator, String::new()).await);
+        Success(Json(invite))
+    }
+
+    #[oai(path = "/group/profile", method = "put")]
+    /// Set how a group is listed in the group directory: whether it's `discoverable` (so
+    /// anyone can find it and join), whether joining it `requires_approval` (true unless
+    /// given), its `description`, and the `tags` it can be found by. Tags are lowercased,
+    /// and can only have letters, digits and dashes.
+    ///
+    /// Only authorized for members with the `manage_group` permission. DMs can't be listed.
+    #[allow(clippy::too_many_arguments)]
+    async fn update_group_profile(
+        &self,
+        auth: Authorization,
+        id: Query<i64>,
+        discoverable: Query<bool>,
+        requires_approval: Query<Option<bool>>,
+        description: Query<Option<String>>,
+        tags: Json<Vec<String>>,
+    ) -> GenericResponse {
+        use GenericResponse::*;
+        let description = description.0.unwrap_or_default().trim().to_string();
+        if description.chars().count() > MAX_DESCRIPTION_LEN {
+            return BadRequest(PlainText(format!("Descriptions can't be longer than {MAX_DESCRIPTION_LEN} characters")));
+        }
+        let tags = match clean_tags(tags.0) {
+            Ok(tags) => tags,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Group, id.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if db_try!(self.db.is_group_dm(id.0).await) {
+            return BadRequest(PlainText("DMs can't be listed in the directory".to_string()));
+        } else if !db_try!(self.__authorize(&auth.0, id.0, Permission::ManageGroup).await) {
+            return Unauthorized;
+        }
+        let requires_approval = requires_approval.0.unwrap_or(true);
+        db_try!(self.db.set_group_profile(id.0, discoverable.0, requires_approval, description, tags).await);
+        db_try!(self.__audit(auth.0.id, id.0, AuditAction::UpdateGroupProfile, id.0, String::new()).await);
+        Success
+    }
+
+    #[oai(path = "/group/directory", method = "get")]
+    /// Find discoverable groups in the group directory, oldest first: those whose names
+    /// contain `name` (ignoring case) and that have the tag `tag`, if given. Page through
+    /// them by passing the ID of the last group found as `after`.
+    ///
+    /// Returns at most `limit` of them (20 by default, and no more than 50).
+    async fn search_groups(&self, auth: Authorization, name: Query<Option<String>>, tag: Query<Option<String>>, after: Query<Option<i64>>, limit: Query<Option<u64>>) -> DirectoryResponse {
+        use DirectoryResponse::*;
+        let query = DirectoryQuery {
+            after: after.0,
+            name: name.0,
+            tag: tag.0.map(|tag| tag.trim().to_lowercase()),
+            limit: limit.0.unwrap_or(20).clamp(1, MAX_SEARCH_RESULTS),
+        };
+        let groups = db_try!(self.db.search_groups(query).await);
+        Success(Json(groups.into_iter().map(GroupListing::from).collect()))
+    }
+
+    #[oai(path = "/group/join", method = "post")]
+    /// Join a group in the group directory. Groups that don't require approval are joined
+    /// straight away, returning the group. Otherwise this asks to join, with a `message` for
+    /// whoever looks at the request, and returns the request; asking again replaces it.
+    ///
+    /// Groups that aren't discoverable can only be joined with an invite, and users banned
+    /// from a group can't join it or ask to.
+    async fn request_to_join(&self, auth: Authorization, gid: Query<i64>, message: Query<Option<String>>) -> JoinRequestResponse {
+        use JoinRequestResponse::*;
+        let message = message.0.unwrap_or_default().trim().to_string();
+        if message.chars().count() > MAX_JOIN_MESSAGE_LEN {
+            return BadRequest(PlainText(format!("Messages can't be longer than {MAX_JOIN_MESSAGE_LEN} characters")));
+        } else if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        }
+        let group = db_try!(self.db.get_group(gid.0).await);
+        if !group.discoverable {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if group.members.contains(&auth.0.id) {
+            return Conflict(PlainText("Already a member of the group".to_string()));
+        } else if db_try!(self.__is_banned(gid.0, auth.0.id).await) {
+            return Conflict(PlainText("User is banned from the group".to_string()));
         }
-        for dm in self.db.get_user_dms(auth.0.id).unwrap() {
-            self.__remove_group_member(dm, auth.0.id);
+        if !group.requires_approval {
+            db_try!(self.__add_group_member(gid.0, auth.0.id).await);
+            db_try!(self.__audit(auth.0.id, gid.0, AuditAction::AddMember, auth.0.id, String::new()).await);
+            return Joined(Json(db_try!(self.db.get_group(gid.0).await)));
         }
-        self.db.delete_user_groups(auth.0.id).unwrap();     
-        Success
+        let request = JoinRequest { group: gid.0, user: auth.0.id, message, timestamp: Utc::now().timestamp() };
+        db_try!(self.db.set_join_request(request.clone()).await);
+        Success(Json(request))
     }
 
-    #[oai(path = "/user/groups", method = "get")]
-    /// Get all groups accessible to you.
-    async fn get_groups(&self, auth: Authorization) -> GroupsResponse {
-        use GroupsResponse::*;
-        let groups = self.db.get_user_groups(auth.0.id).unwrap();
-        let group_vec = groups.iter().map(|i| {
-            self.db.get_group(*i).unwrap()
-        }).collect();
-        Success(Json(group_vec))
+    #[oai(path = "/group/join", method = "delete")]
+    /// Take back your request to join a group
+    async fn withdraw_join_request(&self, auth: Authorization, gid: Query<i64>) -> DeleteResponse {
+        use DeleteResponse::*;
+        db_try!(self.db.get_join_request(gid.0, auth.0.id).await);
+        db_try!(self.db.delete_join_request(gid.0, auth.0.id).await);
+        Success
     }
 
-    #[oai(path = "/user/dms", method = "get")]
-    /// Get all DMs accessible to you.
-    async fn get_dms(&self, auth: Authorization) -> GroupsResponse {
-        use GroupsResponse::*;
-        let groups = self.db.get_user_dms(auth.0.id).unwrap();
-        let group_vec = groups.iter().map(|i| {
-            self.db.get_group(*i).unwrap()
-        }).collect();
-        Success(Json(group_vec))
+    #[oai(path = "/group/join_requests", method = "get")]
+    /// Get the requests to join a group, oldest first.
+    ///
+    /// Only authorized for members with the `manage_members` permission.
+    async fn get_join_requests(&self, auth: Authorization, gid: Query<i64>) -> JoinRequestsResponse {
+        use JoinRequestsResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if !db_try!(self.__authorize(&auth.0, gid.0, Permission::ManageMembers).await) {
+            return Unauthorized;
+        }
+        Success(Json(db_try!(self.db.get_join_requests(gid.0).await)))
     }
 
-    
-    #[oai(path = "/user/groups", method = "delete")]
-    /// Leave a group accessible to you
-    async fn leave_group(&self, auth: Authorization, gid: Query<i64>) -> GenericResponse {
+    #[oai(path = "/group/join_requests", method = "put")]
+    /// Approve a user's request to join a group, adding them to it like
+    /// `PUT /group/members` does.
+    ///
+    /// Only authorized for members with the `manage_members` permission, and users banned
+    /// from the group can't be let in.
+    async fn approve_join_request(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>) -> GenericResponse {
         use GenericResponse::*;
-        if !self.db.valid_id(IdType::Group, gid.0).unwrap() {
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
             return NotFound(PlainText("Group not found".to_string()));
+        } else if !db_try!(self.__authorize(&auth.0, gid.0, Permission::ManageMembers).await) {
+            return Unauthorized;
         }
-        self.__remove_group_member(gid.0, auth.0.id);
-        Success
-    }
-
-    #[oai(path = "/group", method = "get")]
-    /// Gets the group with the given ID
-    async fn get_group(&self, auth: Authorization, id: Query<i64>) -> GroupResponse {
-        use GroupResponse::*;
-        if !self.db.valid_id(IdType::Group, id.0).unwrap() ||
-           !self.db.get_group_members(id.0).unwrap().contains(&auth.0.id)
-        {
-            return NotFound;
+        db_try!(self.db.get_join_request(gid.0, uid.0).await);
+        if !db_try!(self.db.valid_id(IdType::User, uid.0).await) {
+            // Not every backend deletes users' requests along with them
+            db_try!(self.db.delete_join_request(gid.0, uid.0).await);
+            return NotFound(PlainText("User not found".to_string()));
+        } else if db_try!(self.__is_banned(gid.0, uid.0).await) {
+            return Conflict(PlainText("User is banned from the group".to_string()));
         }
-        Success(Json(self.db.get_group(id.0).unwrap()))
+        db_try!(self.__add_group_member(gid.0, uid.0).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::ApproveJoinRequest, uid.0, String::new()).await);
+        Success
     }
 
-    #[oai(path = "/group", method = "post")]
-    /// Create a new group.
+    #[oai(path = "/group/join_requests", method = "delete")]
+    /// Turn down a user's request to join a group. The `reason`, if given, goes in the
+    /// group's audit log.
     ///
-    /// The group created...
-    /// - will have a default public "main" channel
-    /// - will have your user as the owner
-    /// - will have your user as an admin 
-    async fn make_group(&self, auth: Authorization, name: Query<String>) -> CreateGroupResponse {
-        use CreateGroupResponse::*;
-        let gid = gen_id();
-        if name.0 == "" {
-            return BadRequest(PlainText("Empty string not allowed for name".to_string()))
+    /// Only authorized for members with the `manage_members` permission.
+    async fn deny_join_request(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>, reason: Query<Option<String>>) -> DeleteResponse {
+        use DeleteResponse::*;
+        let reason = match clean_reason(reason.0) {
+            Ok(reason) => reason,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if !db_try!(self.__authorize(&auth.0, gid.0, Permission::ManageMembers).await) {
+            return Unauthorized;
         }
-        self.db.create_group(gid, auth.0.id, name.0.clone(), false).unwrap();
-        self.db.add_user_group(auth.0.id, gid).unwrap();
-        self.db.add_group_admin(gid, auth.0.id).unwrap();
-        let cid = gen_id();
-        self.db.create_channel(cid, gid, auth.0.id, String::from("main")).unwrap();
-        self.db.add_group_channel(gid, cid).unwrap();
-        Success(Json(Group {
-            id: gid,
-            name: name.0,
-            members: vec![auth.0.id],
-            channels: vec![cid],
-            admin: vec![auth.0.id],
-            owner: auth.0.id,
-            is_dm: false
-        }))
+        db_try!(self.db.get_join_request(gid.0, uid.0).await);
+        db_try!(self.db.delete_join_request(gid.0, uid.0).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::DenyJoinRequest, uid.0, reason).await);
+        Success
     }
-    
-    #[oai(path = "/dm", method = "post")]
-    /// Create a new DM with a user `uid`.
+
+    #[oai(path = "/group/bans", method = "get")]
+    /// Get the users banned from a group, apart from those whose bans have expired.
     ///
-    /// The group created...
-    /// - will have the `is_dm` attribute set to true.
-    /// - will have only one channel "main" with you and `uid`
-    /// - will have no owner or admins
-    async fn make_dm(&self, auth: Authorization, uid: Query<i64>) -> CreateGroupResponse {       
-        use CreateGroupResponse::*;
-        if !self.db.valid_id(IdType::User, uid.0).unwrap() {
-            return NotFound;
+    /// Only authorized for members with the `ban_members` permission.
+    async fn get_bans(&self, auth: Authorization, gid: Query<i64>) -> BansResponse {
+        use BansResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if !db_try!(self.__authorize(&auth.0, gid.0, Permission::BanMembers).await) {
+            return Unauthorized;
         }
-        let gid = gen_id();
-        self.db.create_group(gid, auth.0.id, String::from(""), true).unwrap();
-        self.db.add_group_member(gid, uid.0).unwrap();
-        self.db.add_user_dm(auth.0.id, gid).unwrap();
-        self.db.add_user_dm(uid.0, gid).unwrap();
-        let cid = gen_id();
-        self.db.create_channel(cid, gid, auth.0.id, String::from("main")).unwrap();
-        self.db.add_group_channel(gid, cid).unwrap();
-        self.db.add_channel_member(cid, uid.0).unwrap();
-        Success(Json(Group {
-            id: gid,
-            name: String::from(""),
-            members: vec![auth.0.id, uid.0],
-            channels: vec![cid],
-            admin: vec![],
-            owner: auth.0.id,
-            is_dm: true
-        }))
+        Success(Json(db_try!(self.db.get_group_bans(gid.0).await)))
     }
-    
-    #[oai(path = "/group", method = "put")]
-    /// Update the name of an existing group.
+
+    #[oai(path = "/group/bans", method = "put")]
+    /// Ban a user from a group, removing them if they're a member, until `expires` (a Unix
+    /// timestamp) or for good. Banned users can't be added back to the group.
     ///
-    /// Only authorized for the owner of a group.
-    async fn update_group(&self, auth: Authorization, id: Query<i64>, name: Query<String>) -> GenericResponse {
+    /// Only authorized for members with the `ban_members` permission, and only admins can
+    /// ban other admins. Nobody can ban the owner.
+    async fn ban(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>, reason: Query<Option<String>>, expires: Query<Option<i64>>) -> GenericResponse {
         use GenericResponse::*;
-        if name.0 == "" {
-            return BadRequest(PlainText("Empty string not allowed for name".to_string()))
-        } else if !self.db.valid_id(IdType::Group, id.0).unwrap() {
-            return NotFound(PlainText("Didn't find group or experienced database error.".to_string()));
-        } else if self.db.get_group_owner(id.0).unwrap() != auth.0.id {
+        let reason = match clean_reason(reason.0) {
+            Ok(reason) => reason,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if !db_try!(self.db.valid_id(IdType::User, uid.0).await) {
+            return NotFound(PlainText("User not found".to_string()));
+        } else if expires.0.is_some_and(|expires| expires <= Utc::now().timestamp()) {
+            return BadRequest(PlainText("Bans can't expire in the past".to_string()));
+        } else if !db_try!(self.__can_moderate(&auth.0, gid.0, uid.0, Permission::BanMembers).await) {
             return Unauthorized;
-        }        
-        self.db.update_group(id.0, name.0).unwrap();
+        }
+        db_try!(self.db.set_ban(Ban { group: gid.0, user: uid.0, reason: reason.clone(), expires: expires.0 }).await);
+        if db_try!(self.db.get_group_members(gid.0).await).contains(&uid.0) {
+            db_try!(self.__remove_group_member(gid.0, uid.0).await);
+        }
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::Ban, uid.0, reason).await);
         Success
     }
 
-    #[oai(path = "/group", method = "delete")]
-    /// Delete a group. 
-    /// 
-    /// Only authorized for the owner of a group.
-    async fn delete_group(&self, auth: Authorization, id: Query<i64>) -> DeleteResponse {
+    #[oai(path = "/group/bans", method = "delete")]
+    /// Lift a user's ban from a group, for the audit log's `reason` if given. They aren't
+    /// added back to it.
+    ///
+    /// Only authorized for members with the `ban_members` permission.
+    async fn unban(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>, reason: Query<Option<String>>) -> DeleteResponse {
         use DeleteResponse::*;
-        if !self.db.valid_id(IdType::Group, id.0).unwrap() {
+        let reason = match clean_reason(reason.0) {
+            Ok(reason) => reason,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
             return NotFound(PlainText("Group not found".to_string()));
-        } else if self.db.get_group_owner(id.0).unwrap() != auth.0.id {
+        } else if !db_try!(self.__authorize(&auth.0, gid.0, Permission::BanMembers).await) {
             return Unauthorized;
+        } else if !db_try!(self.__is_banned(gid.0, uid.0).await) {
+            return NotFound(PlainText("Ban not found".to_string()));
         }
-        let group = self.db.get_group(id.0).unwrap();
-        for member in group.members {
-            self.db.remove_user_group(member, id.0).unwrap();
-        }
-        for channel in group.channels {
-            self.db.delete_channel(channel).unwrap();
-        }
-        self.db.delete_group(id.0).unwrap();
+        db_try!(self.db.delete_ban(gid.0, uid.0).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::Unban, uid.0, reason).await);
         Success
     }
 
-    #[oai(path = "/group/members", method = "get")]
-    /// Get the members of the specified group.
+    #[oai(path = "/group/timeouts", method = "get")]
+    /// Get the members timed out in a group, apart from those whose timeouts have expired.
     ///
-    /// No specific order for the list is guaranteed.
-    async fn get_group_members(&self, auth: Authorization, id: Query<i64>) -> MembersResponse {
-        use MembersResponse::*;
-        if !self.db.valid_id(IdType::Group, id.0).unwrap() {
-            return NotFound;
-        }       
-        let members = self.db.get_group_members(id.0).unwrap();
-        Success(Json(members.iter().map(|m| {
-            self.db.get_user(*m).unwrap()
-        }).collect::<Vec<User>>()))     
+    /// Only authorized for members with the `timeout_members` permission.
+    async fn get_timeouts(&self, auth: Authorization, gid: Query<i64>) -> TimeoutsResponse {
+        use TimeoutsResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if !db_try!(self.__authorize(&auth.0, gid.0, Permission::TimeoutMembers).await) {
+            return Unauthorized;
+        }
+        Success(Json(db_try!(self.db.get_group_timeouts(gid.0).await)))
     }
 
-    #[oai(path = "/group/members", method = "put")]
-    /// Add a member to an existing group
+    #[oai(path = "/group/timeouts", method = "put")]
+    /// Time out a member of a group until `until` (a Unix timestamp, at most 28 days away).
+    /// Until then they can't do anything in the group, including sending messages.
     ///
-    /// Only authorized for group admins.
-    /// Has the side effect of adding that member to all public channels.
-    async fn add_group_member(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>) -> GenericResponse {
+    /// Only authorized for members with the `timeout_members` permission, and only admins
+    /// can time out other admins. Nobody can time out the owner.
+    async fn timeout(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>, until: Query<i64>, reason: Query<Option<String>>) -> GenericResponse {
         use GenericResponse::*;
-        if !self.db.valid_id(IdType::Group, gid.0).unwrap() {
+        let reason = match clean_reason(reason.0) {
+            Ok(reason) => reason,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        let now = Utc::now().timestamp();
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
             return NotFound(PlainText("Group not found".to_string()));
-        } else if !self.db.get_group_admin(gid.0).unwrap().contains(&auth.0.id) &&
-            self.db.get_group_owner(gid.0).unwrap() != auth.0.id
-        {
+        } else if !db_try!(self.db.get_group_members(gid.0).await).contains(&uid.0) {
+            return NotFound(PlainText("User not found in group".to_string()));
+        } else if until.0 <= now || until.0 > now + MAX_TIMEOUT {
+            return BadRequest(PlainText("Timeouts have to end in the next 28 days".to_string()));
+        } else if !db_try!(self.__can_moderate(&auth.0, gid.0, uid.0, Permission::TimeoutMembers).await) {
             return Unauthorized;
         }
-        self.db.add_group_member(gid.0, uid.0).unwrap();
-        let channels = self.db.get_group_channels(gid.0).unwrap();
-        for channel in channels {
-            if self.db.is_channel_private(channel).unwrap() { continue; }
-            self.db.add_channel_member(channel, uid.0).unwrap();
-        }
-        if !self.db.is_group_dm(gid.0).unwrap() {
-            self.db.add_user_group(uid.0, gid.0).unwrap();
-        } else {
-            self.db.add_user_dm(uid.0, gid.0).unwrap();
-        }
+        db_try!(self.db.set_timeout(Timeout { group: gid.0, user: uid.0, reason: reason.clone(), until: until.0 }).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::Timeout, uid.0, reason).await);
         Success
     }
 
-    #[oai(path = "/group/members", method = "delete")]
-    /// Remove a member from an existing group
+    #[oai(path = "/group/timeouts", method = "delete")]
+    /// Lift a member's timeout in a group, for the audit log's `reason` if given.
     ///
-    /// Only authorized for group admin.
-    /// Attempting to remove the owner from their group will always be unauthorized.
-    /// 
-    /// Has the side effect of removing the member from all channels.    
-    async fn remove_group_member(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>) -> DeleteResponse {
+    /// Only authorized for members with the `timeout_members` permission.
+    async fn lift_timeout(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>, reason: Query<Option<String>>) -> DeleteResponse {
         use DeleteResponse::*;
-        if !self.db.valid_id(IdType::Group, gid.0).unwrap() {
-            return NotFound(PlainText("Group not found".to_string()))
-        } else if !self.db.valid_id(IdType::User, uid.0).unwrap() {
-            return NotFound(PlainText("User not found".to_string()))
-        } else if !self.db.get_group_admin(gid.0).unwrap().contains(&auth.0.id)
-            || self.db.get_group_owner(gid.0).unwrap() == uid.0
-        {            
+        let reason = match clean_reason(reason.0) {
+            Ok(reason) => reason,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if !db_try!(self.__authorize(&auth.0, gid.0, Permission::TimeoutMembers).await) {
             return Unauthorized;
+        } else if !db_try!(roles::timed_out(self.db.as_ref(), gid.0, uid.0).await) {
+            return NotFound(PlainText("Timeout not found".to_string()));
         }
-        self.__remove_group_member(gid.0, uid.0);
+        db_try!(self.db.delete_timeout(gid.0, uid.0).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::LiftTimeout, uid.0, reason).await);
         Success
     }
 
+    #[oai(path = "/group/audit", method = "get")]
+    /// Get a group's audit log, which records the moderation and configuration actions
+    /// taken in it, newest first.
+    ///
+    /// Returns at most `limit` entries (50 by default, and no more than 100). Pass the ID
+    /// of the last entry as `before` to get the next page. Entries can also be filtered by
+    /// who took the action (`actor`), who or what it was taken on (`target`) and `action`.
+    ///
+    /// Only authorized for members with the `manage_group` permission.
+    #[allow(clippy::too_many_arguments)]
+    async fn get_audit_log(
+        &self,
+        auth: Authorization,
+        gid: Query<i64>,
+        before: Query<Option<i64>>,
+        limit: Query<Option<u64>>,
+        actor: Query<Option<i64>>,
+        target: Query<Option<i64>>,
+        action: Query<Option<AuditAction>>,
+    ) -> AuditLogResponse {
+        use AuditLogResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        }
+        if !db_try!(self.__authorize(&auth.0, gid.0, Permission::ManageGroup).await) {
+            return Unauthorized;
+        }
+        let query = AuditQuery {
+            before: before.0,
+            actor: actor.0,
+            target: target.0,
+            action: action.0,
+            limit: limit.0.unwrap_or(50).clamp(1, MAX_AUDIT_ENTRIES),
+        };
+        Success(Json(db_try!(self.db.get_audit_log(gid.0, query).await)))
+    }
+
     #[oai(path = "/group/admin", method = "get")]
     /// Get the admins of the specified group.
     ///
     /// No specific order for the list is guaranteed.
+    ///
+    /// Only authorized for members of the group.
     async fn get_group_admin(&self, auth: Authorization, id: Query<i64>) -> MembersResponse {
         use MembersResponse::*;
-        if !self.db.valid_id(IdType::Group, id.0).unwrap() {
+        if !db_try!(self.db.valid_id(IdType::Group, id.0).await) ||
+           !db_try!(self.__can_view(&auth.0, id.0).await)
+        {
             return NotFound;
-        }       
-        let members = self.db.get_group_admin(id.0).unwrap();
-        Success(Json(members.iter().map(|m| {
-            self.db.get_user(*m).unwrap()
-        }).collect::<Vec<User>>()))     
+        }
+        let members = db_try!(self.db.get_group_admin(id.0).await);
+        let mut users = Vec::with_capacity(members.len());
+        for member in members {
+            users.push(db_try!(self.db.get_user(member).await));
+        }
+        Success(Json(users))     
     }
 
     #[oai(path = "/group/admin", method = "put")]
-    /// Add an admin to an existing group
+    /// Add an admin to an existing group, from among its members
     ///
     /// Only authorized for the owner of a group.
     async fn add_group_admin(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>) -> GenericResponse {
         use GenericResponse::*;
-        if !self.db.valid_id(IdType::Group, gid.0).unwrap() {
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
             return NotFound(PlainText("Group not found".to_string()));
-        } else if !self.db.valid_id(IdType::User, uid.0).unwrap() {
+        } else if !db_try!(self.db.valid_id(IdType::User, uid.0).await) {
             return NotFound(PlainText("User not found".to_string()))
-        } else if self.db.get_group_owner(gid.0).unwrap() != auth.0.id {
+        } else if db_try!(self.db.get_group_owner(gid.0).await) != auth.0.id {
             return Unauthorized;
+        } else if !db_try!(self.db.get_group_members(gid.0).await).contains(&uid.0) {
+            return NotFound(PlainText("User not found in group".to_string()));
         }
-        self.db.add_group_admin(gid.0, uid.0).unwrap();  
+        db_try!(self.db.add_group_admin(gid.0, uid.0).await);  
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::AddAdmin, uid.0, String::new()).await);
         Success
     }
 
@@ -460,52 +1916,220 @@ impl Api {
     /// Only authorized for the owner of a group.
     async fn remove_group_admin(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>) -> DeleteResponse {
         use DeleteResponse::*;
-        if !self.db.valid_id(IdType::Group, gid.0).unwrap() {
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
             return NotFound(PlainText("Group not found".to_string()))
-        } else if !self.db.valid_id(IdType::User, uid.0).unwrap() {
+        } else if !db_try!(self.db.valid_id(IdType::User, uid.0).await) {
             return NotFound(PlainText("User not found".to_string()))
-        } else if self.db.get_group_owner(gid.0).unwrap() != auth.0.id {
+        } else if db_try!(self.db.get_group_owner(gid.0).await) != auth.0.id {
             return Unauthorized;
         }
-        self.db.remove_group_admin(gid.0, uid.0).unwrap();
+        db_try!(self.db.remove_group_admin(gid.0, uid.0).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::RemoveAdmin, uid.0, String::new()).await);
         Success
     }
     
+    #[oai(path = "/group/roles", method = "get")]
+    /// Get the roles in a group, starting with its default role.
+    ///
+    /// Only authorized for members of the group.
+    async fn get_roles(&self, auth: Authorization, gid: Query<i64>) -> RolesResponse {
+        use RolesResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) ||
+           !db_try!(self.__can_view(&auth.0, gid.0).await)
+        {
+            return NotFound;
+        }
+        Success(Json(db_try!(self.__group_roles(gid.0).await)))
+    }
+
+    #[oai(path = "/group/roles", method = "post")]
+    /// Create a role in a group with the given permissions.
+    ///
+    /// Only authorized for members with the `manage_roles` permission, who also have every
+    /// permission given to the role.
+    async fn make_role(&self, auth: Authorization, gid: Query<i64>, name: Query<String>, permissions: Json<Vec<Permission>>) -> RoleResponse {
+        use RoleResponse::*;
+        let name = match self.names.clean(NameKind::Role, &name.0) {
+            Ok(name) => name,
+            Err(e) => return BadRequest(PlainText(e.to_string())),
+        };
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        }
+        let role = Role {
+            id: gen_id(),
+            group: gid.0,
+            name,
+            permissions: permissions.0.into_iter().collect::<Permissions>().list(),
+        };
+        if !db_try!(self.__can_manage_role(&auth.0, &role).await) {
+            return Unauthorized;
+        }
+        db_try!(self.db.set_role(role.clone()).await);
+        db_try!(self.__audit(auth.0.id, role.group, AuditAction::CreateRole, role.id, String::new()).await);
+        Success(Json(role))
+    }
+
+    #[oai(path = "/group/roles", method = "put")]
+    /// Update the name and permissions of a role, including a group's default role.
+    ///
+    /// Only authorized for members with the `manage_roles` permission, who also have every
+    /// permission the role had and will have.
+    async fn update_role(&self, auth: Authorization, id: Query<i64>, name: Query<String>, permissions: Json<Vec<Permission>>) -> RoleResponse {
+        use RoleResponse::*;
+        let name = match self.names.clean(NameKind::Role, &name.0) {
+            Ok(name) => name,
+            Err(e) => return BadRequest(PlainText(e.to_string())),
+        };
+        let old = db_try!(self.__get_role(id.0).await);
+        let role = Role {
+            id: old.id,
+            group: old.group,
+            name,
+            permissions: permissions.0.into_iter().collect::<Permissions>().list(),
+        };
+        if !db_try!(self.__can_manage_role(&auth.0, &old).await) ||
+           !db_try!(self.__can_manage_role(&auth.0, &role).await)
+        {
+            return Unauthorized;
+        }
+        db_try!(self.db.set_role(role.clone()).await);
+        db_try!(self.__audit(auth.0.id, role.group, AuditAction::UpdateRole, role.id, String::new()).await);
+        Success(Json(role))
+    }
+
+    #[oai(path = "/group/roles", method = "delete")]
+    /// Delete a role, taking it away from every member who has it.
+    ///
+    /// Only authorized for members with the `manage_roles` permission, who also have every
+    /// permission the role has. A group's default role can't be deleted.
+    async fn delete_role(&self, auth: Authorization, id: Query<i64>) -> GenericResponse {
+        use GenericResponse::*;
+        let role = db_try!(self.__get_role(id.0).await);
+        if !db_try!(self.__can_manage_role(&auth.0, &role).await) {
+            return Unauthorized;
+        } else if role.id == role.group {
+            return BadRequest(PlainText("A group's default role can't be deleted".to_string()));
+        }
+        db_try!(self.db.delete_role(role.id).await);
+        db_try!(self.__audit(auth.0.id, role.group, AuditAction::DeleteRole, role.id, String::new()).await);
+        Success
+    }
+
+    #[oai(path = "/group/members/roles", method = "get")]
+    /// Get the roles a member of a group has, starting with the group's default role.
+    ///
+    /// Only authorized for members of the group.
+    async fn get_member_roles(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>) -> RolesResponse {
+        use RolesResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) ||
+           !db_try!(self.__can_view(&auth.0, gid.0).await) ||
+           !db_try!(self.db.get_group_members(gid.0).await).contains(&uid.0)
+        {
+            return NotFound;
+        }
+        let mut all = vec![db_try!(roles::default_role(self.db.as_ref(), gid.0).await)];
+        for role in db_try!(self.db.get_member_roles(gid.0, uid.0).await) {
+            all.push(db_try!(self.db.get_role(role).await));
+        }
+        Success(Json(all))
+    }
+
+    #[oai(path = "/group/members/roles", method = "put")]
+    /// Give a member of a group a role.
+    ///
+    /// Only authorized for members with the `manage_roles` permission, who also have every
+    /// permission the role has.
+    async fn add_member_role(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>, role: Query<i64>) -> GenericResponse {
+        use GenericResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if !db_try!(self.db.get_group_members(gid.0).await).contains(&uid.0) {
+            return NotFound(PlainText("User not found in group".to_string()));
+        }
+        let role = db_try!(self.__get_role(role.0).await);
+        if role.group != gid.0 {
+            return NotFound(PlainText("Role not found in group".to_string()));
+        } else if !db_try!(self.__can_manage_role(&auth.0, &role).await) {
+            return Unauthorized;
+        } else if role.id == gid.0 {
+            return BadRequest(PlainText("Every member has a group's default role".to_string()));
+        }
+        db_try!(self.db.add_member_role(gid.0, uid.0, role.id).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::AddMemberRole, uid.0, String::new()).await);
+        Success
+    }
+
+    #[oai(path = "/group/members/roles", method = "delete")]
+    /// Take a role away from a member of a group.
+    ///
+    /// Only authorized for members with the `manage_roles` permission, who also have every
+    /// permission the role has.
+    async fn remove_member_role(&self, auth: Authorization, gid: Query<i64>, uid: Query<i64>, role: Query<i64>) -> GenericResponse {
+        use GenericResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
+            return NotFound(PlainText("Group not found".to_string()));
+        } else if !db_try!(self.db.get_group_members(gid.0).await).contains(&uid.0) {
+            return NotFound(PlainText("User not found in group".to_string()));
+        }
+        let role = db_try!(self.__get_role(role.0).await);
+        if role.group != gid.0 {
+            return NotFound(PlainText("Role not found in group".to_string()));
+        } else if !db_try!(self.__can_manage_role(&auth.0, &role).await) {
+            return Unauthorized;
+        } else if role.id == gid.0 {
+            return BadRequest(PlainText("Every member has a group's default role".to_string()));
+        }
+        db_try!(self.db.remove_member_role(gid.0, uid.0, role.id).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::RemoveMemberRole, uid.0, String::new()).await);
+        Success
+    }
+
     #[oai(path = "/group/channels", method = "get")]
     /// Gets all channels in a group that are accessible to you
-    async fn get_channels(&self, auth: Authorization, gid: Query<i64>) -> ChannelsResponse {
+    async fn get_channels(&self, auth: ScopedAuthorization, gid: Query<i64>) -> ChannelsResponse {
         use ChannelsResponse::*;
-        if !self.db.valid_id(IdType::Group, gid.0).unwrap() {
+        if !auth.0.allows(ScopeKind::ReadMessages, gid.0) ||
+           !db_try!(self.db.valid_id(IdType::Group, gid.0).await)
+        {
             return NotFound;
         }
-        let channels = self.db.get_group_channels(gid.0).unwrap();
-        Success(Json(channels.iter().map(|c| {
-            self.db.get_channel(*c).unwrap()
-        }).filter(|c| c.members.contains(&auth.0.id)).collect::<Vec<Channel>>()))
+        let channels = db_try!(self.db.get_group_channels(gid.0).await);
+        let mut visible = Vec::new();
+        for channel in channels {
+            let channel = db_try!(self.db.get_channel(channel).await);
+            if channel.members.contains(&auth.0.id) {
+                visible.push(channel);
+            }
+        }
+        Success(Json(visible))
     }
 
     #[oai(path = "/group/channels", method = "post")]
     /// Create a channel in a group.
     ///
-    /// Only authorized for a group admin.
+    /// Only authorized for members with the `manage_channels` permission.
     /// Defaults to a public channel with no members but yourself.
     // TODO add some mechanism for auto-inviting current members
-    async fn make_channel(&self, auth: Authorization, gid: Query<i64>, name: Query<String>) -> CreateChannelResponse {
+    async fn make_channel(&self, auth: ScopedAuthorization, gid: Query<i64>, name: Query<String>) -> CreateChannelResponse {
         use CreateChannelResponse::*;
-        if name.0 == "" {
-            return BadRequest(PlainText("Empty string not allowed for name".to_string()))
-        } else if !self.db.valid_id(IdType::Group, gid.0).unwrap() {
+        let name = match self.names.clean(NameKind::Channel, &name.0) {
+            Ok(name) => name,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Group, gid.0).await) {
             return NotFound(PlainText("Group not found".to_string()));
-        } else if !self.db.get_group_admin(gid.0).unwrap().contains(&auth.0.id) {
+        } else if !db_try!(self.__authorize(&auth.0, gid.0, Permission::ManageChannels).await) {
             return Unauthorized;
         }
         let cid = gen_id();
-        self.db.create_channel(cid, gid.0, auth.0.id, name.0.clone()).unwrap();
-        self.db.add_group_channel(gid.0, cid).unwrap();
+        db_try!(self.db.create_channel(cid, gid.0, auth.0.id, name.clone()).await);
+        db_try!(self.db.add_group_channel(gid.0, cid).await);
+        db_try!(self.__audit(auth.0.id, gid.0, AuditAction::CreateChannel, cid, String::new()).await);
         Success(Json(Channel {
             id: cid,
             group: gid.0,
-            name: name.0,
+            name,
             members: vec![auth.0.id],
             private: false
         }))
@@ -514,64 +2138,77 @@ impl Api {
     #[oai(path = "/channel", method = "put")]
     /// Update the name of a channel.
     ///
-    /// Only authorized for group admins.
-    async fn update_channel(&self, auth: Authorization, id: Query<i64>, name: Query<String>) -> GenericResponse {
+    /// Only authorized for members with the `manage_channels` permission in the channel.
+    async fn update_channel(&self, auth: ScopedAuthorization, id: Query<i64>, name: Query<String>) -> GenericResponse {
         use GenericResponse::*;
-        if !self.db.valid_id(IdType::Channel, id.0).unwrap() {
+        let name = match self.names.clean(NameKind::Channel, &name.0) {
+            Ok(name) => name,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Channel, id.0).await) {
             return NotFound(PlainText("Channel not found".to_string()));
         }
-        let channel = self.db.get_channel(id.0).unwrap();
-        if !self.db.get_group_admin(channel.group).unwrap().contains(&auth.0.id) {
+        let channel = db_try!(self.db.get_channel(id.0).await);
+        if !db_try!(self.__authorize_channel(&auth.0, channel.id, Permission::ManageChannels).await) {
             return Unauthorized;
         }
-        self.db.update_channel(id.0, name.0).unwrap();
+        db_try!(self.db.update_channel(id.0, name).await);
+        db_try!(self.__audit(auth.0.id, channel.group, AuditAction::UpdateChannel, channel.id, String::new()).await);
         Success
     }
     
     #[oai(path = "/channel/private", method = "put")]
     /// Make a channel private.
     ///
-    /// Only authorized for group admins.
-    async fn make_channel_private(&self, auth: Authorization, id: Query<i64>, val: Query<bool>) -> GenericResponse {
+    /// Only authorized for members with the `manage_channels` permission in the channel.
+    async fn make_channel_private(&self, auth: ScopedAuthorization, id: Query<i64>, val: Query<bool>) -> GenericResponse {
         use GenericResponse::*;
-        if !self.db.valid_id(IdType::Channel, id.0).unwrap() {
+        if !db_try!(self.db.valid_id(IdType::Channel, id.0).await) {
             return NotFound(PlainText("Channel not found".to_string()));
         }
-        let channel = self.db.get_channel(id.0).unwrap();
-        if !self.db.get_group_admin(channel.group).unwrap().contains(&auth.0.id) {
+        let channel = db_try!(self.db.get_channel(id.0).await);
+        if !db_try!(self.__authorize_channel(&auth.0, channel.id, Permission::ManageChannels).await) {
             return Unauthorized;
         }
-        self.db.set_channel_private(id.0, val.0).unwrap();
+        db_try!(self.db.set_channel_private(id.0, val.0).await);
+        db_try!(self.__audit(auth.0.id, channel.group, AuditAction::SetChannelPrivate, channel.id, String::new()).await);
         Success
     }
     
     #[oai(path = "/channel", method = "get")]
     /// Get a channel.
-    async fn get_channel(&self, auth: Authorization, id: Query<i64>) -> ChannelResponse {
+    ///
+    /// Only authorized for members of the channel's group, and of the channel if it's private.
+    async fn get_channel(&self, auth: ScopedAuthorization, id: Query<i64>) -> ChannelResponse {
         use ChannelResponse::*;
-        if !self.db.valid_id(IdType::Channel, id.0).unwrap() ||
-           !self.db.get_channel_members(id.0).unwrap().contains(&auth.0.id)
+        if !db_try!(self.db.valid_id(IdType::Channel, id.0).await) ||
+           !db_try!(self.__can_read(&auth.0, id.0).await)
         {
             return NotFound;
         }
-        Success(Json(self.db.get_channel(id.0).unwrap()))
+        Success(Json(db_try!(self.db.get_channel(id.0).await)))
     }
 
     #[oai(path = "/channel", method = "delete")]
-    /// Delete a channel.
+    /// Delete a channel, for the audit log's `reason` if given.
     ///
-    /// Only authorized for group admins.
-    async fn delete_channel(&self, auth: Authorization, id: Query<i64>) -> DeleteResponse {
+    /// Only authorized for members with the `manage_channels` permission in the channel.
+    async fn delete_channel(&self, auth: ScopedAuthorization, id: Query<i64>, reason: Query<Option<String>>) -> DeleteResponse {
         use DeleteResponse::*;
-        if !self.db.valid_id(IdType::Channel, id.0).unwrap() {
+        let reason = match clean_reason(reason.0) {
+            Ok(reason) => reason,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Channel, id.0).await) {
             return NotFound(PlainText("Channel not found".to_string()));
         }
-        let channel = self.db.get_channel(id.0).unwrap();        
-        if !self.db.get_group_admin(channel.group).unwrap().contains(&auth.0.id) {
+        let channel = db_try!(self.db.get_channel(id.0).await);        
+        if !db_try!(self.__authorize_channel(&auth.0, channel.id, Permission::ManageChannels).await) {
             return Unauthorized;
         }
-        self.db.remove_group_channel(channel.group, id.0).unwrap();
-        self.db.delete_channel(id.0).unwrap();
+        db_try!(self.db.remove_group_channel(channel.group, id.0).await);
+        db_try!(self.db.delete_channel(id.0).await);
+        db_try!(self.__audit(auth.0.id, channel.group, AuditAction::DeleteChannel, channel.id, reason).await);
         Success
     }
 
@@ -579,49 +2216,144 @@ impl Api {
     /// Get the members that can access a channel.
     ///
     /// No specific order for the list is guaranteed.
-    async fn get_channel_members(&self, auth: Authorization, id: Query<i64>) -> MembersResponse {
+    ///
+    /// Only authorized for members of the channel's group, and of the channel if it's private.
+    async fn get_channel_members(&self, auth: ScopedAuthorization, id: Query<i64>) -> MembersResponse {
         use MembersResponse::*;
-        let members = self.db.get_channel_members(id.0).unwrap();
-        Success(Json(members.iter().map(|m| {
-            self.db.get_user(*m).unwrap()
-        }).collect::<Vec<User>>()))
+        if !db_try!(self.db.valid_id(IdType::Channel, id.0).await) ||
+           !db_try!(self.__can_read(&auth.0, id.0).await)
+        {
+            return NotFound;
+        }
+        let members = db_try!(self.db.get_channel_members(id.0).await);
+        let mut users = Vec::with_capacity(members.len());
+        for member in members {
+            users.push(db_try!(self.db.get_user(member).await));
+        }
+        Success(Json(users))
     }
 
     #[oai(path = "/channel/members", method = "put")]
     /// Add a member to a channel
     ///
-    /// Only authorized for group admins.
-    async fn add_channel_member(&self, auth: Authorization, cid: Query<i64>, uid: Query<i64>) -> GenericResponse {
+    /// Only authorized for members with the `manage_channels` permission in the channel, and
+    /// only members of the channel's group (who aren't banned from it) can be added.
+    async fn add_channel_member(&self, auth: ScopedAuthorization, cid: Query<i64>, uid: Query<i64>) -> GenericResponse {
         use GenericResponse::*;
-        if !self.db.valid_id(IdType::Channel, cid.0).unwrap() {
+        if !db_try!(self.db.valid_id(IdType::Channel, cid.0).await) {
             return NotFound(PlainText("Channel not found".to_string()))
-        } else if !self.db.valid_id(IdType::User, uid.0).unwrap() {
+        } else if !db_try!(self.db.valid_id(IdType::User, uid.0).await) {
             return NotFound(PlainText("User not found".to_string()))
         }
-        let channel = self.db.get_channel(cid.0).unwrap();        
-        if !self.db.get_group_admin(channel.group).unwrap().contains(&auth.0.id) {
+        let channel = db_try!(self.db.get_channel(cid.0).await);        
+        if !db_try!(self.__authorize_channel(&auth.0, channel.id, Permission::ManageChannels).await) {
             return Unauthorized;
+        } else if db_try!(self.__is_banned(channel.group, uid.0).await) {
+            return Conflict(PlainText("User is banned from the group".to_string()));
+        } else if !db_try!(self.db.get_group_members(channel.group).await).contains(&uid.0) {
+            return NotFound(PlainText("User isn't a member of the group".to_string()));
         }
-        self.db.add_channel_member(cid.0, uid.0).unwrap();
+        db_try!(self.db.add_channel_member(cid.0, uid.0).await);
+        db_try!(self.__audit(auth.0.id, channel.group, AuditAction::AddChannelMember, uid.0, String::new()).await);
         Success
     }
 
     #[oai(path = "/channel/members", method = "delete")]
-    /// Remove a member from a channel.
+    /// Remove a member from a channel, for the audit log's `reason` if given.
     ///
-    /// Only authorized for group admins.
-    async fn remove_channel_member(&self, auth: Authorization, cid: Query<i64>, uid: Query<i64>) -> DeleteResponse {
+    /// Only authorized for members with the `manage_channels` permission in the channel.
+    async fn remove_channel_member(&self, auth: ScopedAuthorization, cid: Query<i64>, uid: Query<i64>, reason: Query<Option<String>>) -> DeleteResponse {
         use DeleteResponse::*;
-        if !self.db.valid_id(IdType::Channel, cid.0).unwrap() {
+        let reason = match clean_reason(reason.0) {
+            Ok(reason) => reason,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Channel, cid.0).await) {
             return NotFound(PlainText("Channel not found".to_string()))
-        } else if !self.db.valid_id(IdType::User, uid.0).unwrap() {
+        } else if !db_try!(self.db.valid_id(IdType::User, uid.0).await) {
             return NotFound(PlainText("User not found".to_string()))
         }
-        let channel = self.db.get_channel(cid.0).unwrap();        
-        if !self.db.get_group_admin(channel.group).unwrap().contains(&auth.0.id) {
+        let channel = db_try!(self.db.get_channel(cid.0).await);        
+        if !db_try!(self.__authorize_channel(&auth.0, channel.id, Permission::ManageChannels).await) {
+            return Unauthorized;
+        }
+        db_try!(self.db.remove_channel_member(cid.0, uid.0).await);
+        db_try!(self.__audit(auth.0.id, channel.group, AuditAction::RemoveChannelMember, uid.0, reason).await);
+        Success
+    }
+
+    #[oai(path = "/channel/overrides", method = "get")]
+    /// Get the permissions a channel allows or denies roles and users.
+    ///
+    /// Only authorized for members of the channel's group, and of the channel if it's private.
+    async fn get_channel_overrides(&self, auth: Authorization, id: Query<i64>) -> OverridesResponse {
+        use OverridesResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Channel, id.0).await) ||
+           !db_try!(self.__can_read(&auth.0, id.0).await)
+        {
+            return NotFound;
+        }
+        Success(Json(db_try!(self.db.get_channel_overrides(id.0).await)))
+    }
+
+    #[oai(path = "/channel/overrides", method = "put")]
+    /// Set what a channel allows or denies a role (the group's ID for its default role) or
+    /// a member of the group, replacing what it did before. Only permissions that mean
+    /// something in a single channel can be overridden.
+    ///
+    /// Only authorized for members with the `manage_channels` permission in the channel, who
+    /// also have every permission the override allows or denies.
+    async fn set_channel_override(&self, auth: Authorization, id: Query<i64>, target: Query<i64>, change: Json<OverrideChange>) -> GenericResponse {
+        use GenericResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Channel, id.0).await) {
+            return NotFound(PlainText("Channel not found".to_string()));
+        }
+        let group = db_try!(self.db.get_channel(id.0).await).group;
+        let is_role = match self.db.get_role(target.0).await {
+            Ok(role) => role.group == group,
+            Err(DbError::NotFound(_)) => target.0 == group,
+            Err(e) => return e.into(),
+        };
+        if !is_role && !db_try!(self.db.get_group_members(group).await).contains(&target.0) {
+            return NotFound(PlainText("Role or member not found in group".to_string()));
+        }
+        let over = ChannelOverride {
+            channel: id.0,
+            target: target.0,
+            allow: change.0.allow.into_iter().collect::<Permissions>().list(),
+            deny: change.0.deny.into_iter().collect::<Permissions>().list(),
+        };
+        if !db_try!(self.__can_manage_override(&auth.0, &over).await) {
+            return Unauthorized;
+        } else if !over.allow.iter().chain(&over.deny).all(|p| p.overridable()) {
+            return BadRequest(PlainText("Only permissions that apply in a channel can be overridden".to_string()));
+        }
+        db_try!(self.db.set_channel_override(over).await);
+        db_try!(self.__audit(auth.0.id, group, AuditAction::SetChannelOverride, target.0, String::new()).await);
+        Success
+    }
+
+    #[oai(path = "/channel/overrides", method = "delete")]
+    /// Delete a channel's override for a role or user.
+    ///
+    /// Only authorized for members with the `manage_channels` permission in the channel, who
+    /// also have every permission the override allows or denies.
+    async fn delete_channel_override(&self, auth: Authorization, id: Query<i64>, target: Query<i64>) -> DeleteResponse {
+        use DeleteResponse::*;
+        if !db_try!(self.db.valid_id(IdType::Channel, id.0).await) {
+            return NotFound(PlainText("Channel not found".to_string()));
+        }
+        let overrides = db_try!(self.db.get_channel_overrides(id.0).await);
+        let over = match overrides.iter().find(|over| over.target == target.0) {
+            Some(over) => over,
+            None => return NotFound(PlainText("Override not found".to_string())),
+        };
+        if !db_try!(self.__can_manage_override(&auth.0, over).await) {
             return Unauthorized;
         }
-        self.db.remove_channel_member(cid.0, uid.0).unwrap();
+        db_try!(self.db.delete_channel_override(id.0, target.0).await);
+        let group = db_try!(self.db.get_channel(id.0).await).group;
+        db_try!(self.__audit(auth.0.id, group, AuditAction::DeleteChannelOverride, target.0, String::new()).await);
         Success
     }
 
@@ -629,12 +2361,16 @@ impl Api {
     /// Get a batch of messages in channel containing `term` in the last 100 messages
     ///
     /// Will not search for `term` in any messages older than the last 100.
-    async fn search_channel(&self, auth: Authorization, cid: Query<i64>, term: Query<String>, off: Query<u64>) -> MessagesResponse {
+    ///
+    /// Only authorized for members of the channel's group, and of the channel if it's private.
+    async fn search_channel(&self, auth: ScopedAuthorization, cid: Query<i64>, term: Query<String>, off: Query<u64>) -> MessagesResponse {
         use MessagesResponse::*;
-        if !self.db.valid_id(IdType::Channel, cid.0).unwrap() {
+        if !db_try!(self.db.valid_id(IdType::Channel, cid.0).await) ||
+           !db_try!(self.__can_read(&auth.0, cid.0).await)
+        {
             return NotFound(PlainText("Channel not found".to_string()))
         }
-        let mut messages = self.db.get_messages(cid.0, 100).unwrap();
+        let mut messages = db_try!(self.db.get_messages(cid.0, 100).await);
         messages.retain(|msg| msg.content.contains(&term.0));
         Success(Json(messages)) 
     }
@@ -643,36 +2379,52 @@ impl Api {
     /// Returns batch of messages in channel. Do not use for small batches.
     ///
     /// For small batches, use `chatterbox`, the websocket service for messaging, instead.
-    async fn get_channel_messages(&self, auth: Authorization, cid: Query<i64>, num_msgs: Query<u64>) -> MessagesResponse {
+    ///
+    /// Only authorized for members of the channel's group, and of the channel if it's private.
+    async fn get_channel_messages(&self, auth: ScopedAuthorization, cid: Query<i64>, num_msgs: Query<u64>) -> MessagesResponse {
         use MessagesResponse::*;
-        if !self.db.valid_id(IdType::Channel, cid.0).unwrap() {
+        if !db_try!(self.db.valid_id(IdType::Channel, cid.0).await) ||
+           !db_try!(self.__can_read(&auth.0, cid.0).await)
+        {
             return NotFound(PlainText("Channel not found".to_string()))
         }
-        Success(Json(self.db.get_messages(cid.0, num_msgs.0).unwrap()))
+        Success(Json(db_try!(self.db.get_messages(cid.0, num_msgs.0).await)))
     }
 
     #[oai(path = "/message/thread", method = "put")]
     /// Make a thread for a given message.
     ///
-    /// Thread will be private with you as its sole member
-    async fn make_thread(&self, auth: Authorization, id: Query<i64>, name: Query<String>) -> CreateChannelResponse {
+    /// Thread will be private with you as its sole member.
+    /// Only authorized for members with the `create_threads` permission in the channel, and
+    /// the thread starts with the channel's permission overrides.
+    async fn make_thread(&self, auth: ScopedAuthorization, id: Query<i64>, name: Query<String>) -> CreateChannelResponse {
         use CreateChannelResponse::*;
-        if name.0 == "" {
-            return BadRequest(PlainText("Empty string not allowed for name".to_string()))
-        } else if !self.db.valid_id(IdType::Message, id.0).unwrap() {
+        let name = match self.names.clean(NameKind::Thread, &name.0) {
+            Ok(name) => name,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Message, id.0).await) {
             return NotFound(PlainText("Message not found".to_string()))
         }
         let tid = gen_id();
-        let msg = self.db.get_message(id.0).unwrap();
-        let chan = self.db.get_channel(msg.channel).unwrap();
-        self.db.create_channel(tid, chan.group, auth.0.id, name.0.clone()).unwrap();
-        self.db.set_channel_private(tid, true).unwrap();
-        self.db.set_thread(id.0, tid).unwrap();
+        let msg = db_try!(self.db.get_message(id.0).await);
+        let chan = db_try!(self.db.get_channel(msg.channel).await);
+        if !db_try!(self.__authorize_channel(&auth.0, chan.id, Permission::CreateThreads).await) {
+            return Unauthorized;
+        }
+        db_try!(self.db.create_channel(tid, chan.group, auth.0.id, name.clone()).await);
+        db_try!(self.db.set_channel_private(tid, true).await);
+        db_try!(self.db.set_thread(id.0, tid).await);
+        // So it goes with its group, like any other channel
+        db_try!(self.db.add_group_channel(chan.group, tid).await);
+        for over in db_try!(self.db.get_channel_overrides(chan.id).await) {
+            db_try!(self.db.set_channel_override(ChannelOverride { channel: tid, ..over }).await);
+        }
         Success(Json(Channel {
             id: tid,
             group: chan.group,
             members: vec![auth.0.id],
-            name: name.0,
+            name,
             private: true
         }))
     }
@@ -680,32 +2432,59 @@ impl Api {
     #[oai(path = "/message", method = "delete")]
     /// Delete a message
     ///
-    /// Only authorized for the message author or a group admin.
-    async fn delete_message(&self, auth: Authorization, id: Query<i64>) -> DeleteResponse {
+    /// Only authorized for the message author or members with the `delete_messages` permission
+    /// in the message's channel. Deleting someone else's message goes in the group's audit log,
+    /// with the `reason` if given.
+    async fn delete_message(&self, auth: ScopedAuthorization, id: Query<i64>, reason: Query<Option<String>>) -> DeleteResponse {
         use DeleteResponse::*;
-        if !self.db.valid_id(IdType::Message, id.0).unwrap() {
+        let reason = match clean_reason(reason.0) {
+            Ok(reason) => reason,
+            Err(e) => return BadRequest(PlainText(e)),
+        };
+        if !db_try!(self.db.valid_id(IdType::Message, id.0).await) {
             return NotFound(PlainText("Message not found".to_string()))
         }
-        let msg = self.db.get_message(id.0).unwrap();
-        let chan = self.db.get_channel(msg.channel).unwrap();
-        if msg.author != auth.0.id && !self.db.get_group_admin(chan.group).unwrap().contains(&auth.0.id) {
+        let msg = db_try!(self.db.get_message(id.0).await);
+        let chan = db_try!(self.db.get_channel(msg.channel).await);
+        let allowed = match msg.author == auth.0.id {
+            true => auth.0.allows(ScopeKind::SendMessages, chan.group),
+            false => db_try!(self.__authorize_channel(&auth.0, chan.id, Permission::DeleteMessages).await),
+        };
+        if !allowed {
             return Unauthorized;
         }
-        self.db.delete_message(id.0).unwrap();
+        db_try!(self.db.delete_message(id.0).await);
+        if msg.author != auth.0.id {
+            db_try!(self.__audit(auth.0.id, chan.group, AuditAction::DeleteMessage, msg.id, reason).await);
+        }
         Success
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    use hmac::Mac;
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "poem=debug");
     }
     tracing_subscriber::fmt::init();
 
-    let db = Box::new(Cassandra::new("bsk"));
-    let api_service = OpenApiService::new(Api::new(db), "Scuttlebutt", "1.0")
+    // `BSK_DB` picks the backend (see `common::connect`)
+    let db: Arc<dyn Database> = connect(&std::env::var("BSK_DB").unwrap_or_default()).await.into();
+    // OpenID Connect logins are only on if `BSK_OIDC_ISSUER` is set (see `OidcConfig::from_env`)
+    let mut api = Api::new(db.clone());
+    if let Some(config) = OidcConfig::from_env() {
+        let config = config.unwrap_or_else(|e| panic!("Invalid OpenID Connect settings: {e}"));
+        let provider = Provider::discover(config).await
+            .unwrap_or_else(|e| panic!("Couldn't set up OpenID Connect: {e}"));
+        api = api.with_oidc(provider);
+    }
+    // Where verification and password reset emails go (see `mailer::from_env`)
+    let mailer = mailer::from_env().unwrap_or_else(|e| panic!("Invalid mail settings: {e}"));
+    api = api.with_mailer(mailer);
+    // How long names can be (see `NameLimits::from_env`)
+    let limits = NameLimits::from_env().unwrap_or_else(|e| panic!("Invalid name limits: {e}"));
+    api = api.with_name_limits(limits);
+    let api_service = OpenApiService::new(api, "Scuttlebutt", "1.0")
         .description(
             "Scuttlebutt is the REST API for managing everything but sending/receiving messages \
                       - which means creating/updating/deleting all of your users/groups/channels.",
@@ -715,17 +2494,26 @@ async fn main() -> Result<(), std::io::Error> {
     // API documentation 
     let ui = api_service.swagger_ui();
 
-    // Generate server-side secret key used for signing the JWTs
-    let key: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(7)
-        .map(char::from)
-        .collect();
+    // Server-side secret keys used for signing the JWTs (see `ServerKeys::from_env`).
+    // Chatterbox and other instances need the same keys to accept them, so a random
+    // key (that dies with this process) is only a fallback for trying things out.
+    let keys = match ServerKeys::from_env() {
+        Some(keys) => keys.unwrap_or_else(|e| panic!("Invalid server keys: {e}")),
+        None => {
+            let key: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(32)
+                .map(char::from)
+                .collect();
+            ServerKeys::new("random", key.as_bytes())
+        }
+    };
 
     let app = Route::new()
         .nest("/api", api_service)
         .nest("/", ui)
-        .data(ServerKey::new_from_slice(&key.as_bytes()).unwrap());
+        .data(keys)
+        .data(db);
 
     Server::new(TcpListener::bind("127.0.0.1:3000")).run(app).await
 }
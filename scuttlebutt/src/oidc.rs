@@ -0,0 +1,329 @@
+//! Logging in through an OpenID Connect provider, with the authorization code flow.
+//!
+//! The client app asks `/oidc/login` where to send the user, and gets back the provider's
+//! authorization URL along with a `state` to check when the provider redirects back to it
+//! (at `BSK_OIDC_REDIRECT_URI`) with a code. The app passes the code and state on to
+//! `/oidc/callback`, which trades the code for an ID token at the provider's token
+//! endpoint and logs in the user the token's identity is linked to, making a new user
+//! the first time it's seen.
+//!
+//! The ID token comes straight from the token endpoint over TLS, so (as OpenID Connect
+//! Core 3.1.3.7 allows) we trust it came from the issuer without checking its signature,
+//! which saves fetching and caching the provider's keys. Its issuer, audience, expiry
+//! and nonce are all still checked.
+use chrono::{DateTime, Local, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use common::Identity;
+
+/// How long a login has to finish in once it's started, in seconds
+pub const STATE_LIFETIME: i64 = 10 * 60;
+
+/// Settings for the provider, as registered with it
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    // The provider's issuer URL, where its discovery document lives
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    // Where the provider sends users back to (the client app, not scuttlebutt)
+    pub redirect_uri: String,
+}
+
+impl OidcConfig {
+    /// Read the settings from `BSK_OIDC_ISSUER`, `BSK_OIDC_CLIENT_ID`,
+    /// `BSK_OIDC_CLIENT_SECRET` and `BSK_OIDC_REDIRECT_URI`.
+    ///
+    /// Returns None if `BSK_OIDC_ISSUER` isn't set (so OpenID Connect is off), and an
+    /// error if it is but any of the others aren't.
+    pub fn from_env() -> Option<Result<Self, String>> {
+        let issuer = std::env::var("BSK_OIDC_ISSUER").ok()?;
+        let var = |name: &str| std::env::var(name).map_err(|_| format!("{name} must be set along with BSK_OIDC_ISSUER"));
+        Some((|| Ok(Self {
+            issuer,
+            client_id: var("BSK_OIDC_CLIENT_ID")?,
+            client_secret: var("BSK_OIDC_CLIENT_SECRET")?,
+            redirect_uri: var("BSK_OIDC_REDIRECT_URI")?,
+        }))())
+    }
+}
+
+/// The parts of the provider's discovery document we use
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// The parts of the token endpoint's response we use
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// An OpenID Connect provider, with its endpoints discovered
+pub struct Provider {
+    config: OidcConfig,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    http: reqwest::Client,
+}
+
+impl Provider {
+    /// Fetch the provider's discovery document (`/.well-known/openid-configuration`)
+    ///
+    /// Arguments:
+    /// - `config`: the settings for the provider
+    pub async fn discover(config: OidcConfig) -> Result<Self, String> {
+        let http = reqwest::Client::new();
+        let url = format!("{}/.well-known/openid-configuration", config.issuer.trim_end_matches('/'));
+        let discovery: Discovery = http.get(&url).send().await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| format!("couldn't fetch {url}: {e}"))?
+            .json().await
+            .map_err(|e| format!("invalid discovery document at {url}: {e}"))?;
+        if discovery.issuer != config.issuer {
+            return Err(format!("discovery document is for {}, not {}", discovery.issuer, config.issuer));
+        }
+        Ok(Self {
+            config,
+            authorization_endpoint: discovery.authorization_endpoint,
+            token_endpoint: discovery.token_endpoint,
+            http,
+        })
+    }
+
+    /// The provider's issuer URL
+    pub fn issuer(&self) -> &str {
+        &self.config.issuer
+    }
+
+    /// The URL to send a user to for them to log in with the provider
+    ///
+    /// Arguments:
+    /// - `state`: the state to send them back with
+    /// - `nonce`: the nonce the ID token has to contain
+    pub fn authorization_url(&self, state: &str, nonce: &str) -> Result<String, String> {
+        let url = reqwest::Url::parse_with_params(&self.authorization_endpoint, &[
+            ("response_type", "code"),
+            ("scope", "openid profile email"),
+            ("client_id", &self.config.client_id),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("state", state),
+            ("nonce", nonce),
+        ]).map_err(|e| e.to_string())?;
+        Ok(url.into())
+    }
+
+    /// Trade an authorization code for the ID token of the user who logged in, checking
+    /// that it's meant for us
+    ///
+    /// Arguments:
+    /// - `code`: the code the provider sent the user back with
+    /// - `nonce`: the nonce the login was started with
+    pub async fn exchange(&self, code: &str, nonce: &str) -> Result<IdToken, String> {
+        let resp: TokenResponse = self.http.post(&self.token_endpoint)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_uri),
+            ])
+            .send().await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json().await
+            .map_err(|e| e.to_string())?;
+        let token = IdToken::decode(&resp.id_token).ok_or("malformed ID token")?;
+        token.validate(&self.config, nonce, Utc::now().timestamp())?;
+        Ok(token)
+    }
+}
+
+/// The `aud` claim, which is either one audience or several
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == client_id,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == client_id),
+        }
+    }
+}
+
+/// The claims of an ID token that we use
+#[derive(Deserialize, Debug, Clone)]
+pub struct IdToken {
+    pub iss: String,
+    pub sub: String,
+    pub aud: Audience,
+    // The party the token was issued to, when there are several audiences
+    pub azp: Option<String>,
+    pub exp: i64,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    // Whether the provider has checked that the user owns `email`
+    pub email_verified: Option<bool>,
+    pub preferred_username: Option<String>,
+    pub name: Option<String>,
+}
+
+impl IdToken {
+    /// Read the claims out of an ID token (a JWT), without checking its signature
+    pub fn decode(token: &str) -> Option<Self> {
+        let payload = token.split('.').nth(1)?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+        serde_json::from_slice(&payload).ok()
+    }
+
+    /// Check that the token was issued by our provider, to us, for this login, and
+    /// hasn't expired (OpenID Connect Core 3.1.3.7)
+    ///
+    /// Arguments:
+    /// - `config`: the provider's settings
+    /// - `nonce`: the nonce the login was started with
+    /// - `now`: the current Unix time
+    pub fn validate(&self, config: &OidcConfig, nonce: &str, now: i64) -> Result<(), String> {
+        if self.iss != config.issuer {
+            return Err(format!("ID token issued by {}", self.iss));
+        } else if !self.aud.contains(&config.client_id) {
+            return Err("ID token isn't for this client".to_string());
+        } else if matches!(self.aud, Audience::Many(_)) && self.azp.as_deref() != Some(config.client_id.as_str()) {
+            return Err("ID token wasn't issued to this client".to_string());
+        } else if self.exp <= now {
+            return Err("ID token has expired".to_string());
+        } else if self.nonce.as_deref() != Some(nonce) {
+            return Err("ID token is for another login".to_string());
+        }
+        Ok(())
+    }
+
+    /// The identity the token is for
+    pub fn identity(&self) -> Identity {
+        Identity { issuer: self.iss.clone(), subject: self.sub.clone() }
+    }
+
+    /// The name to give a user made from this identity. Usernames can't have an `@` in
+    /// them, so anything after one (as in an email) is dropped.
+    pub fn username(&self) -> String {
+        [&self.preferred_username, &self.name, &self.email]
+            .into_iter()
+            .flatten()
+            .map(|name| name.split('@').next().unwrap_or_default())
+            .find(|name| !name.is_empty())
+            .unwrap_or(&self.sub)
+            .to_string()
+    }
+}
+
+/// Claims of the `state` handed out by `/oidc/login`, signed like an access token.
+///
+/// `purpose` keeps these from being mixed up with other tokens signed with the same keys.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StateClaims {
+    pub purpose: String,
+    // Random value the ID token has to contain, so it can't be replayed into another login
+    pub nonce: String,
+    // The user to link the identity to, rather than logging in as it
+    pub link: Option<i64>,
+    pub exp: DateTime<Local>,
+}
+
+impl StateClaims {
+    const PURPOSE: &'static str = "oidc";
+
+    /// Make the claims for a new login, expiring after `STATE_LIFETIME`
+    ///
+    /// Arguments:
+    /// - `link`: the user to link the identity to, if any
+    pub fn new(link: Option<i64>) -> Self {
+        Self {
+            purpose: Self::PURPOSE.to_string(),
+            nonce: rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect(),
+            link,
+            exp: Local::now() + chrono::Duration::seconds(STATE_LIFETIME),
+        }
+    }
+
+    /// Whether these claims are for a login, and haven't expired
+    pub fn valid(&self) -> bool {
+        self.purpose == Self::PURPOSE && self.exp > Local::now()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn config() -> OidcConfig {
+        OidcConfig {
+            issuer: "https://idp.example.com".to_string(),
+            client_id: "bsk".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_uri: "https://app.example.com/callback".to_string(),
+        }
+    }
+
+    fn token(claims: serde_json::Value) -> IdToken {
+        let payload = base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD);
+        IdToken::decode(&format!("eyJhbGciOiJSUzI1NiJ9.{payload}.c2ln")).unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = serde_json::json!({
+            "iss": "https://idp.example.com", "sub": "123", "aud": "bsk", "exp": 1000, "nonce": "n",
+        });
+        assert_eq!(token(valid.clone()).validate(&config(), "n", 999), Ok(()));
+
+        let with = |key: &str, value: serde_json::Value| {
+            let mut claims = valid.clone();
+            claims[key] = value;
+            token(claims)
+        };
+        assert!(token(valid.clone()).validate(&config(), "n", 1000).is_err());
+        assert!(token(valid.clone()).validate(&config(), "other", 999).is_err());
+        assert!(with("iss", "https://evil.example.com".into()).validate(&config(), "n", 999).is_err());
+        assert!(with("aud", "someone-else".into()).validate(&config(), "n", 999).is_err());
+        assert!(with("nonce", serde_json::Value::Null).validate(&config(), "n", 999).is_err());
+
+        // Tokens for several audiences have to say they were issued to us
+        let many = with("aud", serde_json::json!(["bsk", "other"]));
+        assert!(many.validate(&config(), "n", 999).is_err());
+        let mut claims = valid;
+        claims["aud"] = serde_json::json!(["bsk", "other"]);
+        claims["azp"] = "bsk".into();
+        assert_eq!(token(claims).validate(&config(), "n", 999), Ok(()));
+    }
+
+    #[test]
+    fn test_decode() {
+        assert!(IdToken::decode("not a token").is_none());
+        assert!(IdToken::decode("a.!!!.c").is_none());
+        let token = token(serde_json::json!({
+            "iss": "i", "sub": "s", "aud": "a", "exp": 1, "email": "fred@example.com",
+        }));
+        assert_eq!(token.identity(), Identity { issuer: "i".to_string(), subject: "s".to_string() });
+        assert_eq!(token.username(), "fred");
+        let token = IdToken { preferred_username: Some("fred.smith@corp".to_string()), ..token };
+        assert_eq!(token.username(), "fred.smith");
+    }
+
+    #[test]
+    fn test_state() {
+        let state = StateClaims::new(Some(5));
+        assert!(state.valid());
+        assert_eq!(state.nonce.len(), 32);
+        assert_ne!(state.nonce, StateClaims::new(None).nonce);
+        let expired = StateClaims { exp: Local::now() - chrono::Duration::seconds(1), ..state };
+        assert!(!expired.valid());
+    }
+}
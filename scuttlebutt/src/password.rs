@@ -0,0 +1,144 @@
+//! Password hashing. Passwords are stored as salted Argon2id hashes in PHC string format
+//! (`$argon2id$v=19$m=...,t=...,p=...$<salt>$<hash>`), so the parameters they were made
+//! with are stored right alongside them and can be raised later without breaking logins.
+//!
+//! Users created before this stored the hex SHA-256 of their password (which clients
+//! hashed themselves). Those still verify, and get upgraded the next time the user logs in.
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use sha2::{Digest, Sha256};
+
+/// The longest password accepted, in bytes. Hashing is (deliberately) expensive,
+/// so this stops anyone from making us hash megabytes at a time.
+pub const MAX_PASSWORD_LEN: usize = 1024;
+
+/// Outcome of checking a password against a stored hash
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verified {
+    /// The password is wrong (or the stored hash is unreadable)
+    No,
+    /// The password is right
+    Yes,
+    /// The password is right, but the stored hash is a legacy SHA-256 one or was made
+    /// with outdated parameters, and should be replaced with a fresh `hash_password`
+    NeedsRehash,
+}
+
+/// Whether a password is acceptable to set
+///
+/// Arguments:
+/// - `password`: the password, as sent by the user
+pub fn valid_password(password: &str) -> bool {
+    !password.is_empty() && password.len() <= MAX_PASSWORD_LEN
+}
+
+/// Hash a password with a random salt. This is slow on purpose; call it off the async
+/// runtime (see `hash_password_blocking`).
+///
+/// Arguments:
+/// - `password`: the password to hash
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+/// Check a password against a stored hash, either a PHC string or a legacy hex SHA-256.
+///
+/// Arguments:
+/// - `password`: the password, as sent by the user
+/// - `stored`: the hash stored for the user
+pub fn verify_password(password: &str, stored: &str) -> Verified {
+    if is_legacy_hash(stored) {
+        let legacy = hex::encode(Sha256::digest(password.as_bytes()));
+        return match legacy.eq_ignore_ascii_case(stored) {
+            true => Verified::NeedsRehash,
+            false => Verified::No,
+        };
+    }
+    let hash = match PasswordHash::new(stored) {
+        Ok(hash) => hash,
+        Err(_) => return Verified::No,
+    };
+    if Argon2::default().verify_password(password.as_bytes(), &hash).is_err() {
+        return Verified::No;
+    }
+    // Rehash anything not made with the current algorithm and parameters
+    let current = argon2::Params::default();
+    let outdated = match argon2::Params::try_from(&hash) {
+        Ok(params) => (params.m_cost(), params.t_cost(), params.p_cost())
+            != (current.m_cost(), current.t_cost(), current.p_cost()),
+        Err(_) => true,
+    };
+    if outdated || hash.algorithm != argon2::Algorithm::default().ident() {
+        Verified::NeedsRehash
+    } else {
+        Verified::Yes
+    }
+}
+
+/// Whether a stored hash is a legacy (client-side, unsalted) SHA-256 one
+fn is_legacy_hash(stored: &str) -> bool {
+    stored.len() == 64 && stored.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// `hash_password` on a blocking thread, so it doesn't stall other requests
+pub async fn hash_password_blocking(password: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || hash_password(&password).map_err(|e| e.to_string()))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// `verify_password` on a blocking thread, so it doesn't stall other requests
+pub async fn verify_password_blocking(password: String, stored: String) -> Verified {
+    tokio::task::spawn_blocking(move || verify_password(&password, &stored))
+        .await
+        .unwrap_or(Verified::No)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hash = hash_password("hunter2").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        // Salted, so the same password never hashes the same way twice
+        assert_ne!(hash, hash_password("hunter2").unwrap());
+
+        assert_eq!(verify_password("hunter2", &hash), Verified::Yes);
+        assert_eq!(verify_password("hunter3", &hash), Verified::No);
+        assert_eq!(verify_password("hunter2", "garbage"), Verified::No);
+        assert_eq!(verify_password("hunter2", ""), Verified::No);
+    }
+
+    #[test]
+    fn test_legacy_hash() {
+        let legacy = hex::encode(Sha256::digest(b"hunter2"));
+        assert_eq!(verify_password("hunter2", &legacy), Verified::NeedsRehash);
+        assert_eq!(verify_password("hunter2", &legacy.to_uppercase()), Verified::NeedsRehash);
+        assert_eq!(verify_password("hunter3", &legacy), Verified::No);
+        // The old hash itself no longer works as a password
+        assert_eq!(verify_password(&legacy, &legacy), Verified::No);
+    }
+
+    #[test]
+    fn test_outdated_params() {
+        let weak = argon2::Params::new(1024, 1, 1, None).unwrap();
+        let argon = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, weak);
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = argon.hash_password(b"hunter2", &salt).unwrap().to_string();
+        assert_eq!(verify_password("hunter2", &hash), Verified::NeedsRehash);
+        assert_eq!(verify_password("hunter3", &hash), Verified::No);
+    }
+
+    #[test]
+    fn test_valid_password() {
+        assert!(valid_password("a"));
+        assert!(!valid_password(""));
+        assert!(!valid_password(&"a".repeat(MAX_PASSWORD_LEN + 1)));
+    }
+}
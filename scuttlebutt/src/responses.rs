@@ -3,71 +3,122 @@ use poem_openapi::{
     ApiResponse, Object,
 };
 use serde::{Deserialize, Serialize};
+use common::{ApiToken, AuditEntry, Ban, Channel, ChannelOverride, DbError, Group, GroupListing, Invite, JoinRequest, Message, Permission, Role, Timeout, User};
 
 #[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
-/// Object representing a user
-pub struct User {
-    pub id: i64,
-    pub username: String,
+/// Tokens for a login session
+pub struct Tokens {
+    // JWT encoding the user's ID, the session and the token expiration date, used to
+    // authenticate requests
+    pub access_token: String,
+    // Single-use token that can be traded for new tokens at `/refresh`
+    pub refresh_token: String,
+    // Seconds until the access token expires
+    pub expires_in: i64,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Request to set a new password with a token from a password reset email
+pub struct PasswordReset {
+    // The token from the email
+    pub token: String,
+    // The new password
+    pub password: String,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Request to set what a channel allows or denies a role or user
+pub struct OverrideChange {
+    // Permissions to allow, whatever the group allows
+    pub allow: Vec<Permission>,
+    // Permissions to deny, whatever the group allows
+    pub deny: Vec<Permission>,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Your email address, and whether you've verified it
+pub struct EmailStatus {
     pub email: String,
+    pub verified: bool,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Request to change your password
+pub struct PasswordChange {
+    // The current password
+    pub old: String,
+    // The new password
+    pub new: String,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Returned by `/login` instead of tokens when the user has two-factor authentication on
+pub struct TotpChallenge {
+    // Token to pass to `/login/totp` along with a code
+    pub challenge_token: String,
+    // Seconds until the challenge token expires
+    pub expires_in: i64,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// Second step of logging in with two-factor authentication
+pub struct TotpLogin {
+    // The challenge token returned by `/login`
+    pub challenge_token: String,
+    // A code from the user's authenticator app, or one of their recovery codes
+    pub code: String,
 }
 
 #[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
-/// Object representing a group.
-/// No guarantees are made for the order of any vector in this struct.
-pub struct Group {
-    pub id: i64,
-    pub name: String,
-    // The IDs of the group members
-    pub members: Vec<i64>,
-    // The IDs of the group's channels
-    pub channels: Vec<i64>,
-	// The IDs of the group's users with admin permissions
-	pub admin: Vec<i64>,
-	// The ID of the owner of the group
-	pub owner: i64,
-	// Whether or not the group is a DM
-	pub is_dm: bool,
+/// A new TOTP secret, which needs confirming with a code before it's used
+pub struct TotpEnrollment {
+    // Base32 secret, for authenticator apps that need it typed in
+    pub secret: String,
+    // `otpauth://` URI with the secret, usually shown as a QR code
+    pub uri: String,
 }
 
 #[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
-/// Object representing a group's channel.
-/// No guarantees are made for the order of any vector in this struct.
-pub struct Channel {
-    pub id: i64,
-    pub name: String,
-	// ID of the group the channel is in
-	pub group: i64,
-	// The IDs of the group members
-    pub members: Vec<i64>,
-	// Whether or not the channel is private
-	pub private: bool,
+/// Single-use codes that can stand in for a TOTP code, for when the authenticator is lost
+pub struct RecoveryCodes {
+    pub codes: Vec<String>,
 }
 
 #[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
-/// Object representing a message from a user.
-pub struct Message {
-    pub id: i64,
-    pub channel: i64,
-    pub author: i64,
-    pub content: String,
-	// The (optional) thread associated with the message
-	pub thread: Option<i64>
+/// Where to send a user to log in through the OpenID Connect provider
+pub struct OidcRedirect {
+    // The provider's authorization URL
+    pub url: String,
+    // The state the provider sends the user back with, to pass to `/oidc/callback`
+    pub state: String,
+}
+
+#[derive(Object, Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+/// What the OpenID Connect provider sent the user back with
+pub struct OidcCallback {
+    // The authorization code
+    pub code: String,
+    // The state returned by `/oidc/login`
+    pub state: String,
 }
 
 #[derive(ApiResponse)]
 pub enum LoginResponse {
-	/// Returns a JWT encoding the user's ID and the token expiration date
-	/// (1 day from now) that can be used to authenticate future requests
+	/// Returns a short-lived access token that can be used to authenticate future
+	/// requests, and a refresh token to get new ones with
     #[oai(status = 200)]
-    Success(PlainText<String>),
-    /// User ID not found
+    Success(Json<Tokens>),
+    /// The password was right, but the user has two-factor authentication on: returns a
+    /// challenge token to pass to `/login/totp` along with a code
+    #[oai(status = 202)]
+    TwoFactorRequired(Json<TotpChallenge>),
+    /// No user with that username or email
     #[oai(status = 404)]
     NotFound,
-    /// Incorrect hash provided
+    /// Incorrect password provided
     #[oai(status = 401)]
     Unauthorized,
-    /// Hash provided is of incorrect length
+    /// Password provided is empty or too long
     #[oai(status = 400)]
     BadRequest,
     /// Internal server error when attempting to access database/sign key
@@ -75,6 +126,97 @@ pub enum LoginResponse {
     InternalError(PlainText<String>),
 }
 
+#[derive(ApiResponse)]
+pub enum RefreshResponse {
+    /// Returns a new access token and the refresh token to use next time
+    #[oai(status = 200)]
+    Success(Json<Tokens>),
+    /// The refresh token is invalid, expired, revoked or has already been used
+    #[oai(status = 401)]
+    Unauthorized,
+    /// Internal server error when attempting to access database/sign key
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum TotpLoginResponse {
+    /// Returns a short-lived access token and a refresh token, like `/login`
+    #[oai(status = 200)]
+    Success(Json<Tokens>),
+    /// The challenge token is invalid or expired, or the code is wrong
+    #[oai(status = 401)]
+    Unauthorized,
+    /// Internal server error when attempting to access database/sign key
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum TotpEnrollResponse {
+    /// Returns the new secret
+    #[oai(status = 200)]
+    Success(Json<TotpEnrollment>),
+    /// Two-factor authentication is already on
+    #[oai(status = 409)]
+    Conflict,
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum TotpConfirmResponse {
+    /// Two-factor authentication is now on. Returns the recovery codes, which won't be
+    /// shown again.
+    #[oai(status = 200)]
+    Success(Json<RecoveryCodes>),
+    /// The code is wrong
+    #[oai(status = 401)]
+    Unauthorized,
+    /// No secret to confirm: enroll first
+    #[oai(status = 404)]
+    NotFound,
+    /// Two-factor authentication is already on
+    #[oai(status = 409)]
+    Conflict,
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum OidcStartResponse {
+    /// Returns where to send the user, and the state they'll come back with
+    #[oai(status = 200)]
+    Success(Json<OidcRedirect>),
+    /// OpenID Connect isn't set up
+    #[oai(status = 404)]
+    NotFound,
+    /// Internal server error when attempting to sign the state
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum OidcLoginResponse {
+    /// Returns a short-lived access token and a refresh token, like `/login`
+    #[oai(status = 200)]
+    Success(Json<Tokens>),
+    /// The state is invalid or expired, or the provider refused the code
+    #[oai(status = 401)]
+    Unauthorized,
+    /// OpenID Connect isn't set up
+    #[oai(status = 404)]
+    NotFound,
+    /// The identity is already linked to another user
+    #[oai(status = 409)]
+    Conflict,
+    /// Internal server error when attempting to access database/sign key
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
 #[derive(ApiResponse)]
 pub enum UserResponse {
     /// Returns the user requested.
@@ -88,6 +230,16 @@ pub enum UserResponse {
     InternalError(PlainText<String>),
 }
 
+#[derive(ApiResponse)]
+pub enum EmailStatusResponse {
+    /// Returns your email address and whether it's verified
+    #[oai(status = 200)]
+    Success(Json<EmailStatus>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
 #[derive(ApiResponse)]
 pub enum CreateUserResponse {
     /// Returns the user requested.
@@ -96,6 +248,23 @@ pub enum CreateUserResponse {
     /// Recieved a bad argument when specifying the user. Returns error type, such as:
     /// - found empty string for any of the arguments
     /// - invalid email
+    /// - invalid username: empty or too long once cleaned up, or with an `@` in it
+    #[oai(status = 400)]
+    BadRequest(PlainText<String>),
+    /// The username or email is already taken. Content specifies which.
+    #[oai(status = 409)]
+    Conflict(PlainText<String>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum SearchResponse {
+    /// Returns the users found
+    #[oai(status = 200)]
+    Success(Json<Vec<User>>),
+    /// Empty prefix
     #[oai(status = 400)]
     BadRequest(PlainText<String>),
     /// Internal server error: likely due to a database operation failing
@@ -108,6 +277,9 @@ pub enum DeleteResponse {
     /// The delete operation succeeded
     #[oai(status = 200)]
     Success,
+    /// Invalid request. Content specifies what was wrong with it.
+    #[oai(status = 400)]
+    BadRequest(PlainText<String>),
     /// You are not authorized to perform the action
     #[oai(status = 401)]
     Unauthorized,
@@ -141,7 +313,7 @@ pub enum CreateGroupResponse {
     #[oai(status = 404)]
     NotFound,
     /// Invalid parameter, such as:
-    /// - empty string for name
+    /// - name is empty or too long once cleaned up
     /// - bad string
     #[oai(status = 400)]
     BadRequest(PlainText<String>),
@@ -172,7 +344,7 @@ pub enum CreateChannelResponse {
     #[oai(status = 401)]
     Unauthorized,
     /// Invalid parameter, such as:
-    /// - empty string for name
+    /// - name is empty or too long once cleaned up
     /// - bad string
     #[oai(status = 400)]
     BadRequest(PlainText<String>),
@@ -198,6 +370,9 @@ pub enum GenericResponse {
     /// Invalid ID. Content specifies which of the IDs passed is invalid.
     #[oai(status = 404)]
     NotFound(PlainText<String>),
+    /// Clashes with something that already exists. Content specifies what.
+    #[oai(status = 409)]
+    Conflict(PlainText<String>),
     /// Internal server error: likely due to a database operation failing
     #[oai(status = 500)]
     InternalError(PlainText<String>),
@@ -214,6 +389,9 @@ pub enum MessagesResponse {
     /// Offset or number of messages requested is bad. Content specifies which error occured.
     #[oai(status = 400)]
     BadRequest(PlainText<String>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
 }
 
 #[derive(ApiResponse)]
@@ -224,6 +402,9 @@ pub enum MembersResponse {
     /// Invalid ID
     #[oai(status = 404)]
     NotFound,
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
 }
 
 #[derive(ApiResponse)]
@@ -234,6 +415,9 @@ pub enum GroupsResponse {
     /// Invalid user ID
     #[oai(status = 404)]
     NotFound,
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
 }
 
 #[derive(ApiResponse)]
@@ -248,3 +432,293 @@ pub enum ChannelsResponse {
     #[oai(status = 500)]
     InternalError(PlainText<String>),
 }
+
+#[derive(ApiResponse)]
+pub enum RoleResponse {
+    /// Returns the role
+    #[oai(status = 200)]
+    Success(Json<Role>),
+    /// You are not authorized to perform the action
+    #[oai(status = 401)]
+    Unauthorized,
+    /// Invalid parameter, such as:
+    /// - name is empty or too long once cleaned up
+    #[oai(status = 400)]
+    BadRequest(PlainText<String>),
+    /// Invalid ID. Content specifies which of the IDs passed is invalid.
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum RolesResponse {
+    /// Returns the roles, starting with the group's default role
+    #[oai(status = 200)]
+    Success(Json<Vec<Role>>),
+    /// Invalid ID, or you're not a member of the group
+    #[oai(status = 404)]
+    NotFound,
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum OverridesResponse {
+    /// Returns the channel's permission overrides
+    #[oai(status = 200)]
+    Success(Json<Vec<ChannelOverride>>),
+    /// Invalid ID, or you're not a member of the channel
+    #[oai(status = 404)]
+    NotFound,
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum BansResponse {
+    /// Returns the bans
+    #[oai(status = 200)]
+    Success(Json<Vec<Ban>>),
+    /// You are not authorized to perform the action
+    #[oai(status = 401)]
+    Unauthorized,
+    /// Invalid ID. Content specifies which of the IDs passed is invalid.
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum TimeoutsResponse {
+    /// Returns the timeouts
+    #[oai(status = 200)]
+    Success(Json<Vec<Timeout>>),
+    /// You are not authorized to perform the action
+    #[oai(status = 401)]
+    Unauthorized,
+    /// Invalid ID. Content specifies which of the IDs passed is invalid.
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum InviteResponse {
+    /// Returns the invite
+    #[oai(status = 200)]
+    Success(Json<Invite>),
+    /// Recieved a bad argument.
+    #[oai(status = 400)]
+    BadRequest(PlainText<String>),
+    /// You are not authorized to perform the action
+    #[oai(status = 401)]
+    Unauthorized,
+    /// Invalid ID or code. Content specifies which.
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+    /// Clashes with something that already exists. Content specifies what.
+    #[oai(status = 409)]
+    Conflict(PlainText<String>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum InvitesResponse {
+    /// Returns the invites
+    #[oai(status = 200)]
+    Success(Json<Vec<Invite>>),
+    /// You are not authorized to perform the action
+    #[oai(status = 401)]
+    Unauthorized,
+    /// Invalid ID. Content specifies which of the IDs passed is invalid.
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum DirectoryResponse {
+    /// Returns the groups found
+    #[oai(status = 200)]
+    Success(Json<Vec<GroupListing>>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum JoinRequestResponse {
+    /// Returns the request
+    #[oai(status = 200)]
+    Success(Json<JoinRequest>),
+    /// The group doesn't require approval, so the user joined it: returns the group
+    #[oai(status = 201)]
+    Joined(Json<Group>),
+    /// Recieved a bad argument.
+    #[oai(status = 400)]
+    BadRequest(PlainText<String>),
+    /// Invalid ID. Content specifies which of the IDs passed is invalid.
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+    /// Clashes with something that already exists. Content specifies what.
+    #[oai(status = 409)]
+    Conflict(PlainText<String>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum JoinRequestsResponse {
+    /// Returns the requests, oldest first
+    #[oai(status = 200)]
+    Success(Json<Vec<JoinRequest>>),
+    /// You are not authorized to perform the action
+    #[oai(status = 401)]
+    Unauthorized,
+    /// Invalid ID. Content specifies which of the IDs passed is invalid.
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum AuditLogResponse {
+    /// Returns the audit log entries, newest first
+    #[oai(status = 200)]
+    Success(Json<Vec<AuditEntry>>),
+    /// You are not authorized to perform the action
+    #[oai(status = 401)]
+    Unauthorized,
+    /// Invalid ID. Content specifies which of the IDs passed is invalid.
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum BotsResponse {
+    /// Returns your bots
+    #[oai(status = 200)]
+    Success(Json<Vec<User>>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum ApiTokenResponse {
+    /// Returns the new API token, including the token itself
+    #[oai(status = 200)]
+    Success(Json<ApiToken>),
+    /// Invalid parameter, such as:
+    /// - empty string for name
+    /// - no scopes
+    #[oai(status = 400)]
+    BadRequest(PlainText<String>),
+    /// Invalid ID, or the bot isn't yours. Content specifies which of the IDs is invalid.
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum ApiTokensResponse {
+    /// Returns the bot's API tokens
+    #[oai(status = 200)]
+    Success(Json<Vec<ApiToken>>),
+    /// Invalid bot ID, or the bot isn't yours
+    #[oai(status = 404)]
+    NotFound,
+    /// Internal server error: likely due to a database operation failing
+    #[oai(status = 500)]
+    InternalError(PlainText<String>),
+}
+
+/// Implements `From<DbError>` for a response so handlers can bail out on database errors.
+///
+/// `DbError::NotFound` becomes the response's `NotFound` variant (if it has one), and
+/// everything else becomes `InternalError` with the error message as its content.
+macro_rules! from_db_error {
+    ($resp:ident) => {
+        impl From<DbError> for $resp {
+            fn from(e: DbError) -> Self {
+                $resp::InternalError(PlainText(e.to_string()))
+            }
+        }
+    };
+    ($resp:ident, NotFound) => {
+        impl From<DbError> for $resp {
+            fn from(e: DbError) -> Self {
+                match e {
+                    DbError::NotFound(_) => $resp::NotFound,
+                    e => $resp::InternalError(PlainText(e.to_string())),
+                }
+            }
+        }
+    };
+    ($resp:ident, NotFound(_)) => {
+        impl From<DbError> for $resp {
+            fn from(e: DbError) -> Self {
+                match e {
+                    DbError::NotFound(_) => $resp::NotFound(PlainText(e.to_string())),
+                    e => $resp::InternalError(PlainText(e.to_string())),
+                }
+            }
+        }
+    };
+}
+
+from_db_error!(LoginResponse, NotFound);
+from_db_error!(RefreshResponse);
+from_db_error!(TotpLoginResponse);
+from_db_error!(OidcLoginResponse);
+from_db_error!(TotpEnrollResponse);
+from_db_error!(TotpConfirmResponse, NotFound);
+from_db_error!(UserResponse, NotFound);
+from_db_error!(CreateUserResponse);
+from_db_error!(EmailStatusResponse);
+from_db_error!(SearchResponse);
+from_db_error!(DeleteResponse, NotFound(_));
+from_db_error!(GroupResponse, NotFound);
+from_db_error!(CreateGroupResponse, NotFound);
+from_db_error!(ChannelResponse, NotFound);
+from_db_error!(CreateChannelResponse, NotFound(_));
+from_db_error!(GenericResponse, NotFound(_));
+from_db_error!(MessagesResponse, NotFound(_));
+from_db_error!(MembersResponse, NotFound);
+from_db_error!(GroupsResponse, NotFound);
+from_db_error!(ChannelsResponse, NotFound);
+from_db_error!(RoleResponse, NotFound(_));
+from_db_error!(RolesResponse, NotFound);
+from_db_error!(OverridesResponse, NotFound);
+from_db_error!(BansResponse, NotFound(_));
+from_db_error!(TimeoutsResponse, NotFound(_));
+from_db_error!(AuditLogResponse, NotFound(_));
+from_db_error!(InviteResponse, NotFound(_));
+from_db_error!(InvitesResponse, NotFound(_));
+from_db_error!(DirectoryResponse);
+from_db_error!(JoinRequestResponse, NotFound(_));
+from_db_error!(JoinRequestsResponse, NotFound(_));
+from_db_error!(BotsResponse);
+from_db_error!(ApiTokenResponse, NotFound(_));
+from_db_error!(ApiTokensResponse, NotFound);
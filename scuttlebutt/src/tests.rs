@@ -1,6 +1,5 @@
 use super::*;
 
-use hmac::Mac;
 use more_asserts::*;
 use poem::{
     http::StatusCode,
@@ -9,26 +8,81 @@ use poem::{
     Route,
 };
 use pretty_assertions::assert_eq;
-use sha2::Digest;
+use sha2::{Digest, Sha256};
 
-type FakeClient = TestClient<AddDataEndpoint<Route, ServerKey>>;
+type FakeClient = TestClient<AddDataEndpoint<AddDataEndpoint<Route, ServerKeys>, Arc<dyn Database>>>;
 
 fn contents_eq<T: PartialEq>(a: Vec<T>, b: Vec<T>) -> bool {
     b.iter().all(|item| a.contains(item))
 }
 
-fn setup() -> FakeClient {
+/// Set up a client along with a handle to the database behind it for whitebox tests
+fn setup_with_db() -> (FakeClient, InMemory) {
+    setup_with_oidc(None)
+}
+
+/// `setup_with_db`, with users able to log in through an OpenID Connect provider
+fn setup_with_oidc(provider: Option<Provider>) -> (FakeClient, InMemory) {
+    setup_with_api(|api| match provider {
+        Some(provider) => api.with_oidc(provider),
+        None => api,
+    })
+}
+
+/// `setup_with_db`, with emails going to a mailbox the test can read them back from
+fn setup_with_mailbox() -> (FakeClient, InMemory, Mailbox) {
+    let path = std::env::temp_dir().join(format!("bsk-test-mail-{}.jsonl", gen_id()));
+    let mailbox = Mailbox { mailer: Arc::new(mailer::FileMailer::new(&path)), path };
+    let mailer = mailbox.mailer.clone();
+    let (cli, db) = setup_with_api(|api| api.with_mailer(mailer));
+    (cli, db, mailbox)
+}
+
+/// `setup_with_db`, with the API configured by `configure`
+fn setup_with_api(configure: impl FnOnce(Api) -> Api) -> (FakeClient, InMemory) {
     let key: String = rand::thread_rng()
         .sample_iter(&Alphanumeric)
         .take(7)
         .map(char::from)
         .collect();
-    let db = Box::new(Cassandra::new("test"));
-    let api_service = OpenApiService::new(Api::new(db), "Scuttlebutt", "1.0").server("http://localhost:3000/api");
+    let db = InMemory::new();
+    let shared: Arc<dyn Database> = Arc::new(db.clone());
+    let api = configure(Api::new(shared.clone()));
+    let api_service = OpenApiService::new(api, "Scuttlebutt", "1.0").server("http://localhost:3000/api");
     let app = Route::new()
         .nest("/api", api_service)
-        .data(ServerKey::new_from_slice(&key.as_bytes()).unwrap());
-    TestClient::new(app)
+        .data(ServerKeys::new("test", key.as_bytes()))
+        .data(shared);
+    (TestClient::new(app), db)
+}
+
+fn setup() -> FakeClient {
+    setup_with_db().0
+}
+
+/// The emails sent during a test, which are deleted along with it
+struct Mailbox {
+    mailer: Arc<mailer::FileMailer>,
+    path: std::path::PathBuf,
+}
+
+impl Mailbox {
+    fn emails(&self) -> Vec<mailer::Email> {
+        self.mailer.read().unwrap()
+    }
+
+    /// The token in the last email sent to an address
+    fn last_token(&self, to: &str) -> String {
+        let emails = self.emails();
+        let email = emails.iter().rev().find(|email| email.to == to).unwrap();
+        email::token_in(email).unwrap().to_string()
+    }
+}
+
+impl Drop for Mailbox {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
 }
 
 fn hash_pass(pass: &str) -> String {
@@ -38,30 +92,38 @@ fn hash_pass(pass: &str) -> String {
 }
 
 async fn make_user(cli: &FakeClient, name: &str, email: &str, pass: &str) -> User {
-    let hash = hash_pass(pass);
     let resp = cli.post(format!("/api/user?name={}&email={}", name, email))
-        .content_type("text/plain").body(hash).send().await;
+        .content_type("text/plain").body(pass.to_string()).send().await;
     resp.assert_status_is_ok();
     resp.json().await.value().deserialize::<User>()
 }
 
-async fn login(cli: &FakeClient, id: i64, pass: &str) -> String {
-    let hash = hash_pass(pass);
-    let mut resp = cli.post(format!("/api/login?id={}", id)).content_type("text/plain").body(hash).send().await;
+/// Log in with a username or email
+async fn login_tokens(cli: &FakeClient, user: &str, pass: &str) -> Tokens {
+    let resp = cli.post(format!("/api/login?user={}", user)).content_type("text/plain").body(pass.to_string()).send().await;
     resp.assert_status_is_ok();
-    resp.0.take_body().into_string().await.unwrap()
+    resp.json().await.value().deserialize::<Tokens>()
+}
+
+async fn login(cli: &FakeClient, user: &str, pass: &str) -> String {
+    login_tokens(cli, user, pass).await.access_token
 }
 
 async fn user_auth(cli: &FakeClient, name: &str, email: &str, pass: &str) -> (User, String) {
-    let user = make_user(&cli, name, email, pass).await;    
-    let auth = login(&cli, user.id, pass).await;
+    let user = make_user(cli, name, email, pass).await;    
+    let auth = login(cli, &user.username, pass).await;
     (user, auth)
 }
 
-async fn setup_user_auth() -> (FakeClient, User) {
-    let cli = setup();
+async fn setup_user_auth_with_db() -> (FakeClient, User, InMemory) {
+    let (cli, db) = setup_with_db();
     let (user, auth) = user_auth(&cli, "test", "test@example.com", "12345").await;
     let cli = cli.default_header("Authorization", &auth);
+    (cli, user, db)
+}
+
+async fn setup_user_auth() -> (FakeClient, User) {
+    let (cli, user, _db) = setup_user_auth_with_db().await;
     (cli, user)
 }
 
@@ -82,12 +144,6 @@ async fn make_dm(cli: &FakeClient, uid: i64) -> Group {
     resp.json().await.value().deserialize::<Group>()
 }
 
-async fn find_groups(cli: &FakeClient) -> Vec<Group> {
-    let resp = cli.post("/api/user/groups").send().await;
-    resp.assert_status_is_ok();
-    resp.json().await.value().deserialize::<Vec<Group>>()
-}
-
 async fn make_channel(cli: &FakeClient, gid: i64, name: &str) -> Channel {
     let resp = cli.post(format!("/api/group/channels?gid={}&name={}", gid, name)).send().await;
     resp.assert_status_is_ok();
@@ -110,28 +166,32 @@ async fn find_group(cli: &FakeClient, id: i64) -> Group {
 async fn post_login() {
     let cli = setup();
     let user = make_user(&cli, "test", "test@example.com", "12345").await;
-    let hash = hash_pass("12345");
+    let pass = "12345".to_string();
 
-    let resp = cli.post(format!("/api/login?id={}", user.id))
-        .content_type("text/plain").body("abc").send().await;
+    let resp = cli.post(format!("/api/login?user={}", user.username))
+        .content_type("text/plain").body("a".repeat(password::MAX_PASSWORD_LEN + 1)).send().await;
     resp.assert_status(StatusCode::BAD_REQUEST);
-    let resp = cli.post(format!("/api/login?id={}", user.id))
+    let resp = cli.post(format!("/api/login?user={}", user.username))
         .content_type("text/plain").send().await;
     resp.assert_status(StatusCode::BAD_REQUEST);
 
-    let resp = cli.post("/api/login?id=12")
-        .content_type("text/plain").body(hash.clone()).send().await;
+    let resp = cli.post("/api/login?user=nobody")
+        .content_type("text/plain").body(pass.clone()).send().await;
     resp.assert_status(StatusCode::NOT_FOUND);
 
-    let resp = cli.post(format!("/api/login?id={}", user.id))
+    let resp = cli.post(format!("/api/login?user={}", user.username))
         .header::<&str, &str>("Authorization", "")
-        .content_type("text/plain").body(hash_pass("123")).send().await;
+        .content_type("text/plain").body("123").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    // Clients used to send the SHA-256 of the password; that's no longer the password
+    let resp = cli.post(format!("/api/login?user={}", user.username))
+        .content_type("text/plain").body(hash_pass("12345")).send().await;
     resp.assert_status(StatusCode::UNAUTHORIZED);
 
-    let mut resp = cli.post(format!("/api/login?id={}", user.id))
-        .content_type("text/plain").body(hash.clone()).send().await;
+    let resp = cli.post(format!("/api/login?user={}", user.username))
+        .content_type("text/plain").body(pass.clone()).send().await;
     resp.assert_status_is_ok();
-    let raw_str = resp.0.take_body().into_string().await.unwrap();
+    let raw_str = resp.json().await.value().deserialize::<Tokens>().access_token;
     let claims: Claims = serde_json::from_str(&String::from_utf8(base64::decode(
         raw_str.split(".").nth(1).unwrap()
     ).unwrap()).unwrap()).unwrap();
@@ -139,6 +199,213 @@ async fn post_login() {
     assert_ge!(claims.exp, Local::now())
 }
 
+#[tokio::test]
+async fn post_refresh() {
+    let cli = setup();
+    let user = make_user(&cli, "test", "test@example.com", "12345").await;
+    let tokens = login_tokens(&cli, &user.username, "12345").await;
+
+    for bad in ["", "abc", "12.abc", &format!("{}x", tokens.refresh_token)] {
+        let resp = cli.post("/api/refresh").content_type("text/plain").body(bad.to_string()).send().await;
+        resp.assert_status(StatusCode::UNAUTHORIZED);
+    }
+    // A wrong secret ends the session, so start a fresh one
+    let tokens = login_tokens(&cli, &user.username, "12345").await;
+
+    let resp = cli.post("/api/refresh").content_type("text/plain").body(tokens.refresh_token.clone()).send().await;
+    resp.assert_status_is_ok();
+    let new_tokens = resp.json().await.value().deserialize::<Tokens>();
+    assert_ne!(new_tokens.refresh_token, tokens.refresh_token);
+    let resp = cli.get("/api/user/groups").header("Authorization", &new_tokens.access_token).send().await;
+    resp.assert_status_is_ok();
+
+    // Reusing a refresh token ends the session it belongs to
+    let resp = cli.post("/api/refresh").content_type("text/plain").body(tokens.refresh_token).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.post("/api/refresh").content_type("text/plain").body(new_tokens.refresh_token).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.get("/api/user/groups").header("Authorization", &new_tokens.access_token).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn post_logout() {
+    let cli = setup();
+    let user = make_user(&cli, "test", "test@example.com", "12345").await;
+    let first = login_tokens(&cli, &user.username, "12345").await;
+    let second = login_tokens(&cli, &user.username, "12345").await;
+    let third = login_tokens(&cli, &user.username, "12345").await;
+
+    let resp = cli.post("/api/logout").header("Authorization", &first.access_token).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.get("/api/user/groups").header("Authorization", &first.access_token).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.post("/api/refresh").content_type("text/plain").body(first.refresh_token).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    // Other sessions are untouched...
+    let resp = cli.get("/api/user/groups").header("Authorization", &second.access_token).send().await;
+    resp.assert_status_is_ok();
+
+    // ...unless logging out of all of them
+    let resp = cli.post("/api/logout?all=true").header("Authorization", &second.access_token).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.get("/api/user/groups").header("Authorization", &third.access_token).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn put_password() {
+    let cli = setup();
+    let user = make_user(&cli, "test", "test@example.com", "12345").await;
+    let auth = login(&cli, &user.username, "12345").await;
+
+    let change = |old: &str, new: &str| PasswordChange { old: old.to_string(), new: new.to_string() };
+    let resp = cli.put("/api/user/password").header("Authorization", &auth)
+        .body_json(&change("wrong", "54321")).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.put("/api/user/password").header("Authorization", &auth)
+        .body_json(&change("12345", "")).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+
+    let resp = cli.put("/api/user/password").header("Authorization", &auth)
+        .body_json(&change("12345", "54321")).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.get("/api/user/groups").header("Authorization", &auth).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    let resp = cli.post(format!("/api/login?user={}", user.username))
+        .content_type("text/plain").body("12345").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    login(&cli, &user.username, "54321").await;
+}
+
+/// Start a stand-in OpenID Connect provider on a local port, serving its discovery
+/// document and token endpoint. Its authorization codes are just the (base64) claims of
+/// the ID token to hand out for them; see `mock_code`.
+async fn mock_provider() -> OidcConfig {
+    use poem::{get, handler, listener::{Acceptor, Listener}, post, web::{self, Form}, IntoResponse, Response};
+
+    #[handler]
+    fn discovery(issuer: web::Data<&String>) -> web::Json<serde_json::Value> {
+        web::Json(serde_json::json!({
+            "issuer": issuer.0,
+            "authorization_endpoint": format!("{}/authorize", issuer.0),
+            "token_endpoint": format!("{}/token", issuer.0),
+        }))
+    }
+
+    #[handler]
+    fn token(req: &Request, Form(params): Form<std::collections::HashMap<String, String>>) -> Response {
+        let expected = format!("Basic {}", base64::encode("bsk:secret"));
+        if req.header("Authorization") != Some(expected.as_str()) || params["grant_type"] != "authorization_code" {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        web::Json(serde_json::json!({
+            "access_token": "unused",
+            "token_type": "Bearer",
+            "id_token": format!("eyJhbGciOiJub25lIn0.{}.", params["code"]),
+        })).into_response()
+    }
+
+    let acceptor = TcpListener::bind("127.0.0.1:0").into_acceptor().await.unwrap();
+    let addr = *acceptor.local_addr()[0].as_socket_addr().unwrap();
+    let issuer = format!("http://{addr}");
+    let app = Route::new()
+        .at("/.well-known/openid-configuration", get(discovery))
+        .at("/token", post(token))
+        .data(issuer.clone());
+    tokio::spawn(Server::new_with_acceptor(acceptor).run(app));
+    OidcConfig {
+        issuer,
+        client_id: "bsk".to_string(),
+        client_secret: "secret".to_string(),
+        redirect_uri: "https://app.example.com/callback".to_string(),
+    }
+}
+
+/// An authorization code `mock_provider` will trade for an ID token for `sub`
+fn mock_code(config: &OidcConfig, sub: &str, nonce: &str) -> String {
+    let claims = serde_json::json!({
+        "iss": config.issuer,
+        "sub": sub,
+        "aud": config.client_id,
+        "exp": Utc::now().timestamp() + 60,
+        "nonce": nonce,
+        "email": format!("{sub}@example.com"),
+        "email_verified": true,
+    });
+    base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Start an OpenID Connect login, returning its state and the nonce the provider was sent
+async fn start_oidc(cli: &FakeClient, path: &str) -> (String, String) {
+    let resp = if path == "/api/oidc/login" { cli.get(path) } else { cli.post(path) }.send().await;
+    resp.assert_status_is_ok();
+    let redirect = resp.json().await.value().deserialize::<OidcRedirect>();
+    let url = reqwest::Url::parse(&redirect.url).unwrap();
+    let param = |name: &str| url.query_pairs().find(|(k, _)| k == name).unwrap().1.to_string();
+    assert_eq!(param("state"), redirect.state);
+    assert_eq!(param("client_id"), "bsk");
+    (redirect.state, param("nonce"))
+}
+
+#[tokio::test]
+async fn oidc_flow() {
+    let config = mock_provider().await;
+    let (cli, db) = setup_with_oidc(Some(Provider::discover(config.clone()).await.unwrap()));
+    let identity = |sub: &str| Identity { issuer: config.issuer.clone(), subject: sub.to_string() };
+
+    // The first login makes a user
+    let (state, nonce) = start_oidc(&cli, "/api/oidc/login").await;
+    let callback = OidcCallback { code: mock_code(&config, "alice", &nonce), state };
+    let resp = cli.post("/api/oidc/callback").body_json(&callback).send().await;
+    resp.assert_status_is_ok();
+    let tokens = resp.json().await.value().deserialize::<Tokens>();
+    let resp = cli.get("/api/user/groups").header("Authorization", &tokens.access_token).send().await;
+    resp.assert_status_is_ok();
+    let alice = db.get_identity_user(identity("alice")).await.unwrap();
+    let resp = cli.get(format!("/api/user?id={}", alice)).send().await;
+    resp.assert_json(User { id: alice, username: "alice".to_string(), email: "alice@example.com".to_string() }).await;
+    // The provider vouches for their email
+    assert_eq!(db.get_verified_email(alice).await.unwrap(), Some("alice@example.com".to_string()));
+
+    // Later ones log in as it
+    let (state, nonce) = start_oidc(&cli, "/api/oidc/login").await;
+    let callback = OidcCallback { code: mock_code(&config, "alice", &nonce), state };
+    let resp = cli.post("/api/oidc/callback").body_json(&callback).send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(db.get_user_identities(alice).await.unwrap(), vec![identity("alice")]);
+
+    // ID tokens only work for the login they were issued for
+    let (state, _) = start_oidc(&cli, "/api/oidc/login").await;
+    let resp = cli.post("/api/oidc/callback").body_json(&OidcCallback { code: mock_code(&config, "alice", &nonce), state }).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.post("/api/oidc/callback").body_json(&OidcCallback { code: mock_code(&config, "alice", &nonce), state: "forged".to_string() }).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    // Existing users can link an identity, and then log in with it
+    let (bob, auth) = user_auth(&cli, "bob", "bob@example.com", "12345").await;
+    let cli = cli.default_header("Authorization", &auth);
+    let (state, nonce) = start_oidc(&cli, "/api/user/oidc").await;
+    let resp = cli.post("/api/oidc/callback").body_json(&OidcCallback { code: mock_code(&config, "bob", &nonce), state }).send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(db.get_identity_user(identity("bob")).await.unwrap(), bob.id);
+
+    // But not one that's someone else's
+    let (state, nonce) = start_oidc(&cli, "/api/user/oidc").await;
+    let resp = cli.post("/api/oidc/callback").body_json(&OidcCallback { code: mock_code(&config, "alice", &nonce), state }).send().await;
+    resp.assert_status(StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn oidc_disabled() {
+    let cli = setup();
+    let resp = cli.get("/api/oidc/login").send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.post("/api/oidc/callback").body_json(&OidcCallback { code: "a".to_string(), state: "b".to_string() }).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+}
+
 #[tokio::test]
 async fn get_user() {
     let cli = setup();
@@ -151,209 +418,1293 @@ async fn get_user() {
     assert_eq!(user, ret_user);
 }
 
-#[tokio::test]
-async fn post_user() {
-    let cli = setup();
-    let user = make_user(&cli, "test", "test@example.com", "12345").await;
+#[tokio::test]
+async fn totp_flow() {
+    let (cli, user) = setup_user_auth().await;
+    let login_resp = |pass: &'static str| cli.post(format!("/api/login?user={}", user.username))
+        .content_type("text/plain").body(pass).send();
+
+    let resp = cli.post("/api/user/totp").send().await;
+    resp.assert_status_is_ok();
+    let enrollment = resp.json().await.value().deserialize::<TotpEnrollment>();
+    assert!(enrollment.uri.contains(&enrollment.secret));
+    // Not on until it's confirmed
+    login_resp("12345").await.assert_status_is_ok();
+
+    let now = Utc::now().timestamp();
+    let resp = cli.put("/api/user/totp").content_type("text/plain").body("000000").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.put("/api/user/totp").content_type("text/plain")
+        .body(totp::generate_code(&enrollment.secret, now).unwrap()).send().await;
+    resp.assert_status_is_ok();
+    let recovery = resp.json().await.value().deserialize::<RecoveryCodes>().codes;
+    assert_eq!(recovery.len(), totp::RECOVERY_CODES);
+    cli.post("/api/user/totp").send().await.assert_status(StatusCode::CONFLICT);
+
+    // Logging in now takes two steps
+    let resp = login_resp("12345").await;
+    resp.assert_status(StatusCode::ACCEPTED);
+    let challenge = resp.json().await.value().deserialize::<TotpChallenge>().challenge_token;
+    let second_step = |token: &str, code: &str| cli.post("/api/login/totp")
+        .body_json(&TotpLogin { challenge_token: token.to_string(), code: code.to_string() }).send();
+
+    second_step(&challenge, "000000").await.assert_status(StatusCode::UNAUTHORIZED);
+    let code = totp::generate_code(&enrollment.secret, now + totp::STEP).unwrap();
+    let resp = second_step(&challenge, &code).await;
+    resp.assert_status_is_ok();
+    let tokens = resp.json().await.value().deserialize::<Tokens>();
+    let resp = cli.get("/api/user/groups").header("Authorization", &tokens.access_token).send().await;
+    resp.assert_status_is_ok();
+    // Challenge tokens aren't access tokens, and vice versa
+    let resp = cli.get("/api/user/groups").header("Authorization", &challenge).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    second_step(&tokens.access_token, &recovery[3]).await.assert_status(StatusCode::UNAUTHORIZED);
+
+    // Codes and recovery codes only work once
+    second_step(&challenge, &code).await.assert_status(StatusCode::UNAUTHORIZED);
+    second_step(&challenge, &recovery[0].to_uppercase()).await.assert_status_is_ok();
+    second_step(&challenge, &recovery[0]).await.assert_status(StatusCode::UNAUTHORIZED);
+
+    let resp = cli.delete("/api/user/totp").content_type("text/plain").body("000000").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.delete("/api/user/totp").content_type("text/plain").body(recovery[1].clone()).send().await;
+    resp.assert_status_is_ok();
+    login_resp("12345").await.assert_status_is_ok();
+    let resp = cli.delete("/api/user/totp").content_type("text/plain").body(recovery[2].clone()).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn post_user() {
+    let cli = setup();
+    let user = make_user(&cli, "test", "test@example.com", "12345").await;
+
+    assert_eq!(user.email, "test@example.com");
+    assert_eq!(user.username, "test");
+
+    let resp = cli.get(format!("/api/user?id={}", user.id)).send().await;
+    resp.assert_status_is_ok();
+
+    let same_user = resp.json().await.value().deserialize::<User>();
+    assert_eq!(user, same_user);
+}
+
+#[tokio::test]
+async fn post_user_whitebox() {
+    let (cli, db) = setup_with_db();
+    let user = make_user(&cli, "test", "test@example.com", "12345").await;
+    assert_eq!(db.get_user(user.id).await.unwrap(), user);
+    assert_eq!(db.get_user_groups(user.id).await.unwrap(), Vec::<i64>::new());
+    assert!(db.get_user_hash(user.id).await.unwrap().starts_with("$argon2id$"));
+
+    let resp = cli.post("/api/user?name=test2&email=test2@example.com")
+        .content_type("text/plain").send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+
+    let resp = cli.post("/api/user?name=test2&email=test2")
+        .content_type("text/plain").body("12345").send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn names_cleaned() {
+    let (cli, _db) = setup_with_api(|api| api.with_name_limits(NameLimits { user: 8, other: 12 }));
+    // A zero-width space, a right-to-left override and a combining accent, padded
+    let user = make_user(&cli, "%20fr%E2%80%8Bed%E2%80%AEe%CC%81%09", "fred@example.com", "12345").await;
+    assert_eq!(user.username, "fred\u{e9}");
+    let auth = login(&cli, "fred%C3%A9", "12345").await;
+    let cli = cli.default_header("Authorization", &auth);
+    let resp = cli.get(format!("/api/user?id={}", user.id)).send().await;
+    assert_eq!(resp.json().await.value().deserialize::<User>().username, "fred\u{e9}");
+
+    for (name, reason) in [
+        ("%E2%80%8B%20", "username can't be empty"),
+        ("abcdefghi", "username can't be longer than 8 characters"),
+        ("a@b", "username can't contain @"),
+    ] {
+        let resp = cli.put(format!("/api/user?name={name}&email=fred@example.com")).send().await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+        resp.assert_text(reason).await;
+    }
+    // Longer than the limit, until the padding goes
+    let resp = cli.put("/api/user?name=%20%20fred%20%20%20&email=fred@example.com").send().await;
+    resp.assert_status_is_ok();
+
+    let group = make_group(&cli, "%E2%80%8E%20the%09group").await;
+    assert_eq!(group.name, "the group");
+    let resp = cli.post("/api/group?name=p%D0%B0ypal").send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    resp.assert_text("group name can't mix lookalike letters from different scripts").await;
+    let resp = cli.post("/api/group?name=a%20very%20long%20group").send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    resp.assert_text("group name can't be longer than 12 characters").await;
+
+    let resp = cli.put(format!("/api/channel?id={}&name=%E2%81%A0", group.channels[0])).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    resp.assert_text("channel name can't be empty").await;
+    let resp = cli.put(format!("/api/channel?id={}&name=general%20", group.channels[0])).send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(find_channel(&cli, group.channels[0]).await.name, "general");
+}
+
+#[tokio::test]
+async fn post_user_taken() {
+    let cli = setup();
+    let user = make_user(&cli, "test", "test@example.com", "12345").await;
+
+    let resp = cli.post("/api/user?name=TEST&email=other@example.com")
+        .content_type("text/plain").body("12345").send().await;
+    resp.assert_status(StatusCode::CONFLICT);
+    resp.assert_text("username TEST is already taken, or looks too much like one that is").await;
+    // Lookalikes are taken too, and names passing off one script as another aren't allowed
+    make_user(&cli, "paypal", "paypal@example.com", "12345").await;
+    for name in ["PAYPA1", "paypa1"] {
+        let resp = cli.post(format!("/api/user?name={name}&email=other@example.com"))
+            .content_type("text/plain").body("12345").send().await;
+        resp.assert_status(StatusCode::CONFLICT);
+    }
+    let resp = cli.post("/api/user?name=p%D0%B0ypal&email=other@example.com")
+        .content_type("text/plain").body("12345").send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    resp.assert_text("username can't mix lookalike letters from different scripts").await;
+    let resp = cli.post("/api/user?name=other&email=Test@Example.com")
+        .content_type("text/plain").body("12345").send().await;
+    resp.assert_status(StatusCode::CONFLICT);
+    let resp = cli.post("/api/user?name=a@b&email=other@example.com")
+        .content_type("text/plain").body("12345").send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+
+    // Either can be used to log in, in any case
+    for name in ["TeSt", "TEST@example.com"] {
+        let auth = login(&cli, name, "12345").await;
+        let resp = cli.get("/api/user/email").header("Authorization", &auth).send().await;
+        resp.assert_status_is_ok();
+    }
+    let resp = cli.post("/api/login?user=nobody@example.com")
+        .content_type("text/plain").body("12345").send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+
+    // Once the user is gone, so are their claims
+    let auth = login(&cli, "test", "12345").await;
+    let resp = cli.delete(format!("/api/user?id={}", user.id)).header("Authorization", &auth).send().await;
+    resp.assert_status_is_ok();
+    make_user(&cli, "Test", "test@example.com", "12345").await;
+}
+
+#[tokio::test]
+async fn search_users() {
+    let (cli, _user) = setup_user_auth().await;
+    for name in ["fred", "Freda", "frank", "george"] {
+        make_user(&cli, name, &format!("{name}@example.com"), "12345").await;
+    }
+
+    let search = |query: &'static str| {
+        let cli = &cli;
+        async move {
+            let resp = cli.get(format!("/api/user/search?{query}")).send().await;
+            resp.assert_status_is_ok();
+            resp.json().await.value().deserialize::<Vec<User>>()
+                .into_iter()
+                .map(|user| user.username)
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(search("prefix=FRE").await, vec!["fred", "Freda"]);
+    assert_eq!(search("prefix=fr&limit=2").await, vec!["frank", "fred"]);
+    assert_eq!(search("prefix=x").await, Vec::<String>::new());
+
+    let resp = cli.get("/api/user/search?prefix=").send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.get("/api/user/search?prefix=fr")
+        .header::<&str, &str>("Authorization", "").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn email_verification() {
+    let (cli, db, mailbox) = setup_with_mailbox();
+    let (user, auth) = user_auth(&cli, "test", "test@example.com", "12345").await;
+    let cli = cli.default_header("Authorization", &auth);
+    let status = |email: &str, verified: bool| EmailStatus { email: email.to_string(), verified };
+    cli.get("/api/user/email").send().await.assert_json(status("test@example.com", false)).await;
+
+    // Signing up mails a token, which verifies the address
+    let token = mailbox.last_token("test@example.com");
+    let resp = cli.post("/api/user/email/verification").content_type("text/plain").body("forged").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.post("/api/user/email/verification").content_type("text/plain").body(token.clone()).send().await;
+    resp.assert_status_is_ok();
+    cli.get("/api/user/email").send().await.assert_json(status("test@example.com", true)).await;
+    let resp = cli.post("/api/user/email/verify").send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+
+    // Changing it unverifies it, and old tokens don't verify the new one
+    let resp = cli.put("/api/user?name=test&email=new@example.com").send().await;
+    resp.assert_status_is_ok();
+    cli.get("/api/user/email").send().await.assert_json(status("new@example.com", false)).await;
+    let resp = cli.post("/api/user/email/verification").content_type("text/plain").body(token).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.put("/api/user?name=test&email=not-an-email").send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+
+    // Tokens can be sent again
+    let sent = mailbox.emails().len();
+    let resp = cli.post("/api/user/email/verify").send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(mailbox.emails().len(), sent + 1);
+    let resp = cli.post("/api/user/email/verification").content_type("text/plain")
+        .body(mailbox.last_token("new@example.com")).send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(db.get_verified_email(user.id).await.unwrap(), Some("new@example.com".to_string()));
+}
+
+#[tokio::test]
+async fn password_reset() {
+    let (cli, _db, mailbox) = setup_with_mailbox();
+    let (user, auth) = user_auth(&cli, "test", "test@example.com", "12345").await;
+    let reset = |token: &str, password: &str| PasswordReset { token: token.to_string(), password: password.to_string() };
+
+    // Asking for unknown users looks the same, but sends nothing
+    let sent = mailbox.emails().len();
+    let resp = cli.post("/api/password/reset?user=nobody").send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(mailbox.emails().len(), sent);
+
+    let resp = cli.post(format!("/api/password/reset?user={}", user.username)).send().await;
+    resp.assert_status_is_ok();
+    let token = mailbox.last_token("test@example.com");
+    assert_eq!(mailbox.emails().last().unwrap().subject, "Reset your password");
+    // Verification tokens aren't reset tokens
+    let verification = mailbox.emails()[0].clone();
+    let resp = cli.put("/api/password/reset").body_json(&reset(email::token_in(&verification).unwrap(), "54321")).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.put("/api/password/reset").body_json(&reset(&token, "")).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+
+    let resp = cli.put("/api/password/reset").body_json(&reset(&token, "54321")).send().await;
+    resp.assert_status_is_ok();
+    login(&cli, &user.username, "54321").await;
+    let resp = cli.post(format!("/api/login?user={}", user.username))
+        .content_type("text/plain").body("12345").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    // It ends all sessions
+    let resp = cli.get("/api/user/groups").header("Authorization", &auth).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    // And only works once
+    let resp = cli.put("/api/password/reset").body_json(&reset(&token, "abcde")).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    login(&cli, &user.username, "54321").await;
+}
+
+#[tokio::test]
+async fn post_login_legacy_hash() {
+    let (cli, db) = setup_with_db();
+    let id = gen_id();
+    db.create_user(id, "old".into(), "old@example.com".into(), hash_pass("12345")).await.unwrap();
+    db.create_user_groups(id).await.unwrap();
+    db.create_user_dms(id).await.unwrap();
+
+    // The stored hash is never accepted as the password itself
+    let resp = cli.post("/api/login?user=old")
+        .content_type("text/plain").body(hash_pass("12345")).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    assert_eq!(db.get_user_hash(id).await.unwrap(), hash_pass("12345"));
+
+    login(&cli, "old", "12345").await;
+    let upgraded = db.get_user_hash(id).await.unwrap();
+    assert!(upgraded.starts_with("$argon2id$"));
+    login(&cli, "old", "12345").await;
+    assert_eq!(db.get_user_hash(id).await.unwrap(), upgraded);
+}
+
+#[tokio::test]
+async fn get_groups_missing_row() {
+    let (cli, user, db) = setup_user_auth_with_db().await;
+    db.delete_user_groups(user.id).await.unwrap();
+    // The handler should report the missing row instead of panicking
+    let resp = cli.get("/api/user/groups").send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn put_user() {
+    let (cli, user) = setup_user_auth().await;
+
+    let resp = cli.put("/api/user?name=fred&email=whoo@whee.com")
+        .header::<&str, &str>("Authorization", "").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    let resp = cli.put("/api/user?name=fred&email=whoo@whee.com").send().await;
+    resp.assert_status_is_ok();
+
+    let resp = cli.get(format!("/api/user?id={}", user.id)).send().await;
+    resp.assert_status_is_ok();
+    let ret_user = resp.json().await.value().deserialize::<User>();
+    assert_eq!(ret_user.email, "whoo@whee.com");
+    assert_eq!(ret_user.username, "fred");
+    // User should retain their underlying ID
+    assert_eq!(user.id, ret_user.id);
+}
+
+#[tokio::test]
+async fn put_user_taken() {
+    let (cli, _user) = setup_user_auth().await;
+    make_user(&cli, "fred", "fred@example.com", "12345").await;
+
+    let resp = cli.put("/api/user?name=Fred&email=test@example.com").send().await;
+    resp.assert_status(StatusCode::CONFLICT);
+    let resp = cli.put("/api/user?name=test&email=FRED@example.com").send().await;
+    resp.assert_status(StatusCode::CONFLICT);
+    let resp = cli.put("/api/user?name=fred@example.com&email=test@example.com").send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    // Nor to a lookalike of someone else's
+    make_user(&cli, "mike", "mike@example.com", "12345").await;
+    let resp = cli.put("/api/user?name=rnike&email=test@example.com").send().await;
+    resp.assert_status(StatusCode::CONFLICT);
+
+    // Changing the case of your own name is fine, and frees up the old one
+    let resp = cli.put("/api/user?name=Test&email=new@example.com").send().await;
+    resp.assert_status_is_ok();
+    login(&cli, "test", "12345").await;
+    login(&cli, "new@example.com", "12345").await;
+    make_user(&cli, "other", "test@example.com", "12345").await;
+}
+
+#[tokio::test]
+/// FIXME non exhaustive
+async fn del_user() {
+    let (cli, user) = setup_user_auth().await;
+
+    let resp = cli.delete(format!("/api/user?id={}", user.id))
+        .header::<&str, &str>("Authorization", "").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    let resp = cli.delete(format!("/api/user?id={}", user.id)).send().await;
+
+    resp.assert_status_is_ok();
+    let resp = cli.get(format!("/api/user?id={}", user.id)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    // The deleted user's sessions end with it
+    let resp = cli.get("/api/user/groups").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+async fn make_bot(cli: &FakeClient, name: &str) -> User {
+    let resp = cli.post(format!("/api/bot?name={}", name)).send().await;
+    resp.assert_status_is_ok();
+    resp.json().await.value().deserialize::<User>()
+}
+
+async fn make_api_token(cli: &FakeClient, bot: i64, scopes: &[Scope]) -> String {
+    let resp = cli.post(format!("/api/bot/tokens?bot={}&name=token", bot)).body_json(&scopes).send().await;
+    resp.assert_status_is_ok();
+    resp.json().await.value().deserialize::<ApiToken>().token.unwrap()
+}
+
+#[tokio::test]
+async fn bot_flow() {
+    let (cli, user) = setup_user_auth().await;
+    let group = make_group(&cli, "group").await;
+    let bot = make_bot(&cli, "robot").await;
+    let resp = cli.get("/api/bots").send().await;
+    resp.assert_status_is_ok();
+    resp.assert_json(vec![bot.clone()]).await;
+
+    // Bots can't log in, they use API tokens
+    let resp = cli.post(format!("/api/login?user={}", bot.username)).content_type("text/plain").body("x").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    let scopes = vec![Scope { kind: ScopeKind::ReadMessages, group: group.id }];
+    let resp = cli.post(format!("/api/bot/tokens?bot={}&name=reader", bot.id)).body_json(&scopes).send().await;
+    resp.assert_status_is_ok();
+    let token = resp.json().await.value().deserialize::<ApiToken>();
+    let secret = token.token.clone().unwrap();
+    assert!(secret.starts_with(API_TOKEN_PREFIX));
+
+    // Only for groups that exist, and only for your own bots
+    let missing = vec![Scope { kind: ScopeKind::ReadMessages, group: 5 }];
+    let resp = cli.post(format!("/api/bot/tokens?bot={}&name=t", bot.id)).body_json(&missing).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.post(format!("/api/bot/tokens?bot={}&name=t", user.id)).body_json(&scopes).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.post(format!("/api/bot/tokens?bot={}&name=t", bot.id)).body_json(&Vec::<Scope>::new()).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+
+    // The token itself is only shown once
+    let resp = cli.get(format!("/api/bot/tokens?bot={}", bot.id)).send().await;
+    resp.assert_status_is_ok();
+    resp.assert_json(vec![ApiToken { token: None, ..token.clone() }]).await;
+
+    let resp = cli.get("/api/user/groups").header("Authorization", &secret).send().await;
+    resp.assert_status_is_ok();
+    resp.assert_json(Vec::<Group>::new()).await;
+
+    let resp = cli.delete(format!("/api/bot/tokens?bot={}&id={}", bot.id, token.id)).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.get("/api/user/groups").header("Authorization", &secret).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    // Deleting a user deletes their bots
+    let secret = make_api_token(&cli, bot.id, &scopes).await;
+    let resp = cli.delete("/api/user").send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.get(format!("/api/user?id={}", bot.id)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.get("/api/user/groups").header("Authorization", &secret).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn bot_scopes() {
+    let (cli, _user) = setup_user_auth().await;
+    let group = make_group(&cli, "group").await;
+    let other = make_group(&cli, "other").await;
+    let bot = make_bot(&cli, "robot").await;
+    add_group_member(&cli, group.id, bot.id).await;
+    add_group_member(&cli, other.id, bot.id).await;
+    let resp = cli.put(format!("/api/group/admin?gid={}&uid={}", group.id, bot.id)).send().await;
+    resp.assert_status_is_ok();
+    let reader = make_api_token(&cli, bot.id, &[Scope { kind: ScopeKind::ReadMessages, group: group.id }]).await;
+    let manager = make_api_token(&cli, bot.id, &[Scope { kind: ScopeKind::ManageChannels, group: group.id }]).await;
+    let main = group.channels[0];
+
+    let resp = cli.get(format!("/api/group?id={}", group.id)).header("Authorization", &reader).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.get(format!("/api/channel/messages?cid={}&num_msgs=10", main)).header("Authorization", &reader).send().await;
+    resp.assert_status_is_ok();
+    // Only in the groups it has scopes for, even though the bot is in both
+    let resp = cli.get(format!("/api/group?id={}", other.id)).header("Authorization", &reader).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.get(format!("/api/channel/messages?cid={}&num_msgs=10", other.channels[0])).header("Authorization", &reader).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    // And only what its scopes allow
+    let resp = cli.post(format!("/api/group/channels?gid={}&name=new", group.id)).header("Authorization", &reader).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.post(format!("/api/group/channels?gid={}&name=new", group.id)).header("Authorization", &manager).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.get(format!("/api/channel/messages?cid={}&num_msgs=10", main)).header("Authorization", &manager).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+
+    // Endpoints that aren't scoped are for users only
+    let resp = cli.get("/api/user/dms").header("Authorization", &reader).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.post("/api/group?name=mine").header("Authorization", &manager).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.post("/api/bot?name=child").header("Authorization", &manager).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn post_group() {
+    let (cli, user) = setup_user_auth().await;
+    let resp = cli.post("/api/group?name=test")
+        .header::<&str, &str>("Authorization", "").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    let resp = cli.post("/api/group?name=").send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.post("/api/group?name=test").send().await;
+    resp.assert_status_is_ok();
+
+    let group = resp.json().await.value().deserialize::<Group>();
+    assert_eq!(group.name, "test");
+    assert_eq!(group.members, vec![user.id]);
+    assert_eq!(group.admin, vec![user.id]);
+    assert_eq!(group.owner, user.id);
+    assert_eq!(group.channels.len(), 1);
+
+    let channel = find_channel(&cli, group.channels[0]).await;
+    assert_eq!(channel.members, vec![user.id]);
+    assert_eq!(channel.private, false);
+    assert_eq!(
+        find_channel(&cli, group.channels[0]).await.name,
+        String::from("main")
+    );
+}
+
+#[tokio::test]
+async fn post_dm() {
+    let (cli, user) = setup_user_auth().await;
+    let user2 = make_user(&cli, "user2", "who@cares.com", "12").await;
+    let resp = cli.post("/api/dm?uid=12")
+        .header::<&str, &str>("Authorization", "").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    
+    let resp = cli.post(format!("/api/dm?uid={}", user2.id)).send().await;
+    resp.assert_status_is_ok();
+
+    let group = resp.json().await.value().deserialize::<Group>();
+    assert_eq!(group.name, "");
+    assert_eq!(group.members, vec![user.id, user2.id]);
+    assert_eq!(group.admin, Vec::<i64>::new());
+    assert_eq!(group.owner, user.id);
+    assert_eq!(group.channels.len(), 1);   
+}
+
+#[tokio::test]
+async fn post_dm_whitebox() {
+    let (cli, user, db) = setup_user_auth_with_db().await;
+    let user2 = make_user(&cli, "user2", "who@cares.com", "12").await;
+    let resp = cli.post(format!("/api/dm?uid={}", user2.id)).send().await;
+    resp.assert_status_is_ok();
+    let dm = resp.json().await.value().deserialize::<Group>();
+
+    assert_eq!(db.get_group(dm.id).await.unwrap(), dm);
+    assert_eq!(db.get_user_dms(user.id).await.unwrap(), vec![dm.id]);
+}
+
+#[tokio::test]
+async fn put_group() {
+    let (cli, _user) = setup_user_auth().await;
+    let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
+    let group = make_group(&cli, "test").await;
+    add_group_member(&cli, group.id, user2.id).await;
+
+    let resp = cli.put(format!("/api/group?id={}&name=test2", group.id))
+        .header::<&str, &str>("Authorization", "").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.put(format!("/api/group?id={}&name=test2", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    
+    let resp = cli.put(format!("/api/group?id={}&name=", group.id)).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+
+    let resp = cli.put("/api/group?id=12&name=test2").send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+
+    let resp = cli.put(format!("/api/group?id={}&name=test2", group.id)).send().await;
+    resp.assert_status_is_ok();
+
+    let group = find_group(&cli, group.id).await;
+    assert_eq!(group.name, String::from("test2"));
+}
+
+#[tokio::test]
+/// TODO non exhaustive
+async fn del_group() {
+    let (cli, _user, db) = setup_user_auth_with_db().await;
+    let (user2, _) = user_auth(&cli, "user2", "who@cares.com", "12").await;
+    let (user3, auth3) = user_auth(&cli, "user3", "who3@cares.com", "12").await;
+    let group = make_group(&cli, "test").await;
+    add_group_member(&cli, group.id, user2.id).await;
+    let until = Utc::now().timestamp() + 60;
+    cli.put(format!("/api/group/timeouts?gid={}&uid={}&until={}", group.id, user2.id, until)).send().await.assert_status_is_ok();
+    cli.put(format!("/api/group/bans?gid={}&uid={}", group.id, user3.id)).send().await.assert_status_is_ok();
+    let invite = make_invite(&cli, group.id, "").await;
+    cli.put(format!("/api/group/profile?id={}&discoverable=true", group.id))
+        .body_json(&Vec::<String>::new()).send().await.assert_status_is_ok();
+    cli.delete(format!("/api/group/bans?gid={}&uid={}", group.id, user3.id)).send().await.assert_status_is_ok();
+    cli.post(format!("/api/group/join?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth3).send().await.assert_status_is_ok();
+    db.set_ban(Ban { group: group.id, user: user3.id, reason: String::new(), expires: None }).await.unwrap();
+    let msg = Message { id: gen_id(), channel: group.channels[0], author: user2.id, content: "hi".to_string(), thread: None };
+    db.create_message(msg.clone()).await.unwrap();
+    let resp = cli.put(format!("/api/message/thread?id={}&name=thread", msg.id)).send().await;
+    resp.assert_status_is_ok();
+    let thread = resp.json().await.value().deserialize::<Channel>();
+
+    let resp = cli.delete(format!("/api/group?id={}", group.id))
+        .header::<&str, &str>("Authorization", "").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    let resp = cli.delete("/api/group?id=12").send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+
+    let resp = cli.delete(format!("/api/group?id={}", group.id)).send().await;
+    resp.assert_status_is_ok();
+
+    let resp = cli.get(format!("/api/group?id={}", group.id)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.get(format!("/api/channel?id={}", group.channels[0])).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    // Nothing about the group is left behind
+    assert!(!db.valid_id(IdType::Channel, thread.id).await.unwrap());
+    assert!(matches!(db.get_ban(group.id, user3.id).await, Err(DbError::NotFound(_))));
+    assert!(matches!(db.get_timeout(group.id, user2.id).await, Err(DbError::NotFound(_))));
+    assert!(matches!(db.get_invite(invite.code).await, Err(DbError::NotFound(_))));
+    assert!(matches!(db.get_join_request(group.id, user3.id).await, Err(DbError::NotFound(_))));
+    assert_eq!(db.get_audit_log(group.id, AuditQuery { before: None, actor: None, target: None, action: None, limit: 100 }).await.unwrap(), Vec::new());
+
+    let resp = cli.get("/api/user/groups").send().await;
+    resp.assert_status_is_ok();
+    let groups = resp.json().await.value().deserialize::<Vec<Group>>();
+    assert!(!groups.contains(&group));
+}
+
+#[tokio::test]
+async fn transfer_group() {
+    let (cli, user, db) = setup_user_auth_with_db().await;
+    let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
+    let user3 = make_user(&cli, "user3", "who3@cares.com", "12").await;
+    let bot = make_bot(&cli, "bot").await;
+    let group = make_group(&cli, "test").await;
+    add_group_member(&cli, group.id, user2.id).await;
+    add_group_member(&cli, group.id, bot.id).await;
+    let now = Utc::now().timestamp();
+    cli.put(format!("/api/group/timeouts?gid={}&uid={}&until={}", group.id, user2.id, now + 60))
+        .send().await.assert_status_is_ok();
+
+    let resp = cli.put(format!("/api/group/owner?gid={}&uid={}", group.id, user3.id)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.put(format!("/api/group/admin?gid={}&uid={}", group.id, user3.id)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.put(format!("/api/group/owner?gid={}&uid={}", group.id, bot.id)).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.put(format!("/api/group/owner?gid={}&uid={}", group.id, user.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    // The new owner's timeout is lifted, and the old owner is just a member
+    let resp = cli.put(format!("/api/group/owner?gid={}&uid={}", group.id, user2.id)).send().await;
+    resp.assert_status_is_ok();
+    let group = find_group(&cli, group.id).await;
+    assert_eq!(group.owner, user2.id);
+    assert!(group.members.contains(&user.id));
+    assert!(matches!(db.get_timeout(group.id, user2.id).await, Err(DbError::NotFound(_))));
+    let resp = cli.delete(format!("/api/group?id={}", group.id)).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let log = db.get_audit_log(group.id, AuditQuery {
+        before: None, actor: None, target: None, action: Some(AuditAction::TransferOwnership), limit: 10
+    }).await.unwrap();
+    assert_eq!((log[0].actor, log[0].target), (user.id, user2.id));
+}
+
+#[tokio::test]
+async fn owner_leaves() {
+    let (cli, user, db) = setup_user_auth_with_db().await;
+    let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
+    let (user3, auth3) = user_auth(&cli, "user3", "who3@cares.com", "12").await;
+    let (user4, auth4) = user_auth(&cli, "user4", "who4@cares.com", "12").await;
+    let bot = make_bot(&cli, "bot").await;
+    let outsider = make_user(&cli, "outsider", "who5@cares.com", "12").await;
+    let group = make_group(&cli, "test").await;
+    // Left over from before admins had to be members
+    db.add_group_admin(group.id, outsider.id).await.unwrap();
+    // The newest user joins first
+    for member in [bot.id, user4.id, user3.id, user2.id] {
+        add_group_member(&cli, group.id, member).await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    cli.put(format!("/api/group/admin?gid={}&uid={}", group.id, user3.id)).send().await.assert_status_is_ok();
+
+    // Admins come first, then the member who's been in the group the longest, but never bots
+    cli.delete(format!("/api/user/groups?gid={}", group.id)).send().await.assert_status_is_ok();
+    assert_eq!(db.get_group_owner(group.id).await.unwrap(), user3.id);
+    assert!(!db.get_group_members(group.id).await.unwrap().contains(&user.id));
+    cli.delete(format!("/api/user/groups?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth3).send().await.assert_status_is_ok();
+    assert_eq!(db.get_group_owner(group.id).await.unwrap(), user4.id);
+    cli.delete(format!("/api/user/groups?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth4).send().await.assert_status_is_ok();
+    assert_eq!(db.get_group_owner(group.id).await.unwrap(), user2.id);
+    // With only a bot left, the group goes
+    cli.delete(format!("/api/user/groups?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await.assert_status_is_ok();
+    assert!(!db.valid_id(IdType::Group, group.id).await.unwrap());
+
+    // Deleting your account is the same as leaving all your groups
+    let shared = make_group(&cli, "shared").await;
+    let alone = make_group(&cli, "alone").await;
+    add_group_member(&cli, shared.id, user2.id).await;
+    cli.delete("/api/user").send().await.assert_status_is_ok();
+    assert!(!db.valid_id(IdType::Group, alone.id).await.unwrap());
+    let resp = cli.get(format!("/api/group?id={}", shared.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status_is_ok();
+    let shared = resp.json().await.value().deserialize::<Group>();
+    assert_eq!((shared.owner, shared.members), (user2.id, vec![user2.id]));
+}
+
+#[tokio::test]
+async fn get_group_members() {
+    let (cli, user) = setup_user_auth().await;
+    let (user2, auth2) = user_auth(&cli, "wehee", "who@cares.com", "12").await;
+    let group = make_group(&cli, "test").await;
+
+    // Only members can see who else is in a group, or who its admins are
+    for path in ["members", "admin"] {
+        let resp = cli.get(format!("/api/group/{}?id={}", path, group.id))
+            .header::<&str, &str>("Authorization", &auth2).send().await;
+        resp.assert_status(StatusCode::NOT_FOUND);
+    }
+    add_group_member(&cli, group.id, user2.id).await;
+    let resp = cli.get(format!("/api/group/members?id={}", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status_is_ok();
+    let members: Vec<_> = resp.json().await.value().deserialize::<Vec<User>>().into_iter().map(|u| u.id).collect();
+    assert!(contents_eq(members, vec![user.id, user2.id]));
+    let resp = cli.get(format!("/api/group/admin?id={}", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status_is_ok();
+    let admins: Vec<_> = resp.json().await.value().deserialize::<Vec<User>>().into_iter().map(|u| u.id).collect();
+    assert_eq!(admins, vec![user.id]);
+}
+
+#[tokio::test]
+/// TODO non exhaustive
+async fn put_group_members() {
+    let (cli, user) = setup_user_auth().await;
+    let group = make_group(&cli, "test").await;
+    let user2 = make_user(&cli, "testeroo", "test2@example.com", "123456").await;
+    
+    let resp = cli.put(format!("/api/group/members?gid={}&uid={}", group.id, user2.id))
+        .header::<&str, &str>("Authorization", "").send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    let resp = cli.put(format!("/api/group/members?gid={}&uid=", group.id)).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+
+    let resp = cli.put(format!("/api/group/members?gid=12&uid={}", user.id)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+
+    let resp = cli.put(format!("/api/group/members?gid={}&uid=12", group.id)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    assert!(!find_group(&cli, group.id).await.members.contains(&12));
+
+    let resp = cli.put(format!("/api/group/members?gid={}&uid={}", group.id, user2.id)).send().await;
+    resp.assert_status_is_ok();
+    
+    assert!(find_group(&cli, group.id).await.members.contains(&user2.id));
+}
+
+async fn make_role(cli: &FakeClient, gid: i64, name: &str, permissions: &[Permission]) -> Role {
+    let resp = cli.post(format!("/api/group/roles?gid={}&name={}", gid, name))
+        .body_json(&permissions).send().await;
+    resp.assert_status_is_ok();
+    resp.json().await.value().deserialize::<Role>()
+}
+
+async fn find_roles(cli: &FakeClient, gid: i64) -> Vec<Role> {
+    let resp = cli.get(format!("/api/group/roles?gid={}", gid)).send().await;
+    resp.assert_status_is_ok();
+    resp.json().await.value().deserialize::<Vec<Role>>()
+}
+
+#[tokio::test]
+async fn group_roles() {
+    let (cli, _user) = setup_user_auth().await;
+    let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
+    let group = make_group(&cli, "test").await;
+
+    assert_eq!(find_roles(&cli, group.id).await, vec![Role::group_default(group.id)]);
+
+    let resp = cli.post(format!("/api/group/roles?gid={}&name=", group.id)).body_json(&Vec::<Permission>::new()).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.post("/api/group/roles?gid=12&name=mods").body_json(&Vec::<Permission>::new()).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+
+    let role = make_role(&cli, group.id, "mods", &[Permission::ManageChannels, Permission::DeleteMessages, Permission::ManageChannels]).await;
+    assert_eq!(role.permissions, vec![Permission::ManageChannels, Permission::DeleteMessages]);
+    assert_eq!(find_roles(&cli, group.id).await, vec![Role::group_default(group.id), role.clone()]);
+
+    // Non-members can't see the roles
+    let resp = cli.get(format!("/api/group/roles?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+
+    let resp = cli.put(format!("/api/group/roles?id={}&name=channel%20mods", role.id))
+        .body_json(&[Permission::ManageChannels]).send().await;
+    resp.assert_status_is_ok();
+    let role = resp.json().await.value().deserialize::<Role>();
+    assert_eq!((role.name.as_str(), role.permissions.clone()), ("channel mods", vec![Permission::ManageChannels]));
+
+    // Roles can only be given to members, and every member already has the default role
+    let resp = cli.put(format!("/api/group/members/roles?gid={}&uid={}&role={}", group.id, user2.id, role.id)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    add_group_member(&cli, group.id, user2.id).await;
+    let resp = cli.put(format!("/api/group/members/roles?gid={}&uid={}&role={}", group.id, user2.id, group.id)).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.put(format!("/api/group/members/roles?gid={}&uid={}&role={}", group.id, user2.id, role.id)).send().await;
+    resp.assert_status_is_ok();
 
-    assert_eq!(user.email, "test@example.com");
-    assert_eq!(user.username, "test");
+    let resp = cli.get(format!("/api/group/members/roles?gid={}&uid={}", group.id, user2.id)).send().await;
+    resp.assert_status_is_ok();
+    let roles = resp.json().await.value().deserialize::<Vec<Role>>();
+    assert_eq!(roles, vec![Role::group_default(group.id), role.clone()]);
 
-    let resp = cli.get(format!("/api/user?id={}", user.id)).send().await;
+    // The default role can be changed, but not deleted
+    let resp = cli.put(format!("/api/group/roles?id={}&name=everyone", group.id))
+        .body_json(&[Permission::SendMessages]).send().await;
     resp.assert_status_is_ok();
+    assert_eq!(find_roles(&cli, group.id).await[0].permissions, vec![Permission::SendMessages]);
+    let resp = cli.delete(format!("/api/group/roles?id={}", group.id)).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
 
-    let same_user = resp.json().await.value().deserialize::<User>();
-    assert_eq!(user, same_user);
+    let resp = cli.delete(format!("/api/group/roles?id={}", role.id)).send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(find_roles(&cli, group.id).await.len(), 1);
+    let resp = cli.get(format!("/api/group/members/roles?gid={}&uid={}", group.id, user2.id)).send().await;
+    assert_eq!(resp.json().await.value().deserialize::<Vec<Role>>().len(), 1);
 }
 
 #[tokio::test]
-async fn post_user_whitebox() {
-    let cli = setup();
-    let user = make_user(&cli, "test", "test@example.com", "12345").await;
-    let db = Cassandra::new("test");
-    assert_eq!(db.get_user(user.id).unwrap(), user);
-    assert_eq!(db.get_user_groups(user.id).unwrap(), Vec::<i64>::new());
-}
+async fn role_permissions() {
+    let (cli, _user) = setup_user_auth().await;
+    let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
+    let (user3, auth3) = user_auth(&cli, "user3", "who3@cares.com", "12").await;
+    let group = make_group(&cli, "test").await;
+    add_group_member(&cli, group.id, user2.id).await;
+    add_group_member(&cli, group.id, user3.id).await;
 
-#[tokio::test]
-async fn put_user() {
-    let (cli, user) = setup_user_auth().await;
+    // Plain members can't manage channels or roles
+    let resp = cli.post(format!("/api/group/channels?gid={}&name=new", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.post(format!("/api/group/roles?gid={}&name=mods", group.id))
+        .header::<&str, &str>("Authorization", &auth2).body_json(&Vec::<Permission>::new()).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
 
-    let resp = cli.put("/api/user?name=fred&email=whoo@whee.com")
-        .header::<&str, &str>("Authorization", "").send().await;
+    let channels = make_role(&cli, group.id, "channels", &[Permission::ManageChannels]).await;
+    let managers = make_role(&cli, group.id, "managers", &[Permission::ManageRoles, Permission::ManageMembers]).await;
+    cli.put(format!("/api/group/members/roles?gid={}&uid={}&role={}", group.id, user2.id, channels.id))
+        .send().await.assert_status_is_ok();
+    cli.put(format!("/api/group/members/roles?gid={}&uid={}&role={}", group.id, user3.id, managers.id))
+        .send().await.assert_status_is_ok();
+
+    let resp = cli.post(format!("/api/group/channels?gid={}&name=new", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.put(format!("/api/group?id={}&name=test2", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
     resp.assert_status(StatusCode::UNAUTHORIZED);
 
-    let resp = cli.put("/api/user?name=fred&email=whoo@whee.com").send().await;
+    // Managing roles doesn't allow handing out permissions you don't have
+    let resp = cli.put(format!("/api/group/members/roles?gid={}&uid={}&role={}", group.id, user3.id, channels.id))
+        .header::<&str, &str>("Authorization", &auth3).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.post(format!("/api/group/roles?gid={}&name=mods", group.id))
+        .header::<&str, &str>("Authorization", &auth3).body_json(&[Permission::ManageGroup]).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.post(format!("/api/group/roles?gid={}&name=members", group.id))
+        .header::<&str, &str>("Authorization", &auth3).body_json(&[Permission::ManageMembers]).send().await;
     resp.assert_status_is_ok();
 
-    let resp = cli.get(format!("/api/user?id={}", user.id)).send().await;
+    // Leaving a group takes away its roles
+    let resp = cli.delete(format!("/api/group/members?gid={}&uid={}", group.id, user2.id)).send().await;
     resp.assert_status_is_ok();
-    let ret_user = resp.json().await.value().deserialize::<User>();
-    assert_eq!(ret_user.email, "whoo@whee.com");
-    assert_eq!(ret_user.username, "fred");
-    // User should retain their underlying ID
-    assert_eq!(user.id, ret_user.id);
+    add_group_member(&cli, group.id, user2.id).await;
+    let resp = cli.post(format!("/api/group/channels?gid={}&name=newer", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
 }
-
 #[tokio::test]
-/// FIXME non exhaustive
-async fn del_user() {
-    let (cli, user) = setup_user_auth().await;
-
-    let resp = cli.delete(format!("/api/user?id={}", user.id))
-        .header::<&str, &str>("Authorization", "").send().await;
-    resp.assert_status(StatusCode::UNAUTHORIZED);
+async fn channel_overrides() {
+    let (cli, _user, db) = setup_user_auth_with_db().await;
+    let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
+    let (user3, auth3) = user_auth(&cli, "user3", "who3@cares.com", "12").await;
+    let group = make_group(&cli, "test").await;
+    add_group_member(&cli, group.id, user2.id).await;
+    add_group_member(&cli, group.id, user3.id).await;
+    let channel = group.channels[0];
+    let mods = make_role(&cli, group.id, "mods", &[Permission::ManageChannels]).await;
+    cli.put(format!("/api/group/members/roles?gid={}&uid={}&role={}", group.id, user2.id, mods.id))
+        .send().await.assert_status_is_ok();
+    let change = |allow: &[Permission], deny: &[Permission]| OverrideChange { allow: allow.to_vec(), deny: deny.to_vec() };
+
+    // Overrides are for roles or members of the group, and only for permissions in a channel
+    let resp = cli.put(format!("/api/channel/overrides?id={}&target=12", channel))
+        .body_json(&change(&[], &[])).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.put(format!("/api/channel/overrides?id={}&target={}", channel, group.id))
+        .body_json(&change(&[Permission::ManageRoles], &[])).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
 
-    let resp = cli.delete(format!("/api/user?id={}", user.id)).send().await;
+    // A read-only channel, apart from the mods, and one mod who can't make threads
+    let resp = cli.put(format!("/api/channel/overrides?id={}&target={}", channel, group.id))
+        .body_json(&change(&[], &[Permission::SendMessages, Permission::CreateThreads])).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.put(format!("/api/channel/overrides?id={}&target={}", channel, mods.id))
+        .body_json(&change(&[Permission::SendMessages, Permission::CreateThreads], &[])).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.put(format!("/api/channel/overrides?id={}&target={}", channel, user2.id))
+        .body_json(&change(&[], &[Permission::CreateThreads])).send().await;
+    resp.assert_status_is_ok();
 
+    let resp = cli.get(format!("/api/channel/overrides?id={}", channel)).send().await;
     resp.assert_status_is_ok();
-    let resp = cli.get(format!("/api/user?id={}", user.id)).send().await;
+    let overrides = resp.json().await.value().deserialize::<Vec<ChannelOverride>>();
+    assert_eq!(overrides.len(), 3);
+
+    let resp = cli.delete(format!("/api/channel/overrides?id={}&target={}", channel, user3.id)).send().await;
     resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.put(format!("/api/channel/overrides?id={}&target={}", channel, user3.id))
+        .header::<&str, &str>("Authorization", &auth3).body_json(&change(&[Permission::SendMessages], &[])).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+
+    let msg = Message { id: gen_id(), channel, author: user3.id, content: "hi".to_string(), thread: None };
+    db.create_message(msg.clone()).await.unwrap();
+    let thread = |auth: &str| cli.put(format!("/api/message/thread?id={}&name=thread", msg.id))
+        .header::<&str, &str>("Authorization", auth).send();
+    thread(&auth3).await.assert_status(StatusCode::UNAUTHORIZED);
+    thread(&auth2).await.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.delete(format!("/api/channel/overrides?id={}&target={}", channel, user2.id)).send().await;
+    resp.assert_status_is_ok();
+    let resp = thread(&auth2).await;
+    resp.assert_status_is_ok();
+
+    // Threads start with the channel's overrides
+    let tid = resp.json().await.value().deserialize::<Channel>().id;
+    assert_eq!(db.get_channel_overrides(tid).await.unwrap().len(), 2);
+    assert!(!roles::member_channel_permissions(&db, tid, user3.id).await.unwrap().contains(Permission::SendMessages));
+    assert!(roles::member_channel_permissions(&db, tid, user2.id).await.unwrap().contains(Permission::SendMessages));
 }
 
 #[tokio::test]
-async fn post_group() {
+async fn group_bans() {
     let (cli, user) = setup_user_auth().await;
-    let resp = cli.post("/api/group?name=test")
-        .header::<&str, &str>("Authorization", "").send().await;
-    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
+    let user3 = make_user(&cli, "user3", "who3@cares.com", "12").await;
+    let group = make_group(&cli, "test").await;
+    add_group_member(&cli, group.id, user2.id).await;
+    add_group_member(&cli, group.id, user3.id).await;
 
-    let resp = cli.post("/api/group?name=").send().await;
+    let resp = cli.put(format!("/api/group/bans?gid={}&uid={}", group.id, user3.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.put(format!("/api/group/bans?gid={}&uid={}", group.id, user.id)).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.put(format!("/api/group/bans?gid={}&uid={}&expires=1", group.id, user3.id)).send().await;
     resp.assert_status(StatusCode::BAD_REQUEST);
-    let resp = cli.post("/api/group?name=test").send().await;
+    let resp = cli.put(format!("/api/group/bans?gid={}&uid={}&reason={}", group.id, user3.id, "a".repeat(MAX_REASON_LEN + 1)))
+        .send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+
+    // Banning removes the member, and stops them being added back
+    let resp = cli.put(format!("/api/group/bans?gid={}&uid={}&reason=spam", group.id, user3.id)).send().await;
     resp.assert_status_is_ok();
+    assert!(!find_group(&cli, group.id).await.members.contains(&user3.id));
+    let resp = cli.put(format!("/api/group/members?gid={}&uid={}", group.id, user3.id)).send().await;
+    resp.assert_status(StatusCode::CONFLICT);
+    let resp = cli.put(format!("/api/channel/members?cid={}&uid={}", group.channels[0], user3.id)).send().await;
+    resp.assert_status(StatusCode::CONFLICT);
 
-    let group = resp.json().await.value().deserialize::<Group>();
-    assert_eq!(group.name, "test");
-    assert_eq!(group.members, vec![user.id]);
-    assert_eq!(group.admin, vec![user.id]);
-    assert_eq!(group.owner, user.id);
-    assert_eq!(group.channels.len(), 1);
+    let resp = cli.get(format!("/api/group/bans?gid={}", group.id)).send().await;
+    resp.assert_status_is_ok();
+    let bans = resp.json().await.value().deserialize::<Vec<Ban>>();
+    assert_eq!(bans, vec![Ban { group: group.id, user: user3.id, reason: "spam".to_string(), expires: None }]);
+    let resp = cli.get(format!("/api/group/bans?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
 
-    let channel = find_channel(&cli, group.channels[0]).await;
-    assert_eq!(channel.members, vec![user.id]);
-    assert_eq!(channel.private, false);
-    assert_eq!(
-        find_channel(&cli, group.channels[0]).await.name,
-        String::from("main")
-    );
+    let resp = cli.delete(format!("/api/group/bans?gid={}&uid={}", group.id, user3.id)).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.delete(format!("/api/group/bans?gid={}&uid={}", group.id, user3.id)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    add_group_member(&cli, group.id, user3.id).await;
 }
 
 #[tokio::test]
-async fn post_dm() {
-    let (cli, user) = setup_user_auth().await;
+async fn group_timeouts() {
+    let (cli, _user, db) = setup_user_auth_with_db().await;
     let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
-    let resp = cli.post("/api/dm?uid=12")
-        .header::<&str, &str>("Authorization", "").send().await;
+    let group = make_group(&cli, "test").await;
+    add_group_member(&cli, group.id, user2.id).await;
+    let mods = make_role(&cli, group.id, "mods", &[Permission::ManageChannels]).await;
+    cli.put(format!("/api/group/members/roles?gid={}&uid={}&role={}", group.id, user2.id, mods.id))
+        .send().await.assert_status_is_ok();
+
+    let now = Utc::now().timestamp();
+    let resp = cli.put(format!("/api/group/timeouts?gid={}&uid={}&until={}", group.id, user2.id, now - 1)).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.put(format!("/api/group/timeouts?gid={}&uid={}&until={}", group.id, user2.id, now + MAX_TIMEOUT + 60))
+        .send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.put(format!("/api/group/timeouts?gid={}&uid=12&until={}", group.id, now + 60)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+
+    // Timed out members can't post, or do anything else their roles allow
+    let resp = cli.put(format!("/api/group/timeouts?gid={}&uid={}&until={}", group.id, user2.id, now + 60)).send().await;
+    resp.assert_status_is_ok();
+    let channel = group.channels[0];
+    assert_eq!(roles::member_channel_permissions(&db, channel, user2.id).await.unwrap(), Permissions::NONE);
+    let resp = cli.post(format!("/api/group/channels?gid={}&name=new", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
     resp.assert_status(StatusCode::UNAUTHORIZED);
-    
-    let resp = cli.post(format!("/api/dm?uid={}", user2.id)).send().await;
+
+    let resp = cli.get(format!("/api/group/timeouts?gid={}", group.id)).send().await;
     resp.assert_status_is_ok();
+    let timeouts = resp.json().await.value().deserialize::<Vec<Timeout>>();
+    assert_eq!(timeouts, vec![Timeout { group: group.id, user: user2.id, reason: String::new(), until: now + 60 }]);
 
-    let group = resp.json().await.value().deserialize::<Group>();
-    assert_eq!(group.name, "");
-    assert_eq!(group.members, vec![user.id, user2.id]);
-    assert_eq!(group.admin, Vec::<i64>::new());
-    assert_eq!(group.owner, user.id);
-    assert_eq!(group.channels.len(), 1);   
+    let resp = cli.delete(format!("/api/group/timeouts?gid={}&uid={}", group.id, user2.id)).send().await;
+    resp.assert_status_is_ok();
+    assert!(roles::member_channel_permissions(&db, channel, user2.id).await.unwrap().contains(Permission::SendMessages));
+    let resp = cli.post(format!("/api/group/channels?gid={}&name=new", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status_is_ok();
 }
 
-#[tokio::test]
-async fn post_dm_whitebox() {
-    let (cli, user) = setup_user_auth().await;
-    let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
-    let resp = cli.post(format!("/api/dm?uid={}", user2.id)).send().await;
+async fn make_invite(cli: &FakeClient, gid: i64, options: &str) -> Invite {
+    let resp = cli.post(format!("/api/group/invites?gid={gid}{options}")).send().await;
     resp.assert_status_is_ok();
-    let dm = resp.json().await.value().deserialize::<Group>();
-
-    let db = Cassandra::new("test");
-    assert_eq!(db.get_group(dm.id).unwrap(), dm);
-    assert_eq!(db.get_user_dms(user.id).unwrap(), vec![dm.id]);
+    resp.json().await.value().deserialize::<Invite>()
 }
 
 #[tokio::test]
-async fn put_group() {
-    let (cli, _user) = setup_user_auth().await;
+async fn group_invites() {
+    let (cli, user, db) = setup_user_auth_with_db().await;
     let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
+    let (user3, auth3) = user_auth(&cli, "user3", "who3@cares.com", "12").await;
     let group = make_group(&cli, "test").await;
-    add_group_member(&cli, group.id, user2.id).await;
+    let secret = make_channel(&cli, group.id, "secret").await;
+    cli.put(format!("/api/channel/private?id={}&val=true", secret.id)).send().await.assert_status_is_ok();
 
-    let resp = cli.put(format!("/api/group?id={}&name=test2", group.id))
-        .header::<&str, &str>("Authorization", "").send().await;
-    resp.assert_status(StatusCode::UNAUTHORIZED);
-    let resp = cli.put(format!("/api/group?id={}&name=test2", group.id))
+    let resp = cli.post(format!("/api/group/invites?gid={}&max_uses=0", group.id)).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.post(format!("/api/group/invites?gid={}&expires=1", group.id)).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.post(format!("/api/group/invites?gid={}&channel=12", group.id)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.post(format!("/api/group/invites?gid={}", group.id))
         .header::<&str, &str>("Authorization", &auth2).send().await;
     resp.assert_status(StatusCode::UNAUTHORIZED);
-    
-    let resp = cli.put(format!("/api/group?id={}&name=", group.id)).send().await;
+    let dm = make_dm(&cli, user2.id).await;
+    let resp = cli.post(format!("/api/group/invites?gid={}", dm.id)).send().await;
     resp.assert_status(StatusCode::BAD_REQUEST);
 
-    let resp = cli.put("/api/group?id=12&name=test2").send().await;
+    // Accepting an invite to a private channel adds you to it, as well as the public ones
+    let invite = make_invite(&cli, group.id, &format!("&channel={}&max_uses=1", secret.id)).await;
+    assert_eq!((invite.creator, invite.channel, invite.uses), (user.id, Some(secret.id), 0));
+    let resp = cli.post(format!("/api/invite/{}/accept", invite.code))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(resp.json().await.value().deserialize::<Invite>().uses, 1);
+    let group = find_group(&cli, group.id).await;
+    assert!(group.members.contains(&user2.id));
+    assert!(db.is_channel_member(group.channels[0], user2.id).await.unwrap());
+    assert!(db.is_channel_member(secret.id, user2.id).await.unwrap());
+    assert!(db.get_user_groups(user2.id).await.unwrap().contains(&group.id));
+
+    // ...once, since it's used up
+    let resp = cli.post(format!("/api/invite/{}/accept", invite.code))
+        .header::<&str, &str>("Authorization", &auth3).send().await;
     resp.assert_status(StatusCode::NOT_FOUND);
 
-    let resp = cli.put(format!("/api/group?id={}&name=test2", group.id)).send().await;
+    // Members can't use invites up, and banned users can't join with them
+    let invite = make_invite(&cli, group.id, "&max_uses=5").await;
+    let resp = cli.post(format!("/api/invite/{}/accept", invite.code))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status(StatusCode::CONFLICT);
+    cli.put(format!("/api/group/bans?gid={}&uid={}", group.id, user3.id)).send().await.assert_status_is_ok();
+    let resp = cli.post(format!("/api/invite/{}/accept", invite.code))
+        .header::<&str, &str>("Authorization", &auth3).send().await;
+    resp.assert_status(StatusCode::CONFLICT);
+    let resp = cli.get(format!("/api/group/invites?gid={}", group.id)).send().await;
     resp.assert_status_is_ok();
+    assert_eq!(resp.json().await.value().deserialize::<Vec<Invite>>(), vec![invite.clone()]);
 
-    let group = find_group(&cli, group.id).await;
-    assert_eq!(group.name, String::from("test2"));
+    let resp = cli.delete(format!("/api/group/invites?code={}", invite.code))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    cli.delete(format!("/api/group/invites?code={}", invite.code)).send().await.assert_status_is_ok();
+    let resp = cli.delete(format!("/api/group/invites?code={}", invite.code)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+
+    let log = find_audit_log(&cli, group.id, "&action=accept_invite").await;
+    assert_eq!((log[0].actor, log[0].target), (user2.id, user.id));
+    let log = find_audit_log(&cli, group.id, "&action=create_invite").await;
+    let targets: Vec<_> = log.iter().map(|entry| (entry.actor, entry.target)).collect();
+    assert_eq!(targets, vec![(user.id, group.id), (user.id, secret.id)]);
+    let log = find_audit_log(&cli, group.id, "&action=delete_invite").await;
+    let targets: Vec<_> = log.iter().map(|entry| (entry.actor, entry.target)).collect();
+    assert_eq!(targets, vec![(user.id, user.id)]);
+}
+
+async fn search_directory(cli: &FakeClient, filters: &str) -> Vec<GroupListing> {
+    let resp = cli.get(format!("/api/group/directory?{filters}")).send().await;
+    resp.assert_status_is_ok();
+    resp.json().await.value().deserialize::<Vec<GroupListing>>()
 }
 
 #[tokio::test]
-/// TODO non exhaustive
-async fn del_group() {
-    let (cli, _user) = setup_user_auth().await;
-    let group = make_group(&cli, "test").await;
+async fn group_directory() {
+    let (cli, user, db) = setup_user_auth_with_db().await;
+    let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
+    let (user3, auth3) = user_auth(&cli, "user3", "who3@cares.com", "12").await;
+    let group = make_group(&cli, "Rustaceans").await;
+    let hidden = make_group(&cli, "Hidden").await;
 
-    let resp = cli.delete(format!("/api/group?id={}", group.id))
-        .header::<&str, &str>("Authorization", "").send().await;
+    let tags = vec!["Rust".to_string(), " crabs ".to_string(), "rust".to_string()];
+    let resp = cli.put(format!("/api/group/profile?id={}&discoverable=true", group.id))
+        .body_json(&vec!["two words"]).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.put(format!("/api/group/profile?id={}&discoverable=true", group.id))
+        .body_json(&(0..=MAX_TAGS).map(|i| i.to_string()).collect::<Vec<_>>()).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.put(format!("/api/group/profile?id={}&discoverable=true", group.id))
+        .header::<&str, &str>("Authorization", &auth2).body_json(&tags).send().await;
     resp.assert_status(StatusCode::UNAUTHORIZED);
-
-    let resp = cli.delete("/api/group?id=12").send().await;
+    let dm = make_dm(&cli, user2.id).await;
+    let resp = cli.put(format!("/api/group/profile?id={}&discoverable=true", dm.id)).body_json(&tags).send().await;
+    resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.put(format!("/api/group/profile?id={}&discoverable=true&description=Crabs%20only", group.id))
+        .body_json(&tags).send().await;
+    resp.assert_status_is_ok();
+    let found = find_group(&cli, group.id).await;
+    assert_eq!((found.discoverable, found.description.as_str()), (true, "Crabs only"));
+    assert_eq!(found.tags, vec!["rust".to_string(), "crabs".to_string()]);
+
+    // Only discoverable groups are listed, and without their members
+    let listing = GroupListing {
+        id: group.id,
+        name: "Rustaceans".to_string(),
+        description: "Crabs only".to_string(),
+        tags: found.tags.clone(),
+        requires_approval: true,
+        members: 1,
+    };
+    assert_eq!(search_directory(&cli, "").await, vec![listing.clone()]);
+    assert_eq!(search_directory(&cli, "name=ACEAN&tag=Crabs").await, vec![listing]);
+    assert_eq!(search_directory(&cli, "tag=go").await, Vec::new());
+    assert_eq!(search_directory(&cli, &format!("after={}", group.id)).await, Vec::new());
+    let resp = cli.post(format!("/api/group/join?gid={}", hidden.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
     resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.post(format!("/api/group/join?gid={}", group.id)).send().await;
+    resp.assert_status(StatusCode::CONFLICT);
 
-    let resp = cli.delete(format!("/api/group?id={}", group.id)).send().await;
+    let resp = cli.post(format!("/api/group/join?gid={}&message=let%20me%20in", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status_is_ok();
+    let request = resp.json().await.value().deserialize::<JoinRequest>();
+    assert_eq!((request.user, request.message.as_str()), (user2.id, "let me in"));
+    let resp = cli.post(format!("/api/group/join?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth3).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.get(format!("/api/group/join_requests?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    let resp = cli.get(format!("/api/group/join_requests?gid={}", group.id)).send().await;
     resp.assert_status_is_ok();
+    let requests = resp.json().await.value().deserialize::<Vec<JoinRequest>>();
+    assert!(contents_eq(requests.iter().map(|r| r.user).collect(), vec![user2.id, user3.id]));
 
-    let resp = cli.get(format!("/api/group?id={}", group.id)).send().await;
-    resp.assert_status(StatusCode::NOT_FOUND);
-    let resp = cli.get(format!("/api/channel?id={}", group.channels[0])).send().await;
+    // Approving adds them like any other new member
+    let resp = cli.put(format!("/api/group/join_requests?gid={}&uid={}", group.id, user2.id))
+        .header::<&str, &str>("Authorization", &auth3).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    cli.put(format!("/api/group/join_requests?gid={}&uid={}", group.id, user2.id)).send().await.assert_status_is_ok();
+    let found = find_group(&cli, group.id).await;
+    assert!(found.members.contains(&user2.id));
+    assert!(db.is_channel_member(found.channels[0], user2.id).await.unwrap());
+    assert!(db.get_user_groups(user2.id).await.unwrap().contains(&group.id));
+    let resp = cli.put(format!("/api/group/join_requests?gid={}&uid={}", group.id, user2.id)).send().await;
     resp.assert_status(StatusCode::NOT_FOUND);
 
-    let resp = cli.get("/api/user/groups").send().await;
+    // Banned users can't be let in, but can be turned down
+    cli.put(format!("/api/group/bans?gid={}&uid={}", group.id, user3.id)).send().await.assert_status_is_ok();
+    let resp = cli.put(format!("/api/group/join_requests?gid={}&uid={}", group.id, user3.id)).send().await;
+    resp.assert_status(StatusCode::CONFLICT);
+    let resp = cli.delete(format!("/api/group/join_requests?gid={}&uid={}&reason=banned", group.id, user3.id)).send().await;
     resp.assert_status_is_ok();
-    let groups = resp.json().await.value().deserialize::<Vec<Group>>();
-    assert!(!groups.contains(&group));
+    let resp = cli.post(format!("/api/group/join?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth3).send().await;
+    resp.assert_status(StatusCode::CONFLICT);
+
+    // Users can take their requests back, and groups can leave the directory
+    let user4 = make_user(&cli, "user4", "who4@cares.com", "12").await;
+    let auth4 = login(&cli, &user4.username, "12").await;
+    let resp = cli.post(format!("/api/group/join?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth4).send().await;
+    resp.assert_status_is_ok();
+    let resp = cli.delete(format!("/api/group/join?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth4).send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(db.get_join_requests(group.id).await.unwrap(), Vec::new());
+    let resp = cli.put(format!("/api/group/profile?id={}&discoverable=false", group.id))
+        .body_json(&Vec::<String>::new()).send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(search_directory(&cli, "").await, Vec::new());
+
+    // Groups that don't require approval are joined straight away
+    let resp = cli.put(format!("/api/group/profile?id={}&discoverable=true&requires_approval=false", hidden.id))
+        .body_json(&Vec::<String>::new()).send().await;
+    resp.assert_status_is_ok();
+    let listings = search_directory(&cli, "").await;
+    assert_eq!(listings.iter().map(|l| (l.id, l.requires_approval)).collect::<Vec<_>>(), vec![(hidden.id, false)]);
+    let resp = cli.post(format!("/api/group/join?gid={}", hidden.id))
+        .header::<&str, &str>("Authorization", &auth4).send().await;
+    resp.assert_status(StatusCode::CREATED);
+    let joined = resp.json().await.value().deserialize::<Group>();
+    assert!(joined.members.contains(&user4.id));
+    assert!(db.is_channel_member(joined.channels[0], user4.id).await.unwrap());
+    assert!(db.get_user_groups(user4.id).await.unwrap().contains(&hidden.id));
+    assert_eq!(db.get_join_requests(hidden.id).await.unwrap(), Vec::new());
+    let log = find_audit_log(&cli, hidden.id, "&action=add_member").await;
+    assert_eq!((log[0].actor, log[0].target), (user4.id, user4.id));
+
+    let log = find_audit_log(&cli, group.id, "&action=approve_join_request").await;
+    assert_eq!((log[0].actor, log[0].target), (user.id, user2.id));
+    let log = find_audit_log(&cli, group.id, "&action=deny_join_request").await;
+    assert_eq!((log[0].target, log[0].reason.as_str()), (user3.id, "banned"));
+    assert_eq!(find_audit_log(&cli, group.id, "&action=update_group_profile").await.len(), 2);
 }
 
-// #[tokio::test]
-// async fn get_group_members() {
-//     let (cli, user) = setup_user_auth().await;
-//     let group = make_group(&cli, auth.clone(), "test").await;
-//     add_group_member
-// }
+async fn find_audit_log(cli: &FakeClient, gid: i64, filters: &str) -> Vec<AuditEntry> {
+    let resp = cli.get(format!("/api/group/audit?gid={gid}{filters}")).send().await;
+    resp.assert_status_is_ok();
+    resp.json().await.value().deserialize::<Vec<AuditEntry>>()
+}
 
 #[tokio::test]
-/// TODO non exhaustive
-async fn put_group_members() {
-    let (cli, user) = setup_user_auth().await;
+async fn group_audit_log() {
+    let (cli, user, db) = setup_user_auth_with_db().await;
+    let (user2, auth2) = user_auth(&cli, "user2", "who@cares.com", "12").await;
+    let user3 = make_user(&cli, "user3", "who3@cares.com", "12").await;
     let group = make_group(&cli, "test").await;
-    let user2 = make_user(&cli, "testeroo", "test2@example.com", "123456").await;
-    
-    let resp = cli.put(format!("/api/group/members?gid={}&uid={}", group.id, user2.id))
-        .header::<&str, &str>("Authorization", "").send().await;
-    resp.assert_status(StatusCode::UNAUTHORIZED);
-
-    let resp = cli.put(format!("/api/group/members?gid={}&uid=", group.id)).send().await;
+    add_group_member(&cli, group.id, user2.id).await;
+    add_group_member(&cli, group.id, user3.id).await;
+    let channel = group.channels[0];
+
+    // Deleting your own message isn't logged, but deleting someone else's is
+    let own = Message { id: gen_id(), channel, author: user.id, content: "hi".to_string(), thread: None };
+    let theirs = Message { id: gen_id(), channel, author: user3.id, content: "hi".to_string(), thread: None };
+    db.create_message(own.clone()).await.unwrap();
+    db.create_message(theirs.clone()).await.unwrap();
+    cli.delete(format!("/api/message?id={}", own.id)).send().await.assert_status_is_ok();
+    cli.delete(format!("/api/message?id={}&reason=rude", theirs.id)).send().await.assert_status_is_ok();
+    let resp = cli.delete(format!("/api/group/members?gid={}&uid={}&reason={}", group.id, user3.id, "a".repeat(MAX_REASON_LEN + 1)))
+        .send().await;
     resp.assert_status(StatusCode::BAD_REQUEST);
+    let resp = cli.delete(format!("/api/group/members?gid={}&uid={}&reason=%20spam%20", group.id, user3.id)).send().await;
+    resp.assert_status_is_ok();
 
-    let resp = cli.put(format!("/api/group/members?gid=12&uid={}", user.id)).send().await;
-    resp.assert_status(StatusCode::NOT_FOUND);
-
-    let resp = cli.put(format!("/api/group/members?gid={}&uid={}", group.id, user2.id)).send().await;
+    let log = find_audit_log(&cli, group.id, "").await;
+    let actions: Vec<_> = log.iter().map(|entry| (entry.actor, entry.action, entry.target, entry.reason.as_str())).collect();
+    assert_eq!(actions, vec![
+        (user.id, AuditAction::RemoveMember, user3.id, "spam"),
+        (user.id, AuditAction::DeleteMessage, theirs.id, "rude"),
+        (user.id, AuditAction::AddMember, user3.id, ""),
+        (user.id, AuditAction::AddMember, user2.id, ""),
+    ]);
+    assert!(log.iter().all(|entry| entry.group == group.id && entry.timestamp > 0));
+
+    // Paging and filters
+    let page = find_audit_log(&cli, group.id, "&limit=2").await;
+    assert_eq!(page, log[..2]);
+    let page = find_audit_log(&cli, group.id, &format!("&limit=2&before={}", page[1].id)).await;
+    assert_eq!(page, log[2..]);
+    assert_eq!(find_audit_log(&cli, group.id, "&action=add_member").await, log[2..]);
+    assert_eq!(find_audit_log(&cli, group.id, &format!("&target={}", user3.id)).await, vec![log[0].clone(), log[2].clone()]);
+    assert_eq!(find_audit_log(&cli, group.id, &format!("&actor={}", user2.id)).await, Vec::new());
+
+    // Only the owner and admins can read it
+    let resp = cli.get(format!("/api/group/audit?gid={}", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status(StatusCode::UNAUTHORIZED);
+    cli.put(format!("/api/group/admin?gid={}&uid={}", group.id, user2.id)).send().await.assert_status_is_ok();
+    let resp = cli.get(format!("/api/group/audit?gid={}&action=add_admin", group.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
     resp.assert_status_is_ok();
-    
-    assert!(find_group(&cli, group.id).await.members.contains(&user2.id));
+    let log = resp.json().await.value().deserialize::<Vec<AuditEntry>>();
+    assert_eq!(log.len(), 1);
+    assert_eq!((log[0].actor, log[0].target), (user.id, user2.id));
+    let resp = cli.get("/api/group/audit?gid=12").send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
 }
+
 #[test]
 /// Test if gen_id() gives unique IDs on successive calls
 /// and if it can be called from multiple threads without error
@@ -362,7 +1713,7 @@ fn test_id_gen() {
     std::thread::sleep(std::time::Duration::from_secs(1));
     let b = gen_id();
     assert_ge!(b, a);
-    let threads: Vec<_> = (0..100).map(|i| std::thread::spawn(move || gen_id())).collect();
+    let threads: Vec<_> = (0..100).map(|_| std::thread::spawn(gen_id)).collect();
     for handle in threads {
         handle.join().unwrap();
     }
@@ -379,16 +1730,62 @@ async fn get_channel() {
     assert_eq!(chan, recv_chan);
 }
 
+#[tokio::test]
+async fn read_private_channel() {
+    let (cli, user, db) = setup_user_auth_with_db().await;
+    let (user2, auth2) = user_auth(&cli, "wehee", "who@cares.com", "12").await;
+    let (outsider, auth3) = user_auth(&cli, "outsider", "who3@cares.com", "12").await;
+    let group = make_group(&cli, "test").await;
+    add_group_member(&cli, group.id, user2.id).await;
+    let secret = make_channel(&cli, group.id, "secret").await;
+    cli.put(format!("/api/channel/private?id={}&val=true", secret.id)).send().await.assert_status_is_ok();
+    let msg = Message { id: gen_id(), channel: secret.id, author: user.id, content: "psst".to_string(), thread: None };
+    db.create_message(msg.clone()).await.unwrap();
+    let paths = [
+        format!("/api/channel/messages?cid={}&num_msgs=10", secret.id),
+        format!("/api/channel/term?cid={}&term=psst&off=0", secret.id),
+        format!("/api/channel/members?id={}", secret.id),
+        format!("/api/channel?id={}", secret.id),
+        format!("/api/channel/overrides?id={}", secret.id),
+    ];
+
+    // Nobody outside the group can read any of its channels
+    let resp = cli.get(format!("/api/channel/messages?cid={}&num_msgs=10", group.channels[0]))
+        .header::<&str, &str>("Authorization", &auth3).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    for path in [format!("/api/group/roles?gid={}", group.id), format!("/api/group/members/roles?gid={}&uid={}", group.id, user.id)] {
+        let resp = cli.get(&path).header::<&str, &str>("Authorization", &auth3).send().await;
+        resp.assert_status(StatusCode::NOT_FOUND);
+        let resp = cli.get(&path).header::<&str, &str>("Authorization", &auth2).send().await;
+        resp.assert_status_is_ok();
+    }
+    let resp = cli.get(format!("/api/channel?id={}", group.channels[0]))
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status_is_ok();
+
+    // ...and members can only read private channels they're in
+    for path in &paths {
+        let resp = cli.get(path).header::<&str, &str>("Authorization", &auth2).send().await;
+        resp.assert_status(StatusCode::NOT_FOUND);
+        cli.get(path).send().await.assert_status_is_ok();
+    }
+    let resp = cli.put(format!("/api/channel/members?cid={}&uid={}", secret.id, outsider.id)).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    assert!(!find_channel(&cli, secret.id).await.members.contains(&outsider.id));
+    cli.put(format!("/api/channel/members?cid={}&uid={}", secret.id, user2.id)).send().await.assert_status_is_ok();
+    let resp = cli.get(&paths[0]).header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(resp.json().await.value().deserialize::<Vec<Message>>(), vec![msg]);
+}
 
 #[tokio::test]
 async fn post_channel_whitebox() {
-    let (cli, _user) = setup_user_auth().await;
+    let (cli, _user, db) = setup_user_auth_with_db().await;
     let group = make_group(&cli, "test").await;
     let chan = make_channel(&cli, group.id, "random").await;
     
-    let db = Cassandra::new("test");
-    assert_eq!(db.get_channel(chan.id).unwrap(), chan);
-    assert!(db.get_group_channels(group.id).unwrap().contains(&chan.id));
+    assert_eq!(db.get_channel(chan.id).await.unwrap(), chan);
+    assert!(db.get_group_channels(group.id).await.unwrap().contains(&chan.id));
 }
 
 
@@ -477,6 +1874,25 @@ async fn get_dms() {
     resp.assert_status_is_ok();
     let dms = resp.json().await.value().deserialize::<Vec<Group>>();
     assert!(contents_eq(dms, vec![dm1.clone(), dm2.clone()]));
+
+    // Leaving a DM takes it off your list, and it goes once everyone has left
+    cli.delete(format!("/api/user/groups?gid={}", dm1.id))
+        .header::<&str, &str>("Authorization", &auth2).send().await.assert_status_is_ok();
+    cli.delete(format!("/api/user/groups?gid={}", dm2.id)).send().await.assert_status_is_ok();
+    cli.delete(format!("/api/user/groups?gid={}", dm2.id))
+        .header::<&str, &str>("Authorization", &auth3).send().await.assert_status_is_ok();
+    let resp = cli.get(format!("/api/group?id={}", dm2.id))
+        .header::<&str, &str>("Authorization", &auth3).send().await;
+    resp.assert_status(StatusCode::NOT_FOUND);
+    let resp = cli.get("/api/user/dms")
+        .header::<&str, &str>("Authorization", &auth2).send().await;
+    resp.assert_status_is_ok();
+    assert_eq!(resp.json().await.value().deserialize::<Vec<Group>>(), Vec::new());
+    let resp = cli.get("/api/user/dms")
+        .header::<&str, &str>("Authorization", &auth3).send().await;
+    resp.assert_status_is_ok();
+    let dms = resp.json().await.value().deserialize::<Vec<Group>>();
+    assert_eq!(dms.iter().map(|dm| dm.id).collect::<Vec<_>>(), vec![dm1.id]);
 }
 
 #[tokio::test]
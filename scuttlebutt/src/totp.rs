@@ -0,0 +1,221 @@
+//! Time-based one-time passwords (RFC 6238) for two-factor authentication, as used by
+//! Google Authenticator and friends: 6 digit codes from HMAC-SHA1 over 30 second steps.
+//!
+//! Users who've enabled it log in in two steps. `/login` checks their password and hands
+//! out a short-lived challenge token instead of a session, which `/login/totp` trades
+//! (along with a code, or one of their recovery codes) for the real tokens.
+use chrono::{DateTime, Local};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// Length of a time step, in seconds
+pub const STEP: i64 = 30;
+
+/// Number of digits in a code
+pub const DIGITS: u32 = 6;
+
+/// How many steps before/after the current one are also accepted, to allow for clock
+/// drift and slow typists
+pub const SKEW: i64 = 1;
+
+/// Number of recovery codes handed out when 2FA is enabled
+pub const RECOVERY_CODES: usize = 10;
+
+/// How long challenge tokens are valid for, in seconds
+pub const CHALLENGE_LIFETIME: i64 = 5 * 60;
+
+/// Issuer shown in authenticator apps
+pub const ISSUER: &str = "Blatherskite";
+
+/// Claims of the challenge token returned by `/login` when the user has 2FA enabled.
+///
+/// `purpose` keeps these from being mixed up with other tokens signed with the same
+/// keys, and since there's no `sid` they can't be used as access tokens.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChallengeClaims {
+    pub id: i64,
+    pub purpose: String,
+    pub exp: DateTime<Local>,
+}
+
+impl ChallengeClaims {
+    const PURPOSE: &'static str = "totp";
+
+    /// Make the claims for a challenge for the given user, expiring after `CHALLENGE_LIFETIME`
+    pub fn new(id: i64) -> Self {
+        Self {
+            id,
+            purpose: Self::PURPOSE.to_string(),
+            exp: Local::now() + chrono::Duration::seconds(CHALLENGE_LIFETIME),
+        }
+    }
+
+    /// Whether these claims are for a challenge, and haven't expired
+    pub fn valid(&self) -> bool {
+        self.purpose == Self::PURPOSE && self.exp > Local::now()
+    }
+}
+
+/// Generate a new random secret, base32 encoded (the form authenticator apps expect)
+pub fn generate_secret() -> String {
+    let bytes: [u8; 20] = rand::thread_rng().gen();
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes)
+}
+
+/// The `otpauth://` URI for a secret, usually shown to the user as a QR code
+///
+/// Arguments:
+/// - `secret`: the base32 secret
+/// - `account`: the name of the account, shown in the authenticator app
+pub fn provisioning_uri(secret: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={secret}&issuer={}&digits={DIGITS}&period={STEP}",
+        percent_encode(ISSUER), percent_encode(account), percent_encode(ISSUER)
+    )
+}
+
+/// Percent-encode everything but unreserved characters, for use in a URI
+fn percent_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{b:02X}"),
+    }).collect()
+}
+
+/// The code for a given time step (HOTP, RFC 4226)
+///
+/// Arguments:
+/// - `key`: the decoded secret
+/// - `step`: the time step (Unix time divided by `STEP`)
+fn code_at(key: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let bin = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    bin % 10u32.pow(DIGITS)
+}
+
+/// The code an authenticator app would show for a secret at a given time
+///
+/// Arguments:
+/// - `secret`: the base32 secret
+/// - `time`: the Unix time
+pub fn generate_code(secret: &str, time: i64) -> Option<String> {
+    let key = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+    Some(format!("{:0width$}", code_at(&key, time / STEP), width = DIGITS as usize))
+}
+
+/// Check a code against a secret.
+///
+/// Returns the time step the code was for, which the caller should store so that the
+/// code (or any earlier one) can't be used again, or None if the code is wrong, malformed
+/// or already used.
+///
+/// Arguments:
+/// - `secret`: the base32 secret
+/// - `code`: the code, as typed by the user
+/// - `last_step`: the last step a code was accepted for
+/// - `now`: the current Unix time
+pub fn check_code(secret: &str, code: &str, last_step: i64, now: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+    let current = now / STEP;
+    (current - SKEW..=current + SKEW)
+        .filter(|step| *step > last_step)
+        .find(|step| code_at(&key, *step) == code)
+}
+
+/// Generate a fresh set of recovery codes
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(|b| char::from(b).to_ascii_lowercase())
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Hash a recovery code for storage. Codes are compared case-insensitively, and with or
+/// without the dash in the middle.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.trim().chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    // The SHA-1 test secret from RFC 6238, "12345678901234567890"
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn test_rfc_vectors() {
+        let key = base32::decode(base32::Alphabet::RFC4648 { padding: false }, RFC_SECRET).unwrap();
+        // RFC 6238 lists 8 digit codes; ours are the last 6 digits of them
+        for (time, code) in [(59, 287082), (1111111109, 81804), (1234567890, 5924), (2000000000, 279037)] {
+            assert_eq!(code_at(&key, time / STEP), code);
+        }
+    }
+
+    #[test]
+    fn test_check_code() {
+        let now = 1234567890;
+        assert_eq!(check_code(RFC_SECRET, "005924", 0, now), Some(now / STEP));
+        // Codes from neighbouring steps are fine...
+        assert_eq!(check_code(RFC_SECRET, "005924", 0, now + STEP), Some(now / STEP));
+        // ...but not ones further away
+        assert_eq!(check_code(RFC_SECRET, "005924", 0, now + 2 * STEP), None);
+        // Codes can't be reused
+        assert_eq!(check_code(RFC_SECRET, "005924", now / STEP, now), None);
+
+        assert_eq!(check_code(RFC_SECRET, "5924", 0, now), None);
+        assert_eq!(check_code(RFC_SECRET, "00592a", 0, now), None);
+        assert_eq!(check_code("not base32!", "005924", 0, now), None);
+    }
+
+    #[test]
+    fn test_secret_round_trip() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 32);
+        let now = chrono::Utc::now().timestamp();
+        let code = generate_code(&secret, now).unwrap();
+        assert_eq!(check_code(&secret, &code, 0, now), Some(now / STEP));
+        assert_eq!(generate_code(RFC_SECRET, 1234567890).unwrap(), "005924");
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert_eq!(codes[0].len(), 11);
+        let hash = hash_recovery_code(&codes[0]);
+        assert_eq!(hash_recovery_code(&codes[0].to_uppercase()), hash);
+        assert_eq!(hash_recovery_code(&codes[0].replace('-', "")), hash);
+        assert_ne!(hash_recovery_code(&codes[1]), hash);
+    }
+
+    #[test]
+    fn test_provisioning_uri() {
+        assert_eq!(
+            provisioning_uri("ABC", "fred smith"),
+            "otpauth://totp/Blatherskite:fred%20smith?secret=ABC&issuer=Blatherskite&digits=6&period=30"
+        );
+    }
+}